@@ -861,6 +861,30 @@ class _FileManagerViewState extends State<FileManagerView> {
         padding: kDesktopMenuPadding,
         dismissOnClicked: true,
       ),
+      MenuEntrySwitch<String>(
+        switchType: SwitchType.scheckbox,
+        text: translate("Preserve File Attributes"),
+        getter: () async {
+          return bind.sessionGetToggleOptionSync(
+              sessionId: _ffi.sessionId, arg: 'preserve-file-attributes');
+        },
+        setter: (bool v) async {
+          await bind.sessionToggleOption(
+              sessionId: _ffi.sessionId, value: 'preserve-file-attributes');
+        },
+        padding: kDesktopMenuPadding,
+        dismissOnClicked: true,
+      ),
+      MenuEntryButton(
+          childBuilder: (style) =>
+              Text(translate("Mirror Folder"), style: style),
+          proc: () {
+            controller.mirrorDir(
+                selectedItems, controller.getOtherSideDirectoryData());
+            selectedItems.clear();
+          },
+          padding: kDesktopMenuPadding,
+          dismissOnClicked: true),
       MenuEntryButton(
           childBuilder: (style) => Text(translate("Select All"), style: style),
           proc: () => setState(() =>
@@ -22,6 +22,34 @@ class _PortForward {
       : localPort = json[0] as int,
         remoteHost = json[1] as String,
         remotePort = json[2] as int;
+
+  _PortForward.fromText(String text)
+      : localPort = 0,
+        remoteHost = '',
+        remotePort = 0 {
+    final i = text.indexOf(':');
+    final j = text.lastIndexOf(':');
+    if (i <= 0 || j <= i) {
+      throw FormatException(text);
+    }
+    localPort = int.parse(text.substring(0, i).trim());
+    remotePort = int.parse(text.substring(j + 1).trim());
+    remoteHost = text.substring(i + 1, j).trim();
+    if (remoteHost.isEmpty) {
+      remoteHost = 'localhost';
+    }
+    if (localPort <= 0 ||
+        localPort > 65535 ||
+        remotePort < 0 ||
+        remotePort > 65535) {
+      throw FormatException(text);
+    }
+  }
+
+  List<dynamic> toJson() => [localPort, remoteHost, remotePort];
+
+  @override
+  String toString() => '$localPort:$remoteHost:$remotePort';
 }
 
 class PortForwardPage extends StatefulWidget {
@@ -51,6 +79,10 @@ class _PortForwardPageState extends State<PortForwardPage>
   final TextEditingController remoteHostController = TextEditingController();
   final TextEditingController remotePortController = TextEditingController();
   RxList<_PortForward> pfs = RxList.empty(growable: true);
+  // by profile name, the reverse forwards are (remote port, local host, local port)
+  RxMap<String, List<_PortForward>> profiles = RxMap();
+  RxMap<String, List<_PortForward>> reverseProfiles = RxMap();
+  RxSet<String> runningProfiles = RxSet();
   late FFI _ffi;
 
   @override
@@ -84,6 +116,7 @@ class _PortForwardPageState extends State<PortForwardPage>
       body: FutureBuilder(future: () async {
         if (!widget.isRDP) {
           refreshTunnelConfig();
+          refreshProfiles();
         }
       }(), builder: (context, snapshot) {
         if (snapshot.connectionState == ConnectionState.done) {
@@ -105,6 +138,7 @@ class _PortForwardPageState extends State<PortForwardPage>
                         widget.isRDP ? buildRdp(context) : buildTunnel(context),
                   ),
                 ),
+                if (!widget.isRDP) buildProfiles(context),
               ],
             ),
           );
@@ -279,6 +313,203 @@ class _PortForwardPageState extends State<PortForwardPage>
     pfs.value = result;
   }
 
+  void refreshProfiles() {
+    profiles.value = parseProfiles(
+        bind.sessionGetPortForwardProfiles(sessionId: _ffi.sessionId));
+    reverseProfiles.value = parseProfiles(
+        bind.sessionGetReversePortForwards(sessionId: _ffi.sessionId));
+  }
+
+  Map<String, List<_PortForward>> parseProfiles(String json) {
+    if (json.isEmpty) return {};
+    final Map<String, dynamic> map = jsonDecode(json);
+    return map.map((name, forwards) => MapEntry(name,
+        (forwards as List).map((e) => _PortForward.fromJson(e)).toList()));
+  }
+
+  Widget buildProfiles(BuildContext context) {
+    return Container(
+      constraints: const BoxConstraints(maxHeight: 200),
+      decoration: BoxDecoration(
+          color: Theme.of(context).colorScheme.background,
+          border: Border.all(width: 1, color: MyTheme.border)),
+      child: Obx(() {
+        final names = {...profiles.keys, ...reverseProfiles.keys}.toList()
+          ..sort();
+        final running = runningProfiles.toSet();
+        return ListView.builder(
+            controller: ScrollController(),
+            shrinkWrap: true,
+            itemCount: names.length + 1,
+            itemBuilder: ((context, index) {
+              if (index == 0) {
+                return Container(
+                  height: 35,
+                  color: Theme.of(context).scaffoldBackgroundColor,
+                  child: Row(children: [
+                    Expanded(
+                        child: Text(translate('Port Forward Profiles'))
+                            .marginOnly(left: _kTextLeftMargin)),
+                    TextButton(
+                      onPressed: () => showProfileDialog(null),
+                      child: Text(translate('Add')),
+                    ).marginSymmetric(horizontal: 10),
+                  ]),
+                );
+              }
+              final name = names[index - 1];
+              return buildProfileRow(
+                  context, name, running.contains(name), index - 1);
+            }));
+      }),
+    ).marginOnly(top: 8);
+  }
+
+  Widget buildProfileRow(
+      BuildContext context, String name, bool running, int index) {
+    final forwards = profiles[name] ?? [];
+    final reverseForwards = reverseProfiles[name] ?? [];
+    return Container(
+      height: 45,
+      color: index % 2 == 0
+          ? MyTheme.currentThemeMode() == ThemeMode.dark
+              ? const Color(0xFF202020)
+              : const Color(0xFFF4F5F6)
+          : Theme.of(context).colorScheme.background,
+      child: Row(children: [
+        Expanded(
+            child: Text(name, style: const TextStyle(fontSize: 16))
+                .marginOnly(left: _kTextLeftMargin)),
+        Expanded(
+          flex: 3,
+          child: Text(
+            [
+              ...forwards.map((e) => e.toString()),
+              ...reverseForwards.map((e) => '-R $e'),
+            ].join(', '),
+            overflow: TextOverflow.ellipsis,
+          ),
+        ),
+        IconButton(
+          tooltip: translate(running ? 'Stop' : 'Start'),
+          icon: Icon(running ? Icons.stop : Icons.play_arrow),
+          onPressed: () async {
+            if (running) {
+              await bind.sessionStopPortForwardProfile(
+                  sessionId: _ffi.sessionId, name: name);
+              runningProfiles.remove(name);
+            } else {
+              await bind.sessionStartPortForwardProfile(
+                  sessionId: _ffi.sessionId, name: name);
+              runningProfiles.add(name);
+            }
+          },
+        ),
+        IconButton(
+          icon: const Icon(Icons.edit),
+          onPressed: running ? null : () => showProfileDialog(name),
+        ),
+        IconButton(
+          tooltip: translate('Delete'),
+          icon: const Icon(Icons.close),
+          onPressed: running
+              ? null
+              : () async {
+                  await setProfile(name, [], []);
+                  refreshProfiles();
+                },
+        ).marginOnly(right: 10),
+      ]),
+    );
+  }
+
+  Future<void> setProfile(String name, List<_PortForward> forwards,
+      List<_PortForward> reverseForwards) async {
+    await bind.sessionSetPortForwardProfile(
+        sessionId: _ffi.sessionId, name: name, forwards: jsonEncode(forwards));
+    await bind.sessionSetReversePortForwards(
+        sessionId: _ffi.sessionId,
+        name: name,
+        forwards: jsonEncode(reverseForwards));
+  }
+
+  // `name` is null to add a profile
+  void showProfileDialog(String? name) {
+    final nameController = TextEditingController(text: name);
+    final forwardsController = TextEditingController(
+        text: (profiles[name] ?? []).map((e) => e.toString()).join('\n'));
+    final reverseController = TextEditingController(
+        text:
+            (reverseProfiles[name] ?? []).map((e) => e.toString()).join('\n'));
+    String? errorText;
+    _ffi.dialogManager.show((setState, close, context) {
+      List<_PortForward> parse(String text) => text
+          .split('\n')
+          .where((e) => e.trim().isNotEmpty)
+          .map((e) => _PortForward.fromText(e))
+          .toList();
+
+      submit() async {
+        final newName = nameController.text.trim();
+        final List<_PortForward> forwards;
+        final List<_PortForward> reverseForwards;
+        try {
+          forwards = parse(forwardsController.text);
+          reverseForwards = parse(reverseController.text);
+        } catch (_) {
+          setState(() => errorText = translate('Invalid format'));
+          return;
+        }
+        if (newName.isEmpty || forwards.isEmpty && reverseForwards.isEmpty) {
+          setState(() => errorText = translate('Invalid format'));
+          return;
+        }
+        await setProfile(newName, forwards, reverseForwards);
+        close();
+        refreshProfiles();
+      }
+
+      cancel() => close(false);
+
+      textField(TextEditingController controller, String label,
+              {bool enabled = true, int maxLines = 5}) =>
+          TextField(
+            controller: controller,
+            enabled: enabled,
+            minLines: 1,
+            maxLines: maxLines,
+            decoration: InputDecoration(labelText: translate(label)),
+          ).marginOnly(bottom: 10);
+
+      return CustomAlertDialog(
+        title: Text(translate('Port Forward Profiles')),
+        content: SizedBox(
+          width: 400,
+          child: Column(
+            mainAxisSize: MainAxisSize.min,
+            crossAxisAlignment: CrossAxisAlignment.start,
+            children: [
+              textField(nameController, 'Name',
+                  enabled: name == null, maxLines: 1),
+              textField(forwardsController, 'Forwards'),
+              textField(reverseController, 'Reverse Forwards'),
+              Text(translate('port_forward_profile_tip'),
+                  style: const TextStyle(fontSize: 12)),
+              if (errorText != null)
+                Text(errorText!, style: const TextStyle(color: Colors.red))
+                    .marginOnly(top: 10),
+            ],
+          ),
+        ),
+        actions: [
+          dialogButton('Cancel', onPressed: cancel, isOutline: true),
+          dialogButton('OK', onPressed: submit),
+        ],
+        onCancel: cancel,
+      );
+    });
+  }
+
   buildRdp(BuildContext context) {
     text1(String label) => Expanded(
         child: Text(translate(label)).marginOnly(left: _kTextLeftMargin));
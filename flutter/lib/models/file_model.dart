@@ -145,6 +145,77 @@ class FileModel {
     }
   }
 
+  /// The mirror starts after the plan is confirmed, the extraneous files of the target are
+  /// only deleted if it is checked.
+  Future<void> showMirrorPlan(Map<String, dynamic> evt) async {
+    final id = int.tryParse(evt['id']) ?? 0;
+    final plan = jsonDecode(evt['plan']);
+    final List<dynamic> adds = plan['adds'] ?? [];
+    final List<dynamic> updates = plan['updates'] ?? [];
+    final List<dynamic> deletes = plan['deletes'] ?? [];
+    var deleteExtraneous = false;
+    final confirmed = await parent.target?.dialogManager.show<bool>(
+        (setState, Function(bool v) close, context) {
+      cancel() => close(false);
+      submit() => close(true);
+      return CustomAlertDialog(
+        title: Text(translate("Mirror Folder")),
+        contentBoxConstraints:
+            BoxConstraints(minHeight: 100, minWidth: 400, maxWidth: 400),
+        content: Column(
+          crossAxisAlignment: CrossAxisAlignment.start,
+          mainAxisSize: MainAxisSize.min,
+          children: [
+            Text("${translate("Files to add")}: ${adds.length}"),
+            Text("${translate("Files to update")}: ${updates.length}"),
+            Text(
+                "${translate("Extraneous files on the target")}: ${deletes.length}"),
+            ...deletes.take(5).map((e) => Text(e['name'] ?? '',
+                overflow: TextOverflow.ellipsis,
+                style: const TextStyle(fontWeight: FontWeight.w500))),
+            if (deletes.length > 5) const Text('...'),
+            if (deletes.isNotEmpty)
+              CheckboxListTile(
+                contentPadding: const EdgeInsets.all(0),
+                dense: true,
+                controlAffinity: ListTileControlAffinity.leading,
+                title: Text(translate("Delete extraneous files on the target")),
+                value: deleteExtraneous,
+                onChanged: (v) {
+                  if (v == null) return;
+                  setState(() => deleteExtraneous = v);
+                },
+              ),
+          ],
+        ),
+        actions: [
+          dialogButton(
+            "Cancel",
+            icon: Icon(Icons.close_rounded),
+            onPressed: cancel,
+            isOutline: true,
+          ),
+          dialogButton(
+            "OK",
+            icon: Icon(Icons.done_rounded),
+            onPressed: submit,
+          ),
+        ],
+        onSubmit: submit,
+        onCancel: cancel,
+      );
+    }, useAnimation: false);
+    if (confirmed == true) {
+      await bind.sessionStartMirror(
+          sessionId: sessionId,
+          actId: id,
+          deleteExtraneous: deleteExtraneous);
+    } else {
+      await jobController.cancelJob(id);
+      jobController.removeJob(id);
+    }
+  }
+
   bool fileConfirmCheckboxRemember = false;
 
   Future<bool?> showFileConfirmDialog(
@@ -449,6 +520,8 @@ class FileController {
     final toPath = otherSideData.directory.path;
     final isWindows = otherSideData.options.isWindows;
     final showHidden = otherSideData.options.showHidden;
+    final preserveAttributes = bind.sessionGetToggleOptionSync(
+        sessionId: sessionId, arg: 'preserve-file-attributes');
     for (var from in items.items) {
       final jobID = jobController.add(from, isRemoteToLocal);
       bind.sessionSendFiles(
@@ -458,12 +531,36 @@ class FileController {
           to: PathUtil.join(toPath, from.name, isWindows),
           fileNum: 0,
           includeHidden: showHidden,
-          isRemote: isRemoteToLocal);
+          isRemote: isRemoteToLocal,
+          preserveAttributes: preserveAttributes);
       debugPrint(
           "path: ${from.path}, toPath: $toPath, to: ${PathUtil.join(toPath, from.name, isWindows)}");
     }
   }
 
+  /// Mirror the selected directory to the current directory of the other side, the plan is
+  /// shown before anything is transferred.
+  void mirrorDir(SelectedItems items, DirectoryData otherSideData) {
+    if (items.isLocal != isLocal || items.items.length != 1) {
+      return;
+    }
+    final from = items.items.first;
+    if (!from.isDirectory) {
+      return;
+    }
+    final isRemoteToLocal = !isLocal;
+    final to = PathUtil.join(otherSideData.directory.path, from.name,
+        otherSideData.options.isWindows);
+    final jobID = jobController.add(from, isRemoteToLocal);
+    bind.sessionMirrorDir(
+        sessionId: sessionId,
+        actId: jobID,
+        path: from.path,
+        to: to,
+        includeHidden: otherSideData.options.showHidden,
+        isRemote: isRemoteToLocal);
+  }
+
   bool _removeCheckboxRemember = false;
 
   Future<void> removeAction(SelectedItems items) async {
@@ -730,6 +827,10 @@ class JobController {
     await bind.sessionCancelJob(sessionId: sessionId, actId: id);
   }
 
+  void removeJob(int id) {
+    jobTable.removeWhere((element) => element.id == id);
+  }
+
   void loadLastJob(Map<String, dynamic> evt) {
     debugPrint("load last job: $evt");
     Map<String, dynamic> jobDetail = json.decode(evt['value']);
@@ -739,6 +840,7 @@ class JobController {
     bool showHidden = jobDetail['show_hidden'];
     int fileNum = jobDetail['file_num'];
     bool isRemote = jobDetail['is_remote'];
+    bool preserveAttributes = jobDetail['preserve_attributes'] ?? false;
     final currJobId = JobController.jobID.next();
     String fileName = path.basename(isRemote ? remote : to);
     var jobProgress = JobProgress()
@@ -760,6 +862,7 @@ class JobController {
       path: isRemote ? remote : to,
       to: isRemote ? to : remote,
       fileNum: fileNum,
+      preserveAttributes: preserveAttributes,
     );
   }
 
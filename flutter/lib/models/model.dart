@@ -313,6 +313,8 @@ class FfiModel with ChangeNotifier {
         parent.target?.fileModel.jobController.loadLastJob(evt);
       } else if (name == 'update_folder_files') {
         parent.target?.fileModel.jobController.updateFolderFiles(evt);
+      } else if (name == 'mirror_plan') {
+        parent.target?.fileModel.showMirrorPlan(evt);
       } else if (name == 'add_connection') {
         parent.target?.serverModel.addConnection(evt);
       } else if (name == 'on_client_remove') {
@@ -352,6 +354,10 @@ class FfiModel with ChangeNotifier {
         parent.target?.chatModel.onVoiceCallIncoming();
       } else if (name == 'update_voice_call_state') {
         parent.target?.serverModel.updateVoiceCallState(evt);
+      } else if (name == 'reverse_forward_request') {
+        parent.target?.serverModel.showReverseForwardRequest(evt);
+      } else if (name == 'port_forward_request') {
+        parent.target?.serverModel.showPortForwardRequest(evt);
       } else if (name == 'fingerprint') {
         FingerprintState.find(peerId).value = evt['fingerprint'] ?? '';
       } else if (name == 'plugin_manager') {
@@ -687,6 +687,80 @@ class ServerModel with ChangeNotifier {
       debugPrint("updateVoiceCallState failed: $e");
     }
   }
+
+  void showReverseForwardRequest(Map<String, dynamic> evt) {
+    final id = int.tryParse(evt['id'].toString()) ?? 0;
+    final listenId = int.tryParse(evt['listen_id'].toString()) ?? 0;
+    final port = evt['port'].toString();
+    final index = _clients.indexWhere((client) => client.id == id);
+    if (index < 0) return;
+    final client = _clients[index];
+    parent.target?.dialogManager.show((setState, close, context) {
+      response(bool accepted) {
+        bind.cmReverseForwardResponse(
+            connId: id, listenId: listenId, accepted: accepted);
+        close();
+      }
+
+      cancel() => response(false);
+      submit() => response(true);
+
+      return CustomAlertDialog(
+        title: Text(translate("Port Forwarding")),
+        content: Column(
+          mainAxisSize: MainAxisSize.min,
+          crossAxisAlignment: CrossAxisAlignment.start,
+          children: [
+            Text('${translate("reverse_port_forward_tip")}: $port'),
+            ClientInfo(client),
+          ],
+        ),
+        actions: [
+          dialogButton("Dismiss", onPressed: cancel, isOutline: true),
+          dialogButton("Accept", onPressed: submit),
+        ],
+        onSubmit: submit,
+        onCancel: cancel,
+      );
+    }, tag: 'reverse-forward-$id-$listenId');
+  }
+
+  void showPortForwardRequest(Map<String, dynamic> evt) {
+    final id = int.tryParse(evt['id'].toString()) ?? 0;
+    final streamId = int.tryParse(evt['stream_id'].toString()) ?? 0;
+    final target = evt['target'].toString();
+    final index = _clients.indexWhere((client) => client.id == id);
+    if (index < 0) return;
+    final client = _clients[index];
+    parent.target?.dialogManager.show((setState, close, context) {
+      response(bool accepted) {
+        bind.cmPortForwardResponse(
+            connId: id, streamId: streamId, accepted: accepted);
+        close();
+      }
+
+      cancel() => response(false);
+      submit() => response(true);
+
+      return CustomAlertDialog(
+        title: Text(translate("Port Forwarding")),
+        content: Column(
+          mainAxisSize: MainAxisSize.min,
+          crossAxisAlignment: CrossAxisAlignment.start,
+          children: [
+            Text('${translate("port_forward_request_tip")}: $target'),
+            ClientInfo(client),
+          ],
+        ),
+        actions: [
+          dialogButton("Dismiss", onPressed: cancel, isOutline: true),
+          dialogButton("Accept", onPressed: submit),
+        ],
+        onSubmit: submit,
+        onCancel: cancel,
+      );
+    }, tag: 'port-forward-$id-$streamId');
+  }
 }
 
 enum ClientType {
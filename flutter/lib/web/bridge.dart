@@ -480,6 +480,26 @@ class RustdeskImpl {
       required int fileNum,
       required bool includeHidden,
       required bool isRemote,
+      required bool preserveAttributes,
+      dynamic hint}) {
+    throw UnimplementedError();
+  }
+
+  Future<void> sessionMirrorDir(
+      {required UuidValue sessionId,
+      required int actId,
+      required String path,
+      required String to,
+      required bool includeHidden,
+      required bool isRemote,
+      dynamic hint}) {
+    throw UnimplementedError();
+  }
+
+  Future<void> sessionStartMirror(
+      {required UuidValue sessionId,
+      required int actId,
+      required bool deleteExtraneous,
       dynamic hint}) {
     throw UnimplementedError();
   }
@@ -564,6 +584,7 @@ class RustdeskImpl {
       required int fileNum,
       required bool includeHidden,
       required bool isRemote,
+      required bool preserveAttributes,
       dynamic hint}) {
     throw UnimplementedError();
   }
@@ -989,6 +1010,42 @@ class RustdeskImpl {
     throw UnimplementedError();
   }
 
+  String sessionGetPortForwardProfiles(
+      {required UuidValue sessionId, dynamic hint}) {
+    throw UnimplementedError();
+  }
+
+  Future<void> sessionSetPortForwardProfile(
+      {required UuidValue sessionId,
+      required String name,
+      required String forwards,
+      dynamic hint}) {
+    throw UnimplementedError();
+  }
+
+  String sessionGetReversePortForwards(
+      {required UuidValue sessionId, dynamic hint}) {
+    throw UnimplementedError();
+  }
+
+  Future<void> sessionSetReversePortForwards(
+      {required UuidValue sessionId,
+      required String name,
+      required String forwards,
+      dynamic hint}) {
+    throw UnimplementedError();
+  }
+
+  Future<void> sessionStartPortForwardProfile(
+      {required UuidValue sessionId, required String name, dynamic hint}) {
+    throw UnimplementedError();
+  }
+
+  Future<void> sessionStopPortForwardProfile(
+      {required UuidValue sessionId, required String name, dynamic hint}) {
+    throw UnimplementedError();
+  }
+
   Future<void> sessionNewRdp({required UuidValue sessionId, dynamic hint}) {
     throw UnimplementedError();
   }
@@ -1244,6 +1301,22 @@ class RustdeskImpl {
     throw UnimplementedError();
   }
 
+  Future<void> cmReverseForwardResponse(
+      {required int connId,
+      required int listenId,
+      required bool accepted,
+      dynamic hint}) {
+    throw UnimplementedError();
+  }
+
+  Future<void> cmPortForwardResponse(
+      {required int connId,
+      required int streamId,
+      required bool accepted,
+      dynamic hint}) {
+    throw UnimplementedError();
+  }
+
   Future<void> cmSwitchPermission(
       {required int connId,
       required String name,
@@ -1592,6 +1665,44 @@ class RustdeskImpl {
     throw UnimplementedError();
   }
 
+  Future<String> mainGenerate2FaDevice(
+      {required String name,
+      required String algorithm,
+      required int digits,
+      required int period,
+      required bool hotp,
+      dynamic hint}) {
+    throw UnimplementedError();
+  }
+
+  Future<String> mainList2FaDevices({dynamic hint}) {
+    throw UnimplementedError();
+  }
+
+  Future<bool> mainRevoke2FaDevice({required String name, dynamic hint}) {
+    throw UnimplementedError();
+  }
+
+  Future<List<String>> mainGenerate2FaRecoveryCodes({dynamic hint}) {
+    throw UnimplementedError();
+  }
+
+  Future<int> mainCount2FaRecoveryCodes({dynamic hint}) {
+    throw UnimplementedError();
+  }
+
+  Future<String> mainGenAuthKey({dynamic hint}) {
+    throw UnimplementedError();
+  }
+
+  String mainGetAuthPublicKey({dynamic hint}) {
+    throw UnimplementedError();
+  }
+
+  Future<void> mainRemoveAuthKey({dynamic hint}) {
+    throw UnimplementedError();
+  }
+
   String mainGetHardOption({required String key, dynamic hint}) {
     throw UnimplementedError();
   }
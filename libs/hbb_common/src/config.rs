@@ -25,6 +25,7 @@ use crate::{
         decrypt_str_or_original, decrypt_vec_or_original, encrypt_str_or_original,
         encrypt_vec_or_original, symmetric_crypt,
     },
+    socket_client::check_port,
 };
 
 pub const RENDEZVOUS_TIMEOUT: u64 = 12_000;
@@ -57,6 +58,7 @@ lazy_static::lazy_static! {
     static ref CONFIG2: RwLock<Config2> = RwLock::new(Config2::load());
     static ref LOCAL_CONFIG: RwLock<LocalConfig> = RwLock::new(LocalConfig::load());
     static ref ONLINE: Mutex<HashMap<String, i64>> = Default::default();
+    static ref SERVER_HEALTH: Mutex<HashMap<String, ServerHealth>> = Default::default();
     pub static ref PROD_RENDEZVOUS_SERVER: RwLock<String> = RwLock::new(match option_env!("RENDEZVOUS_SERVER") {
         Some(key) if !key.is_empty() => key,
         _ => "",
@@ -111,6 +113,8 @@ pub const RS_PUB_KEY: &str = match option_env!("RS_PUB_KEY") {
 
 pub const RENDEZVOUS_PORT: i32 = 21116;
 pub const RELAY_PORT: i32 = 21117;
+// a server answering slower than this is only used if the faster ones are down, in microseconds
+const SLOW_SERVER_LATENCY: i64 = 1_000_000;
 
 macro_rules! serde_field_string {
     ($default_func:ident, $de_func:ident, $default_expr:expr) => {
@@ -271,6 +275,19 @@ pub struct PeerConfig {
     pub allow_swap_key: AllowSwapKey,
     #[serde(default, deserialize_with = "deserialize_vec_i32_string_i32")]
     pub port_forwards: Vec<(i32, String, i32)>,
+    // named sets of (local port, remote host, remote port), carried by one connection,
+    // a remote port of 0 makes the local port a socks5 proxy to the remote side
+    #[serde(
+        default,
+        deserialize_with = "deserialize_hashmap_string_vec_i32_string_i32"
+    )]
+    pub port_forward_profiles: HashMap<String, Vec<(i32, String, i32)>>,
+    // per profile, (remote listen port, local host, local port) to forward back to this side
+    #[serde(
+        default,
+        deserialize_with = "deserialize_hashmap_string_vec_i32_string_i32"
+    )]
+    pub reverse_port_forwards: HashMap<String, Vec<(i32, String, i32)>>,
     #[serde(default, deserialize_with = "deserialize_i32")]
     pub direct_failures: i32,
     #[serde(flatten)]
@@ -352,6 +369,8 @@ impl Default for PeerConfig {
             privacy_mode: Default::default(),
             allow_swap_key: Default::default(),
             port_forwards: Default::default(),
+            port_forward_profiles: Default::default(),
+            reverse_port_forwards: Default::default(),
             direct_failures: Default::default(),
             disable_audio: Default::default(),
             disable_clipboard: Default::default(),
@@ -392,6 +411,30 @@ pub struct TransferSerde {
     pub read_jobs: Vec<String>,
 }
 
+/// Result of the health checks of a rendezvous or relay server.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ServerHealth {
+    /// Latency of the last successful check in microseconds, 0 if not checked yet.
+    pub latency: i64,
+    /// Checks failed in a row.
+    pub failures: u32,
+}
+
+impl ServerHealth {
+    // healthy servers keep the configured order, slow and unknown ones come next, failing ones last
+    fn rank(&self) -> (u8, u32) {
+        if self.failures > 0 {
+            (3, self.failures)
+        } else if self.latency > SLOW_SERVER_LATENCY {
+            (1, 0)
+        } else if self.latency > 0 {
+            (0, 0)
+        } else {
+            (2, 0)
+        }
+    }
+}
+
 #[inline]
 pub fn get_online_state() -> i64 {
     *ONLINE.lock().unwrap().values().max().unwrap_or(&0)
@@ -696,7 +739,13 @@ impl Config {
     pub fn get_rendezvous_server() -> String {
         let mut rendezvous_server = EXE_RENDEZVOUS_SERVER.read().unwrap().clone();
         if rendezvous_server.is_empty() {
-            rendezvous_server = Self::get_option("custom-rendezvous-server");
+            rendezvous_server = Self::rank_servers(
+                Self::get_server_list("custom-rendezvous-server"),
+                RENDEZVOUS_PORT,
+            )
+            .drain(..)
+            .next()
+            .unwrap_or_default();
         }
         if rendezvous_server.is_empty() {
             rendezvous_server = PROD_RENDEZVOUS_SERVER.read().unwrap().clone();
@@ -721,9 +770,9 @@ impl Config {
         if !s.is_empty() {
             return vec![s];
         }
-        let s = Self::get_option("custom-rendezvous-server");
-        if !s.is_empty() {
-            return vec![s];
+        let ss = Self::get_server_list("custom-rendezvous-server");
+        if !ss.is_empty() {
+            return ss;
         }
         let s = PROD_RENDEZVOUS_SERVER.read().unwrap().clone();
         if !s.is_empty() {
@@ -743,12 +792,69 @@ impl Config {
         return RENDEZVOUS_SERVERS.iter().map(|x| x.to_string()).collect();
     }
 
+    /// Servers of an option holding a comma separated list, e.g. `custom-rendezvous-server`
+    /// or `relay-server`, in the configured order.
+    pub fn get_server_list(option: &str) -> Vec<String> {
+        Self::get_option(option)
+            .split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_owned())
+            .collect()
+    }
+
+    /// The relay server to use from the `relay-server` option, empty if not set.
+    pub fn get_relay_server() -> String {
+        Self::rank_servers(Self::get_server_list("relay-server"), RELAY_PORT)
+            .drain(..)
+            .next()
+            .unwrap_or_default()
+    }
+
+    /// Sorts the servers by their health, keeping the configured order among equally healthy ones.
+    pub fn rank_servers(mut servers: Vec<String>, port: i32) -> Vec<String> {
+        let health = SERVER_HEALTH.lock().unwrap();
+        servers.sort_by_key(|host| {
+            health
+                .get(&check_port(host, port))
+                .copied()
+                .unwrap_or_default()
+                .rank()
+        });
+        servers
+    }
+
+    /// Records a health check, `None` if the server could not be reached.
+    pub fn update_server_health(host: &str, port: i32, latency: Option<i64>) {
+        let mut health = SERVER_HEALTH.lock().unwrap();
+        let health = health.entry(check_port(host, port)).or_default();
+        match latency {
+            Some(latency) => {
+                health.latency = latency;
+                health.failures = 0;
+            }
+            None => health.failures = health.failures.saturating_add(1),
+        }
+    }
+
+    pub fn get_server_health(host: &str, port: i32) -> ServerHealth {
+        SERVER_HEALTH
+            .lock()
+            .unwrap()
+            .get(&check_port(host, port))
+            .copied()
+            .unwrap_or_default()
+    }
+
     pub fn reset_online() {
         *ONLINE.lock().unwrap() = Default::default();
     }
 
     pub fn update_latency(host: &str, latency: i64) {
         ONLINE.lock().unwrap().insert(host.to_owned(), latency);
+        if latency != 0 {
+            Self::update_server_health(host, RENDEZVOUS_PORT, Some(latency).filter(|x| *x > 0));
+        }
         let mut host = "".to_owned();
         let mut delay = i64::MAX;
         for (tmp_host, tmp_delay) in ONLINE.lock().unwrap().iter() {
@@ -1510,6 +1616,85 @@ impl LanPeers {
     }
 }
 
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct LoginFailure {
+    // ms
+    #[serde(default)]
+    pub window_start: i64,
+    #[serde(default)]
+    pub window_count: i32,
+    #[serde(default)]
+    pub total: i32,
+    #[serde(default)]
+    pub lockouts: i32,
+    // ms
+    #[serde(default)]
+    pub locked_until: i64,
+}
+
+/// Login failures of the password and the 2FA code, keyed by `ip:<ip>` or `id:<peer id>`.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct LoginFailures {
+    #[serde(default)]
+    pub password: HashMap<String, LoginFailure>,
+    #[serde(default)]
+    pub tfa: HashMap<String, LoginFailure>,
+}
+
+impl LoginFailures {
+    pub fn load() -> LoginFailures {
+        let _lock = CONFIG.read().unwrap();
+        match confy::load_path(Config::file_("_login_failures")) {
+            Ok(failures) => failures,
+            Err(err) => {
+                log::error!("Failed to load login failures: {}", err);
+                Default::default()
+            }
+        }
+    }
+
+    pub fn store(&self) {
+        if let Err(err) = store_path(Config::file_("_login_failures"), self) {
+            log::error!("Failed to store login failures: {}", err);
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RecordUpload {
+    #[serde(default, deserialize_with = "deserialize_string")]
+    pub filepath: String,
+    // bytes acknowledged by the server
+    #[serde(default)]
+    pub acked: u64,
+}
+
+/// Recordings not uploaded completely, resumed after a restart.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct RecordUploads {
+    #[serde(default)]
+    pub files: Vec<RecordUpload>,
+}
+
+impl RecordUploads {
+    pub fn load() -> RecordUploads {
+        let _lock = CONFIG.read().unwrap();
+        match confy::load_path(Config::file_("_record_uploads")) {
+            Ok(uploads) => uploads,
+            Err(err) => {
+                log::error!("Failed to load record uploads: {}", err);
+                Default::default()
+            }
+        }
+    }
+
+    pub fn store(&self) {
+        if let Err(err) = store_path(Config::file_("_record_uploads"), self) {
+            log::error!("Failed to store record uploads: {}", err);
+        }
+    }
+}
+
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct HwCodecConfig {
     #[serde(default, deserialize_with = "deserialize_string")]
@@ -1899,6 +2084,10 @@ deserialize_default!(deserialize_keypair, KeyPair);
 deserialize_default!(deserialize_size, Size);
 deserialize_default!(deserialize_hashmap_string_string, HashMap<String, String>);
 deserialize_default!(deserialize_hashmap_string_bool,  HashMap<String, bool>);
+deserialize_default!(
+    deserialize_hashmap_string_vec_i32_string_i32,
+    HashMap<String, Vec<(i32, String, i32)>>
+);
 deserialize_default!(deserialize_hashmap_resolutions, HashMap<String, Resolution>);
 
 #[inline]
@@ -2214,4 +2403,33 @@ mod tests {
             assert_eq!(cfg, Ok(cfg_to_compare), "Failed to test wrong_field_str");
         }
     }
+
+    #[test]
+    fn test_rank_servers() {
+        let servers: Vec<String> = ["rank-a", "rank-b", "rank-c", "rank-d"]
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        assert_eq!(
+            Config::rank_servers(servers.clone(), RENDEZVOUS_PORT),
+            servers
+        );
+        Config::update_server_health("rank-a", RENDEZVOUS_PORT, None);
+        Config::update_server_health("rank-b:21116", RENDEZVOUS_PORT, Some(2_000_000));
+        Config::update_server_health("rank-c", RENDEZVOUS_PORT, Some(20_000));
+        assert_eq!(
+            Config::rank_servers(servers.clone(), RENDEZVOUS_PORT),
+            vec!["rank-c", "rank-b", "rank-d", "rank-a"]
+        );
+        Config::update_server_health("rank-a", RENDEZVOUS_PORT, Some(10_000));
+        assert_eq!(
+            Config::rank_servers(servers.clone(), RENDEZVOUS_PORT),
+            vec!["rank-a", "rank-c", "rank-b", "rank-d"]
+        );
+        // the relay port is tracked on its own
+        assert_eq!(
+            Config::get_server_health("rank-c", RELAY_PORT),
+            ServerHealth::default()
+        );
+    }
 }
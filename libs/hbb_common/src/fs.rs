@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 #[cfg(windows)]
 use std::os::windows::prelude::*;
 use std::path::{Path, PathBuf};
@@ -5,6 +6,7 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use serde_derive::{Deserialize, Serialize};
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use tokio::{fs::File, io::*};
 
 use crate::{anyhow::anyhow, bail, get_version_number, message_proto::*, ResultType, Stream};
@@ -126,24 +128,36 @@ fn read_dir_recursive(
     path: &PathBuf,
     prefix: &Path,
     include_hidden: bool,
+    with_attributes: bool,
 ) -> ResultType<Vec<FileEntry>> {
     let mut files = Vec::new();
     if path.is_dir() {
-        // to-do: symbol link handling, cp the link rather than the content
-        // to-do: file mode, for unix
+        // symbol links are skipped, unless listed with the attributes to be recreated
         let fd = read_dir(path, include_hidden)?;
         for entry in fd.entries.iter() {
             match entry.entry_type.enum_value() {
                 Ok(FileType::File) => {
                     let mut entry = entry.clone();
+                    if with_attributes {
+                        entry.attributes = get_file_attributes(&path.join(&entry.name)).into();
+                    }
                     entry.name = get_string(&prefix.join(entry.name));
                     files.push(entry);
                 }
+                Ok(FileType::FileLink) | Ok(FileType::DirLink) if with_attributes => {
+                    if let Some(attributes) = get_file_attributes(&path.join(&entry.name)) {
+                        let mut entry = entry.clone();
+                        entry.attributes = protobuf::MessageField::some(attributes);
+                        entry.name = get_string(&prefix.join(entry.name));
+                        files.push(entry);
+                    }
+                }
                 Ok(FileType::Dir) => {
                     if let Ok(mut tmp) = read_dir_recursive(
                         &path.join(&entry.name),
                         &prefix.join(&entry.name),
                         include_hidden,
+                        with_attributes,
                     ) {
                         for entry in tmp.drain(0..) {
                             files.push(entry);
@@ -173,6 +187,11 @@ fn read_dir_recursive(
             entry_type: FileType::File.into(),
             size,
             modified_time,
+            attributes: if with_attributes {
+                get_file_attributes(path).into()
+            } else {
+                Default::default()
+            },
             ..Default::default()
         });
         Ok(files)
@@ -182,7 +201,239 @@ fn read_dir_recursive(
 }
 
 pub fn get_recursive_files(path: &str, include_hidden: bool) -> ResultType<Vec<FileEntry>> {
-    read_dir_recursive(&get_path(path), &get_path(""), include_hidden)
+    read_dir_recursive(&get_path(path), &get_path(""), include_hidden, false)
+}
+
+#[cfg(unix)]
+fn get_file_attributes(path: &Path) -> Option<FileAttributes> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = std::fs::symlink_metadata(path).ok()?;
+    let link_target = if meta.file_type().is_symlink() {
+        get_string(&std::fs::read_link(path).ok()?)
+    } else {
+        "".to_owned()
+    };
+    Some(FileAttributes {
+        mode: meta.mode() & 0o7777,
+        owner: unix_user::user_name(meta.uid()).unwrap_or_default(),
+        group: unix_user::group_name(meta.gid()).unwrap_or_default(),
+        link_target,
+        xattrs: get_xattrs(path),
+        ..Default::default()
+    })
+}
+
+#[cfg(not(unix))]
+fn get_file_attributes(_path: &Path) -> Option<FileAttributes> {
+    None
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn get_xattrs(path: &Path) -> Vec<FileXattr> {
+    let mut xattrs = Vec::new();
+    if let Ok(names) = xattr::list(path) {
+        for name in names {
+            if let Ok(Some(value)) = xattr::get(path, &name) {
+                xattrs.push(FileXattr {
+                    name: name.to_string_lossy().to_string(),
+                    value: value.into(),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    xattrs
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "macos"))))]
+fn get_xattrs(_path: &Path) -> Vec<FileXattr> {
+    Vec::new()
+}
+
+// The owner sent by the peer is only applied when the receiving side allows it.
+#[inline]
+pub fn is_preserve_file_owner_allowed() -> bool {
+    Config::get_option("allow-preserve-file-owner") == "Y"
+}
+
+// Apply what `get_file_attributes` got on the sender, the owner is looked up by name.
+// Failures are only logged, e.g. changing the owner requires root.
+// The setuid, setgid and sticky bits are never applied.
+#[cfg(unix)]
+fn set_file_attributes(path: &Path, attributes: &FileAttributes) {
+    use std::os::unix::fs::PermissionsExt;
+    if is_preserve_file_owner_allowed() {
+        let uid = unix_user::user_id(&attributes.owner);
+        let gid = unix_user::group_id(&attributes.group);
+        if uid.is_some() || gid.is_some() {
+            if let Err(err) = std::os::unix::fs::lchown(path, uid, gid) {
+                log::debug!("Failed to change owner of {:?}: {}", path, err);
+            }
+        }
+    }
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    for x in attributes.xattrs.iter() {
+        if let Err(err) = xattr::set(path, &x.name, &x.value) {
+            log::debug!("Failed to set xattr {} of {:?}: {}", x.name, path, err);
+        }
+    }
+    let mode = attributes.mode & 0o777;
+    if attributes.link_target.is_empty() && mode != 0 {
+        let permissions = std::fs::Permissions::from_mode(mode);
+        if let Err(err) = std::fs::set_permissions(path, permissions) {
+            log::debug!("Failed to set mode of {:?}: {}", path, err);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn set_file_attributes(_path: &Path, _attributes: &FileAttributes) {}
+
+#[cfg(unix)]
+fn create_symlink(path: &Path, target: &str) -> ResultType<()> {
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if meta.is_dir() {
+            bail!("{:?} is a directory", path);
+        }
+        std::fs::remove_file(path)?;
+    }
+    std::os::unix::fs::symlink(target, path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_symlink(path: &Path, _target: &str) -> ResultType<()> {
+    log::warn!("Symbolic link {:?} is skipped, not supported", path);
+    Ok(())
+}
+
+// Refuse to write below a symbol link between `base` and `path`, so that a link
+// in the target directory can not lead the writes out of it.
+fn check_no_link_in_path(base: &Path, path: &Path) -> ResultType<()> {
+    let relative = match path.strip_prefix(base) {
+        Ok(relative) => relative,
+        Err(_) => bail!("{:?} is outside of {:?}", path, base),
+    };
+    let mut current = base.to_path_buf();
+    for component in relative.components() {
+        if !matches!(component, std::path::Component::Normal(_)) {
+            bail!("Invalid path {:?}", path);
+        }
+        current.push(component);
+        if current == path {
+            break;
+        }
+        if let Ok(meta) = std::fs::symlink_metadata(&current) {
+            if meta.file_type().is_symlink() {
+                bail!("{:?} is a symbolic link", current);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Open without following a symbol link at `path` itself.
+async fn open_no_follow(path: &str, write: bool) -> ResultType<File> {
+    let mut options = tokio::fs::OpenOptions::new();
+    if write {
+        options.write(true).create(true).truncate(true);
+    } else {
+        options.read(true);
+    }
+    #[cfg(unix)]
+    options.custom_flags(libc::O_NOFOLLOW);
+    Ok(options.open(path).await?)
+}
+
+#[inline]
+fn get_link_attributes(entry: &FileEntry) -> Option<&FileAttributes> {
+    entry
+        .attributes
+        .as_ref()
+        .filter(|a| !a.link_target.is_empty())
+}
+
+#[cfg(unix)]
+mod unix_user {
+    use std::ffi::{CStr, CString};
+
+    const BUF_SIZE: usize = 16 * 1024;
+
+    pub fn user_name(uid: u32) -> Option<String> {
+        let mut buf = vec![0 as libc::c_char; BUF_SIZE];
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let ret =
+            unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+        if ret != 0 || result.is_null() {
+            return None;
+        }
+        Some(
+            unsafe { CStr::from_ptr(pwd.pw_name) }
+                .to_string_lossy()
+                .to_string(),
+        )
+    }
+
+    pub fn group_name(gid: u32) -> Option<String> {
+        let mut buf = vec![0 as libc::c_char; BUF_SIZE];
+        let mut grp: libc::group = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let ret =
+            unsafe { libc::getgrgid_r(gid, &mut grp, buf.as_mut_ptr(), buf.len(), &mut result) };
+        if ret != 0 || result.is_null() {
+            return None;
+        }
+        Some(
+            unsafe { CStr::from_ptr(grp.gr_name) }
+                .to_string_lossy()
+                .to_string(),
+        )
+    }
+
+    pub fn user_id(name: &str) -> Option<u32> {
+        let name = CString::new(name)
+            .ok()
+            .filter(|n| !n.as_bytes().is_empty())?;
+        let mut buf = vec![0 as libc::c_char; BUF_SIZE];
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let ret = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        if ret != 0 || result.is_null() {
+            return None;
+        }
+        Some(pwd.pw_uid)
+    }
+
+    pub fn group_id(name: &str) -> Option<u32> {
+        let name = CString::new(name)
+            .ok()
+            .filter(|n| !n.as_bytes().is_empty())?;
+        let mut buf = vec![0 as libc::c_char; BUF_SIZE];
+        let mut grp: libc::group = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let ret = unsafe {
+            libc::getgrnam_r(
+                name.as_ptr(),
+                &mut grp,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        if ret != 0 || result.is_null() {
+            return None;
+        }
+        Some(grp.gr_gid)
+    }
 }
 
 #[inline]
@@ -220,6 +471,35 @@ pub struct TransferJob {
     file_skipped: bool,
     file_is_waiting: bool,
     default_overwrite_strategy: Option<bool>,
+    // signatures of the receiver's copy of the current file, got with the digest of an upload
+    #[serde(skip_serializing)]
+    peer_signatures: Option<FileTransferBlockSignatures>,
+    #[serde(skip_serializing)]
+    delta: Option<DeltaEncoder>,
+    #[serde(skip_serializing)]
+    hasher: Option<Sha256>,
+    // the hashes of the files read, sent with the done message
+    #[serde(skip_serializing)]
+    hashes: Vec<FileTransferHash>,
+    // the files written, renamed once verified with the hashes of the done message
+    #[serde(skip_serializing)]
+    written_files: Vec<i32>,
+    // files whose hash mismatches, on the receiver side
+    #[serde(skip_serializing)]
+    failed_files: Vec<i32>,
+    // files transferred again, the others are skipped by the overwrite detection
+    #[serde(skip_serializing)]
+    retry_files: Vec<i32>,
+    #[serde(skip_serializing)]
+    hash_retries: usize,
+    // mirroring a directory, only the files which are not identical are transferred
+    #[serde(skip_serializing)]
+    mirror: bool,
+    // keep the POSIX mode, ownership, symbol links and xattrs of the files
+    preserve_attributes: bool,
+    // symbol links received, created by `create_links` after the regular files
+    #[serde(skip_serializing)]
+    pending_links: Vec<i32>,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
@@ -236,6 +516,8 @@ pub struct TransferJobMeta {
     pub file_num: i32,
     #[serde(default)]
     pub is_remote: bool,
+    #[serde(default)]
+    pub preserve_attributes: bool,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
@@ -270,6 +552,252 @@ fn is_compressed_file(name: &str) -> bool {
         || ext == "jpg"
 }
 
+const DELTA_MIN_BLOCK_SIZE: usize = 4 * 1024;
+const DELTA_MAX_BLOCK_SIZE: usize = 128 * 1024;
+// smaller files are always sent in full
+const DELTA_MIN_FILE_SIZE: u64 = 1024 * 1024;
+const DELTA_MAX_DATA_SIZE: usize = 128 * 1024;
+const DELTA_MAX_COPY_SIZE: u64 = 16 * 1024 * 1024;
+const STRONG_CHECKSUM_LEN: usize = 32;
+
+pub const ERR_HASH_MISMATCH: &str = "File hash mismatch";
+// the times the files with a mismatched hash are transferred again before the job fails
+pub const MAX_HASH_RETRIES: usize = 2;
+
+#[inline]
+pub fn is_delta_transfer_enabled() -> bool {
+    Config::get_option("enable-delta-transfer").is_empty()
+}
+
+#[inline]
+fn delta_block_size(file_size: u64) -> usize {
+    // sqrt of the file size like rsync, which balances signature size and match rate
+    let n = (file_size as f64).sqrt() as usize;
+    (n.clamp(DELTA_MIN_BLOCK_SIZE, DELTA_MAX_BLOCK_SIZE) + 1023) & !1023
+}
+
+/// The rsync weak checksum, which can be rolled forward one byte at a time.
+#[derive(Debug, Clone, Copy)]
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    fn new(data: &[u8]) -> Self {
+        let len = data.len() as u32;
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for (i, x) in data.iter().enumerate() {
+            a = a.wrapping_add(*x as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(*x as u32));
+        }
+        Self { a, b, len }
+    }
+
+    #[inline]
+    fn roll(&mut self, out: u8, input: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(input as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    #[inline]
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn read_full(file: &mut std::fs::File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut offset = 0;
+    while offset < buf.len() {
+        match std::io::Read::read(file, &mut buf[offset..]) {
+            Ok(0) => break,
+            Ok(n) => offset += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(offset)
+}
+
+// sha256 of what landed on disk, compared with the hash of the sender
+async fn file_hash(path: &str) -> ResultType<Vec<u8>> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 128 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Compute the block signatures of `path`, which is the existing copy on the receiver side.
+/// Only full blocks are included, the tail is always sent as data.
+pub fn new_block_signatures(path: &Path) -> ResultType<Option<FileTransferBlockSignatures>> {
+    let meta = std::fs::metadata(path)?;
+    if !meta.is_file() || meta.len() < DELTA_MIN_FILE_SIZE {
+        return Ok(None);
+    }
+    let block_size = delta_block_size(meta.len());
+    let mut file = std::fs::File::open(path)?;
+    let mut buf = vec![0u8; block_size];
+    let mut weak = Vec::new();
+    let mut strong = Vec::new();
+    while read_full(&mut file, &mut buf)? == block_size {
+        weak.push(RollingChecksum::new(&buf).digest());
+        strong.extend_from_slice(&Sha256::digest(&buf));
+    }
+    Ok(Some(FileTransferBlockSignatures {
+        block_size: block_size as _,
+        weak,
+        strong: strong.into(),
+        ..Default::default()
+    }))
+}
+
+#[derive(Debug, PartialEq)]
+enum DeltaOp {
+    /// Literal data, empty at the end of the file.
+    Data(Vec<u8>),
+    /// Reuse `(offset, length)` of the receiver's copy.
+    Copy(u64, u64),
+}
+
+/// Scans the file to send against the receiver's block signatures.
+#[derive(Debug, Default)]
+struct DeltaEncoder {
+    block_size: usize,
+    blocks: HashMap<u32, Vec<usize>>,
+    strong: bytes::Bytes,
+    buf: Vec<u8>,
+    pos: usize,
+    eof: bool,
+    rolling: Option<RollingChecksum>,
+    data: Vec<u8>,
+    copy: Option<(u64, u64)>,
+}
+
+impl DeltaEncoder {
+    fn new(signatures: FileTransferBlockSignatures) -> Option<Self> {
+        let block_size = signatures.block_size as usize;
+        if block_size == 0
+            || block_size > DELTA_MAX_BLOCK_SIZE
+            || signatures.weak.is_empty()
+            || signatures.strong.len() != signatures.weak.len() * STRONG_CHECKSUM_LEN
+        {
+            return None;
+        }
+        let mut blocks: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (i, weak) in signatures.weak.iter().enumerate() {
+            blocks.entry(*weak).or_default().push(i);
+        }
+        Some(Self {
+            block_size,
+            blocks,
+            strong: signatures.strong,
+            ..Default::default()
+        })
+    }
+
+    fn find_block(&self, weak: u32, window: &[u8]) -> Option<usize> {
+        let candidates = self.blocks.get(&weak)?;
+        let strong = Sha256::digest(window);
+        candidates.iter().copied().find(|i| {
+            self.strong[i * STRONG_CHECKSUM_LEN..(i + 1) * STRONG_CHECKSUM_LEN] == strong[..]
+        })
+    }
+
+    // keep at least one block after `pos` unless the end of the file is reached
+    async fn fill(&mut self, file: &mut File, hasher: &mut Sha256) -> ResultType<()> {
+        if self.pos > 0 {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+        }
+        while !self.eof && self.buf.len() < self.block_size {
+            let len = self.buf.len();
+            self.buf.resize(len + 2 * self.block_size, 0);
+            let n = file.read(&mut self.buf[len..]).await?;
+            self.buf.truncate(len + n);
+            hasher.update(&self.buf[len..]);
+            if n == 0 {
+                self.eof = true;
+            }
+        }
+        Ok(())
+    }
+
+    async fn next(&mut self, file: &mut File, hasher: &mut Sha256) -> ResultType<DeltaOp> {
+        let block_size = self.block_size;
+        loop {
+            if self.buf.len() - self.pos < block_size && !self.eof {
+                self.fill(file, hasher).await?;
+            }
+            if self.buf.len() - self.pos < block_size {
+                // no full block left to match
+                if let Some((offset, length)) = self.copy.take() {
+                    return Ok(DeltaOp::Copy(offset, length));
+                }
+                let n = (self.buf.len() - self.pos).min(DELTA_MAX_DATA_SIZE - self.data.len());
+                self.data
+                    .extend_from_slice(&self.buf[self.pos..self.pos + n]);
+                self.pos += n;
+                return Ok(DeltaOp::Data(std::mem::take(&mut self.data)));
+            }
+            let window = &self.buf[self.pos..self.pos + block_size];
+            let mut rolling = self
+                .rolling
+                .take()
+                .unwrap_or_else(|| RollingChecksum::new(window));
+            if let Some(i) = self.find_block(rolling.digest(), window) {
+                if !self.data.is_empty() {
+                    self.rolling = Some(rolling);
+                    return Ok(DeltaOp::Data(std::mem::take(&mut self.data)));
+                }
+                let offset = (i * block_size) as u64;
+                match self.copy {
+                    Some((start, length))
+                        if start + length == offset && length < DELTA_MAX_COPY_SIZE =>
+                    {
+                        self.copy = Some((start, length + block_size as u64));
+                    }
+                    Some(copy) => {
+                        self.rolling = Some(rolling);
+                        self.copy = None;
+                        return Ok(DeltaOp::Copy(copy.0, copy.1));
+                    }
+                    None => {
+                        self.copy = Some((offset, block_size as u64));
+                    }
+                }
+                self.pos += block_size;
+                continue;
+            }
+            if let Some((offset, length)) = self.copy.take() {
+                self.rolling = Some(rolling);
+                return Ok(DeltaOp::Copy(offset, length));
+            }
+            let out = self.buf[self.pos];
+            self.data.push(out);
+            self.pos += 1;
+            if self.pos + block_size <= self.buf.len() {
+                rolling.roll(out, self.buf[self.pos + block_size - 1]);
+                self.rolling = Some(rolling);
+            }
+            if self.data.len() >= DELTA_MAX_DATA_SIZE {
+                return Ok(DeltaOp::Data(std::mem::take(&mut self.data)));
+            }
+        }
+    }
+}
+
 impl TransferJob {
     #[allow(clippy::too_many_arguments)]
     pub fn new_write(
@@ -329,6 +857,25 @@ impl TransferJob {
         &self.files
     }
 
+    /// List the files of a read job again with their attributes,
+    /// symbol links are listed rather than skipped.
+    pub fn load_attributes(&mut self) -> ResultType<()> {
+        self.files = read_dir_recursive(&self.path, &get_path(""), self.show_hidden, true)?;
+        self.total_size = self.files.iter().map(|x| x.size).sum();
+        self.preserve_attributes = true;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn set_preserve_attributes(&mut self, preserve_attributes: bool) {
+        self.preserve_attributes = preserve_attributes;
+    }
+
+    #[inline]
+    pub fn preserve_attributes(&self) -> bool {
+        self.preserve_attributes
+    }
+
     #[inline]
     pub fn set_files(&mut self, files: Vec<FileEntry>) {
         self.files = files;
@@ -359,18 +906,50 @@ impl TransferJob {
         self.file_num
     }
 
-    pub fn modify_time(&self) {
-        let file_num = self.file_num as usize;
+    fn rename_download_file(&self, file_num: i32) {
+        let file_num = file_num as usize;
         if file_num < self.files.len() {
             let entry = &self.files[file_num];
             let path = self.join(&entry.name);
             let download_path = format!("{}.download", get_string(&path));
-            std::fs::rename(download_path, &path).ok();
-            filetime::set_file_mtime(
-                &path,
-                filetime::FileTime::from_unix_time(entry.modified_time as _, 0),
-            )
-            .ok();
+            if std::fs::rename(download_path, &path).is_ok() {
+                filetime::set_file_mtime(
+                    &path,
+                    filetime::FileTime::from_unix_time(entry.modified_time as _, 0),
+                )
+                .ok();
+                if self.preserve_attributes {
+                    if let Some(attributes) = entry.attributes.as_ref() {
+                        set_file_attributes(&path, attributes);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Create the symbol links of the job, only once all regular files are
+    /// written, so that no file of the job is written through one of them.
+    pub fn create_links(&mut self) {
+        for file_num in std::mem::take(&mut self.pending_links) {
+            let entry = match self.files.get(file_num as usize) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let attributes = match get_link_attributes(entry) {
+                Some(attributes) => attributes,
+                None => continue,
+            };
+            let path = self.join(&entry.name);
+            let res = check_no_link_in_path(&self.path, &path).and_then(|_| {
+                if let Some(p) = path.parent() {
+                    std::fs::create_dir_all(p).ok();
+                }
+                create_symlink(&path, &attributes.link_target)
+            });
+            match res {
+                Ok(()) => set_file_attributes(&path, attributes),
+                Err(err) => log::error!("Failed to create symbol link {:?}: {}", path, err),
+            }
         }
     }
 
@@ -393,18 +972,47 @@ impl TransferJob {
             bail!("Wrong file number");
         }
         if file_num != self.file_num as usize || self.file.is_none() {
-            self.modify_time();
-            if let Some(file) = self.file.as_mut() {
+            if let Some(file) = self.file.take() {
                 file.sync_all().await?;
+                if !self.written_files.contains(&self.file_num) {
+                    self.written_files.push(self.file_num);
+                }
             }
             self.file_num = block.file_num;
             let entry = &self.files[file_num];
             let path = self.join(&entry.name);
+            check_no_link_in_path(&self.path, &path)?;
+            if self.preserve_attributes && get_link_attributes(entry).is_some() {
+                // the only block of a symbol link, there is no content
+                self.file = None;
+                if !self.pending_links.contains(&block.file_num) {
+                    self.pending_links.push(block.file_num);
+                }
+                return Ok(());
+            }
             if let Some(p) = path.parent() {
                 std::fs::create_dir_all(p).ok();
             }
             let path = format!("{}.download", get_string(&path));
-            self.file = Some(File::create(&path).await?);
+            self.file = Some(open_no_follow(&path, true).await?);
+        }
+        if let Some(range) = block.copy_from.as_ref() {
+            // delta transfer, the range is reused from the existing file, which is
+            // only replaced by the download file in `finish`
+            let path = self.join(&self.files[file_num].name);
+            let mut base = open_no_follow(&get_string(&path), false).await?;
+            base.seek(SeekFrom::Start(range.offset)).await?;
+            let mut base = base.take(range.length);
+            let n = copy(
+                &mut base,
+                self.file.as_mut().ok_or(anyhow!("file is None"))?,
+            )
+            .await?;
+            if n != range.length {
+                bail!("Existing file is changed during transfer");
+            }
+            self.finished_size += n;
+            return Ok(());
         }
         if block.compressed {
             let tmp = decompress(&block.data);
@@ -426,6 +1034,102 @@ impl TransferJob {
         Ok(())
     }
 
+    /// Verify the written files with the hashes of the done message, and rename the download
+    /// files which match. A mismatched file is removed and recorded for `retry_failed_files`,
+    /// a file without a hash, from an older peer, is renamed as it is.
+    pub async fn finish(&mut self, hashes: &[FileTransferHash]) {
+        if let Some(file) = self.file.take() {
+            file.sync_all().await.ok();
+            if !self.written_files.contains(&self.file_num) {
+                self.written_files.push(self.file_num);
+            }
+        }
+        for file_num in std::mem::take(&mut self.written_files) {
+            let Some(hash) = hashes.iter().find(|h| h.file_num == file_num) else {
+                self.rename_download_file(file_num);
+                continue;
+            };
+            let path = format!(
+                "{}.download",
+                get_string(&self.join(&self.files[file_num as usize].name))
+            );
+            match file_hash(&path).await {
+                Ok(v) if v[..] == hash.hash[..] => self.rename_download_file(file_num),
+                res => {
+                    log::error!(
+                        "id: {}, file_num: {}, {}: {}, {:?}",
+                        self.id,
+                        file_num,
+                        ERR_HASH_MISMATCH,
+                        path,
+                        res.err()
+                    );
+                    std::fs::remove_file(&path).ok();
+                    if !self.failed_files.contains(&file_num) {
+                        self.failed_files.push(file_num);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Rename the files written before the job is cancelled, fails or is disconnected, which
+    /// can't be verified without the done message.
+    pub fn rename_written_files(&mut self) {
+        for file_num in std::mem::take(&mut self.written_files) {
+            self.rename_download_file(file_num);
+        }
+    }
+
+    #[inline]
+    pub fn failed_files(&self) -> &Vec<i32> {
+        &self.failed_files
+    }
+
+    /// Prepare the write job to receive the files with mismatched hash once more.
+    /// Returns the file number to request from, `None` if there is nothing to retry
+    /// or they have been retried `MAX_HASH_RETRIES` times.
+    pub fn retry_failed_files(&mut self) -> Option<i32> {
+        if self.failed_files.is_empty() || self.hash_retries >= MAX_HASH_RETRIES {
+            return None;
+        }
+        self.hash_retries += 1;
+        self.retry_files = std::mem::take(&mut self.failed_files);
+        self.file = None;
+        self.file_num = *self.retry_files.iter().min()?;
+        log::info!("id: {}, retry files {:?}", self.id, self.retry_files);
+        Some(self.file_num)
+    }
+
+    #[inline]
+    pub fn add_retry_file(&mut self, file_num: i32) {
+        if !self.retry_files.contains(&file_num) {
+            self.retry_files.push(file_num);
+        }
+    }
+
+    /// The overwrite strategy of `file_num`. When retrying, only the failed files are transferred.
+    /// When mirroring, only the files differing from the existing ones are transferred.
+    pub fn overwrite_strategy_of(&self, file_num: i32, is_identical: bool) -> Option<bool> {
+        if !self.retry_files.is_empty() {
+            return Some(self.retry_files.contains(&file_num));
+        }
+        if self.mirror {
+            return Some(!is_identical);
+        }
+        self.default_overwrite_strategy
+    }
+
+    #[inline]
+    pub fn set_mirror(&mut self, mirror: bool) {
+        self.mirror = mirror;
+    }
+
+    #[inline]
+    pub fn is_mirror(&self) -> bool {
+        self.mirror
+    }
+
     #[inline]
     pub fn join(&self, name: &str) -> PathBuf {
         if name.is_empty() {
@@ -441,6 +1145,18 @@ impl TransferJob {
             self.file.take();
             return Ok(None);
         }
+        if get_link_attributes(&self.files[file_num]).is_some() {
+            // the receiver recreates the link from the attributes, send an empty block as done
+            self.file_num += 1;
+            self.file = None;
+            self.file_confirmed = false;
+            self.file_is_waiting = false;
+            return Ok(Some(FileTransferBlock {
+                id: self.id,
+                file_num: file_num as _,
+                ..Default::default()
+            }));
+        }
         let name = &self.files[file_num].name;
         if self.file.is_none() {
             match File::open(self.join(name)).await {
@@ -448,6 +1164,9 @@ impl TransferJob {
                     self.file = Some(file);
                     self.file_confirmed = false;
                     self.file_is_waiting = false;
+                    self.peer_signatures = None;
+                    self.delta = None;
+                    self.hasher = Some(Sha256::new());
                 }
                 Err(err) => {
                     self.file_num += 1;
@@ -464,39 +1183,81 @@ impl TransferJob {
             }
             return Ok(None);
         }
-        const BUF_SIZE: usize = 128 * 1024;
-        let mut buf: Vec<u8> = vec![0; BUF_SIZE];
         let mut compressed = false;
-        let mut offset: usize = 0;
-        loop {
-            match self
-                .file
-                .as_mut()
-                .ok_or(anyhow!("file is None"))?
-                .read(&mut buf[offset..])
-                .await
-            {
+        let mut buf = if let Some(delta) = self.delta.as_mut() {
+            let file = self.file.as_mut().ok_or(anyhow!("file is None"))?;
+            let hasher = self.hasher.get_or_insert_with(Sha256::new);
+            match delta.next(file, hasher).await {
                 Err(err) => {
                     self.file_num += 1;
                     self.file = None;
                     self.file_confirmed = false;
                     self.file_is_waiting = false;
-                    return Err(err.into());
+                    self.delta = None;
+                    return Err(err);
+                }
+                Ok(DeltaOp::Copy(offset, length)) => {
+                    self.finished_size += length;
+                    return Ok(Some(FileTransferBlock {
+                        id: self.id,
+                        file_num: file_num as _,
+                        copy_from: protobuf::MessageField::some(FileTransferBlockCopy {
+                            offset,
+                            length,
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }));
                 }
-                Ok(n) => {
-                    offset += n;
-                    if n == 0 || offset == BUF_SIZE {
-                        break;
+                Ok(DeltaOp::Data(buf)) => buf,
+            }
+        } else {
+            const BUF_SIZE: usize = 128 * 1024;
+            let mut buf: Vec<u8> = vec![0; BUF_SIZE];
+            let mut offset: usize = 0;
+            loop {
+                match self
+                    .file
+                    .as_mut()
+                    .ok_or(anyhow!("file is None"))?
+                    .read(&mut buf[offset..])
+                    .await
+                {
+                    Err(err) => {
+                        self.file_num += 1;
+                        self.file = None;
+                        self.file_confirmed = false;
+                        self.file_is_waiting = false;
+                        return Err(err.into());
+                    }
+                    Ok(n) => {
+                        offset += n;
+                        if n == 0 || offset == BUF_SIZE {
+                            break;
+                        }
                     }
                 }
             }
-        }
-        unsafe { buf.set_len(offset) };
+            unsafe { buf.set_len(offset) };
+            if let Some(hasher) = self.hasher.as_mut() {
+                hasher.update(&buf);
+            }
+            buf
+        };
+        let offset = buf.len();
         if offset == 0 {
             self.file_num += 1;
             self.file = None;
             self.file_confirmed = false;
             self.file_is_waiting = false;
+            self.delta = None;
+            if let Some(hasher) = self.hasher.take() {
+                self.hashes.push(FileTransferHash {
+                    file_num: file_num as _,
+                    hash: hasher.finalize().to_vec().into(),
+                    ..Default::default()
+                });
+            }
         } else {
             self.finished_size += offset as u64;
             if !is_compressed_file(name) {
@@ -606,12 +1367,17 @@ impl TransferJob {
         if self.job_skipped() {
             return Some("skipped".to_string());
         }
+        if !self.failed_files.is_empty() {
+            return Some(ERR_HASH_MISMATCH.to_string());
+        }
         None
     }
 
     pub fn set_file_skipped(&mut self) -> bool {
         log::debug!("skip file {} in job {}", self.file_num, self.id);
         self.file.take();
+        self.peer_signatures = None;
+        self.delta = None;
         self.set_file_confirmed(false);
         self.set_file_is_waiting(false);
         self.file_num += 1;
@@ -633,6 +1399,19 @@ impl TransferJob {
                 }
                 Some(file_transfer_send_confirm_request::Union::OffsetBlk(_offset)) => {
                     self.set_file_confirmed(true);
+                    let signatures = r
+                        .signatures
+                        .clone()
+                        .into_option()
+                        .or(self.peer_signatures.take());
+                    self.delta = signatures.and_then(DeltaEncoder::new);
+                    if self.delta.is_some() {
+                        log::info!(
+                            "id: {}, file_num: {}, delta transfer",
+                            self.id,
+                            self.file_num
+                        );
+                    }
                 }
                 _ => {}
             }
@@ -640,6 +1419,38 @@ impl TransferJob {
         true
     }
 
+    /// Keep the signatures which the receiver of an upload sent with its digest,
+    /// they are used once the overwrite is confirmed.
+    #[inline]
+    pub fn set_peer_signatures(&mut self, signatures: Option<FileTransferBlockSignatures>) {
+        self.peer_signatures = signatures;
+    }
+
+    /// Signatures of the existing local file `file_num` of a write job,
+    /// `None` if delta transfer is disabled or not worthwhile.
+    /// The whole file is read, on a blocking thread.
+    pub async fn local_block_signatures(
+        &self,
+        file_num: i32,
+    ) -> Option<FileTransferBlockSignatures> {
+        if !is_delta_transfer_enabled() {
+            return None;
+        }
+        let entry = self.files.get(file_num as usize)?;
+        let path = self.join(&entry.name);
+        match tokio::task::spawn_blocking(move || new_block_signatures(&path)).await {
+            Ok(Ok(signatures)) => signatures,
+            Ok(Err(err)) => {
+                log::warn!("Failed to get block signatures of {}: {}", entry.name, err);
+                None
+            }
+            Err(err) => {
+                log::error!("Failed to get block signatures of {}: {}", entry.name, err);
+                None
+            }
+        }
+    }
+
     #[inline]
     pub fn gen_meta(&self) -> TransferJobMeta {
         TransferJobMeta {
@@ -649,6 +1460,7 @@ impl TransferJob {
             file_num: self.file_num,
             show_hidden: self.show_hidden,
             is_remote: self.is_remote,
+            preserve_attributes: self.preserve_attributes,
         }
     }
 }
@@ -706,6 +1518,7 @@ pub fn new_receive(
     file_num: i32,
     files: Vec<FileEntry>,
     total_size: u64,
+    preserve_attributes: bool,
 ) -> Message {
     let mut action = FileAction::new();
     action.set_receive(FileTransferReceiveRequest {
@@ -714,6 +1527,7 @@ pub fn new_receive(
         files,
         file_num,
         total_size,
+        preserve_attributes,
         ..Default::default()
     });
     let mut msg_out = Message::new();
@@ -722,7 +1536,13 @@ pub fn new_receive(
 }
 
 #[inline]
-pub fn new_send(id: i32, path: String, file_num: i32, include_hidden: bool) -> Message {
+pub fn new_send(
+    id: i32,
+    path: String,
+    file_num: i32,
+    include_hidden: bool,
+    preserve_attributes: bool,
+) -> Message {
     log::info!("new send: {}, id: {}", path, id);
     let mut action = FileAction::new();
     action.set_send(FileTransferSendRequest {
@@ -730,6 +1550,7 @@ pub fn new_send(id: i32, path: String, file_num: i32, include_hidden: bool) -> M
         path,
         include_hidden,
         file_num,
+        preserve_attributes,
         ..Default::default()
     });
     let mut msg_out = Message::new();
@@ -739,10 +1560,16 @@ pub fn new_send(id: i32, path: String, file_num: i32, include_hidden: bool) -> M
 
 #[inline]
 pub fn new_done(id: i32, file_num: i32) -> Message {
+    new_done_with_hashes(id, file_num, Vec::new())
+}
+
+#[inline]
+pub fn new_done_with_hashes(id: i32, file_num: i32, hashes: Vec<FileTransferHash>) -> Message {
     let mut resp = FileResponse::new();
     resp.set_done(FileTransferDone {
         id,
         file_num,
+        hashes,
         ..Default::default()
     });
     let mut msg_out = Message::new();
@@ -795,7 +1622,12 @@ pub async fn handle_read_jobs(
                                 .send(&new_error(job.id(), err, job.file_num()))
                                 .await?
                         }
-                        None => stream.send(&new_done(job.id(), job.file_num())).await?,
+                        None => {
+                            let hashes = std::mem::take(&mut job.hashes);
+                            stream
+                                .send(&new_done_with_hashes(job.id(), job.file_num(), hashes))
+                                .await?
+                        }
                     }
                 } else {
                     // waiting confirmation.
@@ -845,6 +1677,80 @@ pub fn transform_windows_path(entries: &mut Vec<FileEntry>) {
     }
 }
 
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct MirrorPlanEntry {
+    pub name: String,
+    pub size: u64,
+    pub modified_time: u64,
+}
+
+impl From<&FileEntry> for MirrorPlanEntry {
+    fn from(entry: &FileEntry) -> Self {
+        Self {
+            name: entry.name.clone(),
+            size: entry.size,
+            modified_time: entry.modified_time,
+        }
+    }
+}
+
+/// What mirroring a source directory to a target directory will do.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct MirrorPlan {
+    // only in the source
+    pub adds: Vec<MirrorPlanEntry>,
+    // in both, but with different size or modified time
+    pub updates: Vec<MirrorPlanEntry>,
+    // only in the target, names are the target's
+    pub deletes: Vec<MirrorPlanEntry>,
+}
+
+impl MirrorPlan {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.adds.is_empty() && self.updates.is_empty() && self.deletes.is_empty()
+    }
+}
+
+#[inline]
+fn mirror_key(name: &str) -> String {
+    name.replace('\\', "/")
+}
+
+/// Compare the recursive file lists of the source and the target by name, size and modified time,
+/// as the overwrite detection does.
+/// Files only in the target are listed to be deleted if `delete_extraneous` is set.
+pub fn mirror_plan(
+    source: &[FileEntry],
+    target: &[FileEntry],
+    delete_extraneous: bool,
+) -> MirrorPlan {
+    let targets: HashMap<String, &FileEntry> =
+        target.iter().map(|e| (mirror_key(&e.name), e)).collect();
+    let mut plan = MirrorPlan::default();
+    let mut sources = std::collections::HashSet::new();
+    for entry in source.iter() {
+        let key = mirror_key(&entry.name);
+        match targets.get(&key) {
+            None => plan.adds.push(entry.into()),
+            Some(t) => {
+                if t.size != entry.size || t.modified_time != entry.modified_time {
+                    plan.updates.push(entry.into());
+                }
+            }
+        }
+        sources.insert(key);
+    }
+    if delete_extraneous {
+        plan.deletes = target
+            .iter()
+            .filter(|e| !sources.contains(&mirror_key(&e.name)))
+            .map(MirrorPlanEntry::from)
+            .collect();
+    }
+    plan
+}
+
 pub enum DigestCheckResult {
     IsSame,
     NeedConfirm(FileTransferDigest),
@@ -898,3 +1804,211 @@ pub fn serialize_transfer_job(job: &TransferJob, done: bool, cancel: bool, error
     value["error"] = json!(error);
     serde_json::to_string(&value).unwrap_or_default()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rolling_checksum() {
+        let data: Vec<u8> = (0..10_000u32).map(|x| (x * 7 + x / 13) as u8).collect();
+        let len = 1024;
+        let mut rolling = RollingChecksum::new(&data[..len]);
+        for i in 1..data.len() - len {
+            rolling.roll(data[i - 1], data[i + len - 1]);
+            assert_eq!(
+                rolling.digest(),
+                RollingChecksum::new(&data[i..i + len]).digest()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delta_encoder() {
+        let dir = std::env::temp_dir().join(format!("rustdesk-delta-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let old_path = dir.join("old");
+        let new_path = dir.join("new");
+        let old: Vec<u8> = (0..3 * 1024 * 1024u32)
+            .map(|_| rand::random::<u8>())
+            .collect();
+        let mut new = old.clone();
+        new[100_000..100_100].copy_from_slice(&[0u8; 100]);
+        new.splice(1_500_000..1_500_000, vec![1u8; 3333]);
+        new.truncate(new.len() - 77_777);
+        std::fs::write(&old_path, &old).unwrap();
+        std::fs::write(&new_path, &new).unwrap();
+
+        let signatures = new_block_signatures(&old_path).unwrap().unwrap();
+        let mut encoder = DeltaEncoder::new(signatures).unwrap();
+        let mut file = File::open(&new_path).await.unwrap();
+        let mut hasher = Sha256::new();
+        let mut out = Vec::new();
+        let mut data_len = 0;
+        loop {
+            match encoder.next(&mut file, &mut hasher).await.unwrap() {
+                DeltaOp::Data(data) if data.is_empty() => break,
+                DeltaOp::Data(data) => {
+                    data_len += data.len();
+                    out.extend_from_slice(&data);
+                }
+                DeltaOp::Copy(offset, length) => {
+                    out.extend_from_slice(&old[offset as usize..(offset + length) as usize]);
+                }
+            }
+        }
+        std::fs::remove_dir_all(&dir).ok();
+        assert!(out == new);
+        assert!(data_len < new.len() / 10);
+        assert!(hasher.finalize()[..] == Sha256::digest(&new)[..]);
+    }
+
+    #[test]
+    fn test_mirror_plan() {
+        let entry = |name: &str, size: u64, modified_time: u64| FileEntry {
+            entry_type: FileType::File.into(),
+            name: name.to_owned(),
+            size,
+            modified_time,
+            ..Default::default()
+        };
+        let source = vec![
+            entry("a", 1, 100),
+            entry("dir/b", 2, 100),
+            entry("dir/c", 3, 100),
+            entry("d", 4, 100),
+        ];
+        let target = vec![
+            entry("a", 1, 100),
+            entry("dir\\b", 2, 200),
+            entry("d", 5, 100),
+            entry("dir\\e", 6, 100),
+        ];
+        let names = |v: &Vec<MirrorPlanEntry>| v.iter().map(|e| e.name.clone()).collect::<Vec<_>>();
+        let plan = mirror_plan(&source, &target, false);
+        assert_eq!(names(&plan.adds), vec!["dir/c"]);
+        assert_eq!(names(&plan.updates), vec!["dir/b", "d"]);
+        assert!(plan.deletes.is_empty());
+        let plan = mirror_plan(&source, &target, true);
+        assert_eq!(names(&plan.deletes), vec!["dir\\e"]);
+        assert!(mirror_plan(&source, &source, true).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_hash_retries() {
+        let dir = std::env::temp_dir().join(format!("rustdesk-hash-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = ["a", "b"]
+            .iter()
+            .map(|name| FileEntry {
+                entry_type: FileType::File.into(),
+                name: name.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        let mut job = TransferJob::new_write(
+            0,
+            "".to_owned(),
+            get_string(&dir),
+            0,
+            false,
+            false,
+            files,
+            false,
+        );
+        let block = |file_num: i32, data: &[u8]| FileTransferBlock {
+            file_num,
+            data: data.to_vec().into(),
+            ..Default::default()
+        };
+        let hashes: Vec<_> = [b"a", b"b"]
+            .iter()
+            .enumerate()
+            .map(|(i, data)| FileTransferHash {
+                file_num: i as _,
+                hash: Sha256::digest(data).to_vec().into(),
+                ..Default::default()
+            })
+            .collect();
+        job.write(block(0, b"a")).await.unwrap();
+        job.write(block(1, b"c")).await.unwrap();
+        // nothing is renamed before it is verified
+        assert!(!dir.join("a").exists());
+        job.finish(&hashes).await;
+        let a = std::fs::read(dir.join("a")).ok();
+        let b_exists = dir.join("b").exists() || dir.join("b.download").exists();
+        let mut retries = 0;
+        while let Some(file_num) = job.retry_failed_files() {
+            assert_eq!(file_num, 1);
+            assert_eq!(job.overwrite_strategy_of(0, false), Some(false));
+            job.write(block(1, b"c")).await.unwrap();
+            job.finish(&hashes).await;
+            retries += 1;
+        }
+        let error = job.job_error();
+        std::fs::remove_dir_all(&dir).ok();
+        assert_eq!(a, Some(b"a".to_vec()));
+        assert!(!b_exists);
+        assert_eq!(retries, MAX_HASH_RETRIES);
+        assert_eq!(error, Some(ERR_HASH_MISMATCH.to_owned()));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_no_write_through_link() {
+        let dir = std::env::temp_dir().join(format!("rustdesk-link-{}", std::process::id()));
+        let outside = dir.join("outside");
+        let to = dir.join("to");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::create_dir_all(&to).unwrap();
+        std::os::unix::fs::symlink(&outside, to.join("existing")).unwrap();
+        let entry = |name: &str, link_target: &str| FileEntry {
+            entry_type: FileType::File.into(),
+            name: name.to_owned(),
+            attributes: Some(FileAttributes {
+                mode: 0o4755,
+                link_target: link_target.to_owned(),
+                ..Default::default()
+            })
+            .into(),
+            ..Default::default()
+        };
+        let files = vec![
+            entry("link", &get_string(&outside)),
+            entry("existing/a", ""),
+            entry("b", ""),
+        ];
+        let mut job = TransferJob::new_write(
+            0,
+            "".to_owned(),
+            get_string(&to),
+            0,
+            false,
+            false,
+            files,
+            false,
+        );
+        job.set_preserve_attributes(true);
+        let block = |file_num: i32| FileTransferBlock {
+            file_num,
+            data: b"data".to_vec().into(),
+            ..Default::default()
+        };
+        job.write(block(0)).await.unwrap();
+        assert!(std::fs::symlink_metadata(to.join("link")).is_err());
+        assert!(job.write(block(1)).await.is_err());
+        job.write(block(2)).await.unwrap();
+        job.finish(&[]).await;
+        job.create_links();
+        let link = std::fs::symlink_metadata(to.join("link")).unwrap();
+        assert!(link.file_type().is_symlink());
+        assert!(!outside.join("a").exists());
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(to.join("b"))
+            .unwrap()
+            .permissions()
+            .mode();
+        std::fs::remove_dir_all(&dir).ok();
+        assert_eq!(mode & 0o7777, 0o755);
+    }
+}
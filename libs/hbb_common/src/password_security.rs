@@ -1,9 +1,70 @@
 use crate::config::Config;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
 use sodiumoxide::base64;
-use std::sync::{Arc, RwLock};
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+
+// The options of the temporary password:
+// - `temporary-password-length`: 6 to 32, 6 by default
+// - `temporary-password-charset`: "numeric", "mixed" for the upper case letters too, or the
+//   characters to use, the lower case letters and digits by default
+// - `temporary-password-rotate-minutes`: a new one is generated at this interval, 0 for never
+// - `temporary-password-expire-minutes`: it is not valid this long after it is generated, until
+//   it is updated, 0 for never
+// - `temporary-password-one-time`: "Y" to update it after a login with it
+// - `temporary-password-rotate-after-session`: "N" not to update it after the authorized sessions
+
+const MIN_TEMPORARY_PASSWORD_LENGTH: usize = 6;
+const MAX_TEMPORARY_PASSWORD_LENGTH: usize = 32;
 
 lazy_static::lazy_static! {
-    pub static ref TEMPORARY_PASSWORD:Arc<RwLock<String>> = Arc::new(RwLock::new(Config::get_auto_password(temporary_password_length())));
+    pub static ref TEMPORARY_PASSWORD: Arc<RwLock<TemporaryPassword>> = Arc::new(RwLock::new(TemporaryPassword::new()));
+    // notified when the temporary password is replaced
+    pub static ref TEMPORARY_PASSWORD_CHANGED: watch::Sender<()> = watch::channel(()).0;
+}
+
+#[derive(Debug, Clone)]
+pub struct TemporaryPassword {
+    value: String,
+    created: Instant,
+}
+
+impl TemporaryPassword {
+    fn new() -> Self {
+        let length = temporary_password_length();
+        let value = match temporary_password_chars() {
+            Some(chars) => {
+                let mut rng = rand::thread_rng();
+                (0..length)
+                    .map(|_| chars[rng.gen_range(0..chars.len())])
+                    .collect()
+            }
+            None => Config::get_auto_password(length),
+        };
+        Self {
+            value,
+            created: Instant::now(),
+        }
+    }
+
+    fn remaining(&self, option: &str) -> Option<Duration> {
+        option_minutes(option).map(|d| d.saturating_sub(self.created.elapsed()))
+    }
+}
+
+/// The temporary password and its policy, shown in the tray and cli.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TemporaryPasswordInfo {
+    // empty if it is expired or disabled
+    pub password: String,
+    // seconds
+    pub expires_in: Option<u64>,
+    pub rotates_in: Option<u64>,
+    pub one_time: bool,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -22,12 +83,60 @@ pub enum ApproveMode {
 
 // Should only be called in server
 pub fn update_temporary_password() {
-    *TEMPORARY_PASSWORD.write().unwrap() = Config::get_auto_password(temporary_password_length());
+    *TEMPORARY_PASSWORD.write().unwrap() = TemporaryPassword::new();
+    TEMPORARY_PASSWORD_CHANGED.send_replace(());
 }
 
-// Should only be called in server
+// Should only be called in server, empty if it is expired
 pub fn temporary_password() -> String {
-    TEMPORARY_PASSWORD.read().unwrap().clone()
+    let mut password = TEMPORARY_PASSWORD.write().unwrap();
+    if password.remaining("temporary-password-rotate-minutes") == Some(Duration::ZERO) {
+        *password = TemporaryPassword::new();
+        TEMPORARY_PASSWORD_CHANGED.send_replace(());
+    }
+    if password.remaining("temporary-password-expire-minutes") == Some(Duration::ZERO) {
+        return "".to_owned();
+    }
+    password.value.clone()
+}
+
+// Should only be called in server
+pub fn temporary_password_info() -> TemporaryPasswordInfo {
+    let enabled = temporary_enabled();
+    let value = if enabled {
+        temporary_password()
+    } else {
+        "".to_owned()
+    };
+    let password = TEMPORARY_PASSWORD.read().unwrap();
+    TemporaryPasswordInfo {
+        expires_in: password
+            .remaining("temporary-password-expire-minutes")
+            .filter(|_| !value.is_empty())
+            .map(|d| d.as_secs()),
+        rotates_in: password
+            .remaining("temporary-password-rotate-minutes")
+            .filter(|_| enabled)
+            .map(|d| d.as_secs()),
+        one_time: temporary_password_one_time(),
+        password: value,
+    }
+}
+
+pub fn temporary_password_one_time() -> bool {
+    Config::get_option("temporary-password-one-time") == "Y"
+}
+
+pub fn rotate_temporary_password_after_session() -> bool {
+    Config::get_option("temporary-password-rotate-after-session") != "N"
+}
+
+// `None` for 0 or not set
+fn option_minutes(name: &str) -> Option<Duration> {
+    match Config::get_option(name).parse::<u64>() {
+        Ok(m) if m > 0 => Some(Duration::from_secs(m * 60)),
+        _ => None,
+    }
 }
 
 fn verification_method() -> VerificationMethod {
@@ -42,14 +151,38 @@ fn verification_method() -> VerificationMethod {
 }
 
 pub fn temporary_password_length() -> usize {
-    let length = Config::get_option("temporary-password-length");
-    if length == "8" {
-        8
-    } else if length == "10" {
-        10
-    } else {
-        6 // default
+    match Config::get_option("temporary-password-length").parse::<usize>() {
+        Ok(length)
+            if (MIN_TEMPORARY_PASSWORD_LENGTH..=MAX_TEMPORARY_PASSWORD_LENGTH)
+                .contains(&length) =>
+        {
+            length
+        }
+        _ => 6, // default
+    }
+}
+
+// `None` for the default characters
+fn temporary_password_chars() -> Option<Vec<char>> {
+    let charset = Config::get_option("temporary-password-charset");
+    let mut chars: Vec<char> = match charset.as_str() {
+        "" => return None,
+        "numeric" => ('0'..='9').collect(),
+        // without the ones easy to mistake
+        "mixed" => ('2'..='9')
+            .chain('a'..='z')
+            .chain('A'..='Z')
+            .filter(|c| !"lIoO".contains(*c))
+            .collect(),
+        custom => custom.chars().filter(|c| !c.is_whitespace()).collect(),
+    };
+    chars.sort();
+    chars.dedup();
+    if chars.len() < 2 {
+        log::error!("Invalid temporary-password-charset: {}", charset);
+        return None;
     }
+    Some(chars)
 }
 
 pub fn temporary_enabled() -> bool {
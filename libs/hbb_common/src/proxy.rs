@@ -1,18 +1,19 @@
 use std::{
+    borrow::Cow,
     io::Error as IoError,
-    net::{SocketAddr, ToSocketAddrs},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
 };
 
 use base64::{engine::general_purpose, Engine};
 use httparse::{Error as HttpParseError, Response, EMPTY_HEADER};
 use log::info;
 use thiserror::Error as ThisError;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream};
 #[cfg(any(target_os = "windows", target_os = "macos"))]
 use tokio_native_tls::{native_tls, TlsConnector, TlsStream};
 #[cfg(not(any(target_os = "windows", target_os = "macos")))]
 use tokio_rustls::{client::TlsStream, TlsConnector};
-use tokio_socks::{tcp::Socks5Stream, IntoTargetAddr};
+use tokio_socks::{tcp::Socks5Stream, IntoTargetAddr, TargetAddr};
 use tokio_util::codec::Framed;
 use url::Url;
 
@@ -45,6 +46,8 @@ pub enum ProxyError {
     HttpCode200(u16),
     #[error("The proxy address resolution failed: {0}")]
     AddressResolutionFailed(String),
+    #[error("Socks5 error: {0}")]
+    Socks5Error(String),
     #[cfg(any(target_os = "windows", target_os = "macos"))]
     #[error("The native tls error: {0}")]
     NativeTlsError(#[from] tokio_native_tls::native_tls::Error),
@@ -385,6 +388,7 @@ impl Proxy {
                     addr,
                     None,
                     0,
+                    None,
                 ))
             }
             ProxyScheme::Https { .. } => {
@@ -396,6 +400,7 @@ impl Proxy {
                     addr,
                     None,
                     0,
+                    None,
                 ))
             }
             ProxyScheme::Socks5 { .. } => {
@@ -423,6 +428,7 @@ impl Proxy {
                     addr,
                     None,
                     0,
+                    None,
                 ))
             }
         };
@@ -559,3 +565,249 @@ where
         None => Err(ProxyError::NoHttpCode),
     };
 }
+
+pub const SOCKS5_REPLY_SUCCEEDED: u8 = 0x00;
+pub const SOCKS5_REPLY_GENERAL_FAILURE: u8 = 0x01;
+pub const SOCKS5_REPLY_HOST_UNREACHABLE: u8 = 0x04;
+pub const SOCKS5_REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+const SOCKS5_VERSION: u8 = 0x05;
+const SOCKS5_AUTH_NONE: u8 = 0x00;
+const SOCKS5_AUTH_NO_ACCEPTABLE: u8 = 0xff;
+const SOCKS5_ATYP_IPV4: u8 = 0x01;
+const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
+const SOCKS5_ATYP_IPV6: u8 = 0x04;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Socks5Command {
+    Connect,
+    UdpAssociate,
+}
+
+/// Server side handshake of socks5 without authentication, returns the request of the client,
+/// which must be answered with [`socks5_reply`].
+pub async fn socks5_accept<S>(
+    stream: &mut S,
+) -> Result<(Socks5Command, TargetAddr<'static>), ProxyError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head).await?;
+    if head[0] != SOCKS5_VERSION {
+        return Err(ProxyError::Socks5Error(format!(
+            "unsupported version {}",
+            head[0]
+        )));
+    }
+    let mut methods = vec![0u8; head[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&SOCKS5_AUTH_NONE) {
+        stream
+            .write_all(&[SOCKS5_VERSION, SOCKS5_AUTH_NO_ACCEPTABLE])
+            .await?;
+        return Err(ProxyError::Socks5Error(
+            "no acceptable authentication method".to_owned(),
+        ));
+    }
+    stream
+        .write_all(&[SOCKS5_VERSION, SOCKS5_AUTH_NONE])
+        .await?;
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    let addr = match head[3] {
+        SOCKS5_ATYP_IPV4 => {
+            let mut buf = [0u8; 6];
+            stream.read_exact(&mut buf).await?;
+            let ip = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
+            TargetAddr::Ip(SocketAddr::new(
+                ip.into(),
+                u16::from_be_bytes([buf[4], buf[5]]),
+            ))
+        }
+        SOCKS5_ATYP_IPV6 => {
+            let mut buf = [0u8; 18];
+            stream.read_exact(&mut buf).await?;
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&buf[..16]);
+            TargetAddr::Ip(SocketAddr::new(
+                Ipv6Addr::from(ip).into(),
+                u16::from_be_bytes([buf[16], buf[17]]),
+            ))
+        }
+        SOCKS5_ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            let mut buf = vec![0u8; len[0] as usize + 2];
+            stream.read_exact(&mut buf).await?;
+            let port = u16::from_be_bytes([buf[buf.len() - 2], buf[buf.len() - 1]]);
+            buf.truncate(buf.len() - 2);
+            let domain = String::from_utf8(buf)
+                .map_err(|_| ProxyError::Socks5Error("invalid domain".to_owned()))?;
+            TargetAddr::Domain(Cow::Owned(domain), port)
+        }
+        atyp => {
+            socks5_reply(stream, SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED, None).await?;
+            return Err(ProxyError::Socks5Error(format!(
+                "unsupported address type {}",
+                atyp
+            )));
+        }
+    };
+    let cmd = match head[1] {
+        0x01 => Socks5Command::Connect,
+        0x03 => Socks5Command::UdpAssociate,
+        cmd => {
+            socks5_reply(stream, SOCKS5_REPLY_COMMAND_NOT_SUPPORTED, None).await?;
+            return Err(ProxyError::Socks5Error(format!(
+                "unsupported command {}",
+                cmd
+            )));
+        }
+    };
+    Ok((cmd, addr))
+}
+
+/// Replies to the request of [`socks5_accept`], `bind` is the udp relay address of an association.
+pub async fn socks5_reply<S>(
+    stream: &mut S,
+    reply: u8,
+    bind: Option<SocketAddr>,
+) -> Result<(), ProxyError>
+where
+    S: AsyncWrite + Unpin,
+{
+    let bind = bind.unwrap_or_else(|| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
+    let mut buf = vec![SOCKS5_VERSION, reply, 0x00];
+    write_socks5_addr(&mut buf, &TargetAddr::Ip(bind));
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Parses a datagram of a socks5 udp association, returns the target and the payload.
+pub fn socks5_udp_parse(buf: &[u8]) -> Result<(TargetAddr<'static>, &[u8]), ProxyError> {
+    let err = || ProxyError::Socks5Error("invalid udp datagram".to_owned());
+    if buf.len() < 4 {
+        return Err(err());
+    }
+    if buf[2] != 0 {
+        return Err(ProxyError::Socks5Error(
+            "udp fragmentation is not supported".to_owned(),
+        ));
+    }
+    let (addr, pos) = match buf[3] {
+        SOCKS5_ATYP_IPV4 => {
+            if buf.len() < 10 {
+                return Err(err());
+            }
+            let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+            let port = u16::from_be_bytes([buf[8], buf[9]]);
+            (TargetAddr::Ip(SocketAddr::new(ip.into(), port)), 10)
+        }
+        SOCKS5_ATYP_IPV6 => {
+            if buf.len() < 22 {
+                return Err(err());
+            }
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&buf[4..20]);
+            let port = u16::from_be_bytes([buf[20], buf[21]]);
+            (
+                TargetAddr::Ip(SocketAddr::new(Ipv6Addr::from(ip).into(), port)),
+                22,
+            )
+        }
+        SOCKS5_ATYP_DOMAIN => {
+            let end = 5 + buf.get(4).copied().ok_or_else(err)? as usize;
+            if buf.len() < end + 2 {
+                return Err(err());
+            }
+            let domain = std::str::from_utf8(&buf[5..end]).map_err(|_| err())?;
+            let port = u16::from_be_bytes([buf[end], buf[end + 1]]);
+            (
+                TargetAddr::Domain(Cow::Owned(domain.to_owned()), port),
+                end + 2,
+            )
+        }
+        _ => return Err(err()),
+    };
+    Ok((addr, &buf[pos..]))
+}
+
+/// Wraps a datagram from `addr` to be sent to the client of a socks5 udp association.
+pub fn socks5_udp_make(addr: &TargetAddr, data: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0x00, 0x00, 0x00];
+    write_socks5_addr(&mut buf, addr);
+    buf.extend_from_slice(data);
+    buf
+}
+
+fn write_socks5_addr(buf: &mut Vec<u8>, addr: &TargetAddr) {
+    match addr {
+        TargetAddr::Ip(addr) => {
+            match addr.ip() {
+                IpAddr::V4(ip) => {
+                    buf.push(SOCKS5_ATYP_IPV4);
+                    buf.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    buf.push(SOCKS5_ATYP_IPV6);
+                    buf.extend_from_slice(&ip.octets());
+                }
+            }
+            buf.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        TargetAddr::Domain(domain, port) => {
+            buf.push(SOCKS5_ATYP_DOMAIN);
+            buf.push(domain.len().min(u8::MAX as usize) as u8);
+            buf.extend_from_slice(&domain.as_bytes()[..domain.len().min(u8::MAX as usize)]);
+            buf.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_socks5_accept() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let task = tokio::spawn(async move {
+            let res = socks5_accept(&mut server).await.unwrap();
+            socks5_reply(&mut server, SOCKS5_REPLY_SUCCEEDED, None)
+                .await
+                .unwrap();
+            res
+        });
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut buf = [0u8; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [5, 0]);
+        let mut req = vec![5, 1, 0, 3, 11];
+        req.extend_from_slice(b"example.com");
+        req.extend_from_slice(&443u16.to_be_bytes());
+        client.write_all(&req).await.unwrap();
+        let mut buf = [0u8; 10];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf[..4], [5, 0, 0, 1]);
+        let (cmd, addr) = task.await.unwrap();
+        assert_eq!(cmd, Socks5Command::Connect);
+        assert_eq!(addr, TargetAddr::Domain(Cow::Borrowed("example.com"), 443));
+    }
+
+    #[test]
+    fn test_socks5_udp() {
+        let addr = TargetAddr::Ip("192.168.1.2:53".parse().unwrap());
+        let buf = socks5_udp_make(&addr, b"query");
+        let (parsed, data) = socks5_udp_parse(&buf).unwrap();
+        assert_eq!(parsed, addr);
+        assert_eq!(data, b"query");
+        let addr = TargetAddr::Domain(Cow::Borrowed("localhost"), 1234);
+        let buf = socks5_udp_make(&addr, b"");
+        let (parsed, data) = socks5_udp_parse(&buf).unwrap();
+        assert_eq!(parsed, addr);
+        assert!(data.is_empty());
+        assert!(socks5_udp_parse(&buf[..8]).is_err());
+    }
+}
@@ -1,135 +1,350 @@
-use crate::{allow_err, anyhow::anyhow, ResultType};
-use protobuf::Message;
-use std::{net::SocketAddr, sync::Arc};
-use tokio::{self, stream::StreamExt, sync::mpsc};
-
-const QUIC_HBB: &[&[u8]] = &[b"hbb"];
-const SERVER_NAME: &str = "hbb";
-
-type Sender = mpsc::UnboundedSender<Value>;
-type Receiver = mpsc::UnboundedReceiver<Value>;
-
-pub fn new_server(socket: std::net::UdpSocket) -> ResultType<(Server, SocketAddr)> {
-    let mut transport_config = quinn::TransportConfig::default();
-    transport_config.stream_window_uni(0);
-    let mut server_config = quinn::ServerConfig::default();
-    server_config.transport = Arc::new(transport_config);
-    let mut server_config = quinn::ServerConfigBuilder::new(server_config);
-    server_config.protocols(QUIC_HBB);
-    // server_config.enable_keylog();
-    // server_config.use_stateless_retry(true);
-    let mut endpoint = quinn::Endpoint::builder();
-    endpoint.listen(server_config.build());
-    let (end, incoming) = endpoint.with_socket(socket)?;
-    Ok((Server { incoming }, end.local_addr()?))
-}
-
-pub async fn new_client(local_addr: &SocketAddr, peer: &SocketAddr) -> ResultType<Connection> {
-    let mut endpoint = quinn::Endpoint::builder();
-    let mut client_config = quinn::ClientConfigBuilder::default();
-    client_config.protocols(QUIC_HBB);
-    //client_config.enable_keylog();
-    endpoint.default_client_config(client_config.build());
-    let (endpoint, _) = endpoint.bind(local_addr)?;
-    let new_conn = endpoint.connect(peer, SERVER_NAME)?.await?;
-    Connection::new_for_client(new_conn.connection).await
-}
-
-pub struct Server {
-    incoming: quinn::Incoming,
-}
-
-impl Server {
-    #[inline]
-    pub async fn next(&mut self) -> ResultType<Option<Connection>> {
-        Connection::new_for_server(&mut self.incoming).await
+// QUIC transport of the peer sessions, negotiated in the punch hole and used over the hole
+// punched udp ports, with the fallback to tcp and relay.
+//
+// The session is a `FramedStream` over a bidirectional stream, which is opened by the
+// controlling side. The video frames go through their own unidirectional stream, opened by the
+// controlled side after login, so that a lost packet of them does not block the input and the
+// file transfer, and vice versa.
+//
+// The certificate is self-signed and not verified, like tcp the peers are authenticated and the
+// session is encrypted by the key exchange of the session itself. The video stream is encrypted
+// with a key derived from the one of its direction.
+//
+// It is built with the feature `quic`. The rendezvous server has to pass on the `quic` fields of
+// the punch hole messages, which the stock hbbs does not, so QUIC is only used with a server
+// which does, or on the lan, and tcp otherwise.
+
+use crate::{
+    bail,
+    tcp::{Encrypt, FramedStream, TcpStreamTrait},
+    ResultType,
+};
+use bytes::BytesMut;
+use std::{
+    convert::TryFrom,
+    io::{self, Error, ErrorKind},
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+const ALPN: &[u8] = b"rustdesk";
+const SERVER_NAME: &str = "rustdesk";
+// the first byte of the session stream, a stream is only seen by the peer after it is written
+const SESSION_STREAM: u8 = 0;
+const KEEP_ALIVE: Duration = Duration::from_secs(5);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The QUIC connection of a session, kept in its `FramedStream`.
+pub struct Channel {
+    // the endpoint is closed if it is dropped
+    _endpoint: quinn::Endpoint,
+    conn: quinn::Connection,
+    video_tx: Option<Box<FramedStream>>,
+    video_rx: Option<Box<FramedStream>>,
+}
+
+// one side of a quic stream, or both
+struct QuicStream {
+    send: Option<quinn::SendStream>,
+    recv: Option<quinn::RecvStream>,
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.recv.as_mut() {
+            Some(recv) => AsyncRead::poll_read(Pin::new(recv), cx, buf),
+            // eof
+            None => Poll::Ready(Ok(())),
+        }
     }
 }
 
-pub struct Connection {
-    conn: quinn::Connection,
-    tx: quinn::SendStream,
-    rx: Receiver,
-}
-
-type Value = ResultType<Vec<u8>>;
-
-impl Connection {
-    async fn new_for_server(incoming: &mut quinn::Incoming) -> ResultType<Option<Self>> {
-        if let Some(conn) = incoming.next().await {
-            let quinn::NewConnection {
-                connection: conn,
-                // uni_streams,
-                mut bi_streams,
-                ..
-            } = conn.await?;
-            let (tx, rx) = mpsc::unbounded_channel::<Value>();
-            tokio::spawn(async move {
-                loop {
-                    let stream = bi_streams.next().await;
-                    if let Some(stream) = stream {
-                        let stream = match stream {
-                            Err(e) => {
-                                tx.send(Err(e.into())).ok();
-                                break;
-                            }
-                            Ok(s) => s,
-                        };
-                        let cloned = tx.clone();
-                        tokio::spawn(async move {
-                            allow_err!(handle_request(stream.1, cloned).await);
-                        });
-                    } else {
-                        tx.send(Err(anyhow!("Reset by the peer"))).ok();
-                        break;
-                    }
-                }
-                log::info!("Exit connection outer loop");
-            });
-            let tx = conn.open_uni().await?;
-            Ok(Some(Self { conn, tx, rx }))
-        } else {
-            Ok(None)
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.send.as_mut() {
+            Some(send) => AsyncWrite::poll_write(Pin::new(send), cx, buf),
+            None => Poll::Ready(Err(Error::new(ErrorKind::BrokenPipe, "receive only"))),
         }
     }
 
-    async fn new_for_client(conn: quinn::Connection) -> ResultType<Self> {
-        let (tx, rx_quic) = conn.open_bi().await?;
-        let (tx_mpsc, rx) = mpsc::unbounded_channel::<Value>();
-        tokio::spawn(async move {
-            allow_err!(handle_request(rx_quic, tx_mpsc).await);
-        });
-        Ok(Self { conn, tx, rx })
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.send.as_mut() {
+            Some(send) => AsyncWrite::poll_flush(Pin::new(send), cx),
+            None => Poll::Ready(Ok(())),
+        }
     }
 
-    #[inline]
-    pub async fn next(&mut self) -> Option<Value> {
-        // None is returned when all Sender halves have dropped,
-        // indicating that no further values can be sent on the channel.
-        self.rx.recv().await
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.send.as_mut() {
+            Some(send) => AsyncWrite::poll_shutdown(Pin::new(send), cx),
+            None => Poll::Ready(Ok(())),
+        }
     }
+}
 
-    #[inline]
-    pub fn remote_address(&self) -> SocketAddr {
-        self.conn.remote_address()
+/// QUIC is tried before tcp, unless option `enable-quic` is "N" or a proxy is used.
+pub fn is_enabled() -> bool {
+    crate::config::Config::get_option("enable-quic") != "N"
+        && crate::config::Config::get_socks().is_none()
+}
+
+/// A udp socket bound to `local_addr`, reusing the port of the tcp connection to the rendezvous
+/// server like the tcp hole punching does.
+pub fn new_socket(local_addr: SocketAddr) -> ResultType<std::net::UdpSocket> {
+    Ok(crate::udp::new_socket(local_addr, true, 0)?.into_udp_socket())
+}
+
+/// Send a few datagrams to `peer` to open the gateway for its incoming QUIC packets.
+pub fn punch(socket: &std::net::UdpSocket, peer: SocketAddr) {
+    for _ in 0..3 {
+        socket.send_to(&[0u8], peer).ok();
     }
+}
 
-    #[inline]
-    pub async fn send_raw(&mut self, bytes: &[u8]) -> ResultType<()> {
-        self.tx.write_all(bytes).await?;
-        Ok(())
+fn transport_config() -> Arc<quinn::TransportConfig> {
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE));
+    if let Ok(timeout) = quinn::IdleTimeout::try_from(IDLE_TIMEOUT) {
+        transport.max_idle_timeout(Some(timeout));
+    }
+    Arc::new(transport)
+}
+
+fn crypto_provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn server_config() -> ResultType<quinn::ServerConfig> {
+    let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_owned()])?;
+    let key = rustls::pki_types::PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+    let mut crypto = rustls::ServerConfig::builder_with_provider(crypto_provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.cert.der().clone()], key.into())?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(
+        quinn::crypto::rustls::QuicServerConfig::try_from(crypto)?,
+    ));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+fn client_config() -> ResultType<quinn::ClientConfig> {
+    let provider = crypto_provider();
+    let mut crypto = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let mut config = quinn::ClientConfig::new(Arc::new(
+        quinn::crypto::rustls::QuicClientConfig::try_from(crypto)?,
+    ));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+// The session authenticates the peer, see above.
+#[derive(Debug)]
+struct NoVerification(Arc<rustls::crypto::CryptoProvider>);
+
+impl rustls::client::danger::ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
     }
 
-    #[inline]
-    pub async fn send(&mut self, msg: &dyn Message) -> ResultType<()> {
-        match msg.write_to_bytes() {
-            Ok(bytes) => self.send_raw(&bytes).await?,
-            err => allow_err!(err),
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn new_endpoint(
+    socket: std::net::UdpSocket,
+    server: Option<quinn::ServerConfig>,
+) -> ResultType<quinn::Endpoint> {
+    Ok(quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        server,
+        socket,
+        Arc::new(quinn::TokioRuntime),
+    )?)
+}
+
+/// Connect to the peer from `socket`, the controlling side.
+pub async fn connect(
+    socket: std::net::UdpSocket,
+    peer: SocketAddr,
+    ms_timeout: u64,
+) -> ResultType<FramedStream> {
+    let local_addr = socket.local_addr()?;
+    let mut endpoint = new_endpoint(socket, None)?;
+    endpoint.set_default_client_config(client_config()?);
+    let conn = super::timeout(ms_timeout, endpoint.connect(peer, SERVER_NAME)?).await??;
+    let (mut send, recv) = conn.open_bi().await?;
+    send.write_all(&[SESSION_STREAM]).await?;
+    Ok(new_session(endpoint, conn, send, recv, local_addr))
+}
+
+/// Accept the connection of the peer on `socket`, the controlled side.
+pub async fn accept(
+    socket: std::net::UdpSocket,
+    ms_timeout: u64,
+) -> ResultType<(FramedStream, SocketAddr)> {
+    let local_addr = socket.local_addr()?;
+    let endpoint = new_endpoint(socket, Some(server_config()?))?;
+    let accept = async {
+        let Some(incoming) = endpoint.accept().await else {
+            bail!("QUIC endpoint closed");
+        };
+        let conn = incoming.await?;
+        let (send, mut recv) = conn.accept_bi().await?;
+        if recv.read_u8().await? != SESSION_STREAM {
+            bail!("Unexpected QUIC stream");
+        }
+        Ok((conn, send, recv))
+    };
+    let (conn, send, recv) = super::timeout(ms_timeout, accept).await??;
+    let peer = conn.remote_address();
+    Ok((new_session(endpoint, conn, send, recv, local_addr), peer))
+}
+
+fn new_session(
+    endpoint: quinn::Endpoint,
+    conn: quinn::Connection,
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+    local_addr: SocketAddr,
+) -> FramedStream {
+    let mut stream = FramedStream::from(
+        QuicStream {
+            send: Some(send),
+            recv: Some(recv),
+        },
+        local_addr,
+    );
+    stream.4 = Some(Box::new(Channel {
+        _endpoint: endpoint,
+        conn,
+        video_tx: None,
+        video_rx: None,
+    }));
+    stream
+}
+
+fn new_stream(
+    stream: impl TcpStreamTrait + Send + Sync + 'static,
+    encrypt: Option<&Encrypt>,
+) -> FramedStream {
+    // not used for the video stream
+    let addr = SocketAddr::from(([0, 0, 0, 0], 0));
+    let mut stream = FramedStream::from(stream, addr);
+    stream.2 = encrypt.map(|e| e.video_stream());
+    stream
+}
+
+impl Channel {
+    /// Open the video stream, the controlled side.
+    pub(crate) async fn open_video(
+        &mut self,
+        encrypt: Option<&Encrypt>,
+        ms_send_timeout: u64,
+    ) -> ResultType<()> {
+        if self.video_tx.is_none() {
+            let send = self.conn.open_uni().await?;
+            let mut stream = new_stream(
+                QuicStream {
+                    send: Some(send),
+                    recv: None,
+                },
+                encrypt,
+            );
+            stream.set_send_timeout(ms_send_timeout);
+            self.video_tx = Some(Box::new(stream));
         }
         Ok(())
     }
-}
 
-async fn handle_request(rx: quinn::RecvStream, tx: Sender) -> ResultType<()> {
-    Ok(())
+    pub(crate) fn video_tx(&mut self) -> Option<&mut FramedStream> {
+        self.video_tx.as_deref_mut()
+    }
+
+    /// The next message of the video stream, which is accepted first, the controlling side.
+    /// It never returns if there is none.
+    pub(crate) async fn next_video(
+        &mut self,
+        encrypt: Option<&Encrypt>,
+    ) -> Option<Result<BytesMut, Error>> {
+        loop {
+            if let Some(rx) = self.video_rx.as_mut() {
+                match rx.next().await {
+                    Some(res) => return Some(res),
+                    None => {
+                        // the peer finished it, the session stream goes on
+                        self.video_rx = None;
+                        return std::future::pending().await;
+                    }
+                }
+            }
+            match self.conn.accept_uni().await {
+                Ok(recv) => {
+                    self.video_rx = Some(Box::new(new_stream(
+                        QuicStream {
+                            send: None,
+                            recv: Some(recv),
+                        },
+                        encrypt,
+                    )));
+                }
+                // the session stream gets the error too
+                Err(_) => return std::future::pending().await,
+            }
+        }
+    }
 }
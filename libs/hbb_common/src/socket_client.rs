@@ -2,6 +2,7 @@ use crate::{
     config::{Config, NetworkType},
     tcp::FramedStream,
     udp::FramedSocket,
+    websocket::{self, is_ws_url},
     ResultType,
 };
 use anyhow::Context;
@@ -27,6 +28,10 @@ pub fn check_port<T: std::string::ToString>(host: T, port: i32) -> String {
 #[inline]
 pub fn increase_port<T: std::string::ToString>(host: T, offset: i32) -> String {
     let host = host.to_string();
+    // a websocket url is one endpoint, the bridge behind it decides the port
+    if is_ws_url(&host) {
+        return host;
+    }
     if crate::is_ipv6_str(&host) {
         if host.starts_with('[') {
             let tmp: Vec<&str> = host.split("]:").collect();
@@ -50,6 +55,22 @@ pub fn increase_port<T: std::string::ToString>(host: T, offset: i32) -> String {
 }
 
 pub fn test_if_valid_server(host: &str, test_with_proxy: bool) -> String {
+    // a list of servers to fail over
+    if host.contains(',') {
+        return host
+            .split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| test_if_valid_server(x, test_with_proxy))
+            .find(|x| !x.is_empty())
+            .unwrap_or_default();
+    }
+    if is_ws_url(host) {
+        return match websocket::parse(host) {
+            Err(err) => err.to_string(),
+            Ok(_) => "".to_owned(),
+        };
+    }
     let host = check_port(host, 0);
     use std::net::ToSocketAddrs;
 
@@ -114,6 +135,10 @@ pub async fn connect_tcp_local<
     local: Option<SocketAddr>,
     ms_timeout: u64,
 ) -> ResultType<FramedStream> {
+    let url = target.to_string();
+    if is_ws_url(&url) {
+        return websocket::connect(&url, ms_timeout).await;
+    }
     if let Some(conf) = Config::get_socks() {
         return FramedStream::connect(target, local, &conf, ms_timeout).await;
     }
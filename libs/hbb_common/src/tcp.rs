@@ -3,6 +3,7 @@ use anyhow::Context as AnyhowCtx;
 use bytes::{BufMut, Bytes, BytesMut};
 use futures::{SinkExt, StreamExt};
 use protobuf::Message;
+use sha2::{Digest, Sha256};
 use sodiumoxide::crypto::{
     box_,
     secretbox::{self, Key, Nonce},
@@ -13,6 +14,7 @@ use std::{
     ops::{Deref, DerefMut},
     pin::Pin,
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadBuf},
@@ -24,16 +26,55 @@ use tokio_util::codec::Framed;
 pub trait TcpStreamTrait: AsyncRead + AsyncWrite + Unpin {}
 pub struct DynTcpStream(pub(crate) Box<dyn TcpStreamTrait + Send + Sync>);
 
+/// The version of the key exchange of the sessions. The version 2 binds the session key to the
+/// ephemeral keys of the handshake, and has a key for each direction which is ratcheted forward
+/// in band. The session key is not kept, the keys before a rekey can not be derived from the
+/// ones after it.
+pub const KEY_VERSION: u32 = 2;
+const REKEY_BYTES: u64 = 1 << 30;
+const REKEY_INTERVAL: Duration = Duration::from_secs(3600);
+// the plaintext of the rekey frame, a protobuf message never starts with 0
+const REKEY_FRAME: &[u8] = b"\0rekey";
+const SESSION_KEY_CONTEXT: &[u8] = b"rustdesk-session-v2";
+const INITIATOR_KEY_CONTEXT: &[u8] = b"rustdesk-initiator";
+const RESPONDER_KEY_CONTEXT: &[u8] = b"rustdesk-responder";
+const REKEY_CONTEXT: &[u8] = b"rustdesk-rekey";
+const BINDING_CONTEXT: &[u8] = b"rustdesk-session-binding";
+const VIDEO_KEY_CONTEXT: &[u8] = b"rustdesk-quic-video";
+
+// key, send and receive sequence numbers, the keys of the version 2 and the session binding,
+// the key is the one of the video stream with the version 2
 #[derive(Clone)]
-pub struct Encrypt(Key, u64, u64);
+pub struct Encrypt(Key, u64, u64, Option<Rekey>, Key);
+
+// The keys of the version 2, the send key is replaced after `max_bytes` or `max_interval`.
+#[derive(Clone)]
+struct Rekey {
+    initiator: bool,
+    send_key: Key,
+    recv_key: Key,
+    // since the send key is set
+    sent_bytes: u64,
+    since: Instant,
+    max_bytes: u64,
+    max_interval: Duration,
+}
 
 pub struct FramedStream(
     pub(crate) Framed<DynTcpStream, BytesCodec>,
     pub(crate) SocketAddr,
     pub(crate) Option<Encrypt>,
     pub(crate) u64,
+    // the QUIC connection, if the stream is a QUIC stream
+    pub(crate) Option<Box<QuicChannel>>,
 );
 
+#[cfg(feature = "quic")]
+pub(crate) type QuicChannel = crate::quic::Channel;
+// never constructed without QUIC
+#[cfg(not(feature = "quic"))]
+pub(crate) enum QuicChannel {}
+
 impl Deref for FramedStream {
     type Target = Framed<DynTcpStream, BytesCodec>;
 
@@ -102,6 +143,7 @@ impl FramedStream {
                         addr,
                         None,
                         0,
+                        None,
                     ));
                 }
             }
@@ -136,6 +178,7 @@ impl FramedStream {
             addr,
             None,
             0,
+            None,
         )
     }
 
@@ -148,6 +191,12 @@ impl FramedStream {
         self.2.is_some()
     }
 
+    /// Known to both sides of the secure connection only, a signature over it can not be
+    /// replayed to another session. Empty if the connection is not secured.
+    pub fn session_binding(&self) -> Vec<u8> {
+        self.2.as_ref().map(|e| e.4 .0.to_vec()).unwrap_or_default()
+    }
+
     #[inline]
     pub async fn send(&mut self, msg: &impl Message) -> ResultType<()> {
         self.send_raw(msg.write_to_bytes()?).await
@@ -157,7 +206,11 @@ impl FramedStream {
     pub async fn send_raw(&mut self, msg: Vec<u8>) -> ResultType<()> {
         let mut msg = msg;
         if let Some(key) = self.2.as_mut() {
+            let rekey = key.rekey();
             msg = key.enc(&msg);
+            if let Some(rekey) = rekey {
+                self.send_bytes(bytes::Bytes::from(rekey)).await?;
+            }
         }
         self.send_bytes(bytes::Bytes::from(msg)).await?;
         Ok(())
@@ -173,17 +226,61 @@ impl FramedStream {
         Ok(())
     }
 
+    /// Send a video frame, through its own stream if it is QUIC.
+    #[inline]
+    pub async fn send_video(&mut self, msg: &impl Message) -> ResultType<()> {
+        #[cfg(feature = "quic")]
+        if let Some(video) = self.4.as_mut().and_then(|c| c.video_tx()) {
+            return video.send(msg).await;
+        }
+        self.send(msg).await
+    }
+
+    pub fn is_quic(&self) -> bool {
+        self.4.is_some()
+    }
+
+    /// Open the stream of the video frames if it is QUIC, after the key is set.
+    pub async fn open_video_stream(&mut self) -> ResultType<()> {
+        #[cfg(feature = "quic")]
+        if let Some(channel) = self.4.as_mut() {
+            channel.open_video(self.2.as_ref(), self.3).await?;
+        }
+        Ok(())
+    }
+
     #[inline]
     pub async fn next(&mut self) -> Option<Result<BytesMut, Error>> {
-        let mut res = self.0.next().await;
-        if let Some(Ok(bytes)) = res.as_mut() {
-            if let Some(key) = self.2.as_mut() {
-                if let Err(err) = key.dec(bytes) {
-                    return Some(Err(err));
+        #[cfg(feature = "quic")]
+        if let Some(channel) = self.4.as_mut() {
+            let encrypt = self.2.clone();
+            return tokio::select! {
+                res = Self::next_framed(&mut self.0, &mut self.2) => res,
+                res = channel.next_video(encrypt.as_ref()) => res,
+            };
+        }
+        Self::next_framed(&mut self.0, &mut self.2).await
+    }
+
+    #[inline]
+    async fn next_framed(
+        framed: &mut Framed<DynTcpStream, BytesCodec>,
+        encrypt: &mut Option<Encrypt>,
+    ) -> Option<Result<BytesMut, Error>> {
+        loop {
+            let mut res = framed.next().await;
+            if let Some(Ok(bytes)) = res.as_mut() {
+                if let Some(key) = encrypt.as_mut() {
+                    if let Err(err) = key.dec(bytes) {
+                        return Some(Err(err));
+                    }
+                    if key.is_rekey(bytes) {
+                        continue;
+                    }
                 }
             }
+            return res;
         }
-        res
     }
 
     #[inline]
@@ -199,6 +296,19 @@ impl FramedStream {
         self.2 = Some(Encrypt::new(key));
     }
 
+    /// Set the key of the version 2 key exchange, `initiator` is the controlling side.
+    pub fn set_session_key(&mut self, key: Key, initiator: bool) {
+        self.2 = Some(Encrypt::new_session(key, initiator));
+    }
+
+    /// Replace the send key after `bytes` or `interval`, only with the version 2 key.
+    pub fn set_rekey_limits(&mut self, bytes: u64, interval: Duration) {
+        if let Some(rekey) = self.2.as_mut().and_then(|e| e.3.as_mut()) {
+            rekey.max_bytes = bytes;
+            rekey.max_interval = interval;
+        }
+    }
+
     fn get_nonce(seqnum: u64) -> Nonce {
         let mut nonce = Nonce([0u8; secretbox::NONCEBYTES]);
         nonce.0[..std::mem::size_of_val(&seqnum)].copy_from_slice(&seqnum.to_le_bytes());
@@ -292,7 +402,49 @@ impl<R: AsyncRead + AsyncWrite + Unpin> TcpStreamTrait for R {}
 
 impl Encrypt {
     pub fn new(key: Key) -> Self {
-        Self(key, 0, 0)
+        let binding = hash_key(BINDING_CONTEXT, &key);
+        Self(key, 0, 0, None, binding)
+    }
+
+    /// The version 2, the two directions have their own keys. `key` is dropped, which zeroes it.
+    pub fn new_session(key: Key, initiator: bool) -> Self {
+        let initiator_key = hash_key(INITIATOR_KEY_CONTEXT, &key);
+        let responder_key = hash_key(RESPONDER_KEY_CONTEXT, &key);
+        // the video goes from the responder to the initiator
+        let video_key = hash_key(VIDEO_KEY_CONTEXT, &responder_key);
+        let (send_key, recv_key) = if initiator {
+            (initiator_key, responder_key)
+        } else {
+            (responder_key, initiator_key)
+        };
+        let rekey = Rekey {
+            initiator,
+            send_key,
+            recv_key,
+            sent_bytes: 0,
+            since: Instant::now(),
+            max_bytes: REKEY_BYTES,
+            max_interval: REKEY_INTERVAL,
+        };
+        let binding = hash_key(BINDING_CONTEXT, &key);
+        Self(video_key, 0, 0, Some(rekey), binding)
+    }
+
+    /// The same version and side for the video stream of the session, which has its own
+    /// sequence numbers.
+    #[cfg(any(feature = "quic", test))]
+    pub(crate) fn video_stream(&self) -> Self {
+        match self.3.as_ref() {
+            Some(rekey) => {
+                let mut encrypt = Self::new_session(self.0.clone(), rekey.initiator);
+                if let Some(r) = encrypt.3.as_mut() {
+                    r.max_bytes = rekey.max_bytes;
+                    r.max_interval = rekey.max_interval;
+                }
+                encrypt
+            }
+            None => Self::new(hash_key(VIDEO_KEY_CONTEXT, &self.0)),
+        }
     }
 
     pub fn dec(&mut self, bytes: &mut BytesMut) -> Result<(), Error> {
@@ -301,7 +453,8 @@ impl Encrypt {
         }
         self.2 += 1;
         let nonce = FramedStream::get_nonce(self.2);
-        match secretbox::open(bytes, &nonce, &self.0) {
+        let key = self.3.as_ref().map(|r| &r.recv_key).unwrap_or(&self.0);
+        match secretbox::open(bytes, &nonce, key) {
             Ok(res) => {
                 bytes.clear();
                 bytes.put_slice(&res);
@@ -314,7 +467,70 @@ impl Encrypt {
     pub fn enc(&mut self, data: &[u8]) -> Vec<u8> {
         self.1 += 1;
         let nonce = FramedStream::get_nonce(self.1);
-        secretbox::seal(&data, &nonce, &self.0)
+        let key = match self.3.as_mut() {
+            Some(rekey) => {
+                rekey.sent_bytes += data.len() as u64;
+                &rekey.send_key
+            }
+            None => &self.0,
+        };
+        secretbox::seal(&data, &nonce, key)
+    }
+
+    // The rekey frame if the send key is due, encrypted with the old key and sent before the
+    // frames with the new one.
+    fn rekey(&mut self) -> Option<Vec<u8>> {
+        let due = self.3.as_ref().map_or(false, |r| {
+            r.sent_bytes >= r.max_bytes || r.since.elapsed() >= r.max_interval
+        });
+        if !due {
+            return None;
+        }
+        let frame = self.enc(REKEY_FRAME);
+        if let Some(rekey) = self.3.as_mut() {
+            rekey.send_key = hash_key(REKEY_CONTEXT, &rekey.send_key);
+            rekey.sent_bytes = 0;
+            rekey.since = Instant::now();
+        }
+        // a new key, the nonces start over
+        self.1 = 0;
+        Some(frame)
+    }
+
+    // Whether the decrypted `bytes` is the rekey frame, the receive key is replaced if so.
+    fn is_rekey(&mut self, bytes: &[u8]) -> bool {
+        match self.3.as_mut() {
+            Some(rekey) if bytes == REKEY_FRAME => {
+                rekey.recv_key = hash_key(REKEY_CONTEXT, &rekey.recv_key);
+                self.2 = 0;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The session key of the version 2, from the key sealed in the handshake and the ephemeral
+    /// keys of both sides, so that it belongs to this handshake only. The secrecy is the one of
+    /// the sealed key, the box of the ephemeral keys.
+    pub fn session_key(
+        key: &Key,
+        our_pk_b: &box_::PublicKey,
+        their_pk_b: &box_::PublicKey,
+        initiator: bool,
+    ) -> Key {
+        let (initiator_pk_b, responder_pk_b) = if initiator {
+            (our_pk_b, their_pk_b)
+        } else {
+            (their_pk_b, our_pk_b)
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(SESSION_KEY_CONTEXT);
+        hasher.update(&key.0);
+        hasher.update(&initiator_pk_b.0);
+        hasher.update(&responder_pk_b.0);
+        let mut session = [0u8; secretbox::KEYBYTES];
+        session.copy_from_slice(&hasher.finalize()[..secretbox::KEYBYTES]);
+        Key(session)
     }
 
     pub fn decode(
@@ -339,3 +555,109 @@ impl Encrypt {
         Ok(Key(key))
     }
 }
+
+pub(crate) fn hash_key(context: &[u8], key: &Key) -> Key {
+    let mut hasher = Sha256::new();
+    hasher.update(context);
+    hasher.update(&key.0);
+    let mut res = [0u8; secretbox::KEYBYTES];
+    res.copy_from_slice(&hasher.finalize()[..secretbox::KEYBYTES]);
+    Key(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (FramedStream, FramedStream) {
+        let (a, b) = tokio::io::duplex(1 << 16);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        (FramedStream::from(a, addr), FramedStream::from(b, addr))
+    }
+
+    async fn echo(a: &mut FramedStream, b: &mut FramedStream, data: &[u8]) {
+        a.send_raw(data.to_vec()).await.unwrap();
+        let bytes = b.next().await.unwrap().unwrap();
+        assert_eq!(&bytes[..], data);
+    }
+
+    fn keys(stream: &FramedStream) -> &Rekey {
+        stream.2.as_ref().unwrap().3.as_ref().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_session_key() {
+        // the controlling side seals a key to the ephemeral key of the controlled side
+        let (server_pk, server_sk) = box_::gen_keypair();
+        let (client_pk, client_sk) = box_::gen_keypair();
+        let key = secretbox::gen_key();
+        let nonce = box_::Nonce([0u8; box_::NONCEBYTES]);
+        let sealed = box_::seal(&key.0, &nonce, &server_pk, &client_sk);
+        let client_key = Encrypt::session_key(&key, &client_pk, &server_pk, true);
+        let opened = Encrypt::decode(&sealed, &client_pk.0, &server_sk).unwrap();
+        let server_key = Encrypt::session_key(&opened, &server_pk, &client_pk, false);
+        assert_eq!(client_key.0, server_key.0);
+        assert_ne!(client_key.0, key.0);
+
+        let (mut a, mut b) = pair();
+        a.set_session_key(client_key, true);
+        b.set_session_key(server_key, false);
+        echo(&mut a, &mut b, b"hello").await;
+        echo(&mut b, &mut a, b"world").await;
+        // the same nonces are not used with the same key in the two directions
+        assert_ne!(keys(&a).send_key.0, keys(&a).recv_key.0);
+        assert_eq!(keys(&a).send_key.0, keys(&b).recv_key.0);
+        assert_eq!(keys(&b).send_key.0, keys(&a).recv_key.0);
+        assert_eq!(a.session_binding(), b.session_binding());
+        assert_eq!(a.session_binding().len(), secretbox::KEYBYTES);
+        assert!(pair().0.session_binding().is_empty());
+        // the video stream has its own keys, and the session key is not kept
+        let (a_video, b_video) = (
+            a.2.as_ref().unwrap().video_stream(),
+            b.2.as_ref().unwrap().video_stream(),
+        );
+        let (a_video, b_video) = (a_video.3.unwrap(), b_video.3.unwrap());
+        assert_eq!(b_video.send_key.0, a_video.recv_key.0);
+        assert_ne!(b_video.send_key.0, keys(&b).send_key.0);
+        assert_ne!(a.2.as_ref().unwrap().0 .0, server_key.0);
+    }
+
+    #[tokio::test]
+    async fn test_rekey() {
+        let key = secretbox::gen_key();
+        let (mut a, mut b) = pair();
+        a.set_session_key(key.clone(), true);
+        b.set_session_key(key, false);
+        let (a_key, b_key) = (keys(&a).send_key.clone(), keys(&b).send_key.clone());
+        // by bytes on one side, on every frame on the other
+        a.set_rekey_limits(100, REKEY_INTERVAL);
+        b.set_rekey_limits(REKEY_BYTES, Duration::ZERO);
+        for i in 1..=10u8 {
+            echo(&mut a, &mut b, &[i; 40]).await;
+            echo(&mut b, &mut a, &[i; 40]).await;
+        }
+        assert_ne!(keys(&a).send_key.0, a_key.0);
+        assert_ne!(keys(&b).send_key.0, b_key.0);
+        assert_eq!(keys(&a).send_key.0, keys(&b).recv_key.0);
+        assert_eq!(keys(&b).send_key.0, keys(&a).recv_key.0);
+    }
+
+    #[tokio::test]
+    async fn test_key_versions() {
+        let key = secretbox::gen_key();
+        let (mut a, mut b) = pair();
+        a.set_key(key.clone());
+        b.set_key(key.clone());
+        // no rekey with the old version, the old peers do not know the rekey frame
+        a.set_rekey_limits(1, Duration::ZERO);
+        echo(&mut a, &mut b, b"v1").await;
+        echo(&mut b, &mut a, b"v1").await;
+        assert!(a.2.as_ref().unwrap().3.is_none());
+
+        let (mut a, mut b) = pair();
+        a.set_session_key(key.clone(), true);
+        b.set_key(key);
+        a.send_raw(b"v2".to_vec()).await.unwrap();
+        assert!(b.next().await.unwrap().is_err());
+    }
+}
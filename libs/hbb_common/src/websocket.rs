@@ -0,0 +1,223 @@
+//! WebSocket transport for networks that only let TLS on 443 out.
+//!
+//! The websocket is used as a plain byte pipe: the usual `BytesCodec` framed
+//! protobuf messages are written into binary websocket messages, so the other
+//! end only needs a websocket to tcp bridge in front of the tcp ports of the
+//! rendezvous and relay servers (e.g. `wss://rs.example.com/` forwarded to
+//! 21116 and `wss://relay.example.com/` forwarded to 21117).
+
+use crate::{
+    bail,
+    config::Config,
+    tcp::{DynTcpStream, FramedStream},
+    ResultType,
+};
+use anyhow::Context;
+use futures::{ready, Sink, Stream};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context as TaskContext, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{client_async, tungstenite::Message, WebSocketStream};
+
+const SECURE_SCHEME: &str = "wss://";
+const PLAIN_SCHEME: &str = "ws://";
+
+/// Whether the server address in the settings is a websocket url rather than `host[:port]`.
+#[inline]
+pub fn is_ws_url(host: &str) -> bool {
+    let host = host.trim().to_lowercase();
+    host.starts_with(SECURE_SCHEME) || host.starts_with(PLAIN_SCHEME)
+}
+
+/// Returns whether tls is used, the host and the port of a websocket url.
+pub fn parse(url: &str) -> ResultType<(bool, String, u16)> {
+    let parsed = url::Url::parse(url.trim())?;
+    let secure = match parsed.scheme() {
+        "wss" => true,
+        "ws" => false,
+        scheme => bail!("Unsupported websocket scheme: {}", scheme),
+    };
+    let host = parsed
+        .host_str()
+        .context(format!("No host in websocket url {}", url))?
+        .to_owned();
+    let port = parsed
+        .port_or_known_default()
+        .unwrap_or(if secure { 443 } else { 80 });
+    Ok((secure, host, port))
+}
+
+pub async fn connect(url: &str, ms_timeout: u64) -> ResultType<FramedStream> {
+    let url = url.trim();
+    let (secure, host, port) = parse(url)?;
+    // the host of an ipv6 url is already in brackets
+    let target = format!("{}:{}", host, port);
+    let stream = if let Some(conf) = Config::get_socks() {
+        FramedStream::connect(target.as_str(), None, &conf, ms_timeout).await?
+    } else {
+        FramedStream::new(target.as_str(), None, ms_timeout).await?
+    };
+    let local_addr = stream.local_addr();
+    let stream = stream.0.into_inner();
+    let stream = if secure {
+        let domain = host.trim_start_matches('[').trim_end_matches(']');
+        let tls = super::timeout(ms_timeout, tls_connect(domain, stream)).await??;
+        DynTcpStream(Box::new(tls))
+    } else {
+        stream
+    };
+    let (ws, _) = super::timeout(ms_timeout, client_async(url, stream)).await??;
+    log::info!("Connected to websocket {}", url);
+    Ok(FramedStream::from(WsStream::new(ws), local_addr))
+}
+
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+async fn tls_connect(
+    domain: &str,
+    stream: DynTcpStream,
+) -> ResultType<tokio_native_tls::TlsStream<DynTcpStream>> {
+    use tokio_native_tls::{native_tls, TlsConnector};
+    let connector = TlsConnector::from(native_tls::TlsConnector::new()?);
+    Ok(connector.connect(domain, stream).await?)
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+async fn tls_connect(
+    domain: &str,
+    stream: DynTcpStream,
+) -> ResultType<tokio_rustls::client::TlsStream<DynTcpStream>> {
+    use std::convert::TryFrom;
+    // the platform verifier honors the roots installed for tls inspection
+    let config = rustls_platform_verifier::tls_config();
+    let domain = rustls_pki_types::ServerName::try_from(domain.to_owned())?;
+    let connector = tokio_rustls::TlsConnector::from(std::sync::Arc::new(config));
+    Ok(connector.connect(domain, stream).await?)
+}
+
+/// Byte stream over the binary messages of a websocket, so that it can be
+/// used as a `DynTcpStream` under `FramedStream`.
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+}
+
+impl<S> WsStream<S> {
+    pub fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            read_buf: Vec::new(),
+            read_pos: 0,
+        }
+    }
+}
+
+#[inline]
+fn to_io_error(err: tokio_tungstenite::tungstenite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.read_pos < self.read_buf.len() {
+                let n = buf.remaining().min(self.read_buf.len() - self.read_pos);
+                let pos = self.read_pos;
+                buf.put_slice(&self.read_buf[pos..pos + n]);
+                self.read_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    self.read_buf = data;
+                    self.read_pos = 0;
+                }
+                // eof
+                None | Some(Ok(Message::Close(_))) => return Poll::Ready(Ok(())),
+                // pings are answered by tungstenite itself
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Poll::Ready(Err(to_io_error(err))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(to_io_error)?;
+        Pin::new(&mut self.inner)
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(to_io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(to_io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_close(cx)
+            .map_err(to_io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_parse() {
+        assert!(is_ws_url("wss://rs.example.com"));
+        assert!(is_ws_url("WS://rs.example.com:8080/rendezvous"));
+        assert!(!is_ws_url("rs.example.com:21116"));
+        assert_eq!(
+            parse("wss://rs.example.com").unwrap(),
+            (true, "rs.example.com".to_owned(), 443)
+        );
+        assert_eq!(
+            parse("ws://[::1]:8080/relay").unwrap(),
+            (false, "[::1]".to_owned(), 8080)
+        );
+        assert!(parse("https://rs.example.com").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_framed_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, addr) = listener.accept().await.unwrap();
+            let ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let mut stream = FramedStream::from(WsStream::new(ws), addr);
+            while let Some(Ok(bytes)) = stream.next().await {
+                stream.send_bytes(bytes.freeze()).await.unwrap();
+            }
+        });
+        let mut stream = connect(&format!("ws://{}/", addr), 3_000).await.unwrap();
+        let big = vec![7u8; 100_000];
+        for data in [&b"hello"[..], &[], &big[..]] {
+            stream
+                .send_bytes(Bytes::copy_from_slice(data))
+                .await
+                .unwrap();
+            let echo = stream.next().await.unwrap().unwrap();
+            assert_eq!(&echo[..], data);
+        }
+    }
+}
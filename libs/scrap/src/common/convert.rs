@@ -6,8 +6,6 @@
 
 include!(concat!(env!("OUT_DIR"), "/yuv_ffi.rs"));
 
-#[cfg(not(target_os = "ios"))]
-use crate::PixelBuffer;
 use crate::{generate_call_macro, EncodeYuvFormat, TraitPixelBuffer};
 use hbb_common::{bail, log, ResultType};
 
@@ -195,7 +193,7 @@ pub mod hw {
 }
 #[cfg(not(target_os = "ios"))]
 pub fn convert_to_yuv(
-    captured: &PixelBuffer,
+    captured: &impl TraitPixelBuffer,
     dst_fmt: EncodeYuvFormat,
     dst: &mut Vec<u8>,
     mid_data: &mut Vec<u8>,
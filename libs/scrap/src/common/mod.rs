@@ -49,7 +49,11 @@ pub const STRIDE_ALIGN: usize = 64; // commonly used in libvpx vpx_img_alloc cal
 pub const HW_STRIDE_ALIGN: usize = 0; // recommended by av_frame_get_buffer
 
 pub mod aom;
+pub mod mp4_audio;
 pub mod record;
+pub mod record_store;
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+pub mod session_record;
 mod vpx;
 
 #[repr(usize)]
@@ -0,0 +1,507 @@
+// The hardware muxer writes video only, so the audio is buffered while recording and added to
+// the mp4 as an opus track when it is finished.
+// https://opus-codec.org/docs/opus_in_isobmff.html
+
+use crate::record::opus_packet_duration_ns;
+use hbb_common::{bail, message_proto::AudioFormat, ResultType};
+use std::{
+    convert::TryInto,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::PathBuf,
+};
+
+// opus is always decoded at 48kHz
+const TIMESCALE: u32 = 48_000;
+
+/// Opus packets buffered in a file until the mp4 is finished.
+pub struct OpusPackets {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    count: usize,
+    failed: bool,
+}
+
+impl OpusPackets {
+    pub fn create(path: PathBuf) -> ResultType<Self> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(&path)?),
+            path,
+            count: 0,
+            failed: false,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// `timestamp_ns` is counted from the first video frame.
+    pub fn write(&mut self, data: &[u8], timestamp_ns: u64) -> bool {
+        // only the packets before a failed write are read back
+        if self.failed {
+            return false;
+        }
+        let ok = self.writer.write_all(&timestamp_ns.to_le_bytes()).is_ok()
+            && self
+                .writer
+                .write_all(&(data.len() as u32).to_le_bytes())
+                .is_ok()
+            && self.writer.write_all(data).is_ok();
+        if ok {
+            self.count += 1;
+        } else {
+            self.failed = true;
+        }
+        ok
+    }
+
+    pub fn remove(self) {
+        let path = self.path.clone();
+        drop(self);
+        fs::remove_file(path).ok();
+    }
+}
+
+/// Appends the packets to the mp4 as an opus track, the buffered packets are removed.
+///
+/// The new track goes in a copy of the moov box written at the end of the file, the old moov
+/// box becomes a free box as the last step, so the file stays playable if it fails halfway.
+pub fn append_opus_track(
+    filename: &str,
+    format: &AudioFormat,
+    mut packets: OpusPackets,
+) -> ResultType<()> {
+    let res = match packets.writer.flush() {
+        Ok(()) => append(filename, format, &packets),
+        Err(e) => Err(e.into()),
+    };
+    packets.remove();
+    res
+}
+
+struct Sample {
+    size: u32,
+    // in TIMESCALE
+    start: u64,
+    duration: u64,
+}
+
+fn append(filename: &str, format: &AudioFormat, packets: &OpusPackets) -> ResultType<()> {
+    if packets.is_empty() {
+        bail!("no audio packets");
+    }
+    let mut file = OpenOptions::new().read(true).write(true).open(filename)?;
+    let end = file.seek(SeekFrom::End(0))?;
+    let (moov_offset, mut children) = read_moov(&mut file, end)?;
+    let Some(mvhd) = find_box(&children, b"mvhd") else {
+        bail!("no mvhd box");
+    };
+    if mvhd.len() <= 8 {
+        bail!("invalid mvhd box");
+    }
+    let version = children[mvhd.start + 8];
+    let (timescale_at, duration_len) = if version == 1 {
+        (mvhd.start + 28, 8)
+    } else {
+        (mvhd.start + 20, 4)
+    };
+    if mvhd.end < timescale_at + 4 + duration_len + 4 {
+        bail!("invalid mvhd box");
+    }
+    let movie_timescale = read_u32(&children, timescale_at);
+    let track_id = read_u32(&children, mvhd.end - 4);
+    if movie_timescale == 0 || track_id == 0 || track_id == u32::MAX {
+        bail!("invalid mvhd box");
+    }
+
+    // the samples go in one chunk, in a mdat box after the old moov
+    let chunk_offset = end + 16;
+    let mut samples = Vec::with_capacity(packets.count);
+    let mut reader = BufReader::new(File::open(&packets.path)?);
+    let mut out = BufWriter::new(&mut file);
+    out.write_all(&1u32.to_be_bytes())?;
+    out.write_all(b"mdat")?;
+    out.write_all(&0u64.to_be_bytes())?;
+    let mut mdat_size = 16;
+    let mut data = Vec::new();
+    for _ in 0..packets.count {
+        let mut header = [0u8; 12];
+        reader.read_exact(&mut header)?;
+        let timestamp_ns = u64::from_le_bytes(header[..8].try_into()?);
+        data.resize(u32::from_le_bytes(header[8..].try_into()?) as usize, 0);
+        reader.read_exact(&mut data)?;
+        out.write_all(&data)?;
+        mdat_size += data.len() as u64;
+        samples.push(Sample {
+            size: data.len() as _,
+            start: timestamp_ns * TIMESCALE as u64 / 1_000_000_000,
+            duration: opus_packet_duration_ns(&data).unwrap_or_default() * TIMESCALE as u64
+                / 1_000_000_000,
+        });
+    }
+    out.flush()?;
+    drop(out);
+    file.seek(SeekFrom::Start(end + 8))?;
+    file.write_all(&mdat_size.to_be_bytes())?;
+
+    let deltas = sample_deltas(&samples);
+    let media_start = samples[0].start;
+    let media_duration: u64 = deltas.iter().sum();
+    let to_movie = |t: u64| (t as u128 * movie_timescale as u128 / TIMESCALE as u128) as u64;
+    let trak = trak(
+        track_id,
+        format,
+        &samples,
+        &deltas,
+        chunk_offset,
+        to_movie(media_start),
+        to_movie(media_duration),
+    );
+
+    let movie_duration = to_movie(media_start + media_duration);
+    let duration_at = timescale_at + 4;
+    if duration_len == 8 {
+        let old = u64::from_be_bytes(children[duration_at..duration_at + 8].try_into()?);
+        children[duration_at..duration_at + 8]
+            .copy_from_slice(&old.max(movie_duration).to_be_bytes());
+    } else {
+        let old = read_u32(&children, duration_at) as u64;
+        let duration = old.max(movie_duration).min(u32::MAX as _) as u32;
+        children[duration_at..duration_at + 4].copy_from_slice(&duration.to_be_bytes());
+    }
+    children[mvhd.end - 4..mvhd.end].copy_from_slice(&(track_id + 1).to_be_bytes());
+    children.extend_from_slice(&trak);
+    file.seek(SeekFrom::End(0))?;
+    file.write_all(&make_box(b"moov", &children))?;
+    file.seek(SeekFrom::Start(moov_offset + 4))?;
+    file.write_all(b"free")?;
+    file.sync_all()?;
+    Ok(())
+}
+
+// Returns the offset and the payload of the moov box, which must be the last top level box.
+fn read_moov(file: &mut File, len: u64) -> ResultType<(u64, Vec<u8>)> {
+    let mut offset = 0;
+    while offset < len {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; 16];
+        file.read_exact(&mut header[..8])?;
+        let mut size = read_u32(&header, 0) as u64;
+        let mut header_len = 8;
+        if size == 1 {
+            file.read_exact(&mut header[8..])?;
+            size = u64::from_be_bytes(header[8..].try_into()?);
+            header_len = 16;
+        } else if size == 0 {
+            size = len - offset;
+        }
+        if size < header_len || offset + size > len {
+            bail!("invalid mp4 box at {}", offset);
+        }
+        if &header[4..8] == b"moov" {
+            if offset + size != len {
+                bail!("the moov box is not at the end of the file");
+            }
+            let mut moov = vec![0; (size - header_len) as usize];
+            file.read_exact(&mut moov)?;
+            return Ok((offset, moov));
+        }
+        offset += size;
+    }
+    bail!("no moov box")
+}
+
+fn find_box(data: &[u8], kind: &[u8; 4]) -> Option<Range<usize>> {
+    let mut offset = 0;
+    while offset + 8 <= data.len() {
+        let size = read_u32(data, offset) as usize;
+        if size < 8 || offset + size > data.len() {
+            return None;
+        }
+        if &data[offset + 4..offset + 8] == kind {
+            return Some(offset..offset + size);
+        }
+        offset += size;
+    }
+    None
+}
+
+// The time to the next sample, samples which start too early are moved after the previous
+// one, and a gap is added to the previous sample.
+fn sample_deltas(samples: &[Sample]) -> Vec<u64> {
+    let mut deltas = Vec::with_capacity(samples.len());
+    let mut position = samples.first().map(|s| s.start).unwrap_or_default();
+    for (i, sample) in samples.iter().enumerate() {
+        let end = position + sample.duration.max(1);
+        let next = match samples.get(i + 1) {
+            Some(next) => next.start.max(end),
+            None => end,
+        };
+        deltas.push(next - position);
+        position = next;
+    }
+    deltas
+}
+
+fn trak(
+    track_id: u32,
+    format: &AudioFormat,
+    samples: &[Sample],
+    deltas: &[u64],
+    chunk_offset: u64,
+    movie_start: u64,
+    movie_duration: u64,
+) -> Vec<u8> {
+    let media_duration: u64 = deltas.iter().sum();
+
+    let mut tkhd = Vec::new();
+    tkhd.extend_from_slice(&[0; 16]); // creation and modification time
+    tkhd.extend_from_slice(&track_id.to_be_bytes());
+    tkhd.extend_from_slice(&[0; 4]);
+    tkhd.extend_from_slice(&(movie_start + movie_duration).to_be_bytes());
+    tkhd.extend_from_slice(&[0; 8]);
+    tkhd.extend_from_slice(&0u16.to_be_bytes()); // layer
+    tkhd.extend_from_slice(&1u16.to_be_bytes()); // alternate group
+    tkhd.extend_from_slice(&0x0100u16.to_be_bytes()); // volume
+    tkhd.extend_from_slice(&[0; 2]);
+    for v in [0x10000u32, 0, 0, 0, 0x10000, 0, 0, 0, 0x40000000] {
+        tkhd.extend_from_slice(&v.to_be_bytes());
+    }
+    tkhd.extend_from_slice(&[0; 8]); // width and height
+    let tkhd = full_box(b"tkhd", 1, 3, &tkhd); // enabled and in movie
+
+    // an empty edit delays the audio to the first packet
+    let mut edits = Vec::new();
+    if movie_start > 0 {
+        edits.push((movie_start, -1i64));
+    }
+    edits.push((movie_duration, 0));
+    let mut elst = (edits.len() as u32).to_be_bytes().to_vec();
+    for (duration, media_time) in edits {
+        elst.extend_from_slice(&duration.to_be_bytes());
+        elst.extend_from_slice(&media_time.to_be_bytes());
+        elst.extend_from_slice(&1u16.to_be_bytes());
+        elst.extend_from_slice(&0u16.to_be_bytes());
+    }
+    let edts = make_box(b"edts", &full_box(b"elst", 1, 0, &elst));
+
+    let mut mdhd = Vec::new();
+    mdhd.extend_from_slice(&[0; 16]);
+    mdhd.extend_from_slice(&TIMESCALE.to_be_bytes());
+    mdhd.extend_from_slice(&media_duration.to_be_bytes());
+    mdhd.extend_from_slice(&0x55c4u16.to_be_bytes()); // und
+    mdhd.extend_from_slice(&[0; 2]);
+    let mdhd = full_box(b"mdhd", 1, 0, &mdhd);
+
+    let mut hdlr = vec![0; 4];
+    hdlr.extend_from_slice(b"soun");
+    hdlr.extend_from_slice(&[0; 12]);
+    hdlr.extend_from_slice(b"SoundHandler\0");
+    let hdlr = full_box(b"hdlr", 0, 0, &hdlr);
+
+    let smhd = full_box(b"smhd", 0, 0, &[0; 4]);
+    let mut dref = 1u32.to_be_bytes().to_vec();
+    // the data is in this file
+    dref.extend_from_slice(&full_box(b"url ", 0, 1, &[]));
+    let dinf = make_box(b"dinf", &full_box(b"dref", 0, 0, &dref));
+
+    let mut dops = vec![0, format.channels as u8];
+    dops.extend_from_slice(&0u16.to_be_bytes()); // pre-skip
+    dops.extend_from_slice(&format.sample_rate.to_be_bytes());
+    dops.extend_from_slice(&0i16.to_be_bytes()); // output gain
+    dops.push(0); // channel mapping family, mono or stereo
+    let mut entry = vec![0; 6];
+    entry.extend_from_slice(&1u16.to_be_bytes()); // data reference index
+    entry.extend_from_slice(&[0; 8]);
+    entry.extend_from_slice(&(format.channels as u16).to_be_bytes());
+    entry.extend_from_slice(&16u16.to_be_bytes()); // sample size
+    entry.extend_from_slice(&[0; 4]);
+    entry.extend_from_slice(&(TIMESCALE << 16).to_be_bytes());
+    entry.extend_from_slice(&make_box(b"dOps", &dops));
+    let mut stsd = 1u32.to_be_bytes().to_vec();
+    stsd.extend_from_slice(&make_box(b"Opus", &entry));
+    let stsd = full_box(b"stsd", 0, 0, &stsd);
+
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for &delta in deltas {
+        let delta = delta.min(u32::MAX as _) as u32;
+        match runs.last_mut() {
+            Some((count, d)) if *d == delta => *count += 1,
+            _ => runs.push((1, delta)),
+        }
+    }
+    let mut stts = (runs.len() as u32).to_be_bytes().to_vec();
+    for (count, delta) in runs {
+        stts.extend_from_slice(&count.to_be_bytes());
+        stts.extend_from_slice(&delta.to_be_bytes());
+    }
+    let stts = full_box(b"stts", 0, 0, &stts);
+
+    let mut stsc = 1u32.to_be_bytes().to_vec();
+    stsc.extend_from_slice(&1u32.to_be_bytes()); // first chunk
+    stsc.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+    stsc.extend_from_slice(&1u32.to_be_bytes()); // sample description index
+    let stsc = full_box(b"stsc", 0, 0, &stsc);
+
+    let mut stsz = vec![0; 4];
+    stsz.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+    for sample in samples {
+        stsz.extend_from_slice(&sample.size.to_be_bytes());
+    }
+    let stsz = full_box(b"stsz", 0, 0, &stsz);
+
+    let mut stco = 1u32.to_be_bytes().to_vec();
+    let stco = if chunk_offset > u32::MAX as u64 {
+        stco.extend_from_slice(&chunk_offset.to_be_bytes());
+        full_box(b"co64", 0, 0, &stco)
+    } else {
+        stco.extend_from_slice(&(chunk_offset as u32).to_be_bytes());
+        full_box(b"stco", 0, 0, &stco)
+    };
+
+    let stbl = make_box(b"stbl", &[stsd, stts, stsc, stsz, stco].concat());
+    let minf = make_box(b"minf", &[smhd, dinf, stbl].concat());
+    let mdia = make_box(b"mdia", &[mdhd, hdlr, minf].concat());
+    make_box(b"trak", &[tkhd, edts, mdia].concat())
+}
+
+fn make_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut b = Vec::with_capacity(8 + payload.len());
+    b.extend_from_slice(&(8 + payload.len() as u32).to_be_bytes());
+    b.extend_from_slice(kind);
+    b.extend_from_slice(payload);
+    b
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, payload: &[u8]) -> Vec<u8> {
+    let mut b = vec![version];
+    b.extend_from_slice(&flags.to_be_bytes()[1..]);
+    b.extend_from_slice(payload);
+    make_box(kind, &b)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn top_level_boxes(data: &[u8]) -> Vec<(String, Range<usize>)> {
+        let mut boxes = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let mut size = read_u32(data, offset) as usize;
+            if size == 1 {
+                size = u64::from_be_bytes(data[offset + 8..offset + 16].try_into().unwrap()) as _;
+            }
+            let kind = String::from_utf8_lossy(&data[offset + 4..offset + 8]).to_string();
+            boxes.push((kind, offset..offset + size));
+            offset += size;
+        }
+        boxes
+    }
+
+    fn child<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> &'a [u8] {
+        let mut data = data;
+        for kind in path {
+            let range = find_box(data, kind).unwrap();
+            data = &data[range.start + 8..range.end];
+        }
+        data
+    }
+
+    #[test]
+    fn test_append_opus_track() {
+        let dir = std::env::temp_dir().join(format!("mp4_audio_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let filename = dir.join("a.mp4").to_string_lossy().to_string();
+        let mut mvhd = vec![0; 8];
+        mvhd.extend_from_slice(&1000u32.to_be_bytes());
+        mvhd.extend_from_slice(&50u32.to_be_bytes());
+        mvhd.extend_from_slice(&[0; 76]);
+        mvhd.extend_from_slice(&2u32.to_be_bytes());
+        let mvhd = full_box(b"mvhd", 0, 0, &mvhd);
+        let moov = make_box(b"moov", &[mvhd, make_box(b"trak", &[1; 8])].concat());
+        let mp4 = [
+            make_box(b"ftyp", b"isom"),
+            make_box(b"mdat", &[9; 100]),
+            moov.clone(),
+        ]
+        .concat();
+        fs::write(&filename, &mp4).unwrap();
+
+        let format = AudioFormat {
+            sample_rate: 48000,
+            channels: 2,
+            ..Default::default()
+        };
+        // 20ms each, the third one after a gap
+        let packets_path = dir.join("a.opus.tmp");
+        let mut packets = OpusPackets::create(packets_path.clone()).unwrap();
+        assert!(packets.write(&[0xf8, 1, 2], 10_000_000));
+        assert!(packets.write(&[0xf8, 3], 30_000_000));
+        assert!(packets.write(&[0xf8, 4, 5, 6], 100_000_000));
+        append_opus_track(&filename, &format, packets).unwrap();
+        assert!(!packets_path.exists());
+
+        let data = fs::read(&filename).unwrap();
+        let boxes = top_level_boxes(&data);
+        let kinds: Vec<_> = boxes.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(kinds, ["ftyp", "mdat", "free", "mdat", "moov"]);
+        assert_eq!(&data[boxes[2].1.clone()][8..], &moov[8..]);
+
+        let moov = &data[boxes[4].1.start + 8..];
+        let mvhd = child(moov, &[b"mvhd"]);
+        assert_eq!(read_u32(mvhd, 16), 120);
+        assert_eq!(read_u32(mvhd, mvhd.len() - 4), 3);
+        let trak = &moov[find_box(moov, b"trak").unwrap().end..];
+        assert_eq!(read_u32(child(trak, &[b"trak", b"tkhd"]), 20), 2);
+        let stbl = child(trak, &[b"trak", b"mdia", b"minf", b"stbl"]);
+        let stts = child(stbl, &[b"stts"]);
+        assert_eq!(read_u32(stts, 4), 3);
+        assert_eq!(read_u32(stts, 12), 960);
+        assert_eq!(read_u32(stts, 20), 3360);
+        assert_eq!(read_u32(stts, 28), 960);
+        let chunk_offset = read_u32(child(stbl, &[b"stco"]), 8) as usize;
+        assert_eq!(chunk_offset, boxes[3].1.start + 16);
+        assert_eq!(
+            &data[chunk_offset..boxes[3].1.end],
+            &[0xf8, 1, 2, 0xf8, 3, 0xf8, 4, 5, 6]
+        );
+        let elst = child(trak, &[b"trak", b"edts", b"elst"]);
+        assert_eq!(read_u32(elst, 4), 2);
+        // 10ms in the timescale of the movie
+        assert_eq!(u64::from_be_bytes(elst[8..16].try_into().unwrap()), 10);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_truncated_mvhd() {
+        let dir = std::env::temp_dir().join(format!("mp4_audio_mvhd_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let filename = dir.join("a.mp4").to_string_lossy().to_string();
+        // the mvhd box ends before its version
+        let mp4 = [
+            make_box(b"ftyp", b"isom"),
+            make_box(b"moov", &make_box(b"mvhd", &[])),
+        ]
+        .concat();
+        fs::write(&filename, &mp4).unwrap();
+        let packets_path = dir.join("a.opus.tmp");
+        let mut packets = OpusPackets::create(packets_path.clone()).unwrap();
+        assert!(packets.write(&[0xf8, 1, 2], 0));
+        assert!(append_opus_track(&filename, &AudioFormat::default(), packets).is_err());
+        assert!(!packets_path.exists());
+        assert_eq!(fs::read(&filename).unwrap(), mp4);
+        fs::remove_dir_all(&dir).ok();
+    }
+}
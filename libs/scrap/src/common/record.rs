@@ -1,25 +1,34 @@
-use crate::CodecFormat;
+#[cfg(feature = "hwcodec")]
+use crate::mp4_audio::{self, OpusPackets};
+use crate::{
+    record_store::{self, Direction, RecordWriter, RecordedFile},
+    CodecFormat,
+};
 #[cfg(feature = "hwcodec")]
 use hbb_common::anyhow::anyhow;
 use hbb_common::{
     bail, chrono,
     log,
-    message_proto::{message, video_frame, EncodedVideoFrame, Message},
+    message_proto::{
+        message, misc, video_frame, AudioFormat, AudioFrame, EncodedVideoFrame, Message,
+    },
     ResultType,
 };
 #[cfg(feature = "hwcodec")]
 use hwcodec::mux::{MuxContext, Muxer};
 use std::{
-    fs::{File, OpenOptions},
-    io,
+    io::{Seek, Write},
     ops::{Deref, DerefMut},
     path::PathBuf,
     sync::mpsc::Sender,
     time::Instant,
 };
-use webm::mux::{self, Segment, Track, VideoTrack, Writer};
+use webm::mux::{self, AudioTrack, Segment, Track, VideoTrack, Writer};
 
 const MIN_SECS: u64 = 1;
+// Audio timestamps follow the packet durations, unless they drift this far from the clock of
+// video, eg. the peer sends nothing while silent.
+const AUDIO_RESYNC_NS: u64 = 200_000_000;
 
 #[derive(Debug, Clone)]
 pub struct RecorderContext {
@@ -30,6 +39,8 @@ pub struct RecorderContext {
     pub width: usize,
     pub height: usize,
     pub format: CodecFormat,
+    // opus audio of the session, no audio track if None
+    pub audio: Option<AudioFormat>,
     pub tx: Option<Sender<RecordState>>,
 }
 
@@ -51,13 +62,29 @@ impl RecorderContext {
             } else {
                 ".mp4"
             };
-        self.filename = PathBuf::from(&self.dir)
-            .join(file)
-            .to_string_lossy()
-            .to_string();
+        self.filename = record_store::record_path(
+            PathBuf::from(&self.dir)
+                .join(file)
+                .to_string_lossy()
+                .to_string(),
+        );
         log::info!("video will save to {}", self.filename);
         Ok(())
     }
+
+    fn recorded_file(&self, path: &str, codec: String, start: Instant) -> RecordedFile {
+        RecordedFile {
+            path: path.to_owned(),
+            direction: if self.server {
+                Direction::Incoming
+            } else {
+                Direction::Outgoing
+            },
+            id: self.id.clone(),
+            codec,
+            start: record_store::unix_ms_of(start),
+        }
+    }
 }
 
 unsafe impl Send for Recorder {}
@@ -68,6 +95,9 @@ pub trait RecorderApi {
     where
         Self: Sized;
     fn write_video(&mut self, frame: &EncodedVideoFrame) -> bool;
+    fn write_audio(&mut self, frame: &AudioFrame, timestamp_ns: u64) -> bool;
+    // Adds the audio track, false if the file can not take it any more.
+    fn set_audio_format(&mut self, format: &AudioFormat) -> bool;
 }
 
 #[derive(Debug)]
@@ -82,6 +112,12 @@ pub struct Recorder {
     pub inner: Box<dyn RecorderApi>,
     ctx: RecorderContext,
     pts: Option<i64>,
+    // pts of the last video frame and when it is written
+    video_clock: Option<(i64, Instant)>,
+    // timestamp of the next audio frame
+    audio_ns: Option<u64>,
+    // whether the current file has a track of ctx.audio
+    audio_track: bool,
 }
 
 impl Deref for Recorder {
@@ -106,12 +142,18 @@ impl Recorder {
                 inner: Box::new(WebmRecorder::new(ctx.clone())?),
                 ctx,
                 pts: None,
+                video_clock: None,
+                audio_ns: None,
+                audio_track: true,
             },
             #[cfg(feature = "hwcodec")]
             _ => Recorder {
                 inner: Box::new(HwRecorder::new(ctx.clone())?),
                 ctx,
                 pts: None,
+                video_clock: None,
+                audio_ns: None,
+                audio_track: true,
             },
             #[cfg(not(feature = "hwcodec"))]
             _ => bail!("unsupported codec type"),
@@ -133,16 +175,71 @@ impl Recorder {
         };
         self.ctx = ctx;
         self.pts = None;
+        self.video_clock = None;
+        self.audio_ns = None;
+        self.audio_track = true;
         self.send_state(RecordState::NewFile(self.ctx.filename.clone()));
         Ok(())
     }
 
     pub fn write_message(&mut self, msg: &Message) {
-        if let Some(message::Union::VideoFrame(vf)) = &msg.union {
-            if let Some(frame) = &vf.union {
-                self.write_frame(frame).ok();
+        match &msg.union {
+            Some(message::Union::VideoFrame(vf)) => {
+                if let Some(frame) = &vf.union {
+                    self.write_frame(frame).ok();
+                }
             }
+            Some(message::Union::AudioFrame(af)) => {
+                self.write_audio_frame(af).ok();
+            }
+            Some(message::Union::Misc(misc)) => {
+                if let Some(misc::Union::AudioFormat(f)) = &misc.union {
+                    self.set_audio_format(f).ok();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The recording goes on in the same file, which gets the audio track if it still can,
+    /// otherwise the audio is recorded from the next file.
+    pub fn set_audio_format(&mut self, format: &AudioFormat) -> ResultType<()> {
+        if self.ctx.audio.as_ref() == Some(format) {
+            return Ok(());
+        }
+        self.ctx.audio = Some(format.clone());
+        self.audio_ns = None;
+        self.audio_track = self.inner.set_audio_format(format);
+        if !self.audio_track {
+            log::info!(
+                "no audio in {} after the audio format changed",
+                self.ctx.filename
+            );
+            bail!("the audio format can not be changed in the file");
+        }
+        Ok(())
+    }
+
+    pub fn write_audio_frame(&mut self, frame: &AudioFrame) -> ResultType<()> {
+        if self.ctx.audio.is_none() || !self.audio_track {
+            bail!("no audio track");
+        }
+        // frames before the first video frame are dropped, as there is no clock to align with
+        let Some((pts, instant)) = self.video_clock else {
+            bail!("no video frame");
+        };
+        let video_ns = pts.max(0) as u64 * 1_000_000;
+        let clock_ns = video_ns + instant.elapsed().as_nanos() as u64;
+        let ns = match self.audio_ns {
+            Some(ns) if ns.abs_diff(clock_ns) < AUDIO_RESYNC_NS => ns,
+            _ => clock_ns,
+        }
+        .max(video_ns);
+        self.audio_ns = Some(ns + opus_packet_duration_ns(&frame.data).unwrap_or_default());
+        if !self.write_audio(frame, ns) {
+            bail!("failed to write audio");
         }
+        Ok(())
     }
 
     pub fn write_frame(&mut self, frame: &video_frame::Union) -> ResultType<()> {
@@ -223,6 +320,7 @@ impl Recorder {
             log::info!("pts {:?} -> {}, change record filename", old_pts, pts);
             self.change(self.ctx.clone())?;
         }
+        self.video_clock = Some((pts, Instant::now()));
         Ok(())
     }
 
@@ -233,7 +331,8 @@ impl Recorder {
 
 struct WebmRecorder {
     vt: VideoTrack,
-    webm: Option<Segment<Writer<File>>>,
+    at: Option<AudioTrack>,
+    webm: Option<Segment<Writer<RecordWriter>>>,
     ctx: RecorderContext,
     key: bool,
     written: bool,
@@ -242,16 +341,7 @@ struct WebmRecorder {
 
 impl RecorderApi for WebmRecorder {
     fn new(ctx: RecorderContext) -> ResultType<Self> {
-        let out = match {
-            OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(&ctx.filename)
-        } {
-            Ok(file) => file,
-            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => File::create(&ctx.filename)?,
-            Err(e) => return Err(e.into()),
-        };
+        let out = RecordWriter::create(&ctx.filename)?;
         let mut webm = match mux::Segment::new(mux::Writer::new(out)) {
             Some(v) => v,
             None => bail!("Failed to create webm mux"),
@@ -275,8 +365,13 @@ impl RecorderApi for WebmRecorder {
                 bail!("Failed to set codec private");
             }
         }
+        let at = match &ctx.audio {
+            Some(format) => Some(add_opus_track(&mut webm, format)?),
+            None => None,
+        };
         Ok(WebmRecorder {
             vt,
+            at,
             webm: Some(webm),
             ctx,
             key: false,
@@ -301,6 +396,37 @@ impl RecorderApi for WebmRecorder {
             false
         }
     }
+
+    fn write_audio(&mut self, frame: &AudioFrame, timestamp_ns: u64) -> bool {
+        // a cluster starts with a video key frame
+        if !self.key {
+            return false;
+        }
+        match self.at.as_mut() {
+            Some(at) => at.add_frame(&frame.data, timestamp_ns, true),
+            None => false,
+        }
+    }
+
+    fn set_audio_format(&mut self, format: &AudioFormat) -> bool {
+        // tracks are written in the header, before the first frame
+        if self.at.is_some() || self.written {
+            return false;
+        }
+        let Some(webm) = self.webm.as_mut() else {
+            return false;
+        };
+        match add_opus_track(webm, format) {
+            Ok(at) => {
+                self.at = Some(at);
+                true
+            }
+            Err(e) => {
+                log::error!("Failed to add audio track: {}", e);
+                false
+            }
+        }
+    }
 }
 
 impl Drop for WebmRecorder {
@@ -311,13 +437,25 @@ impl Drop for WebmRecorder {
             std::fs::remove_file(&self.ctx.filename).ok();
             state = RecordState::RemoveFile;
         }
+        let written = matches!(state, RecordState::WriteTail);
         self.ctx.tx.as_ref().map(|tx| tx.send(state));
+        if written {
+            record_store::finish(self.ctx.recorded_file(
+                &self.ctx.filename,
+                self.ctx.format.to_string().to_lowercase(),
+                self.start,
+            ));
+        }
     }
 }
 
 #[cfg(feature = "hwcodec")]
 struct HwRecorder {
     muxer: Muxer,
+    // the muxer takes no audio, which is added to the mp4 after its tail is written
+    audio: Option<(AudioFormat, OpusPackets)>,
+    // pts of the first video frame, where the audio starts
+    start_pts: Option<i64>,
     ctx: RecorderContext,
     written: bool,
     key: bool,
@@ -327,6 +465,10 @@ struct HwRecorder {
 #[cfg(feature = "hwcodec")]
 impl RecorderApi for HwRecorder {
     fn new(ctx: RecorderContext) -> ResultType<Self> {
+        // the muxer writes the file itself, there is no way to encrypt it while written
+        if record_store::is_encrypted_path(&ctx.filename) {
+            bail!("{} recordings can not be encrypted", ctx.format.to_string());
+        }
         let muxer = Muxer::new(MuxContext {
             filename: ctx.filename.clone(),
             width: ctx.width,
@@ -335,13 +477,19 @@ impl RecorderApi for HwRecorder {
             framerate: crate::hwcodec::DEFAULT_TIME_BASE[1] as _,
         })
         .map_err(|_| anyhow!("Failed to create hardware muxer"))?;
-        Ok(HwRecorder {
+        let mut recorder = HwRecorder {
             muxer,
+            audio: None,
+            start_pts: None,
             ctx,
             written: false,
             key: false,
             start: Instant::now(),
-        })
+        };
+        if let Some(format) = recorder.ctx.audio.clone() {
+            recorder.set_audio_format(&format);
+        }
+        Ok(recorder)
     }
 
     fn write_video(&mut self, frame: &EncodedVideoFrame) -> bool {
@@ -352,12 +500,43 @@ impl RecorderApi for HwRecorder {
             let ok = self.muxer.write_video(&frame.data, frame.key).is_ok();
             if ok {
                 self.written = true;
+                self.start_pts.get_or_insert(frame.pts);
             }
             ok
         } else {
             false
         }
     }
+
+    fn write_audio(&mut self, frame: &AudioFrame, timestamp_ns: u64) -> bool {
+        let (Some((_, packets)), Some(start_pts)) = (self.audio.as_mut(), self.start_pts) else {
+            return false;
+        };
+        let start_ns = start_pts.max(0) as u64 * 1_000_000;
+        packets.write(&frame.data, timestamp_ns.saturating_sub(start_ns))
+    }
+
+    fn set_audio_format(&mut self, format: &AudioFormat) -> bool {
+        // the track has one format, which may change until the first packet
+        if let Some((f, packets)) = self.audio.as_mut() {
+            if f != format && !packets.is_empty() {
+                return false;
+            }
+            *f = format.clone();
+            return true;
+        }
+        let path = PathBuf::from(&self.ctx.filename).with_extension("opus.tmp");
+        match OpusPackets::create(path) {
+            Ok(packets) => {
+                self.audio = Some((format.clone(), packets));
+                true
+            }
+            Err(e) => {
+                log::error!("Failed to create audio file: {}", e);
+                false
+            }
+        }
+    }
 }
 
 #[cfg(feature = "hwcodec")]
@@ -369,6 +548,104 @@ impl Drop for HwRecorder {
             std::fs::remove_file(&self.ctx.filename).ok();
             state = RecordState::RemoveFile;
         }
+        let written = matches!(state, RecordState::WriteTail);
+        if let Some((format, packets)) = self.audio.take() {
+            if written && !packets.is_empty() {
+                if let Err(e) = mp4_audio::append_opus_track(&self.ctx.filename, &format, packets) {
+                    log::error!("Failed to add audio to {}: {}", self.ctx.filename, e);
+                }
+            } else {
+                packets.remove();
+            }
+        }
         self.ctx.tx.as_ref().map(|tx| tx.send(state));
+        if written {
+            record_store::finish(self.ctx.recorded_file(
+                &self.ctx.filename,
+                self.ctx.format.to_string().to_lowercase(),
+                self.start,
+            ));
+        }
+    }
+}
+
+pub(crate) fn add_opus_track<W: Write + Seek>(
+    webm: &mut Segment<Writer<W>>,
+    format: &AudioFormat,
+) -> ResultType<AudioTrack> {
+    let at = webm.add_audio_track(
+        format.sample_rate as _,
+        format.channels as _,
+        None,
+        mux::AudioCodecId::Opus,
+    );
+    if !webm.set_codec_private(at.track_number(), &opus_head(format)) {
+        bail!("Failed to set opus codec private");
+    }
+    Ok(at)
+}
+
+// The identification header of opus, https://www.rfc-editor.org/rfc/rfc7845#section-5.1
+fn opus_head(format: &AudioFormat) -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1);
+    head.push(format.channels as u8);
+    // pre-skip
+    head.extend_from_slice(&0u16.to_le_bytes());
+    head.extend_from_slice(&format.sample_rate.to_le_bytes());
+    // output gain
+    head.extend_from_slice(&0i16.to_le_bytes());
+    // channel mapping family, mono or stereo
+    head.push(0);
+    head
+}
+
+// https://www.rfc-editor.org/rfc/rfc6716#section-3.1
+pub(crate) fn opus_packet_duration_ns(data: &[u8]) -> Option<u64> {
+    let toc = *data.first()?;
+    let config = (toc >> 3) as usize;
+    let frame_us: u64 = match config {
+        0..=11 => [10_000, 20_000, 40_000, 60_000][config % 4],
+        12..=15 => [10_000, 20_000][config % 2],
+        _ => [2_500, 5_000, 10_000, 20_000][config % 4],
+    };
+    let frames = match toc & 0x3 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => (*data.get(1)? & 0x3f) as u64,
+    };
+    Some(frame_us * frames * 1_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opus_packet_duration() {
+        assert_eq!(opus_packet_duration_ns(&[]), None);
+        // celt 20ms, one frame
+        assert_eq!(opus_packet_duration_ns(&[31 << 3]), Some(20_000_000));
+        // silk 60ms, two frames
+        assert_eq!(opus_packet_duration_ns(&[(3 << 3) | 1]), Some(120_000_000));
+        // celt 2.5ms, code 3 with 4 frames
+        assert_eq!(
+            opus_packet_duration_ns(&[(16 << 3) | 3, 4]),
+            Some(10_000_000)
+        );
+        assert_eq!(opus_packet_duration_ns(&[(16 << 3) | 3]), None);
+    }
+
+    #[test]
+    fn test_opus_head() {
+        let head = opus_head(&AudioFormat {
+            sample_rate: 48000,
+            channels: 2,
+            ..Default::default()
+        });
+        assert_eq!(head.len(), 19);
+        assert_eq!(&head[..8], b"OpusHead");
+        assert_eq!(head[9], 2);
+        assert_eq!(&head[12..16], &48000u32.to_le_bytes());
     }
 }
@@ -0,0 +1,774 @@
+// Storage of the finished recordings in the record directory:
+// - encrypted while written with the public key of option "record-encrypt-public-key" if set,
+//   so only the holder of the secret key can read them, not the host which records. The plain
+//   data never hits the disk.
+// - indexed in `MANIFEST` by peer id, direction, time and codec.
+// - removed by the retention options, "record-retention-days", "record-retention-size-mb" and
+//   "record-retention-count", 0 or empty for no limit.
+//
+// Encrypted file layout: magic "RDENC2", the xchacha20poly1305 key sealed to the public key,
+// then the blocks of `BLOCK` plain bytes, the last one may be shorter: a random nonce and the
+// ciphertext of the block. The additional data of a block is its index and whether it is the
+// last one, so blocks can not be reordered and a truncated file is detected. The muxer seeks
+// back to rewrite sizes, a rewritten block is encrypted again with a new nonce.
+
+use hbb_common::{
+    bail,
+    config::Config,
+    lazy_static, log, serde_json,
+    sodiumoxide::{
+        base64,
+        crypto::{aead::xchacha20poly1305_ietf as aead, box_, sealedbox},
+    },
+    ResultType,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+pub const MANIFEST: &str = "manifest.json";
+pub const ENCRYPTED_EXTENSION: &str = "enc";
+const ENCRYPTED_MAGIC: &[u8; 6] = b"RDENC2";
+const SEALED_KEY_LEN: usize = sealedbox::SEALBYTES + aead::KEYBYTES;
+const HEADER_LEN: u64 = (ENCRYPTED_MAGIC.len() + SEALED_KEY_LEN) as u64;
+const BLOCK: usize = 64 * 1024;
+const ENCRYPTED_BLOCK: usize = aead::NONCEBYTES + BLOCK + aead::TAGBYTES;
+// how long the peers of ended sessions are kept to index the recordings they appear in
+const PEER_KEEP_MS: i64 = 7 * 24 * 3600 * 1000;
+
+lazy_static::lazy_static! {
+    // the manifest is read, modified and written back under the lock
+    static ref MANIFEST_LOCK: Mutex<()> = Default::default();
+    static ref SESSION_PEERS: Mutex<Vec<SessionPeer>> = Default::default();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    // file name in the record directory
+    pub file: String,
+    // the peer of outgoing recordings, the peers connected during incoming ones
+    pub peer_ids: Vec<String>,
+    pub direction: Direction,
+    // unix time in milliseconds
+    pub start: i64,
+    pub end: i64,
+    pub codec: String,
+    pub size: u64,
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ManifestQuery {
+    pub peer_id: Option<String>,
+    pub direction: Option<Direction>,
+    // recordings overlapping from..to, unix time in milliseconds
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub codec: Option<String>,
+}
+
+impl ManifestQuery {
+    /// From `--peer <id> --direction <incoming|outgoing> --from <unix ms> --to <unix ms>
+    /// --codec <codec>`, all optional.
+    pub fn from_args(args: &[String]) -> ResultType<Self> {
+        let mut query = Self::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let Some(value) = args.next() else {
+                bail!("no value of {}", arg);
+            };
+            match arg.as_str() {
+                "--peer" => query.peer_id = Some(value.clone()),
+                "--direction" => {
+                    query.direction = Some(match value.to_lowercase().as_str() {
+                        "incoming" => Direction::Incoming,
+                        "outgoing" => Direction::Outgoing,
+                        _ => bail!("invalid direction {}", value),
+                    })
+                }
+                "--from" => query.from = Some(value.parse()?),
+                "--to" => query.to = Some(value.parse()?),
+                "--codec" => query.codec = Some(value.clone()),
+                _ => bail!("unknown argument {}", arg),
+            }
+        }
+        Ok(query)
+    }
+
+    pub fn matches(&self, entry: &ManifestEntry) -> bool {
+        self.peer_id
+            .as_ref()
+            .map_or(true, |id| entry.peer_ids.contains(id))
+            && self.direction.map_or(true, |d| d == entry.direction)
+            && self.from.map_or(true, |from| entry.end >= from)
+            && self.to.map_or(true, |to| entry.start <= to)
+            && self
+                .codec
+                .as_ref()
+                .map_or(true, |codec| codec.eq_ignore_ascii_case(&entry.codec))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub max_age_days: u64,
+    pub max_total_size: u64,
+    pub max_count: usize,
+}
+
+impl RetentionPolicy {
+    pub fn from_options() -> Self {
+        let get = |k: &str| {
+            Config::get_option(k)
+                .trim()
+                .parse::<u64>()
+                .unwrap_or_default()
+        };
+        Self {
+            max_age_days: get("record-retention-days"),
+            max_total_size: get("record-retention-size-mb") * 1024 * 1024,
+            max_count: get("record-retention-count") as _,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// A recording closed by its recorder, which is not written any more.
+#[derive(Debug, Clone)]
+pub struct RecordedFile {
+    pub path: String,
+    pub direction: Direction,
+    // id in the filename, the peer of outgoing recordings
+    pub id: String,
+    pub codec: String,
+    // unix time in milliseconds
+    pub start: i64,
+}
+
+struct SessionPeer {
+    conn_id: i32,
+    peer_id: String,
+    start: i64,
+    end: Option<i64>,
+}
+
+pub fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// Unix time in milliseconds of a past instant.
+pub fn unix_ms_of(instant: Instant) -> i64 {
+    now_ms() - instant.elapsed().as_millis() as i64
+}
+
+/// Incoming recordings are indexed by the peers connected while recording.
+pub fn peer_connected(conn_id: i32, peer_id: &str) {
+    SESSION_PEERS.lock().unwrap().push(SessionPeer {
+        conn_id,
+        peer_id: peer_id.to_owned(),
+        start: now_ms(),
+        end: None,
+    });
+}
+
+pub fn peer_disconnected(conn_id: i32) {
+    let now = now_ms();
+    let mut peers = SESSION_PEERS.lock().unwrap();
+    for peer in peers.iter_mut() {
+        if peer.conn_id == conn_id && peer.end.is_none() {
+            peer.end = Some(now);
+        }
+    }
+    peers.retain(|p| p.end.map_or(true, |end| now - end < PEER_KEEP_MS));
+}
+
+fn peers_between(start: i64, end: i64) -> Vec<String> {
+    let mut ids: Vec<String> = SESSION_PEERS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|p| p.start <= end && p.end.map_or(true, |e| e >= start))
+        .map(|p| p.peer_id.clone())
+        .collect();
+    ids.sort();
+    ids.dedup();
+    ids
+}
+
+pub fn is_encryption_enabled() -> bool {
+    !Config::get_option("record-encrypt-public-key")
+        .trim()
+        .is_empty()
+}
+
+/// Returns the public and the secret key in base64, the public one is for option
+/// "record-encrypt-public-key".
+pub fn gen_keypair() -> (String, String) {
+    let (pk, sk) = box_::gen_keypair();
+    (
+        base64::encode(&pk.0, base64::Variant::Original),
+        base64::encode(&sk.0, base64::Variant::Original),
+    )
+}
+
+pub fn parse_public_key(key: &str) -> ResultType<box_::PublicKey> {
+    let Ok(bytes) = base64::decode(key.trim(), base64::Variant::Original) else {
+        bail!("invalid base64 key");
+    };
+    let Some(pk) = box_::PublicKey::from_slice(&bytes) else {
+        bail!("invalid public key length");
+    };
+    Ok(pk)
+}
+
+pub fn parse_secret_key(key: &str) -> ResultType<box_::SecretKey> {
+    let Ok(bytes) = base64::decode(key.trim(), base64::Variant::Original) else {
+        bail!("invalid base64 key");
+    };
+    let Some(sk) = box_::SecretKey::from_slice(&bytes) else {
+        bail!("invalid secret key length");
+    };
+    Ok(sk)
+}
+
+/// The path a recording is written to, `<path>.enc` if it is encrypted.
+pub fn record_path(path: String) -> String {
+    if is_encryption_enabled() {
+        format!("{}.{}", path, ENCRYPTED_EXTENSION)
+    } else {
+        path
+    }
+}
+
+#[inline]
+pub fn is_encrypted_path(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .map_or(false, |e| e == ENCRYPTED_EXTENSION)
+}
+
+/// The file of a recording, a path of `record_path` which ends with `.enc` is encrypted while
+/// written.
+pub enum RecordWriter {
+    Plain(File),
+    Encrypted(EncryptedWriter),
+}
+
+impl RecordWriter {
+    pub fn create(path: &str) -> ResultType<Self> {
+        if !is_encrypted_path(path) {
+            return Ok(Self::Plain(File::create(path)?));
+        }
+        let pk = parse_public_key(&Config::get_option("record-encrypt-public-key"))?;
+        // blocks are read back when the muxer seeks to rewrite them
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self::Encrypted(EncryptedWriter::new(file, &pk)?))
+    }
+}
+
+impl Write for RecordWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(f) => f.write(buf),
+            Self::Encrypted(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(f) => f.flush(),
+            Self::Encrypted(w) => w.flush(),
+        }
+    }
+}
+
+impl Seek for RecordWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Self::Plain(f) => f.seek(pos),
+            Self::Encrypted(w) => w.seek(pos),
+        }
+    }
+}
+
+/// Encrypts the blocks of a recording as they are written, the block being written is kept in
+/// memory and the others are on disk.
+pub struct EncryptedWriter {
+    file: File,
+    key: aead::Key,
+    block: Vec<u8>,
+    index: u64,
+    dirty: bool,
+    // plain length and position
+    len: u64,
+    pos: u64,
+    finished: bool,
+}
+
+impl EncryptedWriter {
+    pub fn new(mut file: File, pk: &box_::PublicKey) -> ResultType<Self> {
+        let key = aead::gen_key();
+        file.write_all(ENCRYPTED_MAGIC)?;
+        file.write_all(&sealedbox::seal(&key.0, pk))?;
+        Ok(Self {
+            file,
+            key,
+            block: Vec::new(),
+            index: 0,
+            dirty: false,
+            len: 0,
+            pos: 0,
+            finished: false,
+        })
+    }
+
+    /// Tag the last block, the file is complete then.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        let last = self.len.saturating_sub(1) / BLOCK as u64;
+        if self.index != last {
+            self.load(last)?;
+        }
+        self.store(true)?;
+        self.file.flush()?;
+        self.finished = true;
+        Ok(())
+    }
+
+    fn load(&mut self, index: u64) -> io::Result<()> {
+        self.store(false)?;
+        let len = self
+            .len
+            .saturating_sub(index * BLOCK as u64)
+            .min(BLOCK as u64) as usize;
+        self.block.clear();
+        if len > 0 {
+            let mut buf = vec![0u8; aead::NONCEBYTES + len + aead::TAGBYTES];
+            self.file
+                .seek(SeekFrom::Start(HEADER_LEN + index * ENCRYPTED_BLOCK as u64))?;
+            self.file.read_exact(&mut buf)?;
+            let (nonce, c) = buf.split_at(aead::NONCEBYTES);
+            self.block = aead::Nonce::from_slice(nonce)
+                .and_then(|nonce| {
+                    aead::open(c, Some(&block_ad(index, false)), &nonce, &self.key).ok()
+                })
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "failed to decrypt"))?;
+        }
+        self.index = index;
+        Ok(())
+    }
+
+    fn store(&mut self, last: bool) -> io::Result<()> {
+        if !self.dirty && !last {
+            return Ok(());
+        }
+        let nonce = aead::gen_nonce();
+        let c = aead::seal(
+            &self.block,
+            Some(&block_ad(self.index, last)),
+            &nonce,
+            &self.key,
+        );
+        self.file.seek(SeekFrom::Start(
+            HEADER_LEN + self.index * ENCRYPTED_BLOCK as u64,
+        ))?;
+        self.file.write_all(&nonce.0)?;
+        self.file.write_all(&c)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl Write for EncryptedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(io::Error::new(io::ErrorKind::Other, "finished"));
+        }
+        let index = self.pos / BLOCK as u64;
+        if index != self.index {
+            self.load(index)?;
+        }
+        let offset = (self.pos % BLOCK as u64) as usize;
+        let n = buf.len().min(BLOCK - offset);
+        if self.block.len() < offset + n {
+            self.block.resize(offset + n, 0);
+        }
+        self.block[offset..offset + n].copy_from_slice(&buf[..n]);
+        self.dirty = true;
+        self.pos += n as u64;
+        self.len = self.len.max(self.pos);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for EncryptedWriter {
+    // only within the written data, blocks are never skipped
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.len.checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        };
+        match pos {
+            Some(pos) if pos <= self.len => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek beyond the end of an encrypted recording",
+            )),
+        }
+    }
+}
+
+impl Drop for EncryptedWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            log::error!("Failed to finish encrypted recording: {}", e);
+        }
+    }
+}
+
+fn block_ad(index: u64, last: bool) -> [u8; 9] {
+    let mut ad = [0u8; 9];
+    ad[..8].copy_from_slice(&index.to_le_bytes());
+    ad[8] = last as u8;
+    ad
+}
+
+/// Decrypt a file of `EncryptedWriter` with the secret key of the public key it is encrypted to.
+pub fn decrypt_file(src: &Path, dst: &Path, sk: &box_::SecretKey) -> ResultType<()> {
+    let mut reader = BufReader::new(File::open(src)?);
+    let mut magic = [0u8; 6];
+    reader.read_exact(&mut magic)?;
+    if &magic != ENCRYPTED_MAGIC {
+        bail!("not an encrypted recording");
+    }
+    let mut sealed = [0u8; SEALED_KEY_LEN];
+    reader.read_exact(&mut sealed)?;
+    let Ok(key) = sealedbox::open(&sealed, &sk.public_key(), sk) else {
+        bail!("the recording is not encrypted to this key");
+    };
+    let Some(key) = aead::Key::from_slice(&key) else {
+        bail!("invalid key");
+    };
+    let mut writer = BufWriter::new(File::create(dst)?);
+    let mut buf = vec![0u8; ENCRYPTED_BLOCK];
+    for index in 0u64.. {
+        let n = read_full(&mut reader, &mut buf)?;
+        if n < aead::NONCEBYTES + aead::TAGBYTES {
+            bail!("truncated recording");
+        }
+        // a full block is the last one if the file ends there
+        let last = n < ENCRYPTED_BLOCK || reader.fill_buf()?.is_empty();
+        let (nonce, c) = buf[..n].split_at(aead::NONCEBYTES);
+        let Some(nonce) = aead::Nonce::from_slice(nonce) else {
+            bail!("invalid nonce");
+        };
+        let Ok(m) = aead::open(c, Some(&block_ad(index, last)), &nonce, &key) else {
+            bail!("failed to decrypt block {}, truncated or corrupted", index);
+        };
+        writer.write_all(&m)?;
+        if last {
+            break;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+pub fn load_manifest(dir: &str) -> Vec<ManifestEntry> {
+    std::fs::read_to_string(Path::new(dir).join(MANIFEST))
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+fn save_manifest(dir: &str, entries: &[ManifestEntry]) -> ResultType<()> {
+    let path = Path::new(dir).join(MANIFEST);
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_string_pretty(entries)?)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+pub fn lookup(dir: &str, query: &ManifestQuery) -> Vec<ManifestEntry> {
+    load_manifest(dir)
+        .into_iter()
+        .filter(|e| query.matches(e))
+        .collect()
+}
+
+/// Index and apply the retention policy to a finished recording, in a new thread as the
+/// retention policy removes files.
+pub fn finish(file: RecordedFile) {
+    let end = now_ms();
+    std::thread::spawn(move || {
+        if let Err(e) = finish_(file, end) {
+            log::error!("Failed to store recording: {}", e);
+        }
+    });
+}
+
+fn finish_(file: RecordedFile, end: i64) -> ResultType<()> {
+    let path = PathBuf::from(&file.path);
+    let Some(dir) = path.parent().map(|p| p.to_string_lossy().to_string()) else {
+        bail!("no directory of {}", file.path);
+    };
+    let encrypted = is_encrypted_path(&file.path);
+    let peer_ids = match file.direction {
+        Direction::Incoming => peers_between(file.start, end),
+        Direction::Outgoing => vec![file.id],
+    };
+    let entry = ManifestEntry {
+        file: path
+            .file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        peer_ids,
+        direction: file.direction,
+        start: file.start,
+        end,
+        codec: file.codec,
+        size: std::fs::metadata(&path)?.len(),
+        encrypted,
+    };
+    let _lock = MANIFEST_LOCK.lock().unwrap();
+    let mut entries = load_manifest(&dir);
+    entries.push(entry);
+    apply_retention(&dir, &mut entries, &RetentionPolicy::from_options(), end);
+    save_manifest(&dir, &entries)
+}
+
+/// Remove the recordings out of the retention policy of the options.
+pub fn enforce_retention(dir: &str) -> ResultType<()> {
+    if !Path::new(dir).join(MANIFEST).exists() {
+        return Ok(());
+    }
+    let _lock = MANIFEST_LOCK.lock().unwrap();
+    let mut entries = load_manifest(dir);
+    let len = entries.len();
+    apply_retention(
+        dir,
+        &mut entries,
+        &RetentionPolicy::from_options(),
+        now_ms(),
+    );
+    if entries.len() != len {
+        save_manifest(dir, &entries)?;
+    }
+    Ok(())
+}
+
+// The oldest recordings go first, the removed files are dropped from the manifest, as well as
+// the ones removed by the user.
+fn apply_retention(
+    dir: &str,
+    entries: &mut Vec<ManifestEntry>,
+    policy: &RetentionPolicy,
+    now: i64,
+) {
+    let dir = Path::new(dir);
+    entries.retain(|e| dir.join(&e.file).exists());
+    if policy.is_empty() {
+        return;
+    }
+    entries.sort_by_key(|e| e.end);
+    let max_age_ms = policy.max_age_days as i64 * 24 * 3600 * 1000;
+    let mut total: u64 = entries.iter().map(|e| e.size).sum();
+    let mut count = entries.len();
+    entries.retain(|e| {
+        let expired = (policy.max_age_days > 0 && now - e.end > max_age_ms)
+            || (policy.max_count > 0 && count > policy.max_count)
+            || (policy.max_total_size > 0 && total > policy.max_total_size);
+        if !expired {
+            return true;
+        }
+        match std::fs::remove_file(dir.join(&e.file)) {
+            Ok(()) => {
+                log::info!("recording {} removed by the retention policy", e.file);
+                total -= e.size;
+                count -= 1;
+                false
+            }
+            Err(err) => {
+                log::error!("Failed to remove recording {}: {}", e.file, err);
+                true
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let dir = test_dir("record_store_crypt");
+        let (pk, sk) = gen_keypair();
+        let (pk, sk) = (
+            parse_public_key(&pk).unwrap(),
+            parse_secret_key(&sk).unwrap(),
+        );
+        for len in [0, 10, BLOCK, BLOCK * 2 + 1] {
+            let mut data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let encrypted = dir.join("a.webm.enc");
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&encrypted)
+                .unwrap();
+            let mut writer = EncryptedWriter::new(file, &pk).unwrap();
+            for chunk in data.chunks(1000) {
+                writer.write_all(chunk).unwrap();
+            }
+            assert!(writer.seek(SeekFrom::End(1)).is_err());
+            // the muxer rewrites sizes at the start when it is closed
+            if len > 4 {
+                writer.seek(SeekFrom::Start(1)).unwrap();
+                writer.write_all(b"size").unwrap();
+                data[1..5].copy_from_slice(b"size");
+                writer.seek(SeekFrom::End(0)).unwrap();
+            }
+            writer.write_all(b"tail").unwrap();
+            data.extend_from_slice(b"tail");
+            drop(writer);
+            let enc = std::fs::read(&encrypted).unwrap();
+            assert!(!enc.windows(4).any(|w| w == b"tail"));
+            let decrypted = dir.join("b.webm");
+            decrypt_file(&encrypted, &decrypted, &sk).unwrap();
+            assert_eq!(std::fs::read(&decrypted).unwrap(), data);
+            let (_, other) = box_::gen_keypair();
+            assert!(decrypt_file(&encrypted, &decrypted, &other).is_err());
+            // truncated, in a block and at the end of a block
+            std::fs::write(&encrypted, &enc[..enc.len() - 1]).unwrap();
+            assert!(decrypt_file(&encrypted, &decrypted, &sk).is_err());
+            let end = HEADER_LEN as usize + ENCRYPTED_BLOCK;
+            if enc.len() > end {
+                std::fs::write(&encrypted, &enc[..end]).unwrap();
+                assert!(decrypt_file(&encrypted, &decrypted, &sk).is_err());
+            }
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_retention() {
+        let dir = test_dir("record_store_retention");
+        let dir_str = dir.to_string_lossy().to_string();
+        let day = 24 * 3600 * 1000;
+        let now = 100 * day;
+        let mut entries: Vec<ManifestEntry> = (0..5)
+            .map(|i| {
+                let file = format!("{}.webm", i);
+                std::fs::write(dir.join(&file), vec![0u8; 10]).unwrap();
+                ManifestEntry {
+                    file,
+                    peer_ids: vec![i.to_string()],
+                    direction: Direction::Incoming,
+                    start: now - (5 - i) * day - 1000,
+                    end: now - (5 - i) * day,
+                    codec: "vp9".to_owned(),
+                    size: 10,
+                    encrypted: false,
+                }
+            })
+            .collect();
+        std::fs::remove_file(dir.join("4.webm")).unwrap();
+        apply_retention(&dir_str, &mut entries, &RetentionPolicy::default(), now);
+        assert_eq!(entries.len(), 4);
+        let policy = RetentionPolicy {
+            max_age_days: 4,
+            ..Default::default()
+        };
+        apply_retention(&dir_str, &mut entries, &policy, now);
+        assert_eq!(entries.len(), 3);
+        assert!(!dir.join("0.webm").exists());
+        let policy = RetentionPolicy {
+            max_total_size: 25,
+            ..Default::default()
+        };
+        apply_retention(&dir_str, &mut entries, &policy, now);
+        assert_eq!(entries.len(), 2);
+        let policy = RetentionPolicy {
+            max_count: 1,
+            ..Default::default()
+        };
+        apply_retention(&dir_str, &mut entries, &policy, now);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].file, "3.webm");
+        let query = ManifestQuery {
+            peer_id: Some("3".to_owned()),
+            direction: Some(Direction::Incoming),
+            from: Some(now - 2 * day),
+            codec: Some("VP9".to_owned()),
+            ..Default::default()
+        };
+        assert!(query.matches(&entries[0]));
+        let query = ManifestQuery {
+            direction: Some(Direction::Outgoing),
+            ..Default::default()
+        };
+        assert!(!query.matches(&entries[0]));
+        let args = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let query =
+            ManifestQuery::from_args(&args(&["--peer", "3", "--direction", "Incoming"])).unwrap();
+        assert!(query.matches(&entries[0]));
+        assert!(ManifestQuery::from_args(&args(&["--from"])).is_err());
+        assert!(ManifestQuery::from_args(&args(&["--to", "x"])).is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
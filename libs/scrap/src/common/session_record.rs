@@ -0,0 +1,698 @@
+// The session recording keeps the raw, timestamped message stream of a session in one file:
+// video of every display, cursor, display switches, chat, audio and input.
+// Unlike `record`, which muxes the video of one display and starts a new file whenever the pts
+// goes backwards or the codec changes, it can be replayed as it happened by `SessionPlayer`.
+//
+// The messages are written by a thread of the recorder, the sessions are not held up by the
+// disk. The key events, which may be typed passwords, are only kept if the file is encrypted.
+//
+// File layout, all integers are little endian:
+//   magic "RDREC1", server u8, id length u16, id, start time i64 (unix milliseconds)
+//   entries of: milliseconds since start u64, message length u32, message
+
+use crate::{
+    codec::{Decoder, Encoder, EncoderApi, EncoderCfg, Quality},
+    convert_to_yuv,
+    record::add_opus_track,
+    record_store::{self, Direction, RecordWriter, RecordedFile},
+    CodecFormat, EncodeInput, ImageFormat, ImageRgb, Pixfmt, TraitPixelBuffer, VpxEncoderConfig,
+    VpxVideoCodecId,
+};
+use hbb_common::{
+    anyhow::anyhow,
+    bail, chrono, log,
+    message_proto::{message, misc, video_frame, AudioFormat, Message},
+    protobuf::Message as _,
+    ResultType,
+};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+    thread::JoinHandle,
+    time::Instant,
+};
+use webm::mux::{self, Track};
+
+pub const EXTENSION: &str = "rdrec";
+const MAGIC: &[u8; 6] = b"RDREC1";
+const ENTRY_HEAD_LEN: u64 = 12;
+const TRANSCODE_KEYFRAME_INTERVAL: usize = 250;
+// the messages waiting for the writer thread, the recording stops if the disk can not keep up
+const QUEUE_LEN: usize = 256;
+
+fn is_recorded(msg: &Message) -> bool {
+    match &msg.union {
+        Some(message::Union::VideoFrame(_))
+        | Some(message::Union::AudioFrame(_))
+        | Some(message::Union::CursorData(_))
+        | Some(message::Union::CursorId(_))
+        | Some(message::Union::CursorPosition(_))
+        | Some(message::Union::PeerInfo(_))
+        | Some(message::Union::KeyEvent(_))
+        | Some(message::Union::MouseEvent(_)) => true,
+        Some(message::Union::Misc(misc)) => matches!(
+            misc.union,
+            Some(misc::Union::SwitchDisplay(_))
+                | Some(misc::Union::ChatMessage(_))
+                | Some(misc::Union::AudioFormat(_))
+        ),
+        _ => false,
+    }
+}
+
+/// Messages sent by the controlling side which are recorded, the voice call audio is not.
+pub fn is_input_or_chat(msg: &Message) -> bool {
+    match &msg.union {
+        Some(message::Union::KeyEvent(_)) | Some(message::Union::MouseEvent(_)) => true,
+        Some(message::Union::Misc(misc)) => {
+            matches!(misc.union, Some(misc::Union::ChatMessage(_)))
+        }
+        _ => false,
+    }
+}
+
+pub struct SessionRecorder {
+    // the messages to the writer thread, which stores the recording after it is dropped
+    tx: Option<SyncSender<(u64, Vec<u8>)>>,
+    thread: Option<JoinHandle<()>>,
+    filename: String,
+    start: Instant,
+    keys: bool,
+}
+
+impl SessionRecorder {
+    pub fn new(server: bool, id: &str, dir: &str) -> ResultType<Self> {
+        if !PathBuf::from(dir).exists() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = if server { "incoming" } else { "outgoing" }.to_string()
+            + "_"
+            + id
+            + &chrono::Local::now().format("_%Y%m%d%H%M%S%3f.").to_string()
+            + EXTENSION;
+        let filename =
+            record_store::record_path(PathBuf::from(dir).join(file).to_string_lossy().to_string());
+        let mut writer = BufWriter::new(RecordWriter::create(&filename)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[server as u8])?;
+        writer.write_all(&(id.len() as u16).to_le_bytes())?;
+        writer.write_all(id.as_bytes())?;
+        writer.write_all(&chrono::Utc::now().timestamp_millis().to_le_bytes())?;
+        log::info!("session will save to {}", filename);
+        let start = Instant::now();
+        let file = RecordedFile {
+            path: filename.clone(),
+            direction: if server {
+                Direction::Incoming
+            } else {
+                Direction::Outgoing
+            },
+            id: id.to_owned(),
+            codec: EXTENSION.to_owned(),
+            start: record_store::unix_ms_of(start),
+        };
+        let (tx, rx) = mpsc::sync_channel(QUEUE_LEN);
+        let thread = std::thread::spawn(move || write_loop(writer, rx, file));
+        Ok(Self {
+            tx: Some(tx),
+            thread: Some(thread),
+            keys: record_store::is_encrypted_path(&filename),
+            filename,
+            start,
+        })
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// Messages which are not needed to replay the session are skipped, so are the key events
+    /// if the file is not encrypted. It does not wait for the disk.
+    pub fn write_message(&mut self, msg: &Message) -> ResultType<()> {
+        if !is_recorded(msg) {
+            return Ok(());
+        }
+        if !self.keys && matches!(msg.union, Some(message::Union::KeyEvent(_))) {
+            return Ok(());
+        }
+        let Some(tx) = self.tx.as_ref() else {
+            bail!("closed");
+        };
+        let ms = self.start.elapsed().as_millis() as u64;
+        match tx.try_send((ms, msg.write_to_bytes()?)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => bail!("the disk is too slow"),
+            Err(TrySendError::Disconnected(_)) => bail!("the writer is stopped"),
+        }
+    }
+
+    /// Wait until the recording is written and stored, which the drop does not.
+    pub fn close(mut self) {
+        self.tx.take();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+impl Drop for SessionRecorder {
+    fn drop(&mut self) {
+        // the writer thread finishes the file once the queue is written
+        self.tx.take();
+    }
+}
+
+fn write_loop(
+    mut writer: BufWriter<RecordWriter>,
+    rx: Receiver<(u64, Vec<u8>)>,
+    file: RecordedFile,
+) {
+    let mut written = false;
+    while let Ok((ms, data)) = rx.recv() {
+        let res = writer
+            .write_all(&ms.to_le_bytes())
+            .and_then(|_| writer.write_all(&(data.len() as u32).to_le_bytes()))
+            .and_then(|_| writer.write_all(&data));
+        if let Err(e) = res {
+            log::error!("Failed to write session recording {}: {}", file.path, e);
+            break;
+        }
+        written = true;
+    }
+    writer.flush().ok();
+    // the last block of an encrypted file is sealed when it is closed
+    drop(writer);
+    if !written {
+        std::fs::remove_file(&file.path).ok();
+        return;
+    }
+    record_store::finish(file);
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionHeader {
+    pub server: bool,
+    pub id: String,
+    // unix time in milliseconds
+    pub start: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EntryKind {
+    Video { display: usize, key: bool },
+    AudioFrame,
+    AudioFormat,
+    Other,
+}
+
+struct Entry {
+    ms: u64,
+    offset: u64,
+    len: u32,
+    kind: EntryKind,
+}
+
+/// Headless player of the files of `SessionRecorder`.
+pub struct SessionPlayer {
+    reader: BufReader<File>,
+    header: SessionHeader,
+    entries: Vec<Entry>,
+    pos: usize,
+}
+
+impl SessionPlayer {
+    pub fn open<P: AsRef<Path>>(path: P) -> ResultType<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 6];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("not a session recording");
+        }
+        let mut buf = [0u8; 8];
+        reader.read_exact(&mut buf[..1])?;
+        let server = buf[0] != 0;
+        reader.read_exact(&mut buf[..2])?;
+        let mut id = vec![0u8; u16::from_le_bytes([buf[0], buf[1]]) as usize];
+        reader.read_exact(&mut id)?;
+        reader.read_exact(&mut buf)?;
+        let header = SessionHeader {
+            server,
+            id: String::from_utf8_lossy(&id).to_string(),
+            start: i64::from_le_bytes(buf),
+        };
+        let mut offset = (MAGIC.len() + 1 + 2 + id.len() + 8) as u64;
+        let mut entries = Vec::new();
+        loop {
+            let mut head = [0u8; ENTRY_HEAD_LEN as usize];
+            // the tail is incomplete if the recorder is not closed normally
+            if reader.read_exact(&mut head).is_err() {
+                break;
+            }
+            let mut ms = [0u8; 8];
+            ms.copy_from_slice(&head[..8]);
+            let len = u32::from_le_bytes([head[8], head[9], head[10], head[11]]);
+            let mut data = vec![0u8; len as usize];
+            if reader.read_exact(&mut data).is_err() {
+                break;
+            }
+            let kind = match Message::parse_from_bytes(&data) {
+                Ok(msg) => entry_kind(&msg),
+                Err(e) => {
+                    log::error!("invalid message at {}: {}", offset, e);
+                    EntryKind::Other
+                }
+            };
+            entries.push(Entry {
+                ms: u64::from_le_bytes(ms),
+                offset: offset + ENTRY_HEAD_LEN,
+                len,
+                kind,
+            });
+            offset += ENTRY_HEAD_LEN + len as u64;
+        }
+        Ok(Self {
+            reader,
+            header,
+            entries,
+            pos: 0,
+        })
+    }
+
+    pub fn header(&self) -> &SessionHeader {
+        &self.header
+    }
+
+    /// In milliseconds.
+    pub fn duration(&self) -> u64 {
+        self.entries.last().map(|e| e.ms).unwrap_or_default()
+    }
+
+    pub fn displays(&self) -> Vec<usize> {
+        let mut displays: Vec<usize> = self
+            .entries
+            .iter()
+            .filter_map(|e| match e.kind {
+                EntryKind::Video { display, .. } => Some(display),
+                _ => None,
+            })
+            .collect();
+        displays.sort();
+        displays.dedup();
+        displays
+    }
+
+    /// The next message is the first one at or after `ms`.
+    pub fn seek(&mut self, ms: u64) {
+        self.pos = self.entries.partition_point(|e| e.ms < ms);
+    }
+
+    fn read(&mut self, index: usize) -> ResultType<Message> {
+        let entry = &self.entries[index];
+        let mut data = vec![0u8; entry.len as usize];
+        self.reader.seek(SeekFrom::Start(entry.offset))?;
+        self.reader.read_exact(&mut data)?;
+        Ok(Message::parse_from_bytes(&data)?)
+    }
+
+    fn is_frame(&self, index: usize, display: usize, key_only: bool) -> bool {
+        match self.entries[index].kind {
+            EntryKind::Video { display: d, key } => d == display && (key || !key_only),
+            _ => false,
+        }
+    }
+
+    /// Decode the frame of `display` shown at `ms` into `rgb`, whose format and stride must be
+    /// set, returns the time of the frame.
+    pub fn frame_at(&mut self, display: usize, ms: u64, rgb: &mut ImageRgb) -> ResultType<u64> {
+        let end = self.entries.partition_point(|e| e.ms <= ms);
+        let Some(last) = (0..end).rev().find(|i| self.is_frame(*i, display, false)) else {
+            bail!("no frame of display {} at {}ms", display, ms);
+        };
+        let Some(first) = (0..=last).rev().find(|i| self.is_frame(*i, display, true)) else {
+            bail!("no key frame of display {} before {}ms", display, ms);
+        };
+        let mut decoder = None;
+        for i in first..=last {
+            if self.is_frame(i, display, false) {
+                let msg = self.read(i)?;
+                decode(&mut decoder, &msg, rgb)?;
+            }
+        }
+        Ok(self.entries[last].ms)
+    }
+
+    pub fn export_png<P: AsRef<Path>>(
+        &mut self,
+        display: usize,
+        ms: u64,
+        path: P,
+    ) -> ResultType<()> {
+        let mut rgb = ImageRgb::new(ImageFormat::ABGR, 1);
+        self.frame_at(display, ms, &mut rgb)?;
+        if rgb.w == 0 || rgb.h == 0 {
+            bail!("empty frame");
+        }
+        // rows may be padded
+        let stride = rgb.raw.len() / rgb.h;
+        let mut data = Vec::with_capacity(rgb.w * rgb.h * 4);
+        for row in rgb.raw.chunks(stride).take(rgb.h) {
+            data.extend_from_slice(&row[..rgb.w * 4]);
+        }
+        repng::encode(File::create(path)?, rgb.w as _, rgb.h as _, &data)?;
+        Ok(())
+    }
+
+    /// Transcode the video of `display` and the audio from `from` to `to` milliseconds into a
+    /// VP9 webm file.
+    pub fn transcode<P: AsRef<Path>>(
+        &mut self,
+        display: usize,
+        from: u64,
+        to: u64,
+        path: P,
+    ) -> ResultType<()> {
+        if from > to {
+            bail!("invalid range {}..{}", from, to);
+        }
+        let end = self.entries.partition_point(|e| e.ms <= to);
+        let begin = self.entries.partition_point(|e| e.ms < from);
+        // decoding starts from the key frame before the range
+        let start = (0..begin)
+            .rev()
+            .find(|i| self.is_frame(*i, display, true))
+            .unwrap_or(begin);
+        let audio_format = match (0..end)
+            .rev()
+            .find(|i| self.entries[*i].kind == EntryKind::AudioFormat)
+        {
+            Some(i) => match self.read(i)?.union {
+                Some(message::Union::Misc(misc)) => match misc.union {
+                    Some(misc::Union::AudioFormat(f)) => Some(f),
+                    _ => None,
+                },
+                _ => None,
+            },
+            None => None,
+        };
+        let path = path.as_ref();
+        let mut transcoder = Transcoder::new(path, audio_format)?;
+        let mut decoder = None;
+        let mut rgb = ImageRgb::new(ImageFormat::ARGB, 1);
+        for i in start..end {
+            let ms = self.entries[i].ms;
+            match self.entries[i].kind {
+                EntryKind::Video { display: d, .. } if d == display => {
+                    let msg = self.read(i)?;
+                    let decoded = match decode(&mut decoder, &msg, &mut rgb) {
+                        Ok(decoded) => decoded,
+                        Err(e) => {
+                            log::error!("Failed to decode frame at {}ms: {}", ms, e);
+                            false
+                        }
+                    };
+                    if decoded && ms >= from {
+                        transcoder.write_video(&rgb, ms - from)?;
+                    }
+                }
+                EntryKind::AudioFrame if ms >= from => {
+                    if let Some(message::Union::AudioFrame(af)) = self.read(i)?.union {
+                        transcoder.write_audio(&af.data, ms - from);
+                    }
+                }
+                _ => {}
+            }
+        }
+        if !transcoder.finalize() {
+            std::fs::remove_file(path).ok();
+            bail!("no frame of display {} in {}..{}ms", display, from, to);
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for SessionPlayer {
+    type Item = ResultType<(u64, Message)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.entries.len() {
+            return None;
+        }
+        let index = self.pos;
+        self.pos += 1;
+        Some(self.read(index).map(|msg| (self.entries[index].ms, msg)))
+    }
+}
+
+fn entry_kind(msg: &Message) -> EntryKind {
+    match &msg.union {
+        Some(message::Union::VideoFrame(vf)) => {
+            let key = match &vf.union {
+                Some(video_frame::Union::Vp8s(f))
+                | Some(video_frame::Union::Vp9s(f))
+                | Some(video_frame::Union::Av1s(f))
+                | Some(video_frame::Union::H264s(f))
+                | Some(video_frame::Union::H265s(f)) => {
+                    f.frames.first().map(|f| f.key).unwrap_or_default()
+                }
+                _ => false,
+            };
+            EntryKind::Video {
+                display: vf.display as _,
+                key,
+            }
+        }
+        Some(message::Union::AudioFrame(_)) => EntryKind::AudioFrame,
+        Some(message::Union::Misc(misc)) => match misc.union {
+            Some(misc::Union::AudioFormat(_)) => EntryKind::AudioFormat,
+            _ => EntryKind::Other,
+        },
+        _ => EntryKind::Other,
+    }
+}
+
+fn decode(decoder: &mut Option<Decoder>, msg: &Message, rgb: &mut ImageRgb) -> ResultType<bool> {
+    let Some(message::Union::VideoFrame(vf)) = &msg.union else {
+        return Ok(false);
+    };
+    let Some(frame) = &vf.union else {
+        return Ok(false);
+    };
+    let format = CodecFormat::from(vf);
+    if decoder.as_ref().map(|d| d.format()) != Some(format) {
+        *decoder = Some(Decoder::new(format, None));
+    }
+    let Some(decoder) = decoder.as_mut() else {
+        return Ok(false);
+    };
+    let mut texture = std::ptr::null_mut();
+    let mut pixelbuffer = true;
+    let mut chroma = None;
+    decoder.handle_video_frame(frame, rgb, &mut texture, &mut pixelbuffer, &mut chroma)
+}
+
+struct RgbPixelBuffer<'a>(&'a ImageRgb);
+
+impl TraitPixelBuffer for RgbPixelBuffer<'_> {
+    fn data(&self) -> &[u8] {
+        &self.0.raw
+    }
+
+    fn width(&self) -> usize {
+        self.0.w
+    }
+
+    fn height(&self) -> usize {
+        self.0.h
+    }
+
+    fn stride(&self) -> Vec<usize> {
+        vec![self.0.raw.len() / self.0.h.max(1)]
+    }
+
+    fn pixfmt(&self) -> Pixfmt {
+        Pixfmt::BGRA
+    }
+}
+
+// Tracks are added with the first video frame, whose size is unknown before.
+struct Transcoder {
+    webm: Option<mux::Segment<mux::Writer<File>>>,
+    tracks: Option<(mux::VideoTrack, Option<mux::AudioTrack>, Encoder)>,
+    audio_format: Option<AudioFormat>,
+    size: (usize, usize),
+    yuv: Vec<u8>,
+    mid_data: Vec<u8>,
+    written: bool,
+}
+
+impl Transcoder {
+    fn new(path: &Path, audio_format: Option<AudioFormat>) -> ResultType<Self> {
+        let webm = mux::Segment::new(mux::Writer::new(File::create(path)?))
+            .ok_or_else(|| anyhow!("Failed to create webm mux"))?;
+        Ok(Self {
+            webm: Some(webm),
+            tracks: None,
+            audio_format,
+            size: (0, 0),
+            yuv: Vec::new(),
+            mid_data: Vec::new(),
+            written: false,
+        })
+    }
+
+    fn write_video(&mut self, rgb: &ImageRgb, ms: u64) -> ResultType<()> {
+        let Some(webm) = self.webm.as_mut() else {
+            bail!("finalized");
+        };
+        if self.tracks.is_none() {
+            let vt = webm.add_video_track(rgb.w as _, rgb.h as _, None, mux::VideoCodecId::VP9);
+            let at = match &self.audio_format {
+                Some(format) => Some(add_opus_track(webm, format)?),
+                None => None,
+            };
+            let encoder = Encoder::new(
+                EncoderCfg::VPX(VpxEncoderConfig {
+                    width: rgb.w as _,
+                    height: rgb.h as _,
+                    quality: Quality::Best,
+                    codec: VpxVideoCodecId::VP9,
+                    keyframe_interval: Some(TRANSCODE_KEYFRAME_INTERVAL),
+                }),
+                false,
+            )?;
+            self.size = (rgb.w, rgb.h);
+            self.tracks = Some((vt, at, encoder));
+        }
+        if self.size != (rgb.w, rgb.h) {
+            bail!(
+                "resolution changed from {:?} to {:?}",
+                self.size,
+                (rgb.w, rgb.h)
+            );
+        }
+        let Some((vt, _, encoder)) = self.tracks.as_mut() else {
+            bail!("no video track");
+        };
+        convert_to_yuv(
+            &RgbPixelBuffer(rgb),
+            encoder.yuvfmt(),
+            &mut self.yuv,
+            &mut self.mid_data,
+        )?;
+        // frames may be dropped by the rate control
+        let Ok(vf) = encoder.encode_to_message(EncodeInput::YUV(&self.yuv), ms as _) else {
+            return Ok(());
+        };
+        if let Some(video_frame::Union::Vp9s(vp9s)) = &vf.union {
+            for f in vp9s.frames.iter() {
+                if vt.add_frame(&f.data, f.pts as u64 * 1_000_000, f.key) {
+                    self.written = true;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_audio(&mut self, data: &[u8], ms: u64) {
+        // audio before the first video frame is dropped, a cluster starts with a key frame
+        if let Some((_, Some(at), _)) = self.tracks.as_mut() {
+            at.add_frame(data, ms * 1_000_000, true);
+        }
+    }
+
+    // Returns if any frame is written.
+    fn finalize(mut self) -> bool {
+        // the encoder is dropped before the file is closed
+        self.tracks.take();
+        if let Some(webm) = self.webm.take() {
+            webm.finalize(None);
+        }
+        self.written
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hbb_common::message_proto::{ChatMessage, KeyEvent, Misc, MouseEvent, TestDelay};
+
+    #[test]
+    fn test_session_record() {
+        let dir = std::env::temp_dir().join(format!("session_record_{}", std::process::id()));
+        let dir = dir.to_string_lossy().to_string();
+        let filename = {
+            let mut recorder = SessionRecorder::new(true, "123456789", &dir).unwrap();
+            let mut msg = Message::new();
+            msg.set_mouse_event(MouseEvent {
+                x: 10,
+                y: 20,
+                ..Default::default()
+            });
+            recorder.write_message(&msg).unwrap();
+            // not kept unless the file is encrypted
+            let mut msg = Message::new();
+            msg.set_key_event(KeyEvent {
+                chr: 65,
+                down: true,
+                ..Default::default()
+            });
+            recorder.write_message(&msg).unwrap();
+            let mut msg = Message::new();
+            msg.set_test_delay(TestDelay::new());
+            recorder.write_message(&msg).unwrap();
+            let mut misc = Misc::new();
+            misc.set_chat_message(ChatMessage {
+                text: "hello".to_owned(),
+                ..Default::default()
+            });
+            let mut msg = Message::new();
+            msg.set_misc(misc);
+            recorder.write_message(&msg).unwrap();
+            let filename = recorder.filename().to_owned();
+            recorder.close();
+            filename
+        };
+        let mut player = SessionPlayer::open(&filename).unwrap();
+        assert!(player.header().server);
+        assert_eq!(player.header().id, "123456789");
+        assert!(player.displays().is_empty());
+        let msgs: Vec<Message> = player.by_ref().map(|r| r.unwrap().1).collect();
+        assert_eq!(msgs.len(), 2);
+        assert!(matches!(msgs[0].union, Some(message::Union::MouseEvent(_))));
+        assert!(matches!(msgs[1].union, Some(message::Union::Misc(_))));
+        player.seek(player.duration() + 1);
+        assert!(player.next().is_none());
+        assert!(player
+            .frame_at(0, 0, &mut ImageRgb::new(ImageFormat::ARGB, 1))
+            .is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_session_record_truncated() {
+        let dir = std::env::temp_dir().join(format!("session_truncated_{}", std::process::id()));
+        let dir = dir.to_string_lossy().to_string();
+        let filename = {
+            let mut recorder = SessionRecorder::new(false, "1", &dir).unwrap();
+            let mut msg = Message::new();
+            msg.set_mouse_event(MouseEvent::new());
+            recorder.write_message(&msg).unwrap();
+            recorder.write_message(&msg).unwrap();
+            let filename = recorder.filename().to_owned();
+            recorder.close();
+            filename
+        };
+        let len = std::fs::metadata(&filename).unwrap().len();
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&filename)
+            .unwrap();
+        file.set_len(len - 1).unwrap();
+        let player = SessionPlayer::open(&filename).unwrap();
+        assert!(!player.header().server);
+        assert_eq!(player.count(), 1);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
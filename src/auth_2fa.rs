@@ -1,20 +1,31 @@
 use hbb_common::{
     bail,
     config::Config,
-    get_time,
+    get_time, log,
     password_security::{decrypt_vec_or_original, encrypt_vec_or_original},
+    rand::{self, Rng},
     ResultType,
 };
 use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::sync::Mutex;
 use totp_rs::{Algorithm, Secret, TOTP};
 
 lazy_static::lazy_static! {
     static ref CURRENT_2FA: Mutex<Option<(TOTPInfo, TOTP)>> = Mutex::new(None);
+    // option "2fa" is changed under it by the service, HOTP counters and recovery codes by the
+    // login which uses them, the rest by `compare_and_set` from the ui
+    static ref VERIFY_LOCK: Mutex<()> = Mutex::new(());
 }
 
 const ISSUER: &str = "RustDesk";
 const TAG_LOGIN: &str = "Connection";
+const DEFAULT_PERIOD: u64 = 30;
+// codes of HOTP counters ahead of the stored one are accepted, for the ones generated but not used
+const HOTP_LOOKAHEAD: u64 = 10;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LEN: usize = 10;
+const RECOVERY_CODE_CHARS: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TOTPInfo {
@@ -22,15 +33,44 @@ pub struct TOTPInfo {
     pub secret: Vec<u8>,
     pub digits: usize,
     pub created_at: i64,
+    // SHA1, SHA256 or SHA512, empty for SHA1
+    #[serde(default)]
+    pub algorithm: String,
+    // seconds, 0 for 30
+    #[serde(default)]
+    pub period: u64,
+    // the counter of the next code of a HOTP device, None for TOTP
+    #[serde(default)]
+    pub hotp_counter: Option<u64>,
 }
 
 impl TOTPInfo {
+    fn algorithm(&self) -> Algorithm {
+        match self.algorithm.to_uppercase().as_str() {
+            "SHA256" => Algorithm::SHA256,
+            "SHA512" => Algorithm::SHA512,
+            _ => Algorithm::SHA1,
+        }
+    }
+
+    // HOTP codes are the ones of TOTP with a step of 1 second at the time of the counter.
     fn new_totp(&self) -> ResultType<TOTP> {
+        let (skew, step) = match self.hotp_counter {
+            Some(_) => (0, 1),
+            None => (
+                1,
+                if self.period == 0 {
+                    DEFAULT_PERIOD
+                } else {
+                    self.period
+                },
+            ),
+        };
         let totp = TOTP::new(
-            Algorithm::SHA1,
+            self.algorithm(),
             self.digits,
-            1,
-            30,
+            skew,
+            step,
             self.secret.clone(),
             Some(format!("{} {}", ISSUER, TAG_LOGIN)),
             self.name.clone(),
@@ -38,38 +78,257 @@ impl TOTPInfo {
         Ok(totp)
     }
 
-    fn gen_totp_info(name: String, digits: usize) -> ResultType<TOTPInfo> {
+    fn gen_totp_info(
+        name: String,
+        digits: usize,
+        algorithm: String,
+        period: u64,
+        hotp: bool,
+    ) -> ResultType<TOTPInfo> {
         let secret = Secret::generate_secret();
         let totp = TOTPInfo {
             secret: secret.to_bytes()?,
             name,
             digits,
             created_at: get_time(),
-            ..Default::default()
+            algorithm,
+            period,
+            hotp_counter: if hotp { Some(0) } else { None },
         };
         Ok(totp)
     }
 
-    pub fn into_string(&self) -> ResultType<String> {
-        let secret = encrypt_vec_or_original(self.secret.as_slice(), "00", 1024);
-        let totp_info = TOTPInfo {
-            secret,
-            ..self.clone()
+    fn url(&self, totp: &TOTP) -> ResultType<String> {
+        let Some(counter) = self.hotp_counter else {
+            return Ok(totp.get_url());
+        };
+        let issuer = format!("{} {}", ISSUER, TAG_LOGIN);
+        let mut url = url::Url::parse("otpauth://hotp")?;
+        url.set_path(&format!("/{}:{}", issuer, self.name));
+        url.query_pairs_mut()
+            .append_pair("secret", &totp.get_secret_base32())
+            .append_pair("issuer", &issuer)
+            .append_pair("counter", &counter.to_string())
+            .append_pair("digits", &self.digits.to_string())
+            .append_pair("algorithm", &format!("{}", self.algorithm()));
+        Ok(url.to_string())
+    }
+
+    // The counter of a HOTP device moves past the accepted code.
+    fn check(&mut self, code: &str) -> bool {
+        let Ok(totp) = self.new_totp() else {
+            return false;
+        };
+        match self.hotp_counter {
+            Some(counter) => {
+                for c in counter..counter + HOTP_LOOKAHEAD {
+                    if totp.generate(c) == code {
+                        self.hotp_counter = Some(c + 1);
+                        return true;
+                    }
+                }
+                false
+            }
+            None => totp.check_current(code).unwrap_or(false),
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        if self.hotp_counter.is_some() {
+            "hotp"
+        } else {
+            "totp"
+        }
+    }
+}
+
+/// The authenticators and recovery codes of option "2fa".
+/// The option of one `TOTPInfo` of the former versions is read as a single device.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TwoFactor {
+    #[serde(default)]
+    pub devices: Vec<TOTPInfo>,
+    // hex sha256 of `recovery_salt` and a code, removed when used
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+    #[serde(default)]
+    pub recovery_salt: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub kind: String,
+    pub algorithm: String,
+    pub digits: usize,
+    pub period: u64,
+    pub created_at: i64,
+}
+
+impl TwoFactor {
+    pub fn from_str(data: &str) -> ResultType<TwoFactor> {
+        let value = serde_json::from_str::<serde_json::Value>(data)?;
+        let mut tfa = if value.get("devices").is_some() {
+            serde_json::from_value::<TwoFactor>(value)?
+        } else {
+            TwoFactor {
+                devices: vec![serde_json::from_value::<TOTPInfo>(value)?],
+                ..Default::default()
+            }
         };
-        let s = serde_json::to_string(&totp_info)?;
+        for device in tfa.devices.iter_mut() {
+            let (secret, success, _) = decrypt_vec_or_original(&device.secret, "00");
+            if !success {
+                bail!("decrypt_vec_or_original 2fa secret failed")
+            }
+            device.secret = secret;
+        }
+        Ok(tfa)
+    }
+
+    pub fn into_string(&self) -> ResultType<String> {
+        let mut tfa = self.clone();
+        for device in tfa.devices.iter_mut() {
+            device.secret = encrypt_vec_or_original(device.secret.as_slice(), "00", 1024);
+        }
+        let s = serde_json::to_string(&tfa)?;
         Ok(s)
     }
 
-    pub fn from_str(data: &str) -> ResultType<TOTP> {
-        let mut totp_info = serde_json::from_str::<TOTPInfo>(data)?;
-        let (secret, success, _) = decrypt_vec_or_original(&totp_info.secret, "00");
-        if success {
-            totp_info.secret = secret;
-            return Ok(totp_info.new_totp()?);
+    /// Returns the factor which accepts `code`, as `totp:<name>`, `hotp:<name>` or
+    /// `recovery-code`.
+    pub fn verify(&mut self, code: &str) -> Option<String> {
+        let code = code.trim();
+        for device in self.devices.iter_mut() {
+            if device.check(code) {
+                return Some(format!("{}:{}", device.kind(), device.name));
+            }
+        }
+        let hash = self.recovery_hash(code);
+        if let Some(i) = self.recovery_codes.iter().position(|h| *h == hash) {
+            self.recovery_codes.remove(i);
+            return Some("recovery-code".to_owned());
+        }
+        None
+    }
+
+    fn recovery_hash(&self, code: &str) -> String {
+        let code: String = code
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        let mut hasher = Sha256::new();
+        hasher.update(self.recovery_salt.as_bytes());
+        hasher.update(code.as_bytes());
+        hasher
+            .finalize()
+            .iter()
+            .map(|u| format!("{:02x}", u))
+            .collect()
+    }
+
+    /// Replace the recovery codes, the plain ones are returned to be shown only once.
+    pub fn gen_recovery_codes(&mut self) -> Vec<String> {
+        let mut rng = rand::thread_rng();
+        let mut gen = |len: usize| -> String {
+            (0..len)
+                .map(|_| RECOVERY_CODE_CHARS[rng.gen_range(0..RECOVERY_CODE_CHARS.len())] as char)
+                .collect()
+        };
+        self.recovery_salt = gen(16);
+        let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+            .map(|_| {
+                let code = gen(RECOVERY_CODE_LEN);
+                format!(
+                    "{}-{}",
+                    &code[..RECOVERY_CODE_LEN / 2],
+                    &code[RECOVERY_CODE_LEN / 2..]
+                )
+            })
+            .collect();
+        self.recovery_codes = codes.iter().map(|c| self.recovery_hash(c)).collect();
+        codes
+    }
+}
+
+fn parse(raw: &str) -> TwoFactor {
+    if raw.is_empty() {
+        return Default::default();
+    }
+    TwoFactor::from_str(raw).unwrap_or_else(|e| {
+        log::error!("Failed to parse 2fa: {}", e);
+        Default::default()
+    })
+}
+
+// The option of the service, the one of the ui may be stale.
+fn load_raw() -> String {
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    return crate::ipc::get_options()
+        .get("2fa")
+        .cloned()
+        .unwrap_or_default();
+    #[cfg(any(target_os = "android", target_os = "ios"))]
+    return Config::get_option("2fa");
+}
+
+fn load() -> TwoFactor {
+    parse(&load_raw())
+}
+
+/// Set option "2fa" if it is still `expected`, in the service.
+pub fn compare_and_set(expected: &str, value: String) -> bool {
+    let _lock = VERIFY_LOCK.lock().unwrap();
+    if Config::get_option("2fa") != expected {
+        return false;
+    }
+    Config::set_option("2fa".to_owned(), value);
+    true
+}
+
+// Apply `f` to the current 2fa of the service, again if a login or another ui changes it in
+// between. `f` returns false to keep it.
+fn update(mut f: impl FnMut(&mut TwoFactor) -> bool) -> bool {
+    for _ in 0..3 {
+        let raw = load_raw();
+        let mut tfa = parse(&raw);
+        if !f(&mut tfa) {
+            return false;
+        }
+        // no recovery without devices, 2fa is off
+        let v = if tfa.devices.is_empty() {
+            "".to_owned()
         } else {
-            bail!("decrypt_vec_or_original 2fa secret failed")
+            match tfa.into_string() {
+                Ok(v) => v,
+                Err(e) => {
+                    log::error!("Failed to serialize 2fa: {}", e);
+                    return false;
+                }
+            }
+        };
+        #[cfg(not(any(target_os = "android", target_os = "ios")))]
+        let res = match crate::ipc::compare_and_set_2fa(raw, v.clone()) {
+            Ok(res) => {
+                if res {
+                    Config::set_option("2fa".to_owned(), v);
+                }
+                res
+            }
+            Err(e) => {
+                log::error!("Failed to save 2fa: {}", e);
+                return false;
+            }
+        };
+        #[cfg(any(target_os = "android", target_os = "ios"))]
+        let res = compare_and_set(&raw, v);
+        if res {
+            return true;
         }
+        log::info!("2fa changed while updated, retry");
     }
+    false
 }
 
 pub fn generate2fa() -> String {
@@ -77,35 +336,192 @@ pub fn generate2fa() -> String {
     let id = crate::ipc::get_id();
     #[cfg(any(target_os = "android", target_os = "ios"))]
     let id = Config::get_id();
-    if let Ok(info) = TOTPInfo::gen_totp_info(id, 6) {
-        if let Ok(totp) = info.new_totp() {
-            let code = totp.get_url();
-            *CURRENT_2FA.lock().unwrap() = Some((info, totp));
-            return code;
-        }
+    generate2fa_device(id, "SHA1".to_owned(), 6, DEFAULT_PERIOD, false)
+}
+
+/// A new device to add once `verify2fa` accepts its code, returns its otpauth url.
+pub fn generate2fa_device(
+    name: String,
+    algorithm: String,
+    digits: usize,
+    period: u64,
+    hotp: bool,
+) -> String {
+    match TOTPInfo::gen_totp_info(name, digits, algorithm, period, hotp) {
+        Ok(info) => match info.new_totp() {
+            Ok(totp) => {
+                let url = info.url(&totp).unwrap_or_default();
+                *CURRENT_2FA.lock().unwrap() = Some((info, totp));
+                return url;
+            }
+            Err(e) => log::error!("Failed to create 2fa device: {}", e),
+        },
+        Err(e) => log::error!("Failed to generate 2fa device: {}", e),
     }
     "".to_owned()
 }
 
+/// Add the device of `generate2fa_device` if `code` is its current one, a device of the same
+/// name is replaced.
 pub fn verify2fa(code: String) -> bool {
-    if let Some((info, totp)) = CURRENT_2FA.lock().unwrap().as_ref() {
-        if let Ok(res) = totp.check_current(&code) {
-            if res {
-                if let Ok(v) = info.into_string() {
-                    #[cfg(not(any(target_os = "android", target_os = "ios")))]
-                    crate::ipc::set_option("2fa", &v);
-                    #[cfg(any(target_os = "android", target_os = "ios"))]
-                    Config::set_option("2fa".to_owned(), v);
-                    return res;
-                }
+    let Some((mut info, _)) = CURRENT_2FA.lock().unwrap().clone() else {
+        return false;
+    };
+    if !info.check(&code) {
+        return false;
+    }
+    let res = update(|tfa| {
+        tfa.devices.retain(|d| d.name != info.name);
+        tfa.devices.push(info.clone());
+        true
+    });
+    if res {
+        CURRENT_2FA.lock().unwrap().take();
+        return true;
+    }
+    false
+}
+
+pub fn list_2fa_devices() -> Vec<DeviceInfo> {
+    load()
+        .devices
+        .iter()
+        .map(|d| DeviceInfo {
+            name: d.name.clone(),
+            kind: d.kind().to_owned(),
+            algorithm: format!("{}", d.algorithm()),
+            digits: d.digits,
+            period: if d.hotp_counter.is_some() {
+                0
+            } else if d.period == 0 {
+                DEFAULT_PERIOD
+            } else {
+                d.period
+            },
+            created_at: d.created_at,
+        })
+        .collect()
+}
+
+/// Revoking the last device turns 2fa off.
+pub fn revoke_2fa_device(name: &str) -> bool {
+    update(|tfa| {
+        let len = tfa.devices.len();
+        tfa.devices.retain(|d| d.name != name);
+        tfa.devices.len() != len
+    })
+}
+
+/// Empty if 2fa is off.
+pub fn generate_2fa_recovery_codes() -> Vec<String> {
+    let mut codes = vec![];
+    let res = update(|tfa| {
+        if tfa.devices.is_empty() {
+            return false;
+        }
+        codes = tfa.gen_recovery_codes();
+        true
+    });
+    if res {
+        codes
+    } else {
+        vec![]
+    }
+}
+
+pub fn count_2fa_recovery_codes() -> usize {
+    load().recovery_codes.len()
+}
+
+pub fn get_2fa(raw: Option<String>) -> Option<TwoFactor> {
+    TwoFactor::from_str(&raw.unwrap_or(Config::get_option("2fa")))
+        .ok()
+        .filter(|tfa| !tfa.devices.is_empty())
+}
+
+/// Check the 2fa code of a login, the used HOTP counter or recovery code is saved, so it can not
+/// be used again. Returns the factor which accepts it. Called in the service, which has the
+/// option.
+pub fn verify_login(code: &str) -> Option<String> {
+    let _lock = VERIFY_LOCK.lock().unwrap();
+    let mut tfa = get_2fa(None)?;
+    let factor = tfa.verify(code)?;
+    let changed = factor == "recovery-code" || factor.starts_with("hotp:");
+    if changed {
+        match tfa.into_string() {
+            Ok(v) => Config::set_option("2fa".to_owned(), v),
+            Err(e) => {
+                log::error!("Failed to save 2fa: {}", e);
+                return None;
             }
         }
     }
-    false
+    Some(factor)
 }
 
-pub fn get_2fa(raw: Option<String>) -> Option<TOTP> {
-    TOTPInfo::from_str(&raw.unwrap_or(Config::get_option("2fa")))
-        .map(|x| Some(x))
-        .unwrap_or_default()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(name: &str, hotp: bool) -> TOTPInfo {
+        TOTPInfo::gen_totp_info(name.to_owned(), 6, "SHA256".to_owned(), 60, hotp).unwrap()
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut tfa = TwoFactor {
+            devices: vec![device("phone", false), device("key", true)],
+            ..Default::default()
+        };
+        let totp = tfa.devices[0].new_totp().unwrap();
+        let code = totp.generate_current().unwrap();
+        assert_eq!(tfa.verify(&code), Some("totp:phone".to_owned()));
+
+        let hotp = tfa.devices[1].new_totp().unwrap();
+        assert_eq!(tfa.verify(&hotp.generate(2)), Some("hotp:key".to_owned()));
+        assert_eq!(tfa.devices[1].hotp_counter, Some(3));
+        // used
+        assert_ne!(tfa.verify(&hotp.generate(2)), Some("hotp:key".to_owned()));
+        assert_eq!(tfa.verify(&hotp.generate(3)), Some("hotp:key".to_owned()));
+
+        let codes = tfa.gen_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert!(!tfa.recovery_codes.contains(&codes[0]));
+        assert_eq!(
+            tfa.verify(&codes[0].to_uppercase().replace('-', " ")),
+            Some("recovery-code".to_owned())
+        );
+        assert_eq!(tfa.verify(&codes[0]), None);
+        assert_eq!(tfa.recovery_codes.len(), RECOVERY_CODE_COUNT - 1);
+    }
+
+    #[test]
+    fn test_serialize() {
+        let mut tfa = TwoFactor {
+            devices: vec![device("phone", false)],
+            ..Default::default()
+        };
+        tfa.gen_recovery_codes();
+        let s = tfa.into_string().unwrap();
+        let tfa2 = TwoFactor::from_str(&s).unwrap();
+        assert_eq!(tfa2.devices[0].secret, tfa.devices[0].secret);
+        assert_eq!(tfa2.recovery_codes, tfa.recovery_codes);
+
+        // the option of the former versions
+        let old = TOTPInfo {
+            secret: encrypt_vec_or_original(&tfa.devices[0].secret, "00", 1024),
+            ..device("old", false)
+        };
+        let old = serde_json::json!({
+            "name": old.name,
+            "secret": old.secret,
+            "digits": old.digits,
+            "created_at": old.created_at,
+        });
+        let tfa3 = TwoFactor::from_str(&old.to_string()).unwrap();
+        assert_eq!(tfa3.devices.len(), 1);
+        assert_eq!(tfa3.devices[0].secret, tfa.devices[0].secret);
+        assert!(tfa3.devices[0].hotp_counter.is_none());
+        assert_eq!(tfa3.devices[0].new_totp().unwrap().step, DEFAULT_PERIOD);
+    }
 }
@@ -0,0 +1,243 @@
+use crate::server::access_rules::ConnKind;
+use hbb_common::{
+    bail,
+    config::{Config, LocalConfig},
+    log,
+    message_proto::{Hash, PublicKeyAuth},
+    password_security::{decrypt_vec_or_original, encrypt_vec_or_original},
+    sodiumoxide::crypto::sign,
+    ResultType,
+};
+use serde_derive::{Deserialize, Serialize};
+
+// json of `Vec<AuthorizedKey>`, on the controlled side
+pub const OPTION_AUTHORIZED_KEYS: &str = "authorized-keys";
+// the encrypted secret key, on the controlling side
+pub const OPTION_AUTH_KEY: &str = "auth-key";
+
+const SIGN_CONTEXT: &[u8] = b"rustdesk-public-key-auth";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthorizedKey {
+    #[serde(default)]
+    pub name: String,
+    // base64 of the ed25519 public key
+    pub key: String,
+    // empty for all connection types
+    #[serde(default)]
+    pub conn_type: Vec<ConnKind>,
+}
+
+impl AuthorizedKey {
+    fn allows(&self, conn_type: ConnKind) -> bool {
+        self.conn_type.is_empty() || self.conn_type.contains(&conn_type)
+    }
+}
+
+pub fn load_authorized_keys() -> Vec<AuthorizedKey> {
+    let v = Config::get_option(OPTION_AUTHORIZED_KEYS);
+    if v.is_empty() {
+        return vec![];
+    }
+    match serde_json::from_str(&v) {
+        Ok(keys) => keys,
+        Err(e) => {
+            log::error!("Invalid {}: {}", OPTION_AUTHORIZED_KEYS, e);
+            vec![]
+        }
+    }
+}
+
+/// What the controlling side signs: the id of the controlled side, its challenge, which is new
+/// for each connection, and the binding of the secure connection, so a signature can not be
+/// relayed to another peer or session.
+fn signed_data(id: &str, hash: &Hash, binding: &[u8]) -> Vec<u8> {
+    let mut data = SIGN_CONTEXT.to_vec();
+    for v in [
+        id.as_bytes(),
+        hash.salt.as_bytes(),
+        hash.challenge.as_bytes(),
+        binding,
+    ] {
+        data.push(0);
+        data.extend_from_slice(v);
+    }
+    data
+}
+
+/// Returns the base64 public key and secret key.
+pub fn gen_keypair() -> (String, String) {
+    let (pk, sk) = sign::gen_keypair();
+    (crate::encode64(pk.0), crate::encode64(&sk.0[..]))
+}
+
+fn parse_secret_key(v: &str) -> ResultType<sign::SecretKey> {
+    match sign::SecretKey::from_slice(&crate::decode64(v.trim())?) {
+        Some(sk) => Ok(sk),
+        None => bail!("Wrong secret key length"),
+    }
+}
+
+fn load_secret_key() -> Option<sign::SecretKey> {
+    let v = LocalConfig::get_option(OPTION_AUTH_KEY);
+    if v.is_empty() {
+        return None;
+    }
+    let (sk, success, _) = decrypt_vec_or_original(&crate::decode64(v).ok()?, "00");
+    if !success {
+        log::error!("Failed to decrypt the auth key");
+        return None;
+    }
+    sign::SecretKey::from_slice(&sk)
+}
+
+/// Use the base64 `secret_key` for the login of the outgoing connections, empty to remove it.
+pub fn set_auth_key(secret_key: &str) -> ResultType<()> {
+    if secret_key.is_empty() {
+        LocalConfig::set_option(OPTION_AUTH_KEY.to_owned(), "".to_owned());
+        return Ok(());
+    }
+    let sk = parse_secret_key(secret_key)?;
+    let v = encrypt_vec_or_original(&sk.0[..], "00", 1024);
+    LocalConfig::set_option(OPTION_AUTH_KEY.to_owned(), crate::encode64(v));
+    Ok(())
+}
+
+/// Generate and save a new key, returns its base64 public key for the authorized keys of peers.
+pub fn gen_auth_key() -> ResultType<String> {
+    let (pk, sk) = gen_keypair();
+    set_auth_key(&sk)?;
+    Ok(pk)
+}
+
+pub fn get_auth_public_key() -> String {
+    load_secret_key()
+        .map(|sk| crate::encode64(sk.public_key().0))
+        .unwrap_or_default()
+}
+
+/// Sign the challenge of `id` with the saved key, if any and the peer asks for it.
+pub fn sign_login(id: &str, hash: &Hash, binding: &[u8]) -> Option<PublicKeyAuth> {
+    if !hash.public_key_auth {
+        return None;
+    }
+    let sk = load_secret_key()?;
+    let signature = sign::sign_detached(&signed_data(id, hash, binding), &sk);
+    Some(PublicKeyAuth {
+        public_key: sk.public_key().0.to_vec().into(),
+        signature: signature.to_bytes().to_vec().into(),
+        ..Default::default()
+    })
+}
+
+pub enum Verified {
+    // the key is not authorized for this connection type
+    Unknown,
+    BadSignature,
+    // the name of the authorized key
+    Ok(String),
+}
+
+/// `id` is the id of this side, not the one the peer claims to connect to, so a peer which
+/// connects by the ip address can not log in with its key.
+pub fn verify_login(
+    keys: &[AuthorizedKey],
+    id: &str,
+    hash: &Hash,
+    binding: &[u8],
+    auth: &PublicKeyAuth,
+    conn_type: ConnKind,
+) -> Verified {
+    let Some(key) = keys.iter().find(|k| {
+        crate::decode64(k.key.trim()).map_or(false, |pk| pk[..] == auth.public_key[..])
+            && k.allows(conn_type)
+    }) else {
+        return Verified::Unknown;
+    };
+    let (Some(pk), Ok(signature)) = (
+        sign::PublicKey::from_slice(&auth.public_key),
+        sign::Signature::try_from(&auth.signature[..]),
+    ) else {
+        return Verified::BadSignature;
+    };
+    if sign::verify_detached(&signature, &signed_data(id, hash, binding), &pk) {
+        Verified::Ok(if key.name.is_empty() {
+            key.key.clone()
+        } else {
+            key.name.clone()
+        })
+    } else {
+        Verified::BadSignature
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_login() {
+        let (pk, sk) = gen_keypair();
+        let sk = parse_secret_key(&sk).unwrap();
+        let hash = Hash {
+            salt: "salt".to_owned(),
+            challenge: "challenge".to_owned(),
+            ..Default::default()
+        };
+        let binding = [7u8; 32];
+        let signature = sign::sign_detached(&signed_data("123456789", &hash, &binding), &sk);
+        let auth = PublicKeyAuth {
+            public_key: sk.public_key().0.to_vec().into(),
+            signature: signature.to_bytes().to_vec().into(),
+            ..Default::default()
+        };
+        let keys = vec![AuthorizedKey {
+            name: "tech".to_owned(),
+            key: pk,
+            conn_type: vec![ConnKind::Remote, ConnKind::FileTransfer],
+        }];
+        assert!(matches!(
+            verify_login(&keys, "123456789", &hash, &binding, &auth, ConnKind::Remote),
+            Verified::Ok(name) if name == "tech"
+        ));
+        assert!(matches!(
+            verify_login(
+                &keys,
+                "123456789",
+                &hash,
+                &binding,
+                &auth,
+                ConnKind::PortForward
+            ),
+            Verified::Unknown
+        ));
+        assert!(matches!(
+            verify_login(&keys, "987654321", &hash, &binding, &auth, ConnKind::Remote),
+            Verified::BadSignature
+        ));
+        let other = Hash {
+            challenge: "other".to_owned(),
+            ..hash.clone()
+        };
+        assert!(matches!(
+            verify_login(
+                &keys,
+                "123456789",
+                &other,
+                &binding,
+                &auth,
+                ConnKind::FileTransfer
+            ),
+            Verified::BadSignature
+        ));
+        // another session
+        assert!(matches!(
+            verify_login(&keys, "123456789", &hash, &[], &auth, ConnKind::Remote),
+            Verified::BadSignature
+        ));
+        assert!(matches!(
+            verify_login(&[], "123456789", &hash, &binding, &auth, ConnKind::Remote),
+            Verified::Unknown
+        ));
+    }
+}
@@ -45,10 +45,10 @@ use hbb_common::{
     socket_client,
     sodiumoxide::base64,
     sodiumoxide::crypto::sign,
-    tcp::FramedStream,
+    tcp::{self, FramedStream},
     timeout,
     tokio::time::Duration,
-    AddrMangle, ResultType, Stream,
+    websocket, AddrMangle, ResultType, Stream,
 };
 pub use helper::*;
 use scrap::{
@@ -60,7 +60,8 @@ use scrap::{
 use crate::{
     check_port,
     common::input::{MOUSE_BUTTON_LEFT, MOUSE_BUTTON_RIGHT, MOUSE_TYPE_DOWN, MOUSE_TYPE_UP},
-    create_symmetric_key_msg, decode_id_pk, get_rs_pk, is_keyboard_mode_supported, secure_tcp,
+    create_session_key_msg, create_symmetric_key_msg, decode_id_pk, decode_id_pk_version,
+    get_rs_pk, is_keyboard_mode_supported, secure_tcp,
     ui_session_interface::{InvokeUiSession, Session},
 };
 
@@ -81,6 +82,10 @@ pub const SEC30: Duration = Duration::from_secs(30);
 pub const VIDEO_QUEUE_SIZE: usize = 120;
 const MAX_DECODE_FAIL_COUNTER: usize = 10; // Currently, failed decode cause refresh_video, so make it small
 
+// the QUIC connection is tried first, the rest of the punch time is left to tcp
+#[cfg(feature = "quic")]
+const QUIC_CONNECT_TIMEOUT: u64 = 3_000;
+
 #[cfg(target_os = "linux")]
 pub const LOGIN_MSG_DESKTOP_NOT_INITED: &str = "Desktop env is not inited";
 pub const LOGIN_MSG_DESKTOP_SESSION_NOT_READY: &str = "Desktop session not ready";
@@ -122,6 +127,23 @@ pub(crate) struct ClientClipboardContext {
 /// Client of the remote desktop.
 pub struct Client;
 
+/// What a rendezvous server answered to our punch hole request.
+enum PunchHole {
+    Punched(PunchedHole),
+    /// The peer asked for a relay and the secured relay connection is ready.
+    Relayed(Stream, Option<Vec<u8>>),
+}
+
+struct PunchedHole {
+    my_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    peer_nat_type: NatType,
+    is_local: bool,
+    peer_quic: bool,
+    signed_id_pk: Vec<u8>,
+    relay_server: String,
+}
+
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 struct TextClipboardState {
     is_required: bool,
@@ -278,7 +300,7 @@ impl Client {
         } else {
             (peer, "", key, token)
         };
-        let (mut rendezvous_server, servers, contained) = if other_server.is_empty() {
+        let (first_server, servers, contained) = if other_server.is_empty() {
             crate::get_rendezvous_server(1_000).await
         } else {
             if other_server == PUBLIC_SERVER {
@@ -295,38 +317,125 @@ impl Client {
             }
         };
 
-        let mut socket = socket_client::connect_tcp(&*rendezvous_server, CONNECT_TIMEOUT).await;
-        debug_assert!(!servers.contains(&rendezvous_server));
-        if socket.is_err() && !servers.is_empty() {
-            log::info!("try the other servers: {:?}", servers);
-            for server in servers {
-                let server = check_port(server, RENDEZVOUS_PORT);
-                socket = socket_client::connect_tcp(&*server, CONNECT_TIMEOUT).await;
-                if socket.is_ok() {
-                    rendezvous_server = server;
-                    break;
-                }
+        // the other servers are in the order of their health, so fail over to them
+        // one after another if the punch hole breaks off half way
+        debug_assert!(!servers.contains(&first_server));
+        let mut candidates = vec![first_server.clone()];
+        candidates.extend(
+            servers
+                .into_iter()
+                .map(|server| check_port(server, RENDEZVOUS_PORT)),
+        );
+        let start = std::time::Instant::now();
+        let my_nat_type = crate::get_nat_type(100).await;
+        let mut punched = Err(anyhow!("No rendezvous server"));
+        let mut rendezvous_server = String::new();
+        for (i, server) in candidates.into_iter().enumerate() {
+            if i > 0 {
+                log::info!("fail over to rendezvous server {}", server);
             }
+            punched = Self::punch_hole(
+                &server,
+                peer,
+                key,
+                token,
+                conn_type,
+                my_nat_type,
+                start,
+                &interface,
+            )
+            .await;
+            rendezvous_server = server;
+            match &punched {
+                Ok(_) => break,
+                Err(err) => log::error!("punch hole via {} failed: {}", rendezvous_server, err),
+            }
+        }
+        if punched.is_err() || !contained || rendezvous_server != first_server {
             crate::refresh_rendezvous_server();
-        } else if !contained {
-            crate::refresh_rendezvous_server();
         }
+        let PunchedHole {
+            my_addr,
+            peer_addr,
+            peer_nat_type,
+            is_local,
+            peer_quic,
+            signed_id_pk,
+            mut relay_server,
+        } = match punched? {
+            PunchHole::Punched(punched) => punched,
+            PunchHole::Relayed(conn, pk) => return Ok((conn, false, pk)),
+        };
+        relay_server = Self::ws_relay_server(&rendezvous_server, relay_server).await;
+        let time_used = start.elapsed().as_millis() as u64;
+        log::info!(
+            "{} ms used to punch hole, relay_server: {}, {}",
+            time_used,
+            relay_server,
+            if is_local {
+                "is_local: true".to_owned()
+            } else {
+                format!("nat_type: {:?}", peer_nat_type)
+            }
+        );
+        Self::connect(
+            my_addr,
+            peer_addr,
+            peer,
+            signed_id_pk,
+            &relay_server,
+            &rendezvous_server,
+            time_used,
+            peer_nat_type,
+            my_nat_type,
+            is_local,
+            peer_quic,
+            key,
+            token,
+            conn_type,
+            interface,
+        )
+        .await
+    }
+
+    /// The relay server handed out by a rendezvous server behind a websocket bridge
+    /// is usually the tcp one, so the configured websocket relay is used instead.
+    async fn ws_relay_server(rendezvous_server: &str, relay_server: String) -> String {
+        if websocket::is_ws_url(rendezvous_server) {
+            let custom_relay_server = crate::get_relay_server(1_000).await;
+            if !custom_relay_server.is_empty() {
+                return custom_relay_server;
+            }
+        }
+        relay_server
+    }
+
+    /// Ask one rendezvous server to punch a hole to the peer.
+    async fn punch_hole(
+        rendezvous_server: &str,
+        peer: &str,
+        key: &str,
+        token: &str,
+        conn_type: ConnType,
+        my_nat_type: i32,
+        start: std::time::Instant,
+        interface: &impl Interface,
+    ) -> ResultType<PunchHole> {
+        let mut socket = socket_client::connect_tcp(rendezvous_server, CONNECT_TIMEOUT).await?;
         log::info!("rendezvous server: {}", rendezvous_server);
-        let mut socket = socket?;
         let my_addr = socket.local_addr();
-        let mut signed_id_pk = Vec::new();
-        let mut relay_server = "".to_owned();
 
         if !key.is_empty() && !token.is_empty() {
             // mainly for the security of token
             allow_err!(secure_tcp(&mut socket, key).await);
         }
 
-        let start = std::time::Instant::now();
-        let mut peer_addr = Config::get_any_listen_addr(true);
-        let mut peer_nat_type = NatType::UNKNOWN_NAT;
-        let my_nat_type = crate::get_nat_type(100).await;
-        let mut is_local = false;
+        let is_websocket = websocket::is_ws_url(rendezvous_server);
+        #[cfg(feature = "quic")]
+        let quic_enabled =
+            hbb_common::quic::is_enabled() && !interface.is_force_relay() && !is_websocket;
+        #[cfg(not(feature = "quic"))]
+        let quic_enabled = false;
         for i in 1..=3 {
             log::info!("#{} punch attempt with {}, id: {}", i, my_addr, peer);
             let mut msg_out = RendezvousMessage::new();
@@ -342,6 +451,7 @@ impl Client {
                 nat_type: nat_type.into(),
                 licence_key: key.to_owned(),
                 conn_type: conn_type.into(),
+                quic: quic_enabled,
                 ..Default::default()
             });
             socket.send(&msg_out).await?;
@@ -370,13 +480,17 @@ impl Client {
                                 _ => bail!("other punch hole failure"),
                             }
                         } else {
-                            peer_nat_type = ph.nat_type();
-                            is_local = ph.is_local();
-                            signed_id_pk = ph.pk.into();
-                            relay_server = ph.relay_server;
-                            peer_addr = AddrMangle::decode(&ph.socket_addr);
+                            let peer_addr = AddrMangle::decode(&ph.socket_addr);
                             log::info!("Hole Punched {} = {}", peer, peer_addr);
-                            break;
+                            return Ok(PunchHole::Punched(PunchedHole {
+                                my_addr,
+                                peer_addr,
+                                peer_nat_type: ph.nat_type(),
+                                is_local: ph.is_local(),
+                                peer_quic: quic_enabled && ph.quic,
+                                signed_id_pk: ph.pk.into(),
+                                relay_server: ph.relay_server,
+                            }));
                         }
                     }
                     Some(rendezvous_message::Union::RelayResponse(rr)) => {
@@ -385,11 +499,13 @@ impl Client {
                             start.elapsed(),
                             rr.relay_server
                         );
-                        signed_id_pk = rr.pk().into();
+                        let signed_id_pk = rr.pk().into();
+                        let relay_server =
+                            Self::ws_relay_server(rendezvous_server, rr.relay_server).await;
                         let mut conn = Self::create_relay(
                             peer,
                             rr.uuid,
-                            rr.relay_server,
+                            relay_server,
                             key,
                             conn_type,
                             my_addr.is_ipv4(),
@@ -397,7 +513,7 @@ impl Client {
                         .await?;
                         let pk =
                             Self::secure_connection(peer, signed_id_pk, key, &mut conn).await?;
-                        return Ok((conn, false, pk));
+                        return Ok(PunchHole::Relayed(conn, pk));
                     }
                     _ => {
                         log::error!("Unexpected protobuf msg received: {:?}", msg_in);
@@ -405,38 +521,7 @@ impl Client {
                 }
             }
         }
-        drop(socket);
-        if peer_addr.port() == 0 {
-            bail!("Failed to connect via rendezvous server");
-        }
-        let time_used = start.elapsed().as_millis() as u64;
-        log::info!(
-            "{} ms used to punch hole, relay_server: {}, {}",
-            time_used,
-            relay_server,
-            if is_local {
-                "is_local: true".to_owned()
-            } else {
-                format!("nat_type: {:?}", peer_nat_type)
-            }
-        );
-        Self::connect(
-            my_addr,
-            peer_addr,
-            peer,
-            signed_id_pk,
-            &relay_server,
-            &rendezvous_server,
-            time_used,
-            peer_nat_type,
-            my_nat_type,
-            is_local,
-            key,
-            token,
-            conn_type,
-            interface,
-        )
-        .await
+        bail!("Failed to connect via rendezvous server");
     }
 
     /// Connect to the peer.
@@ -451,6 +536,7 @@ impl Client {
         peer_nat_type: NatType,
         my_nat_type: i32,
         is_local: bool,
+        peer_quic: bool,
         key: &str,
         token: &str,
         conn_type: ConnType,
@@ -491,8 +577,17 @@ impl Client {
         log::info!("peer address: {}, timeout: {}", peer, connect_timeout);
         let start = std::time::Instant::now();
         // NOTICE: Socks5 is be used event in intranet. Which may be not a good way.
-        let mut conn =
-            socket_client::connect_tcp_local(peer, Some(local_addr), connect_timeout).await;
+        let mut conn = if peer_quic {
+            match Self::connect_quic(local_addr, peer, connect_timeout).await {
+                Ok(conn) => Ok(conn),
+                Err(err) => {
+                    log::info!("Failed to connect with QUIC, fall back to tcp: {}", err);
+                    socket_client::connect_tcp_local(peer, Some(local_addr), connect_timeout).await
+                }
+            }
+        } else {
+            socket_client::connect_tcp_local(peer, Some(local_addr), connect_timeout).await
+        };
         let mut direct = !conn.is_err();
         interface.update_direct(Some(direct));
         if interface.is_force_relay() || conn.is_err() {
@@ -527,6 +622,24 @@ impl Client {
         Ok((conn, direct, pk))
     }
 
+    /// Connect to the peer with QUIC from the port of the punch hole request.
+    #[cfg(feature = "quic")]
+    async fn connect_quic(
+        local_addr: SocketAddr,
+        peer: SocketAddr,
+        connect_timeout: u64,
+    ) -> ResultType<Stream> {
+        let socket = hbb_common::quic::new_socket(local_addr)?;
+        hbb_common::quic::punch(&socket, peer);
+        hbb_common::quic::connect(socket, peer, connect_timeout.min(QUIC_CONNECT_TIMEOUT)).await
+    }
+
+    // `peer_quic` is never set without QUIC
+    #[cfg(not(feature = "quic"))]
+    async fn connect_quic(_: SocketAddr, _: SocketAddr, _: u64) -> ResultType<Stream> {
+        bail!("QUIC is not enabled in this build");
+    }
+
     /// Establish secure connection with the server.
     async fn secure_connection(
         peer_id: &str,
@@ -567,18 +680,29 @@ impl Client {
                 let bytes = res?;
                 if let Ok(msg_in) = Message::parse_from_bytes(&bytes) {
                     if let Some(message::Union::SignedId(si)) = msg_in.union {
-                        if let Ok((id, their_pk_b)) = decode_id_pk(&si.id, &sign_pk) {
+                        if let Ok((id, their_pk_b, key_version)) =
+                            decode_id_pk_version(&si.id, &sign_pk)
+                        {
                             if id == peer_id {
-                                let (asymmetric_value, symmetric_value, key) =
-                                    create_symmetric_key_msg(their_pk_b);
+                                let key_version = key_version.min(tcp::KEY_VERSION);
+                                let (asymmetric_value, symmetric_value, key) = if key_version >= 2 {
+                                    create_session_key_msg(their_pk_b)
+                                } else {
+                                    create_symmetric_key_msg(their_pk_b)
+                                };
                                 let mut msg_out = Message::new();
                                 msg_out.set_public_key(PublicKey {
                                     asymmetric_value,
                                     symmetric_value,
+                                    key_version,
                                     ..Default::default()
                                 });
                                 timeout(CONNECT_TIMEOUT, conn.send(&msg_out)).await??;
-                                conn.set_key(key);
+                                if key_version >= 2 {
+                                    conn.set_session_key(key, true);
+                                } else {
+                                    conn.set_key(key);
+                                }
                             } else {
                                 log::error!("Handshake failed: sign failure");
                                 conn.send(&Message::new()).await?;
@@ -1105,8 +1229,15 @@ impl VideoHandler {
         self.fail_counter = 0;
     }
 
-    /// Start or stop screen record.
-    pub fn record_screen(&mut self, start: bool, w: i32, h: i32, id: String) {
+    /// Start or stop screen record, with the audio of the session if `audio` is Some.
+    pub fn record_screen(
+        &mut self,
+        start: bool,
+        w: i32,
+        h: i32,
+        id: String,
+        audio: Option<AudioFormat>,
+    ) {
         self.record = false;
         if start {
             self.recorder = Recorder::new(RecorderContext {
@@ -1117,6 +1248,7 @@ impl VideoHandler {
                 width: w as _,
                 height: h as _,
                 format: scrap::CodecFormat::VP9,
+                audio,
                 tx: None,
             })
             .map_or(Default::default(), |r| Arc::new(Mutex::new(Some(r))));
@@ -1126,6 +1258,15 @@ impl VideoHandler {
 
         self.record = start;
     }
+
+    /// Write the audio message of the session to the record.
+    pub fn record_audio(&mut self, msg: &Message) {
+        if self.record {
+            if let Some(r) = self.recorder.lock().unwrap().as_mut() {
+                r.write_message(msg);
+            }
+        }
+    }
 }
 
 // The source of sent password
@@ -1185,6 +1326,7 @@ pub struct LoginConfigHandler {
     pub remember: bool,
     config: PeerConfig,
     pub port_forward: (String, i32),
+    pub port_forward_multiplex: bool,
     pub version: i64,
     features: Option<Features>,
     pub session_id: u64, // used for local <-> server communication
@@ -1203,6 +1345,7 @@ pub struct LoginConfigHandler {
     pub peer_info: Option<PeerInfo>,
     password_source: PasswordSource, // where the sent password comes from
     shared_password: Option<String>, // Store the shared password
+    session_binding: Vec<u8>,        // of the secure connection, signed by the public key auth
 }
 
 impl Deref for LoginConfigHandler {
@@ -1998,6 +2141,8 @@ impl LoginConfigHandler {
         } else {
             (my_id, self.id.clone())
         };
+        let public_key_auth =
+            crate::auth_pk::sign_login(&pure_id, &self.hash, &self.session_binding);
         let mut lr = LoginRequest {
             username: pure_id,
             password: password.into(),
@@ -2012,6 +2157,7 @@ impl LoginConfigHandler {
                 ..Default::default()
             })
             .into(),
+            public_key_auth: public_key_auth.into(),
             ..Default::default()
         };
         match self.conn_type {
@@ -2023,6 +2169,7 @@ impl LoginConfigHandler {
             ConnType::PORT_FORWARD | ConnType::RDP => lr.set_port_forward(PortForward {
                 host: self.port_forward.0.clone(),
                 port: self.port_forward.1,
+                multiplex: self.port_forward_multiplex,
                 ..Default::default()
             }),
             _ => {}
@@ -2108,6 +2255,8 @@ where
     let chroma = Arc::new(RwLock::new(None));
     let chroma_cloned = chroma.clone();
     let mut last_chroma = None;
+    // the audio of the session, for the records
+    let mut audio_format: Option<AudioFormat> = None;
 
     std::thread::spawn(move || {
         #[cfg(windows)]
@@ -2250,16 +2399,38 @@ where
                         // Compatible with the sciter version(single ui session).
                         // For the sciter version, there're no multi-ui-sessions for one connection.
                         // The display is always 0, video_handler_controllers.len() is always 1. So we use the first video handler.
+                        let audio = audio_format.clone();
                         if let Some(handler_controler) = handler_controller_map.get_mut(&display) {
-                            handler_controler.handler.record_screen(start, w, h, id);
+                            handler_controler
+                                .handler
+                                .record_screen(start, w, h, id, audio);
                         } else if handler_controller_map.len() == 1 {
                             if let Some(handler_controler) =
                                 handler_controller_map.values_mut().next()
                             {
-                                handler_controler.handler.record_screen(start, w, h, id);
+                                handler_controler
+                                    .handler
+                                    .record_screen(start, w, h, id, audio);
                             }
                         }
                     }
+                    MediaData::AudioFrame(af) => {
+                        let mut msg = Message::new();
+                        msg.set_audio_frame(*af);
+                        for h in handler_controller_map.values_mut() {
+                            h.handler.record_audio(&msg);
+                        }
+                    }
+                    MediaData::AudioFormat(f) => {
+                        let mut misc = Misc::new();
+                        misc.set_audio_format(f.clone());
+                        let mut msg = Message::new();
+                        msg.set_misc(misc);
+                        for h in handler_controller_map.values_mut() {
+                            h.handler.record_audio(&msg);
+                        }
+                        audio_format = Some(f);
+                    }
                     _ => {}
                 }
             } else {
@@ -2664,6 +2835,7 @@ pub async fn handle_hash(
     peer: &mut Stream,
 ) {
     lc.write().unwrap().hash = hash.clone();
+    lc.write().unwrap().session_binding = peer.session_binding();
     // Take care of password application order
 
     // switch_uuid
@@ -2714,8 +2886,11 @@ pub async fn handle_hash(
     }
     lc.write().unwrap().password = password.clone();
     let password = if password.is_empty() {
-        // login without password, the remote side can click accept
-        interface.msgbox("input-password", "Password Required", "", "");
+        // login without password, the remote side can click accept,
+        // or the peer asks for the password if the key is not authorized
+        if !hash.public_key_auth || crate::auth_pk::get_auth_public_key().is_empty() {
+            interface.msgbox("input-password", "Password Required", "", "");
+        }
         Vec::new()
     } else {
         let mut hasher = Sha256::new();
@@ -2924,7 +3099,8 @@ pub enum Data {
     Close,
     Login((String, String, String, bool)),
     Message(Message),
-    SendFiles((i32, String, String, i32, bool, bool)),
+    // id, path, to, file_num, include_hidden, is_remote, preserve_attributes
+    SendFiles((i32, String, String, i32, bool, bool, bool)),
     RemoveDirAll((i32, String, bool, bool)),
     ConfirmDeleteFiles((i32, i32)),
     SetNoConfirm(i32),
@@ -2934,12 +3110,18 @@ pub enum Data {
     CancelJob(i32),
     RemovePortForward(i32),
     AddPortForward((i32, String, i32)),
+    StartPortForwardProfile(String),
+    StopPortForwardProfile(String),
     #[cfg(not(feature = "flutter"))]
     ToggleClipboardFile,
     NewRDP,
     SetConfirmOverrideFile((i32, i32, bool, bool, bool)),
-    AddJob((i32, String, String, i32, bool, bool)),
+    AddJob((i32, String, String, i32, bool, bool, bool)),
     ResumeJob((i32, bool)),
+    // id, path, to, include_hidden, is_remote
+    MirrorDir((i32, String, String, bool, bool)),
+    // id, delete_extraneous
+    StartMirror((i32, bool)),
     RecordScreen(bool, usize, i32, i32, String),
     ElevateDirect,
     ElevateWithLogon(String, String),
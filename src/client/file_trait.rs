@@ -73,6 +73,7 @@ pub trait FileManager: Interface {
         self.send(Data::CreateDir((id, path, is_remote)));
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn send_files(
         &self,
         id: i32,
@@ -81,6 +82,7 @@ pub trait FileManager: Interface {
         file_num: i32,
         include_hidden: bool,
         is_remote: bool,
+        preserve_attributes: bool,
     ) {
         self.send(Data::SendFiles((
             id,
@@ -89,9 +91,11 @@ pub trait FileManager: Interface {
             file_num,
             include_hidden,
             is_remote,
+            preserve_attributes,
         )));
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn add_job(
         &self,
         id: i32,
@@ -100,6 +104,7 @@ pub trait FileManager: Interface {
         file_num: i32,
         include_hidden: bool,
         is_remote: bool,
+        preserve_attributes: bool,
     ) {
         self.send(Data::AddJob((
             id,
@@ -108,6 +113,7 @@ pub trait FileManager: Interface {
             file_num,
             include_hidden,
             is_remote,
+            preserve_attributes,
         )));
     }
 
@@ -115,6 +121,17 @@ pub trait FileManager: Interface {
         self.send(Data::ResumeJob((id, is_remote)));
     }
 
+    /// Compare `path` with `to` and report the plan, the mirror starts after `start_mirror`.
+    fn mirror_dir(&self, id: i32, path: String, to: String, include_hidden: bool, is_remote: bool) {
+        self.send(Data::MirrorDir((id, path, to, include_hidden, is_remote)));
+    }
+
+    /// Run the plan the user confirmed, the extraneous files of the target are deleted after
+    /// the transfer only if `delete_extraneous` is set.
+    fn start_mirror(&self, id: i32, delete_extraneous: bool) {
+        self.send(Data::StartMirror((id, delete_extraneous)));
+    }
+
     fn set_confirm_override_file(
         &self,
         id: i32,
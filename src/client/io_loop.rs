@@ -60,7 +60,11 @@ pub struct Remote<T: InvokeUiSession> {
     voice_call_request_timestamp: Option<NonZeroI64>,
     read_jobs: Vec<fs::TransferJob>,
     write_jobs: Vec<fs::TransferJob>,
+    // uploads waiting for the peer's done, to retry the files with mismatched hash,
+    // with the times they are retried
+    upload_jobs: HashMap<i32, (fs::TransferJobMeta, usize)>,
     remove_jobs: HashMap<i32, RemoveJob>,
+    mirror_jobs: HashMap<i32, MirrorJob>,
     timer: crate::RustDeskInterval,
     last_update_jobs_status: (Instant, HashMap<i32, u64>),
     is_connected: bool,
@@ -74,6 +78,8 @@ pub struct Remote<T: InvokeUiSession> {
     fps_control_map: HashMap<usize, FpsControl>,
     decode_fps_map: Arc<RwLock<HashMap<usize, usize>>>,
     chroma: Arc<RwLock<Option<Chroma>>>,
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    session_recorder: Option<scrap::session_record::SessionRecorder>,
 }
 
 impl<T: InvokeUiSession> Remote<T> {
@@ -97,7 +103,9 @@ impl<T: InvokeUiSession> Remote<T> {
             sender,
             read_jobs: Vec::new(),
             write_jobs: Vec::new(),
+            upload_jobs: Default::default(),
             remove_jobs: Default::default(),
+            mirror_jobs: Default::default(),
             timer: crate::rustdesk_interval(time::interval(SEC30)),
             last_update_jobs_status: (Instant::now(), Default::default()),
             is_connected: false,
@@ -113,6 +121,8 @@ impl<T: InvokeUiSession> Remote<T> {
             fps_control_map: Default::default(),
             decode_fps_map: decode_fps,
             chroma,
+            #[cfg(not(any(target_os = "android", target_os = "ios")))]
+            session_recorder: None,
         }
     }
 
@@ -267,6 +277,10 @@ impl<T: InvokeUiSession> Remote<T> {
                     }
                 }
                 log::debug!("Exit io_loop of id={}", self.handler.get_id());
+                // the done messages of the unfinished jobs never come
+                for job in self.write_jobs.iter_mut() {
+                    job.rename_written_files();
+                }
                 // Stop client audio server.
                 if let Some(s) = self.stop_voice_call_sender.take() {
                     s.send(()).ok();
@@ -348,6 +362,248 @@ impl<T: InvokeUiSession> Remote<T> {
         }
     }
 
+    // The peer found the hash of an uploaded file mismatched, send the file once more, up to
+    // `fs::MAX_HASH_RETRIES` times. The other files of the job are skipped by the overwrite
+    // detection.
+    async fn retry_upload(&mut self, id: i32, file_num: i32, peer: &mut Stream) -> bool {
+        if let Some(job) = fs::get_job(id, &mut self.read_jobs) {
+            // retry is started by a previous file of the same job
+            job.add_retry_file(file_num);
+            return true;
+        }
+        let meta = match self.upload_jobs.get_mut(&id) {
+            Some((meta, retries)) if *retries < fs::MAX_HASH_RETRIES => {
+                *retries += 1;
+                meta.clone()
+            }
+            _ => return false,
+        };
+        let od = can_enable_overwrite_detection(self.handler.lc.read().unwrap().version);
+        match fs::TransferJob::new_read(
+            id,
+            meta.remote.clone(),
+            meta.to,
+            file_num,
+            meta.show_hidden,
+            meta.is_remote,
+            od,
+        )
+        .and_then(|mut job| {
+            if meta.preserve_attributes {
+                job.load_attributes()?;
+            }
+            Ok(job)
+        }) {
+            Err(err) => {
+                log::error!("Failed to retry job {}: {}", id, err);
+                false
+            }
+            Ok(mut job) => {
+                log::info!("Retry job {} from file {}", id, file_num);
+                job.add_retry_file(file_num);
+                #[cfg(not(windows))]
+                let files = job.files().clone();
+                #[cfg(windows)]
+                let mut files = job.files().clone();
+                #[cfg(windows)]
+                if self.handler.peer_platform() != "Windows" {
+                    fs::transform_windows_path(&mut files);
+                }
+                let total_size = job.total_size();
+                self.read_jobs.push(job);
+                self.timer = crate::rustdesk_interval(time::interval(MILLI1));
+                allow_err!(
+                    peer.send(&fs::new_receive(
+                        id,
+                        meta.remote,
+                        file_num,
+                        files,
+                        total_size,
+                        meta.preserve_attributes,
+                    ))
+                    .await
+                );
+                true
+            }
+        }
+    }
+
+    // Transfer the whole source dir, the overwrite detection skips the files not changed.
+    async fn mirror_files(
+        &mut self,
+        id: i32,
+        path: String,
+        to: String,
+        include_hidden: bool,
+        is_remote: bool,
+        peer: &mut Stream,
+    ) {
+        let od = can_enable_overwrite_detection(self.handler.lc.read().unwrap().version);
+        if is_remote {
+            log::debug!(
+                "New mirror job {}, write to {} from remote {}",
+                id,
+                to,
+                path
+            );
+            let mut job = fs::TransferJob::new_write(
+                id,
+                path.clone(),
+                to,
+                0,
+                include_hidden,
+                is_remote,
+                Vec::new(),
+                od,
+            );
+            job.set_mirror(true);
+            self.write_jobs.push(job);
+            allow_err!(
+                peer.send(&fs::new_send(id, path, 0, include_hidden, false))
+                    .await
+            );
+        } else {
+            match fs::TransferJob::new_read(
+                id,
+                to.clone(),
+                path.clone(),
+                0,
+                include_hidden,
+                is_remote,
+                od,
+            ) {
+                Err(err) => {
+                    self.mirror_jobs.remove(&id);
+                    self.handle_job_status(id, -1, Some(err.to_string()));
+                }
+                Ok(mut job) => {
+                    log::debug!(
+                        "New mirror job {}, read {} to remote {}, {} files",
+                        id,
+                        path,
+                        to,
+                        job.files().len()
+                    );
+                    job.set_mirror(true);
+                    self.handler
+                        .update_folder_files(job.id(), job.files(), path, !is_remote, true);
+                    #[cfg(not(windows))]
+                    let files = job.files().clone();
+                    #[cfg(windows)]
+                    let mut files = job.files().clone();
+                    #[cfg(windows)]
+                    if self.handler.peer_platform() != "Windows" {
+                        fs::transform_windows_path(&mut files);
+                    }
+                    let total_size = job.total_size();
+                    self.upload_jobs.insert(id, (job.gen_meta(), 0));
+                    self.read_jobs.push(job);
+                    self.timer = crate::rustdesk_interval(time::interval(MILLI1));
+                    allow_err!(
+                        peer.send(&fs::new_receive(id, to, 0, files, total_size, false))
+                            .await
+                    );
+                }
+            }
+        }
+    }
+
+    // Delete the extraneous files of the target once the files are mirrored.
+    // Returns false if `id` is not a started mirror job or the mirror failed.
+    async fn handle_mirror_status(
+        &mut self,
+        id: i32,
+        file_num: i32,
+        err: &Option<String>,
+        peer: &mut Stream,
+    ) -> bool {
+        let job = match self.mirror_jobs.get_mut(&id) {
+            Some(job) if job.started => job,
+            _ => return false,
+        };
+        if job.pending_deletes > 0 {
+            job.pending_deletes -= 1;
+            if let Some(err) = err {
+                log::warn!("Failed to delete extraneous file of mirror {}: {}", id, err);
+                job.err.get_or_insert(err.clone());
+            }
+            if job.pending_deletes > 0 {
+                return true;
+            }
+        } else {
+            if err.is_some() {
+                self.mirror_jobs.remove(&id);
+                return false;
+            }
+            job.file_num = file_num;
+            let deletes = job
+                .plan
+                .as_ref()
+                .map(|plan| plan.deletes.clone())
+                .unwrap_or_default();
+            let sep = self.handler.get_path_sep(!job.is_remote);
+            if job.is_remote {
+                for entry in deletes.iter() {
+                    let path = format!("{}{}{}", job.to, sep, entry.name);
+                    if let Err(err) = fs::remove_file(&path) {
+                        log::warn!("Failed to delete extraneous file {}: {}", path, err);
+                        job.err.get_or_insert(err.to_string());
+                    }
+                }
+            } else if !deletes.is_empty() {
+                job.pending_deletes = deletes.len();
+                for (i, entry) in deletes.iter().enumerate() {
+                    let mut msg_out = Message::new();
+                    let mut file_action = FileAction::new();
+                    file_action.set_remove_file(FileRemoveFile {
+                        id,
+                        path: format!("{}{}{}", job.to, sep, entry.name),
+                        file_num: i as _,
+                        ..Default::default()
+                    });
+                    msg_out.set_file_action(file_action);
+                    allow_err!(peer.send(&msg_out).await);
+                }
+                return true;
+            }
+        }
+        if let Some(job) = self.mirror_jobs.remove(&id) {
+            self.handle_job_status(id, job.file_num, job.err);
+        }
+        true
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    fn record_session(&mut self, start: bool, id: &str) {
+        if !start {
+            self.session_recorder.take();
+            return;
+        }
+        if self.session_recorder.is_some()
+            || hbb_common::config::Config::get_option("record-session-stream").is_empty()
+        {
+            return;
+        }
+        match scrap::session_record::SessionRecorder::new(
+            false,
+            id,
+            &crate::ui_interface::video_save_directory(false),
+        ) {
+            Ok(recorder) => self.session_recorder = Some(recorder),
+            Err(e) => log::error!("Failed to create session recorder: {}", e),
+        }
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    fn record_session_message(&mut self, msg: &Message) {
+        if let Some(recorder) = self.session_recorder.as_mut() {
+            if let Err(e) = recorder.write_message(msg) {
+                log::error!("Failed to record session message: {}", e);
+                self.session_recorder = None;
+            }
+        }
+    }
+
     fn handle_job_status(&mut self, id: i32, file_num: i32, err: Option<String>) {
         if let Some(job) = self.remove_jobs.get_mut(&id) {
             if job.no_confirm {
@@ -477,14 +733,26 @@ impl<T: InvokeUiSession> Remote<T> {
                 self.check_clipboard_file_context();
             }
             Data::Message(msg) => {
+                #[cfg(not(any(target_os = "android", target_os = "ios")))]
+                if scrap::session_record::is_input_or_chat(&msg) {
+                    self.record_session_message(&msg);
+                }
                 allow_err!(peer.send(&msg).await);
             }
-            Data::SendFiles((id, path, to, file_num, include_hidden, is_remote)) => {
+            Data::SendFiles((
+                id,
+                path,
+                to,
+                file_num,
+                include_hidden,
+                is_remote,
+                preserve_attributes,
+            )) => {
                 log::info!("send files, is remote {}", is_remote);
                 let od = can_enable_overwrite_detection(self.handler.lc.read().unwrap().version);
                 if is_remote {
                     log::debug!("New job {}, write to {} from remote {}", id, to, path);
-                    self.write_jobs.push(fs::TransferJob::new_write(
+                    let mut job = fs::TransferJob::new_write(
                         id,
                         path.clone(),
                         to,
@@ -493,10 +761,18 @@ impl<T: InvokeUiSession> Remote<T> {
                         is_remote,
                         Vec::new(),
                         od,
-                    ));
+                    );
+                    job.set_preserve_attributes(preserve_attributes);
+                    self.write_jobs.push(job);
                     allow_err!(
-                        peer.send(&fs::new_send(id, path, file_num, include_hidden))
-                            .await
+                        peer.send(&fs::new_send(
+                            id,
+                            path,
+                            file_num,
+                            include_hidden,
+                            preserve_attributes
+                        ))
+                        .await
                     );
                 } else {
                     match fs::TransferJob::new_read(
@@ -507,7 +783,13 @@ impl<T: InvokeUiSession> Remote<T> {
                         include_hidden,
                         is_remote,
                         od,
-                    ) {
+                    )
+                    .and_then(|mut job| {
+                        if preserve_attributes {
+                            job.load_attributes()?;
+                        }
+                        Ok(job)
+                    }) {
                         Err(err) => {
                             self.handle_job_status(id, -1, Some(err.to_string()));
                         }
@@ -536,17 +818,33 @@ impl<T: InvokeUiSession> Remote<T> {
                                 fs::transform_windows_path(&mut files);
                             }
                             let total_size = job.total_size();
+                            self.upload_jobs.insert(id, (job.gen_meta(), 0));
                             self.read_jobs.push(job);
                             self.timer = crate::rustdesk_interval(time::interval(MILLI1));
                             allow_err!(
-                                peer.send(&fs::new_receive(id, to, file_num, files, total_size))
-                                    .await
+                                peer.send(&fs::new_receive(
+                                    id,
+                                    to,
+                                    file_num,
+                                    files,
+                                    total_size,
+                                    preserve_attributes
+                                ))
+                                .await
                             );
                         }
                     }
                 }
             }
-            Data::AddJob((id, path, to, file_num, include_hidden, is_remote)) => {
+            Data::AddJob((
+                id,
+                path,
+                to,
+                file_num,
+                include_hidden,
+                is_remote,
+                preserve_attributes,
+            )) => {
                 let od = can_enable_overwrite_detection(self.handler.lc.read().unwrap().version);
                 if is_remote {
                     log::debug!(
@@ -565,6 +863,7 @@ impl<T: InvokeUiSession> Remote<T> {
                         Vec::new(),
                         od,
                     );
+                    job.set_preserve_attributes(preserve_attributes);
                     job.is_last_job = true;
                     self.write_jobs.push(job);
                 } else {
@@ -576,7 +875,13 @@ impl<T: InvokeUiSession> Remote<T> {
                         include_hidden,
                         is_remote,
                         od,
-                    ) {
+                    )
+                    .and_then(|mut job| {
+                        if preserve_attributes {
+                            job.load_attributes()?;
+                        }
+                        Ok(job)
+                    }) {
                         Err(err) => {
                             self.handle_job_status(id, -1, Some(err.to_string()));
                         }
@@ -596,6 +901,7 @@ impl<T: InvokeUiSession> Remote<T> {
                                 true,
                             );
                             job.is_last_job = true;
+                            self.upload_jobs.insert(id, (job.gen_meta(), 0));
                             self.read_jobs.push(job);
                             self.timer = crate::rustdesk_interval(time::interval(MILLI1));
                         }
@@ -611,7 +917,8 @@ impl<T: InvokeUiSession> Remote<T> {
                                 id,
                                 job.remote.clone(),
                                 job.file_num,
-                                job.show_hidden
+                                job.show_hidden,
+                                job.preserve_attributes(),
                             ))
                             .await
                         );
@@ -626,12 +933,67 @@ impl<T: InvokeUiSession> Remote<T> {
                                 job.file_num,
                                 job.files.clone(),
                                 job.total_size(),
+                                job.preserve_attributes(),
                             ))
                             .await
                         );
                     }
                 }
             }
+            Data::MirrorDir((id, path, to, include_hidden, is_remote)) => {
+                log::info!("mirror dir, is remote {}", is_remote);
+                // the missing target is the same as an empty one
+                let (local, remote) = if is_remote {
+                    (&to, &path)
+                } else {
+                    (&path, &to)
+                };
+                let local_files = match fs::get_recursive_files(local, include_hidden) {
+                    Ok(files) => files,
+                    Err(_) if is_remote => Vec::new(),
+                    Err(err) => {
+                        self.handle_job_status(id, -1, Some(err.to_string()));
+                        return true;
+                    }
+                };
+                let mut msg_out = Message::new();
+                let mut file_action = FileAction::new();
+                file_action.set_all_files(ReadAllFiles {
+                    id,
+                    path: remote.clone(),
+                    include_hidden,
+                    ..Default::default()
+                });
+                msg_out.set_file_action(file_action);
+                allow_err!(peer.send(&msg_out).await);
+                self.mirror_jobs.insert(
+                    id,
+                    MirrorJob::new(path, to, include_hidden, is_remote, local_files),
+                );
+            }
+            Data::StartMirror((id, delete_extraneous)) => {
+                if let Some(job) = self.mirror_jobs.get_mut(&id) {
+                    let Some(plan) = job.plan.as_mut() else {
+                        return true;
+                    };
+                    if job.started {
+                        return true;
+                    }
+                    // the plan shows the extraneous files, they are kept unless confirmed
+                    if !delete_extraneous {
+                        plan.deletes.clear();
+                    }
+                    job.started = true;
+                    let (path, to, include_hidden, is_remote) = (
+                        job.path.clone(),
+                        job.to.clone(),
+                        job.include_hidden,
+                        job.is_remote,
+                    );
+                    self.mirror_files(id, path, to, include_hidden, is_remote, peer)
+                        .await;
+                }
+            }
             Data::SetNoConfirm(id) => {
                 if let Some(job) = self.remove_jobs.get_mut(&id) {
                     job.no_confirm = true;
@@ -681,6 +1043,11 @@ impl<T: InvokeUiSession> Remote<T> {
                             } else {
                                 Some(file_transfer_send_confirm_request::Union::Skip(true))
                             },
+                            signatures: if need_override {
+                                job.local_block_signatures(file_num).await.into()
+                            } else {
+                                Default::default()
+                            },
                             ..Default::default()
                         };
                         job.confirm(&req);
@@ -735,10 +1102,13 @@ impl<T: InvokeUiSession> Remote<T> {
                 allow_err!(peer.send(&msg_out).await);
                 if let Some(job) = fs::get_job(id, &mut self.write_jobs) {
                     job.remove_download_file();
+                    job.rename_written_files();
                     fs::remove_job(id, &mut self.write_jobs);
                 }
                 fs::remove_job(id, &mut self.read_jobs);
+                self.upload_jobs.remove(&id);
                 self.remove_jobs.remove(&id);
+                self.mirror_jobs.remove(&id);
             }
             Data::RemoveDir((id, path)) => {
                 let mut msg_out = Message::new();
@@ -798,6 +1168,8 @@ impl<T: InvokeUiSession> Remote<T> {
                 }
             }
             Data::RecordScreen(start, display, w, h, id) => {
+                #[cfg(not(any(target_os = "android", target_os = "ios")))]
+                self.record_session(start, &id);
                 let _ = self
                     .video_sender
                     .send(MediaData::RecordScreen(start, display, w, h, id));
@@ -1042,6 +1414,8 @@ impl<T: InvokeUiSession> Remote<T> {
 
     async fn handle_msg_from_peer(&mut self, data: &[u8], peer: &mut Stream) -> bool {
         if let Ok(msg_in) = Message::parse_from_bytes(&data) {
+            #[cfg(not(any(target_os = "android", target_os = "ios")))]
+            self.record_session_message(&msg_in);
             match msg_in.union {
                 Some(message::Union::VideoFrame(vf)) => {
                     if !self.first_frame {
@@ -1187,6 +1561,14 @@ impl<T: InvokeUiSession> Remote<T> {
                                     fs::transform_windows_path(&mut entries);
                                 }
                             }
+                            if let Some(job) = self.mirror_jobs.get_mut(&fd.id) {
+                                if job.plan.is_none() {
+                                    let plan = job.set_remote_files(&entries);
+                                    let plan = serde_json::to_string(plan).unwrap_or_default();
+                                    self.handler.update_mirror_plan(fd.id, &plan);
+                                    return true;
+                                }
+                            }
                             self.handler
                                 .update_folder_files(fd.id, &entries, fd.path, false, false);
                             if let Some(job) = fs::get_job(fd.id, &mut self.write_jobs) {
@@ -1199,9 +1581,15 @@ impl<T: InvokeUiSession> Remote<T> {
                         Some(file_response::Union::Digest(digest)) => {
                             if digest.is_upload {
                                 if let Some(job) = fs::get_job(digest.id, &mut self.read_jobs) {
+                                    job.set_peer_signatures(
+                                        digest.signatures.clone().into_option(),
+                                    );
                                     if let Some(file) = job.files().get(digest.file_num as usize) {
                                         let read_path = get_string(&job.join(&file.name));
-                                        let overwrite_strategy = job.default_overwrite_strategy();
+                                        let overwrite_strategy = job.overwrite_strategy_of(
+                                            digest.file_num,
+                                            digest.is_identical,
+                                        );
                                         if let Some(overwrite) = overwrite_strategy {
                                             let req = FileTransferSendConfirmRequest {
                                                 id: digest.id,
@@ -1233,7 +1621,6 @@ impl<T: InvokeUiSession> Remote<T> {
                                 if let Some(job) = fs::get_job(digest.id, &mut self.write_jobs) {
                                     if let Some(file) = job.files().get(digest.file_num as usize) {
                                         let write_path = get_string(&job.join(&file.name));
-                                        let overwrite_strategy = job.default_overwrite_strategy();
                                         match fs::is_write_need_confirmation(&write_path, &digest) {
                                             Ok(res) => match res {
                                                 DigestCheckResult::IsSame => {
@@ -1248,6 +1635,11 @@ impl<T: InvokeUiSession> Remote<T> {
                                                     allow_err!(peer.send(&msg).await);
                                                 }
                                                 DigestCheckResult::NeedConfirm(digest) => {
+                                                    let overwrite_strategy = job
+                                                        .overwrite_strategy_of(
+                                                            digest.file_num,
+                                                            digest.is_identical,
+                                                        );
                                                     if let Some(overwrite) = overwrite_strategy {
                                                         let req = FileTransferSendConfirmRequest {
                                                             id: digest.id,
@@ -1257,6 +1649,15 @@ impl<T: InvokeUiSession> Remote<T> {
                                                             } else {
                                                                 file_transfer_send_confirm_request::Union::Skip(true)
                                                             }),
+                                                            signatures: if overwrite {
+                                                                job.local_block_signatures(
+                                                                    digest.file_num,
+                                                                )
+                                                                .await
+                                                                .into()
+                                                            } else {
+                                                                Default::default()
+                                                            },
                                                             ..Default::default()
                                                         };
                                                         job.confirm(&req);
@@ -1302,24 +1703,73 @@ impl<T: InvokeUiSession> Remote<T> {
                         }
                         Some(file_response::Union::Done(d)) => {
                             let mut err: Option<String> = None;
+                            let mut retry = None;
                             if let Some(job) = fs::get_job(d.id, &mut self.write_jobs) {
-                                job.modify_time();
-                                err = job.job_error();
-                                fs::remove_job(d.id, &mut self.write_jobs);
+                                job.finish(&d.hashes).await;
+                                if let Some(file_num) = job.retry_failed_files() {
+                                    retry = Some(fs::new_send(
+                                        d.id,
+                                        job.remote.clone(),
+                                        file_num,
+                                        job.show_hidden,
+                                        job.preserve_attributes(),
+                                    ));
+                                } else {
+                                    job.create_links();
+                                    err = job.job_error();
+                                    fs::remove_job(d.id, &mut self.write_jobs);
+                                }
+                            }
+                            if let Some(msg) = retry {
+                                allow_err!(peer.send(&msg).await);
+                            } else {
+                                self.upload_jobs.remove(&d.id);
+                                if !self
+                                    .handle_mirror_status(d.id, d.file_num, &err, peer)
+                                    .await
+                                {
+                                    self.handle_job_status(d.id, d.file_num, err);
+                                }
                             }
-                            self.handle_job_status(d.id, d.file_num, err);
                         }
                         Some(file_response::Union::Error(e)) => {
-                            if let Some(_job) = fs::get_job(e.id, &mut self.write_jobs) {
+                            if let Some(job) = self.mirror_jobs.get_mut(&e.id) {
+                                if job.plan.is_none() && !job.is_remote {
+                                    // the remote target does not exist yet
+                                    let plan = job.set_remote_files(&[]);
+                                    let plan = serde_json::to_string(plan).unwrap_or_default();
+                                    self.handler.update_mirror_plan(e.id, &plan);
+                                    return true;
+                                }
+                            }
+                            let mut retrying = false;
+                            if let Some(job) = fs::get_job(e.id, &mut self.write_jobs) {
+                                job.rename_written_files();
                                 fs::remove_job(e.id, &mut self.write_jobs);
+                            } else if e.error == fs::ERR_HASH_MISMATCH {
+                                retrying = self.retry_upload(e.id, e.file_num, peer).await;
+                            }
+                            if !retrying {
+                                self.upload_jobs.remove(&e.id);
+                                let err = Some(e.error);
+                                if !self
+                                    .handle_mirror_status(e.id, e.file_num, &err, peer)
+                                    .await
+                                {
+                                    self.mirror_jobs.remove(&e.id);
+                                    self.handle_job_status(e.id, e.file_num, err);
+                                }
                             }
-                            self.handle_job_status(e.id, e.file_num, Some(e.error));
                         }
                         _ => {}
                     }
                 }
                 Some(message::Union::Misc(misc)) => match misc.union {
                     Some(misc::Union::AudioFormat(f)) => {
+                        // also to the video thread for the screen records
+                        self.video_sender
+                            .send(MediaData::AudioFormat(f.clone()))
+                            .ok();
                         self.audio_sender.send(MediaData::AudioFormat(f)).ok();
                     }
                     Some(misc::Union::ChatMessage(c)) => {
@@ -1514,6 +1964,9 @@ impl<T: InvokeUiSession> Remote<T> {
                 }
                 Some(message::Union::AudioFrame(frame)) => {
                     if !self.handler.lc.read().unwrap().disable_audio.v {
+                        self.video_sender
+                            .send(MediaData::AudioFrame(Box::new(frame.clone())))
+                            .ok();
                         self.audio_sender
                             .send(MediaData::AudioFrame(Box::new(frame)))
                             .ok();
@@ -1801,6 +2254,55 @@ impl<T: InvokeUiSession> Remote<T> {
     }
 }
 
+struct MirrorJob {
+    path: String,
+    to: String,
+    include_hidden: bool,
+    // the source is remote
+    is_remote: bool,
+    // the listing of the local side, waiting for the remote one
+    local_files: Vec<FileEntry>,
+    plan: Option<fs::MirrorPlan>,
+    started: bool,
+    // deletions of extraneous remote files not responded yet
+    pending_deletes: usize,
+    file_num: i32,
+    err: Option<String>,
+}
+
+impl MirrorJob {
+    fn new(
+        path: String,
+        to: String,
+        include_hidden: bool,
+        is_remote: bool,
+        local_files: Vec<FileEntry>,
+    ) -> Self {
+        Self {
+            path,
+            to,
+            include_hidden,
+            is_remote,
+            local_files,
+            plan: None,
+            started: false,
+            pending_deletes: 0,
+            file_num: 0,
+            err: None,
+        }
+    }
+
+    fn set_remote_files(&mut self, remote_files: &[FileEntry]) -> &fs::MirrorPlan {
+        let local_files = std::mem::take(&mut self.local_files);
+        let plan = if self.is_remote {
+            fs::mirror_plan(remote_files, &local_files, true)
+        } else {
+            fs::mirror_plan(&local_files, remote_files, true)
+        };
+        self.plan.insert(plan)
+    }
+}
+
 struct RemoveJob {
     files: Vec<FileEntry>,
     path: String,
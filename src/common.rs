@@ -689,6 +689,11 @@ async fn test_nat_type_() -> ResultType<bool> {
     }
     let start = std::time::Instant::now();
     let (rendezvous_server, _, _) = get_rendezvous_server(1_000).await;
+    if hbb_common::websocket::is_ws_url(&rendezvous_server) {
+        // behind a websocket bridge only the relay works
+        Config::set_nat_type(NatType::SYMMETRIC as _);
+        return Ok(true);
+    }
     let server1 = rendezvous_server;
     let server2 = crate::increase_port(&server1, -1);
     let mut msg_out = RendezvousMessage::new();
@@ -786,6 +791,18 @@ async fn get_rendezvous_server_(ms_timeout: u64) -> (String, Vec<String>) {
     crate::ipc::get_rendezvous_server(ms_timeout).await
 }
 
+#[inline]
+#[cfg(any(target_os = "android", target_os = "ios"))]
+pub async fn get_relay_server(_ms_timeout: u64) -> String {
+    Config::get_relay_server()
+}
+
+#[inline]
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+pub async fn get_relay_server(ms_timeout: u64) -> String {
+    crate::ipc::get_relay_server(ms_timeout).await
+}
+
 #[inline]
 #[cfg(any(target_os = "android", target_os = "ios"))]
 pub async fn get_nat_type(_ms_timeout: u64) -> i32 {
@@ -1026,8 +1043,9 @@ pub fn get_custom_rendezvous_server(custom: String) -> String {
             return lic.host.clone();
         }
     }
-    if !custom.is_empty() {
-        return custom;
+    // the first one of a server list, the api server does not fail over
+    if let Some(custom) = custom.split(',').map(|x| x.trim()).find(|x| !x.is_empty()) {
+        return custom.to_owned();
     }
     if !config::PROD_RENDEZVOUS_SERVER.read().unwrap().is_empty() {
         return config::PROD_RENDEZVOUS_SERVER.read().unwrap().clone();
@@ -1407,11 +1425,19 @@ pub fn get_rs_pk(str_base64: &str) -> Option<sign::PublicKey> {
 }
 
 pub fn decode_id_pk(signed: &[u8], key: &sign::PublicKey) -> ResultType<(String, [u8; 32])> {
+    decode_id_pk_version(signed, key).map(|(id, pk, _)| (id, pk))
+}
+
+/// The id, the public key and the version of the key exchange.
+pub fn decode_id_pk_version(
+    signed: &[u8],
+    key: &sign::PublicKey,
+) -> ResultType<(String, [u8; 32], u32)> {
     let res = IdPk::parse_from_bytes(
         &sign::verify(signed, key).map_err(|_| anyhow!("Signature mismatch"))?,
     )?;
     if let Some(pk) = get_pk(&res.pk) {
-        Ok((res.id, pk))
+        Ok((res.id, pk, res.key_version))
     } else {
         bail!("Wrong their public length");
     }
@@ -1426,6 +1452,17 @@ pub fn create_symmetric_key_msg(their_pk_b: [u8; 32]) -> (Bytes, Bytes, secretbo
     (Vec::from(our_pk_b.0).into(), sealed_key.into(), key)
 }
 
+/// Like `create_symmetric_key_msg`, with the session key of the version 2 key exchange.
+pub fn create_session_key_msg(their_pk_b: [u8; 32]) -> (Bytes, Bytes, secretbox::Key) {
+    let their_pk_b = box_::PublicKey(their_pk_b);
+    let (our_pk_b, out_sk_b) = box_::gen_keypair();
+    let key = secretbox::gen_key();
+    let nonce = box_::Nonce([0u8; box_::NONCEBYTES]);
+    let sealed_key = box_::seal(&key.0, &nonce, &their_pk_b, &out_sk_b);
+    let key = hbb_common::tcp::Encrypt::session_key(&key, &our_pk_b, &their_pk_b, true);
+    (Vec::from(our_pk_b.0).into(), sealed_key.into(), key)
+}
+
 #[inline]
 pub fn using_public_server() -> bool {
     option_env!("RENDEZVOUS_SERVER").unwrap_or("").is_empty()
@@ -121,6 +121,92 @@ pub fn core_main() -> Option<Vec<String>> {
         } else if args[0] == "--build-date" {
             println!("{}", crate::BUILD_DATE);
             return None;
+        } else if args[0] == "--gen-record-keypair" {
+            // the public key is for option "record-encrypt-public-key"
+            let (pk, sk) = scrap::record_store::gen_keypair();
+            println!("public key: {pk}");
+            println!("secret key: {sk}");
+            return None;
+        } else if args[0] == "--list-recordings" {
+            // --list-recordings [--peer <id>] [--direction <incoming|outgoing>] [--from <unix ms>]
+            // [--to <unix ms>] [--codec <codec>], the matching recordings as json lines
+            use scrap::record_store;
+            match record_store::ManifestQuery::from_args(&args[1..]) {
+                Ok(query) => {
+                    let mut dirs = vec![crate::ui_interface::video_save_directory(false)];
+                    // the incoming recordings of the service
+                    #[cfg(windows)]
+                    dirs.push(crate::ui_interface::video_save_directory(true));
+                    dirs.dedup();
+                    for dir in dirs {
+                        for mut entry in record_store::lookup(&dir, &query) {
+                            entry.file = std::path::Path::new(&dir)
+                                .join(&entry.file)
+                                .to_string_lossy()
+                                .to_string();
+                            println!("{}", serde_json::to_string(&entry).unwrap_or_default());
+                        }
+                    }
+                }
+                Err(err) => println!("{err}"),
+            }
+            return None;
+        } else if args[0] == "--gen-auth-key" {
+            // the key is used for the login of the outgoing connections,
+            // the public key is for option "authorized-keys" of the peers
+            match crate::auth_pk::gen_auth_key() {
+                Ok(pk) => println!("public key: {pk}"),
+                Err(err) => println!("{err}"),
+            }
+            return None;
+        } else if args[0] == "--decrypt-recording" {
+            // --decrypt-recording <encrypted file> <output file>, the secret key is read from stdin
+            if args.len() == 3 {
+                let mut key = String::new();
+                let res = std::io::stdin()
+                    .read_line(&mut key)
+                    .map_err(|e| e.into())
+                    .and_then(|_| scrap::record_store::parse_secret_key(&key))
+                    .and_then(|sk| {
+                        scrap::record_store::decrypt_file(
+                            std::path::Path::new(&args[1]),
+                            std::path::Path::new(&args[2]),
+                            &sk,
+                        )
+                    });
+                match res {
+                    Ok(()) => println!("Done!"),
+                    Err(err) => println!("{err}"),
+                }
+            }
+            return None;
+        } else if args[0] == "--export-audit-log" {
+            // --export-audit-log <output file>, all the entries from the oldest, with the chain checked
+            if args.len() == 2 {
+                if crate::platform::is_installed() && is_root() {
+                    use crate::server::audit_log;
+                    // the key is never generated here, so no chain is checked with a new one
+                    let res = audit_log::load_key().and_then(|key| {
+                        let mut f = std::fs::File::create(&args[1])?;
+                        audit_log::export(&audit_log::dir(), &key, &mut f)
+                    });
+                    match res {
+                        Ok(v) => {
+                            println!("{} entries from seq {}", v.entries, v.first_seq);
+                            for (line, err) in v.errors.iter() {
+                                println!("line {line}: {err}");
+                            }
+                            if v.errors.is_empty() {
+                                println!("The chain is intact");
+                            }
+                        }
+                        Err(err) => println!("{err}"),
+                    }
+                } else {
+                    println!("Installation and administrative privileges required!");
+                }
+            }
+            return None;
         }
     }
     #[cfg(windows)]
@@ -314,6 +400,35 @@ pub fn core_main() -> Option<Vec<String>> {
                 println!("Installation and administrative privileges required!");
             }
             return None;
+        } else if args[0] == "--get-temporary-password" {
+            if crate::platform::is_installed() && is_root() {
+                match crate::ipc::get_temporary_password_info() {
+                    Some(info) => {
+                        println!("{}", info.password);
+                        if let Some(secs) = info.expires_in {
+                            println!("expires in {} minutes", (secs + 59) / 60);
+                        }
+                        if let Some(secs) = info.rotates_in {
+                            println!("rotates in {} minutes", (secs + 59) / 60);
+                        }
+                        if info.one_time {
+                            println!("one-time");
+                        }
+                    }
+                    None => println!("Failed to get the temporary password from the service"),
+                }
+            } else {
+                println!("Installation and administrative privileges required!");
+            }
+            return None;
+        } else if args[0] == "--get-lan-rendezvous-key" {
+            // the key of the other instances using this one as their ID server
+            if crate::platform::is_installed() && is_root() {
+                println!("{}", crate::ipc::get_lan_rendezvous_key());
+            } else {
+                println!("Installation and administrative privileges required!");
+            }
+            return None;
         } else if args[0] == "--set-id" {
             if args.len() == 2 {
                 if crate::platform::is_installed() && is_root() {
@@ -365,6 +480,36 @@ pub fn core_main() -> Option<Vec<String>> {
                 println!("Installation and administrative privileges required!");
             }
             return None;
+        } else if args[0] == "--login-lockouts" {
+            use hbb_common::chrono::{Local, TimeZone};
+            // --login-lockouts [clear [ip:<ip>|ip:<ip>|id:<peer id>]]
+            if crate::platform::is_installed() && is_root() {
+                let res = if args.len() >= 2 && args[1] == "clear" {
+                    crate::ipc::clear_login_lockouts(args.get(2).cloned())
+                } else {
+                    crate::ipc::get_login_lockouts()
+                };
+                match res {
+                    Ok(lockouts) => {
+                        for x in lockouts {
+                            let until = if x.exceeded {
+                                "until cleared".to_owned()
+                            } else {
+                                Local
+                                    .timestamp_millis_opt(x.locked_until)
+                                    .single()
+                                    .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+                                    .unwrap_or_default()
+                            };
+                            println!("{} {} {} {}", x.key, x.kind, x.attempts, until);
+                        }
+                    }
+                    Err(err) => println!("{err}"),
+                }
+            } else {
+                println!("Installation and administrative privileges required!");
+            }
+            return None;
         } else if args[0] == "--assign" {
             if crate::platform::is_installed() && is_root() {
                 let max = args.len() - 1;
@@ -724,6 +724,14 @@ impl InvokeUiSession for FlutterHandler {
         );
     }
 
+    fn update_mirror_plan(&self, id: i32, plan: &str) {
+        self.push_event(
+            "mirror_plan",
+            &[("id", &id.to_string()), ("plan", plan)],
+            &[],
+        );
+    }
+
     fn job_progress(&self, id: i32, file_num: i32, speed: f64, finished_size: f64) {
         self.push_event(
             "job_progress",
@@ -1255,6 +1263,28 @@ pub mod connection_manager {
         fn file_transfer_log(&self, action: &str, log: &str) {
             self.push_event("cm_file_transfer_log", &[(action, log)]);
         }
+
+        fn reverse_forward_request(&self, id: i32, listen_id: i32, port: i32) {
+            self.push_event(
+                "reverse_forward_request",
+                &[
+                    ("id", &id.to_string()),
+                    ("listen_id", &listen_id.to_string()),
+                    ("port", &port.to_string()),
+                ],
+            );
+        }
+
+        fn port_forward_request(&self, id: i32, stream_id: i32, target: &str) {
+            self.push_event(
+                "port_forward_request",
+                &[
+                    ("id", &id.to_string()),
+                    ("stream_id", &stream_id.to_string()),
+                    ("target", target),
+                ],
+            );
+        }
     }
 
     impl FlutterHandler {
@@ -572,6 +572,7 @@ pub fn session_read_remote_dir(session_id: SessionID, path: String, include_hidd
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn session_send_files(
     session_id: SessionID,
     act_id: i32,
@@ -580,9 +581,37 @@ pub fn session_send_files(
     file_num: i32,
     include_hidden: bool,
     is_remote: bool,
+    preserve_attributes: bool,
 ) {
     if let Some(session) = sessions::get_session_by_session_id(&session_id) {
-        session.send_files(act_id, path, to, file_num, include_hidden, is_remote);
+        session.send_files(
+            act_id,
+            path,
+            to,
+            file_num,
+            include_hidden,
+            is_remote,
+            preserve_attributes,
+        );
+    }
+}
+
+pub fn session_mirror_dir(
+    session_id: SessionID,
+    act_id: i32,
+    path: String,
+    to: String,
+    include_hidden: bool,
+    is_remote: bool,
+) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.mirror_dir(act_id, path, to, include_hidden, is_remote);
+    }
+}
+
+pub fn session_start_mirror(session_id: SessionID, act_id: i32, delete_extraneous: bool) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.start_mirror(act_id, delete_extraneous);
     }
 }
 
@@ -676,6 +705,7 @@ pub fn session_load_last_transfer_jobs(session_id: SessionID) {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn session_add_job(
     session_id: SessionID,
     act_id: i32,
@@ -684,9 +714,18 @@ pub fn session_add_job(
     file_num: i32,
     include_hidden: bool,
     is_remote: bool,
+    preserve_attributes: bool,
 ) {
     if let Some(session) = sessions::get_session_by_session_id(&session_id) {
-        session.add_job(act_id, path, to, file_num, include_hidden, is_remote);
+        session.add_job(
+            act_id,
+            path,
+            to,
+            file_num,
+            include_hidden,
+            is_remote,
+            preserve_attributes,
+        );
     }
 }
 
@@ -1220,6 +1259,50 @@ pub fn session_remove_port_forward(session_id: SessionID, local_port: i32) {
     }
 }
 
+pub fn session_get_port_forward_profiles(session_id: SessionID) -> SyncReturn<String> {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        SyncReturn(serde_json::to_string(&session.get_port_forward_profiles()).unwrap_or_default())
+    } else {
+        SyncReturn("".to_owned())
+    }
+}
+
+/// `forwards` is a json array of `[local port, remote host, remote port]`, empty to remove.
+pub fn session_set_port_forward_profile(session_id: SessionID, name: String, forwards: String) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        let forwards = serde_json::from_str(&forwards).unwrap_or_default();
+        session.set_port_forward_profile(name, forwards);
+    }
+}
+
+pub fn session_get_reverse_port_forwards(session_id: SessionID) -> SyncReturn<String> {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        SyncReturn(serde_json::to_string(&session.get_reverse_port_forwards()).unwrap_or_default())
+    } else {
+        SyncReturn("".to_owned())
+    }
+}
+
+/// `forwards` is a json array of `[remote port, local host, local port]`, empty to remove.
+pub fn session_set_reverse_port_forwards(session_id: SessionID, name: String, forwards: String) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        let forwards = serde_json::from_str(&forwards).unwrap_or_default();
+        session.set_reverse_port_forwards(name, forwards);
+    }
+}
+
+pub fn session_start_port_forward_profile(session_id: SessionID, name: String) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.start_port_forward_profile(name);
+    }
+}
+
+pub fn session_stop_port_forward_profile(session_id: SessionID, name: String) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.stop_port_forward_profile(name);
+    }
+}
+
 pub fn session_new_rdp(session_id: SessionID) {
     if let Some(session) = sessions::get_session_by_session_id(&session_id) {
         session.new_rdp();
@@ -1588,6 +1671,16 @@ pub fn cm_switch_permission(conn_id: i32, name: String, enabled: bool) {
     crate::ui_cm_interface::switch_permission(conn_id, name, enabled)
 }
 
+pub fn cm_reverse_forward_response(conn_id: i32, listen_id: i32, accepted: bool) {
+    #[cfg(not(any(target_os = "ios")))]
+    crate::ui_cm_interface::reverse_forward_response(conn_id, listen_id, accepted)
+}
+
+pub fn cm_port_forward_response(conn_id: i32, stream_id: i32, accepted: bool) {
+    #[cfg(not(any(target_os = "ios")))]
+    crate::ui_cm_interface::port_forward_response(conn_id, stream_id, accepted)
+}
+
 pub fn cm_can_elevate() -> SyncReturn<bool> {
     SyncReturn(crate::ui_cm_interface::can_elevate())
 }
@@ -2110,6 +2203,44 @@ pub fn main_has_valid_2fa_sync() -> SyncReturn<bool> {
     SyncReturn(has_valid_2fa())
 }
 
+pub fn main_generate2fa_device(
+    name: String,
+    algorithm: String,
+    digits: usize,
+    period: u64,
+    hotp: bool,
+) -> String {
+    generate2fa_device(name, algorithm, digits, period, hotp)
+}
+
+pub fn main_list_2fa_devices() -> String {
+    list_2fa_devices()
+}
+
+pub fn main_revoke_2fa_device(name: String) -> bool {
+    revoke_2fa_device(name)
+}
+
+pub fn main_generate_2fa_recovery_codes() -> Vec<String> {
+    generate_2fa_recovery_codes()
+}
+
+pub fn main_count_2fa_recovery_codes() -> usize {
+    count_2fa_recovery_codes()
+}
+
+pub fn main_gen_auth_key() -> String {
+    gen_auth_key()
+}
+
+pub fn main_get_auth_public_key() -> SyncReturn<String> {
+    SyncReturn(get_auth_public_key())
+}
+
+pub fn main_remove_auth_key() {
+    remove_auth_key()
+}
+
 pub fn main_get_hard_option(key: String) -> SyncReturn<String> {
     SyncReturn(get_hard_option(key))
 }
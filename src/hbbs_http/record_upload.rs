@@ -1,123 +1,293 @@
-use crate::hbbs_http::create_http_client;
-use bytes::Bytes;
-use hbb_common::{bail, config::Config, lazy_static, log, ResultType};
-use reqwest::blocking::{Body, Client};
+// Upload of the incoming recordings to the api server, while they are written.
+//
+// Requests are `POST {api-server}/api/record` with query parameters `type`, `file` and, depending
+// on the type, `offset`, `length` and `sha256`. The server replies json, `{"error": "..."}` on
+// failure.
+// - `new`: a new recording `file`.
+// - `status`: the server replies `{"offset": n}`, the length of `file` it has, 0 if unknown.
+// - `part`: the body is `length` bytes of the file at `offset`, `sha256` is their hex sha256. The
+//   server keeps them only if the checksum matches and `offset` is not beyond the length it has,
+//   and replies `{"offset": n}`, the length it has after the part.
+// - `tail`: the body is the first `length` bytes of the file, rewritten when the recording is
+//   closed, with `offset` 0 and `sha256`. The server writes them at the start, and the upload
+//   is complete.
+// - `remove`: the recording is dropped as it is too short.
+//
+// If option "record-upload-secret" is set, the requests carry header `X-Record-Timestamp`, unix
+// time in seconds, and `X-Record-Signature`, hex of the HMAC-SHA256 with the secret of
+// `type\nfile\noffset\nlength\nsha256\ntimestamp`, absent parameters being empty. The server
+// rejects a wrong signature, or a timestamp off by more than `SIGNATURE_MAX_AGE` seconds.
+//
+// Failed requests are retried, resuming from the length the server has. Recordings not uploaded
+// completely are kept in `RecordUploads` with the acknowledged length, and resumed after a restart.
+//
+// Encrypted recordings are uploaded as they are, once closed, because any of their blocks may be
+// encrypted again while written.
+
+use crate::{
+    crypto::{hmac_sha256, sha256_hex, to_hex},
+    hbbs_http::create_http_client,
+};
+use hbb_common::{
+    bail,
+    config::{Config, RecordUpload, RecordUploads},
+    lazy_static, log, ResultType,
+};
+use reqwest::blocking::Client;
 use scrap::record::RecordState;
-use serde::Serialize;
-use serde_json::Map;
+use serde_json::{Map, Value};
 use std::{
     fs::File,
     io::{prelude::*, SeekFrom},
-    sync::{mpsc::Receiver, Arc, Mutex},
-    time::{Duration, Instant},
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 const MAX_HEADER_LEN: usize = 1024;
 const SHOULD_SEND_TIME: Duration = Duration::from_secs(1);
 const SHOULD_SEND_SIZE: u64 = 1024 * 1024;
+// a failed part is sent again as a whole
+const MAX_PART_SIZE: u64 = 4 * 1024 * 1024;
+const RETRY_MIN: Duration = Duration::from_secs(5);
+const RETRY_MAX: Duration = Duration::from_secs(300);
+// a closed recording is left to the next start after failing so many times in a row
+const MAX_RETRIES: usize = 10;
+pub const SIGNATURE_MAX_AGE: u64 = 300;
+pub const TIMESTAMP_HEADER: &str = "X-Record-Timestamp";
+pub const SIGNATURE_HEADER: &str = "X-Record-Signature";
 
 lazy_static::lazy_static! {
     static ref ENABLE: Arc<Mutex<bool>> = Default::default();
+    // `RecordUploads` is read, modified and written back under the lock
+    static ref QUEUE_LOCK: Mutex<()> = Default::default();
 }
 
 pub fn is_enable() -> bool {
-    ENABLE.lock().unwrap().clone()
+    ENABLE.lock().unwrap().clone() || Config::get_option("enable-record-upload") == "Y"
 }
 
 pub fn run(rx: Receiver<RecordState>) {
-    let mut uploader = RecordUploader {
-        client: create_http_client(),
-        api_server: crate::get_api_server(
-            Config::get_option("api-server"),
-            Config::get_option("custom-rendezvous-server"),
-        ),
-        filepath: Default::default(),
-        filename: Default::default(),
-        upload_size: Default::default(),
-        running: Default::default(),
-        last_send: Instant::now(),
-    };
-    std::thread::spawn(move || loop {
-        if let Err(e) = match rx.recv() {
-            Ok(state) => match state {
-                RecordState::NewFile(filepath) => uploader.handle_new_file(filepath),
-                RecordState::NewFrame => {
-                    if uploader.running {
-                        uploader.handle_frame(false)
-                    } else {
-                        Ok(())
+    let mut uploader = RecordUploader::new(true);
+    std::thread::spawn(move || {
+        loop {
+            let state = match rx.recv_timeout(RETRY_MIN) {
+                Ok(state) => Some(state),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let res = match state {
+                Some(RecordState::NewFile(filepath)) => uploader.handle_new_file(filepath),
+                Some(RecordState::RemoveFile) if uploader.running => uploader.handle_remove(),
+                Some(RecordState::WriteTail) if uploader.running => {
+                    uploader.finished = true;
+                    if !uploader.retry_due() {
+                        // the retry sends the tail
+                        continue;
                     }
+                    uploader.resume_or(RecordUploader::handle_tail)
                 }
-                RecordState::WriteTail => {
-                    if uploader.running {
-                        uploader.handle_tail()
-                    } else {
-                        Ok(())
-                    }
+                Some(RecordState::NewFrame) if uploader.running && uploader.retry_due() => {
+                    uploader.resume_or(|u| u.handle_frame(false))
                 }
-                RecordState::RemoveFile => {
-                    if uploader.running {
-                        uploader.handle_remove()
-                    } else {
-                        Ok(())
-                    }
+                // retry the failed upload, or the tail of a closed recording
+                None if uploader.running && uploader.retry.is_some() && uploader.retry_due() => {
+                    uploader.resume()
                 }
-            },
-            Err(e) => {
-                log::trace!("upload thread stop: {}", e);
-                break;
+                // nothing is sent, which must not clear a pending retry
+                _ => continue,
+            };
+            uploader.on_result(res);
+        }
+        // the recorder is gone, finish the closed recording
+        let mut retries = 0;
+        while uploader.running && uploader.finished && retries < MAX_RETRIES {
+            if let Some((at, _)) = uploader.retry {
+                std::thread::sleep(at.saturating_duration_since(Instant::now()));
             }
-        } {
-            uploader.running = false;
-            log::error!("upload stop: {}", e);
+            let res = uploader.resume();
+            uploader.on_result(res);
+            retries += 1;
         }
+        log::trace!("upload thread stop");
     });
 }
 
+/// Resume the uploads not completed before the last exit.
+pub fn resume_pending() {
+    if !is_enable() {
+        return;
+    }
+    let files = {
+        let _lock = QUEUE_LOCK.lock().unwrap();
+        RecordUploads::load().files
+    };
+    if files.is_empty() {
+        return;
+    }
+    std::thread::spawn(move || {
+        for f in files {
+            if !std::path::Path::new(&f.filepath).exists() {
+                log::info!("pending upload {} is removed", f.filepath);
+                remove_from_queue(&f.filepath);
+                continue;
+            }
+            let mut uploader = RecordUploader::new(true);
+            if let Err(e) = uploader.set_file(f.filepath.clone()) {
+                log::error!("{}", e);
+                remove_from_queue(&f.filepath);
+                continue;
+            }
+            // nothing writes a recording of the last run any more
+            uploader.finished = true;
+            uploader.upload_size = f.acked;
+            for _ in 0..MAX_RETRIES {
+                let res = uploader.resume();
+                let ok = res.is_ok();
+                uploader.on_result(res);
+                if ok {
+                    break;
+                }
+                if let Some((at, _)) = uploader.retry {
+                    std::thread::sleep(at.saturating_duration_since(Instant::now()));
+                }
+            }
+        }
+    });
+}
+
+fn update_queue(f: impl FnOnce(&mut Vec<RecordUpload>)) {
+    let _lock = QUEUE_LOCK.lock().unwrap();
+    let mut uploads = RecordUploads::load();
+    f(&mut uploads.files);
+    uploads.store();
+}
+
+fn remove_from_queue(filepath: &str) {
+    update_queue(|files| files.retain(|f| f.filepath != filepath));
+}
+
+/// The signature of a request with `params` of type, file, offset, length and sha256.
+pub fn sign(secret: &str, params: [&str; 5], timestamp: &str) -> String {
+    let message = params.join("\n") + "\n" + timestamp;
+    to_hex(&hmac_sha256(secret.as_bytes(), message.as_bytes()))
+}
+
 struct RecordUploader {
     client: Client,
     api_server: String,
+    secret: String,
+    // whether the progress is kept in `RecordUploads`
+    persist: bool,
     filepath: String,
     filename: String,
+    // acknowledged by the server
     upload_size: u64,
     running: bool,
+    // the recorder has closed the file
+    finished: bool,
+    // only uploaded once closed
+    encrypted: bool,
     last_send: Instant,
+    // when to retry after a failure, and the wait before the next one
+    retry: Option<(Instant, Duration)>,
 }
+
 impl RecordUploader {
-    fn send<Q, B>(&self, query: &Q, body: B) -> ResultType<()>
-    where
-        Q: Serialize + ?Sized,
-        B: Into<Body>,
-    {
-        match self
+    fn new(persist: bool) -> Self {
+        Self::with_server(
+            create_http_client(),
+            crate::get_api_server(
+                Config::get_option("api-server"),
+                Config::get_option("custom-rendezvous-server"),
+            ),
+            Config::get_option("record-upload-secret"),
+            persist,
+        )
+    }
+
+    fn with_server(client: Client, api_server: String, secret: String, persist: bool) -> Self {
+        Self {
+            client,
+            api_server,
+            secret,
+            persist,
+            filepath: Default::default(),
+            filename: Default::default(),
+            upload_size: Default::default(),
+            running: Default::default(),
+            finished: Default::default(),
+            encrypted: Default::default(),
+            last_send: Instant::now(),
+            retry: None,
+        }
+    }
+
+    fn send(&self, query: &[(&str, &str)], body: Vec<u8>) -> ResultType<Map<String, Value>> {
+        let mut request = self
             .client
             .post(format!("{}/api/record", self.api_server))
-            .query(query)
-            .body(body)
-            .send()
-        {
+            .query(query);
+        if !self.secret.is_empty() {
+            let param = |k: &str| {
+                query
+                    .iter()
+                    .find(|(q, _)| *q == k)
+                    .map(|(_, v)| *v)
+                    .unwrap_or_default()
+            };
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default()
+                .to_string();
+            let signature = sign(
+                &self.secret,
+                [
+                    param("type"),
+                    param("file"),
+                    param("offset"),
+                    param("length"),
+                    param("sha256"),
+                ],
+                &timestamp,
+            );
+            request = request
+                .header(TIMESTAMP_HEADER, timestamp)
+                .header(SIGNATURE_HEADER, signature);
+        }
+        match request.body(body).send() {
             Ok(resp) => {
-                if let Ok(m) = resp.json::<Map<String, serde_json::Value>>() {
-                    if let Some(e) = m.get("error") {
-                        bail!(e.to_string());
-                    }
+                let status = resp.status();
+                let m = resp
+                    .json::<Map<String, serde_json::Value>>()
+                    .unwrap_or_default();
+                if let Some(e) = m.get("error") {
+                    bail!(e.to_string());
+                }
+                if !status.is_success() {
+                    bail!("http status {}", status);
                 }
-                Ok(())
+                Ok(m)
             }
             Err(e) => bail!(e.to_string()),
         }
     }
 
-    fn handle_new_file(&mut self, filepath: String) -> ResultType<()> {
+    fn set_file(&mut self, filepath: String) -> ResultType<()> {
         match std::path::PathBuf::from(&filepath).file_name() {
             Some(filename) => match filename.to_owned().into_string() {
                 Ok(filename) => {
-                    self.filename = filename.clone();
-                    self.filepath = filepath.clone();
+                    self.filename = filename;
+                    self.filepath = filepath;
                     self.upload_size = 0;
                     self.running = true;
+                    self.finished = false;
+                    self.encrypted = scrap::record_store::is_encrypted_path(&self.filepath);
                     self.last_send = Instant::now();
-                    self.send(&[("type", "new"), ("file", &filename)], Bytes::new())?;
+                    self.retry = None;
                     Ok(())
                 }
                 Err(_) => bail!("can't parse filename:{:?}", filename),
@@ -126,80 +296,474 @@ impl RecordUploader {
         }
     }
 
+    fn handle_new_file(&mut self, filepath: String) -> ResultType<()> {
+        if self.running && self.finished {
+            // the tail is due, try it once before it is left behind
+            let res = self.resume();
+            self.on_result(res);
+        }
+        if self.running {
+            log::info!("upload of {} is left to the next start", self.filename);
+        }
+        self.set_file(filepath)?;
+        if self.persist {
+            let filepath = self.filepath.clone();
+            update_queue(|files| files.push(RecordUpload { filepath, acked: 0 }));
+        }
+        self.send(&[("type", "new"), ("file", &self.filename)], Vec::new())?;
+        Ok(())
+    }
+
     fn handle_frame(&mut self, flush: bool) -> ResultType<()> {
+        if self.encrypted && !self.finished {
+            return Ok(());
+        }
         if !flush && self.last_send.elapsed() < SHOULD_SEND_TIME {
             return Ok(());
         }
-        match File::open(&self.filepath) {
-            Ok(mut file) => match file.metadata() {
-                Ok(m) => {
-                    let len = m.len();
-                    if len <= self.upload_size {
-                        return Ok(());
-                    }
-                    if !flush && len - self.upload_size < SHOULD_SEND_SIZE {
-                        return Ok(());
-                    }
-                    let mut buf = Vec::new();
-                    match file.seek(SeekFrom::Start(self.upload_size)) {
-                        Ok(_) => match file.read_to_end(&mut buf) {
-                            Ok(length) => {
-                                self.send(
-                                    &[
-                                        ("type", "part"),
-                                        ("file", &self.filename),
-                                        ("offset", &self.upload_size.to_string()),
-                                        ("length", &length.to_string()),
-                                    ],
-                                    buf,
-                                )?;
-                                self.upload_size = len;
-                                self.last_send = Instant::now();
-                                Ok(())
-                            }
-                            Err(e) => bail!(e.to_string()),
-                        },
-                        Err(e) => bail!(e.to_string()),
-                    }
-                }
-                Err(e) => bail!(e.to_string()),
-            },
-            Err(e) => bail!(e.to_string()),
+        let len = std::fs::metadata(&self.filepath)?.len();
+        if len <= self.upload_size {
+            return Ok(());
         }
+        if !flush && len - self.upload_size < SHOULD_SEND_SIZE {
+            return Ok(());
+        }
+        let mut file = File::open(&self.filepath)?;
+        while self.upload_size < len {
+            let length = (len - self.upload_size).min(MAX_PART_SIZE);
+            let mut buf = vec![0u8; length as usize];
+            file.seek(SeekFrom::Start(self.upload_size))?;
+            file.read_exact(&mut buf)?;
+            let sha256 = sha256_hex(&buf);
+            let m = self.send(
+                &[
+                    ("type", "part"),
+                    ("file", &self.filename),
+                    ("offset", &self.upload_size.to_string()),
+                    ("length", &length.to_string()),
+                    ("sha256", &sha256),
+                ],
+                buf,
+            )?;
+            let Some(offset) = m.get("offset").and_then(|v| v.as_u64()) else {
+                bail!("no offset in the response of part");
+            };
+            if offset <= self.upload_size || offset > len {
+                bail!("part at {} is acknowledged as {}", self.upload_size, offset);
+            }
+            self.upload_size = offset;
+            self.last_send = Instant::now();
+            self.store_acked();
+        }
+        Ok(())
     }
 
     fn handle_tail(&mut self) -> ResultType<()> {
         self.handle_frame(true)?;
-        match File::open(&self.filepath) {
-            Ok(mut file) => {
-                let mut buf = vec![0u8; MAX_HEADER_LEN];
-                match file.read(&mut buf) {
-                    Ok(length) => {
-                        buf.truncate(length);
-                        self.send(
-                            &[
-                                ("type", "tail"),
-                                ("file", &self.filename),
-                                ("offset", "0"),
-                                ("length", &length.to_string()),
-                            ],
-                            buf,
-                        )?;
-                        log::info!("upload success, file: {}", self.filename);
-                        Ok(())
+        let mut file = File::open(&self.filepath)?;
+        let mut buf = vec![0u8; MAX_HEADER_LEN];
+        let length = file.read(&mut buf)?;
+        buf.truncate(length);
+        let sha256 = sha256_hex(&buf);
+        self.send(
+            &[
+                ("type", "tail"),
+                ("file", &self.filename),
+                ("offset", "0"),
+                ("length", &length.to_string()),
+                ("sha256", &sha256),
+            ],
+            buf,
+        )?;
+        log::info!("upload success, file: {}", self.filename);
+        self.complete();
+        Ok(())
+    }
+
+    fn handle_remove(&mut self) -> ResultType<()> {
+        self.complete();
+        self.send(&[("type", "remove"), ("file", &self.filename)], Vec::new())?;
+        Ok(())
+    }
+
+    /// Continue from the length the server has, which may differ from the acknowledged one if
+    /// a request failed after the server handled it.
+    fn resume(&mut self) -> ResultType<()> {
+        let m = self.send(&[("type", "status"), ("file", &self.filename)], Vec::new())?;
+        if let Some(offset) = m.get("offset").and_then(|v| v.as_u64()) {
+            self.upload_size = offset;
+        }
+        let len = std::fs::metadata(&self.filepath)?.len();
+        if self.upload_size > len {
+            bail!("the server has {} bytes of {}", self.upload_size, len);
+        }
+        self.store_acked();
+        if self.finished {
+            self.handle_tail()
+        } else {
+            self.handle_frame(true)
+        }
+    }
+
+    // `f` if the last request succeeded, otherwise resume first
+    fn resume_or(&mut self, f: impl FnOnce(&mut Self) -> ResultType<()>) -> ResultType<()> {
+        if self.retry.is_some() {
+            self.resume()
+        } else {
+            f(self)
+        }
+    }
+
+    fn retry_due(&self) -> bool {
+        self.retry.map_or(true, |(at, _)| Instant::now() >= at)
+    }
+
+    fn on_result(&mut self, res: ResultType<()>) {
+        match res {
+            Ok(()) => self.retry = None,
+            Err(e) => {
+                let wait = self
+                    .retry
+                    .map_or(RETRY_MIN, |(_, wait)| (wait * 2).min(RETRY_MAX));
+                log::error!(
+                    "upload {} failed, retry in {:?}: {}",
+                    self.filename,
+                    wait,
+                    e
+                );
+                self.retry = Some((Instant::now() + wait, wait));
+            }
+        }
+    }
+
+    fn store_acked(&self) {
+        if !self.persist {
+            return;
+        }
+        let (filepath, acked) = (&self.filepath, self.upload_size);
+        update_queue(|files| {
+            if let Some(f) = files.iter_mut().find(|f| &f.filepath == filepath) {
+                f.acked = acked;
+            }
+        });
+    }
+
+    fn complete(&mut self) {
+        self.running = false;
+        self.retry = None;
+        if self.persist {
+            remove_from_queue(&self.filepath);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+
+    // The server side of the protocol, failing the parts listed in `fail_parts` by their order.
+    #[derive(Default)]
+    struct MockState {
+        files: HashMap<String, Vec<u8>>,
+        completed: Vec<String>,
+        parts: usize,
+        fail_parts: Vec<usize>,
+    }
+
+    struct MockServer {
+        url: String,
+        state: Arc<Mutex<MockState>>,
+    }
+
+    impl MockServer {
+        fn start(secret: &'static str) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let state: Arc<Mutex<MockState>> = Default::default();
+            let state_cloned = state.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if let Ok(stream) = stream {
+                        handle_request(stream, secret, &state_cloned);
                     }
-                    Err(e) => bail!(e.to_string()),
+                }
+            });
+            Self { url, state }
+        }
+    }
+
+    fn decode(s: &str) -> String {
+        let s = s.replace('+', " ");
+        let bytes = s.as_bytes();
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'%' && i + 2 < bytes.len() {
+                if let Ok(b) = u8::from_str_radix(&s[i + 1..i + 3], 16) {
+                    out.push(b);
+                    i += 3;
+                    continue;
                 }
             }
-            Err(e) => bail!(e.to_string()),
+            out.push(bytes[i]);
+            i += 1;
         }
+        String::from_utf8_lossy(&out).to_string()
     }
 
-    fn handle_remove(&mut self) -> ResultType<()> {
-        self.send(
-            &[("type", "remove"), ("file", &self.filename)],
-            Bytes::new(),
-        )?;
-        Ok(())
+    fn handle_request(stream: TcpStream, secret: &str, state: &Mutex<MockState>) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let target = line.split(' ').nth(1).unwrap_or_default().to_owned();
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((k, v)) = line.split_once(':') {
+                headers.insert(k.trim().to_lowercase(), v.trim().to_owned());
+            }
+        }
+        let len = headers
+            .get("content-length")
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or_default();
+        let mut body = vec![0u8; len];
+        reader.read_exact(&mut body).unwrap();
+        let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+        let params: HashMap<String, String> = query
+            .split('&')
+            .filter_map(|kv| kv.split_once('='))
+            .map(|(k, v)| (decode(k), decode(v)))
+            .collect();
+        let reply = handle(path, &params, &headers, body, secret, state);
+        let mut stream = stream;
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            if reply.get("error").is_some() { "400 Bad Request" } else { "200 OK" },
+            reply.to_string().len(),
+            reply
+        );
+        stream.write_all(response.as_bytes()).ok();
+    }
+
+    fn handle(
+        path: &str,
+        params: &HashMap<String, String>,
+        headers: &HashMap<String, String>,
+        body: Vec<u8>,
+        secret: &str,
+        state: &Mutex<MockState>,
+    ) -> Value {
+        let error = |e: &str| serde_json::json!({ "error": e });
+        let param = |k: &str| params.get(k).map(|v| v.as_str()).unwrap_or_default();
+        if path != "/api/record" {
+            return error("not found");
+        }
+        if !secret.is_empty() {
+            let timestamp = headers
+                .get(&TIMESTAMP_HEADER.to_lowercase())
+                .cloned()
+                .unwrap_or_default();
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            match timestamp.parse::<u64>() {
+                Ok(t) if t.abs_diff(now) <= SIGNATURE_MAX_AGE => {}
+                _ => return error("invalid timestamp"),
+            }
+            let expected = sign(
+                secret,
+                [
+                    param("type"),
+                    param("file"),
+                    param("offset"),
+                    param("length"),
+                    param("sha256"),
+                ],
+                &timestamp,
+            );
+            if headers.get(&SIGNATURE_HEADER.to_lowercase()) != Some(&expected) {
+                return error("invalid signature");
+            }
+        }
+        let mut state = state.lock().unwrap();
+        let file = param("file").to_owned();
+        match param("type") {
+            "new" => {
+                state.files.insert(file, Vec::new());
+                serde_json::json!({})
+            }
+            "status" => {
+                serde_json::json!({ "offset": state.files.get(&file).map(|f| f.len()).unwrap_or_default() })
+            }
+            "part" | "tail" => {
+                if param("sha256") != sha256_hex(&body) || param("length") != body.len().to_string()
+                {
+                    return error("checksum mismatch");
+                }
+                let offset = param("offset").parse::<usize>().unwrap_or(usize::MAX);
+                let is_part = param("type") == "part";
+                if is_part {
+                    state.parts += 1;
+                    let n = state.parts;
+                    if state.fail_parts.contains(&n) {
+                        return error("injected failure");
+                    }
+                }
+                let data = state.files.entry(file.clone()).or_default();
+                if offset > data.len() {
+                    return error("offset beyond the file");
+                }
+                let end = offset + body.len();
+                if data.len() < end {
+                    data.resize(end, 0);
+                }
+                data[offset..end].copy_from_slice(&body);
+                if is_part {
+                    let len = data.len();
+                    serde_json::json!({ "offset": len })
+                } else {
+                    state.completed.push(file);
+                    serde_json::json!({})
+                }
+            }
+            "remove" => {
+                state.files.remove(&file);
+                serde_json::json!({})
+            }
+            _ => error("unknown type"),
+        }
+    }
+
+    fn test_file(name: &str, len: usize) -> (String, Vec<u8>) {
+        let path = std::env::temp_dir()
+            .join(format!("{}_{}.webm", name, std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let data: Vec<u8> = (0..len).map(|i| (i % 253) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        (path, data)
+    }
+
+    fn uploader(server: &MockServer, secret: &str) -> RecordUploader {
+        RecordUploader::with_server(Client::new(), server.url.clone(), secret.to_owned(), false)
+    }
+
+    #[test]
+    fn test_upload() {
+        let server = MockServer::start("secret");
+        let (path, mut data) = test_file("record_upload", MAX_PART_SIZE as usize + 100);
+        let filename = std::path::Path::new(&path)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        let mut wrong = uploader(&server, "wrong");
+        assert!(wrong.handle_new_file(path.clone()).is_err());
+        let mut u = uploader(&server, "secret");
+        u.handle_new_file(path.clone()).unwrap();
+        u.handle_frame(true).unwrap();
+        assert_eq!(u.upload_size, data.len() as u64);
+        assert_eq!(server.state.lock().unwrap().parts, 2);
+        // the header is rewritten when the recording is closed
+        data[..4].copy_from_slice(b"head");
+        std::fs::write(&path, &data).unwrap();
+        u.finished = true;
+        u.handle_tail().unwrap();
+        assert!(!u.running);
+        let state = server.state.lock().unwrap();
+        assert_eq!(state.completed, vec![filename.clone()]);
+        assert_eq!(state.files.get(&filename), Some(&data));
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_upload_encrypted() {
+        let server = MockServer::start("");
+        let (plain, data) = test_file("record_upload_encrypted", 100);
+        let path = format!("{}.enc", plain);
+        std::fs::rename(&plain, &path).unwrap();
+        let filename = std::path::Path::new(&path)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        let mut u = uploader(&server, "");
+        u.handle_new_file(path.clone()).unwrap();
+        u.handle_frame(true).unwrap();
+        assert_eq!(u.upload_size, 0);
+        u.finished = true;
+        u.handle_tail().unwrap();
+        let state = server.state.lock().unwrap();
+        assert_eq!(state.completed, vec![filename.clone()]);
+        assert_eq!(state.files.get(&filename), Some(&data));
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_upload_resume() {
+        let server = MockServer::start("");
+        let (path, data) = test_file("record_upload_resume", MAX_PART_SIZE as usize * 2 + 1);
+        let filename = std::path::Path::new(&path)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        server.state.lock().unwrap().fail_parts = vec![2];
+        let mut u = uploader(&server, "");
+        u.handle_new_file(path.clone()).unwrap();
+        let res = u.handle_frame(true);
+        assert!(res.is_err());
+        u.on_result(res);
+        assert!(!u.retry_due());
+        assert_eq!(u.upload_size, MAX_PART_SIZE);
+        // a restart, only the acknowledged length is known
+        let mut u = uploader(&server, "");
+        u.set_file(path.clone()).unwrap();
+        u.upload_size = MAX_PART_SIZE;
+        u.finished = true;
+        u.resume().unwrap();
+        let state = server.state.lock().unwrap();
+        assert_eq!(state.parts, 4);
+        assert_eq!(state.completed, vec![filename.clone()]);
+        assert_eq!(state.files.get(&filename), Some(&data));
+        std::fs::remove_file(&path).ok();
+    }
+    #[test]
+    fn test_tail_before_new_file() {
+        let server = MockServer::start("");
+        let (path, data) = test_file("record_upload_tail", 100);
+        let (next, _) = test_file("record_upload_next", 100);
+        let filename = std::path::Path::new(&path)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        server.state.lock().unwrap().fail_parts = vec![1];
+        let mut u = uploader(&server, "");
+        u.handle_new_file(path.clone()).unwrap();
+        let res = u.handle_frame(true);
+        u.on_result(res);
+        // closed while the retry is not due yet
+        u.finished = true;
+        assert!(!u.retry_due());
+        u.handle_new_file(next.clone()).unwrap();
+        assert_eq!(u.filepath, next);
+        let state = server.state.lock().unwrap();
+        assert_eq!(state.completed, vec![filename.clone()]);
+        assert_eq!(state.files.get(&filename), Some(&data));
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_file(&next).ok();
     }
 }
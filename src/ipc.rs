@@ -29,7 +29,12 @@ use hbb_common::{
     ResultType,
 };
 
-use crate::{common::is_server, privacy_mode, rendezvous_mediator::RendezvousMediator};
+use crate::{
+    common::is_server,
+    privacy_mode,
+    rendezvous_mediator::RendezvousMediator,
+    server::login_lockout::{self, LoginLockout},
+};
 
 // IPC actions here.
 pub const IPC_ACTION_CLOSE: &str = "close";
@@ -64,6 +69,9 @@ pub enum FS {
         overwrite_detection: bool,
         total_size: u64,
         conn_id: i32,
+        preserve_attributes: bool,
+        // FileAttributes of each file, encoded as protobuf
+        attributes: Vec<Bytes>,
     },
     CancelWrite {
         id: i32,
@@ -74,9 +82,17 @@ pub enum FS {
         data: Bytes,
         compressed: bool,
     },
+    WriteCopy {
+        id: i32,
+        file_num: i32,
+        offset: u64,
+        length: u64,
+    },
     WriteDone {
         id: i32,
         file_num: i32,
+        // sha256 of each file sent, see `FileTransferDone`
+        hashes: Vec<(i32, Bytes)>,
     },
     WriteError {
         id: i32,
@@ -234,6 +250,20 @@ pub enum Data {
     CmErr(String),
     CheckHwcodec,
     VideoConnCount(Option<usize>),
+    // (listen id, port) of a reverse port forward to approve
+    ReverseForwardRequest((i32, i32)),
+    ReverseForwardResponse((i32, bool)),
+    // (stream id, target) of a port forward to approve
+    PortForwardRequest((i32, String)),
+    PortForwardResponse((i32, bool)),
+    LoginLockouts(Option<Vec<LoginLockout>>),
+    // clear the lockouts of the key, or all if `None`, responded with `LoginLockouts`
+    ClearLoginLockouts(Option<String>),
+    // (expected, new) option "2fa", responded with whether it is set
+    CompareAndSet2fa((String, String)),
+    CompareAndSet2faResult(bool),
+    // the connection is answered with the info whenever the password changes
+    WatchTemporaryPassword(Option<password::TemporaryPasswordInfo>),
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -399,16 +429,27 @@ async fn handle(data: Data, stream: &mut Connection) {
                     value = Some(Config::get_id());
                 } else if name == "temporary-password" {
                     value = Some(password::temporary_password());
+                } else if name == "temporary-password-info" {
+                    value = serde_json::to_string(&password::temporary_password_info()).ok();
+                } else if name == "lan-rendezvous-key" {
+                    value = Some(crate::lan_rendezvous::key());
                 } else if name == "permanent-password" {
                     value = Some(Config::get_permanent_password());
                 } else if name == "salt" {
                     value = Some(Config::get_salt());
                 } else if name == "rendezvous_server" {
+                    // the selected one first, then all of them by their health
+                    let servers = Config::rank_servers(
+                        Config::get_rendezvous_servers(),
+                        config::RENDEZVOUS_PORT,
+                    );
                     value = Some(format!(
                         "{},{}",
                         Config::get_rendezvous_server(),
-                        Config::get_rendezvous_servers().join(",")
+                        servers.join(",")
                     ));
+                } else if name == "relay_server" {
+                    value = Some(Config::get_relay_server());
                 } else if name == "rendezvous_servers" {
                     value = Some(Config::get_rendezvous_servers().join(","));
                 } else if name == "fingerprint" {
@@ -449,13 +490,19 @@ async fn handle(data: Data, stream: &mut Connection) {
                 let v = Config::get_options();
                 allow_err!(stream.send(&Data::Options(Some(v))).await);
             }
-            Some(value) => {
+            Some(mut value) => {
                 let _chk = CheckIfRestart::new();
                 if let Some(v) = value.get("privacy-mode-impl-key") {
                     crate::privacy_mode::switch(v);
                 }
                 let pre_opts = Config::get_options();
                 let new_audio_input = pre_opts.get("audio-input");
+                // only set by `CompareAndSet2fa`, a stale copy of the options must not bring
+                // back a used HOTP counter or recovery code
+                match pre_opts.get("2fa") {
+                    Some(v) => value.insert("2fa".to_owned(), v.clone()),
+                    None => value.remove("2fa"),
+                };
                 Config::set_options(value);
                 if new_audio_input != pre_opts.get("audio-input") {
                     crate::audio_service::restart();
@@ -518,6 +565,27 @@ async fn handle(data: Data, stream: &mut Connection) {
                     .await
             );
         }
+        Data::LoginLockouts(_) => {
+            allow_err!(
+                stream
+                    .send(&Data::LoginLockouts(Some(login_lockout::list())))
+                    .await
+            );
+        }
+        Data::ClearLoginLockouts(key) => {
+            let n = login_lockout::clear(key.as_deref());
+            log::info!("{} login failure entries cleared", n);
+            allow_err!(
+                stream
+                    .send(&Data::LoginLockouts(Some(login_lockout::list())))
+                    .await
+            );
+        }
+        Data::CompareAndSet2fa((expected, value)) => {
+            let res = crate::auth_2fa::compare_and_set(&expected, value);
+            allow_err!(stream.send(&Data::CompareAndSet2faResult(res)).await);
+        }
+        Data::WatchTemporaryPassword(_) => watch_temporary_password(stream).await,
         Data::CheckHwcodec =>
         {
             #[cfg(feature = "hwcodec")]
@@ -530,6 +598,45 @@ async fn handle(data: Data, stream: &mut Connection) {
     }
 }
 
+// Until the connection is closed. The password is also replaced when it is read after its
+// rotation time, and it is empty after its expiry, so it is read again at the nearer of them.
+async fn watch_temporary_password(stream: &mut Connection) {
+    const MAX_WAIT: u64 = 60;
+    let mut changed = password::TEMPORARY_PASSWORD_CHANGED.subscribe();
+    let mut last = None;
+    loop {
+        let info = password::temporary_password_info();
+        if last.as_ref() != Some(&info.password) {
+            last = Some(info.password.clone());
+            if stream
+                .send(&Data::WatchTemporaryPassword(Some(info.clone())))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+        let wait = [info.expires_in, info.rotates_in]
+            .into_iter()
+            .flatten()
+            .min()
+            .map_or(MAX_WAIT, |secs| (secs + 1).min(MAX_WAIT));
+        tokio::select! {
+            res = changed.changed() => {
+                if res.is_err() {
+                    return;
+                }
+            }
+            res = stream.next() => {
+                if res.is_err() {
+                    return;
+                }
+            }
+            _ = tokio::time::sleep(std::time::Duration::from_secs(wait)) => {}
+        }
+    }
+}
+
 pub async fn connect(ms_timeout: u64, postfix: &str) -> ResultType<ConnectionTmpl<ConnClient>> {
     let path = Config::ipc_path(postfix);
     let client = timeout(ms_timeout, Endpoint::connect(&path)).await??;
@@ -760,6 +867,18 @@ pub fn update_temporary_password() -> ResultType<()> {
     set_config("temporary-password", "".to_owned())
 }
 
+pub fn get_temporary_password_info() -> Option<password::TemporaryPasswordInfo> {
+    let v = get_config("temporary-password-info").ok()??;
+    serde_json::from_str(&v).ok()
+}
+
+pub fn get_lan_rendezvous_key() -> String {
+    get_config("lan-rendezvous-key")
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
 pub fn get_permanent_password() -> String {
     if let Ok(Some(v)) = get_config("permanent-password") {
         Config::set_permanent_password(&v);
@@ -810,6 +929,14 @@ pub async fn get_rendezvous_server(ms_timeout: u64) -> (String, Vec<String>) {
     }
 }
 
+pub async fn get_relay_server(ms_timeout: u64) -> String {
+    if let Ok(Some(v)) = get_config_async("relay_server", ms_timeout).await {
+        v
+    } else {
+        Config::get_relay_server()
+    }
+}
+
 async fn get_options_(ms_timeout: u64) -> ResultType<HashMap<String, String>> {
     let mut c = connect(ms_timeout, "").await?;
     c.send(&Data::Options(None)).await?;
@@ -838,6 +965,16 @@ pub async fn get_option_async(key: &str) -> String {
     }
 }
 
+#[tokio::main(flavor = "current_thread")]
+pub async fn compare_and_set_2fa(expected: String, value: String) -> ResultType<bool> {
+    let mut c = connect(1000, "").await?;
+    c.send(&Data::CompareAndSet2fa((expected, value))).await?;
+    if let Some(Data::CompareAndSet2faResult(res)) = c.next_timeout(1000).await? {
+        return Ok(res);
+    }
+    bail!("no response of 2fa");
+}
+
 pub fn set_option(key: &str, value: &str) {
     let mut options = get_options();
     if value.is_empty() {
@@ -884,6 +1021,26 @@ pub async fn get_rendezvous_servers(ms_timeout: u64) -> Vec<String> {
     return Config::get_rendezvous_servers();
 }
 
+async fn login_lockouts_(data: Data) -> ResultType<Vec<LoginLockout>> {
+    let mut c = connect(1_000, "").await?;
+    c.send(&data).await?;
+    if let Some(Data::LoginLockouts(Some(v))) = c.next_timeout(1_000).await? {
+        Ok(v)
+    } else {
+        bail!("Failed to get login lockouts");
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn get_login_lockouts() -> ResultType<Vec<LoginLockout>> {
+    login_lockouts_(Data::LoginLockouts(None)).await
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn clear_login_lockouts(key: Option<String>) -> ResultType<Vec<LoginLockout>> {
+    login_lockouts_(Data::ClearLoginLockouts(key)).await
+}
+
 #[inline]
 async fn get_socks_(ms_timeout: u64) -> ResultType<Option<config::Socks5Server>> {
     let mut c = connect(ms_timeout, "").await?;
@@ -0,0 +1,914 @@
+// A minimal rendezvous and relay server run by a designated instance, for the sites where hbbs and
+// hbbr can not be run, e.g. air-gapped networks. The other instances set their ID server to the
+// host of this instance and their key to its key, see `--get-lan-rendezvous-key`.
+//
+// Like hbbs and hbbr it listens on
+// - `port` udp for the registration of the peers,
+// - `port` tcp for the punch hole and relay requests,
+// - `port - 1` tcp for the nat test and the online states,
+// - `port + 1` tcp for the relay.
+//
+// The online peers are only kept in memory, their uuids and public keys are saved so that an ID
+// can not be taken by another machine after a restart.
+//
+// The server signs with its own key pair, generated once and saved beside the peers, not with
+// the one of this instance, so what it signs can not pass for this instance.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use hbb_common::{
+    allow_err, bail,
+    bytes::Bytes,
+    config::{Config, RENDEZVOUS_PORT},
+    log,
+    message_proto::IdPk,
+    protobuf::Message as _,
+    rendezvous_proto::*,
+    sleep,
+    sodiumoxide::crypto::{box_, sign},
+    tcp::{self, FramedStream},
+    tokio::{
+        self,
+        net::TcpListener,
+        select,
+        sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    },
+    udp::FramedSocket,
+    AddrMangle, ResultType, TargetAddr,
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+type Message = RendezvousMessage;
+
+// "Y" to run the server on this instance
+pub const OPTION_LAN_RENDEZVOUS_SERVER: &str = "lan-rendezvous-server";
+// the rendezvous port, the one of hbbs if empty
+pub const OPTION_LAN_RENDEZVOUS_PORT: &str = "lan-rendezvous-server-port";
+
+// a peer is offline if it has not registered for this long, like hbbs
+const REG_TIMEOUT: Duration = Duration::from_secs(30);
+// the idle connections and the relay connections without the other side are closed after it
+const CONN_TIMEOUT: u64 = 30_000;
+const PEERS_FILE: &str = "lan_rendezvous_peers.json";
+const KEY_FILE: &str = "lan_rendezvous_key";
+
+lazy_static::lazy_static! {
+    static ref KEY_LOCK: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Debug, Clone, Default)]
+struct Peer {
+    uuid: Bytes,
+    pk: Bytes,
+    addr: Option<SocketAddr>,
+    last_reg: Option<Instant>,
+}
+
+impl Peer {
+    fn online_addr(&self) -> Option<SocketAddr> {
+        match self.last_reg {
+            Some(t) if t.elapsed() < REG_TIMEOUT => self.addr,
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct SavedPeer {
+    uuid: String,
+    pk: String,
+}
+
+// a request waiting for the answer of the peer
+struct Pending {
+    id: String,
+    // the address of this host seen by the requester
+    local_ip: IpAddr,
+    tx: UnboundedSender<Message>,
+}
+
+#[derive(Default)]
+struct State {
+    peers: HashMap<String, Peer>,
+    // by the mangled address of the requester
+    pending: HashMap<Vec<u8>, Pending>,
+    // the relay connections waiting for the other side, by uuid
+    relays: HashMap<String, (FramedStream, Instant)>,
+}
+
+#[derive(Clone)]
+struct LanServer {
+    port: u16,
+    sk: sign::SecretKey,
+    // the licence key of the clients
+    key: String,
+    peers_file: Option<PathBuf>,
+    state: Arc<Mutex<State>>,
+    udp_tx: UnboundedSender<(Message, SocketAddr)>,
+}
+
+pub struct Sockets {
+    port: u16,
+    udp: FramedSocket,
+    tcp: TcpListener,
+    nat: TcpListener,
+    relay: TcpListener,
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    Config::get_option(OPTION_LAN_RENDEZVOUS_SERVER) == "Y"
+}
+
+fn get_port() -> u16 {
+    match Config::get_option(OPTION_LAN_RENDEZVOUS_PORT).parse::<u16>() {
+        Ok(port) if port > 1 && port < u16::MAX => port,
+        _ => RENDEZVOUS_PORT as _,
+    }
+}
+
+/// The key the other instances set to use this server, the public key of the server.
+pub fn key() -> String {
+    match get_secret_key() {
+        Ok(sk) => crate::encode64(&sk.0[sign::SECRETKEYBYTES - sign::PUBLICKEYBYTES..]),
+        Err(err) => {
+            log::error!("Failed to get the LAN rendezvous key: {}", err);
+            "".to_owned()
+        }
+    }
+}
+
+// The secret key of the server, generated at the first use.
+fn get_secret_key() -> ResultType<sign::SecretKey> {
+    let _lock = KEY_LOCK.lock().unwrap();
+    let file = Config::path(KEY_FILE);
+    if let Ok(v) = std::fs::read_to_string(&file) {
+        // not replaced, the other instances have its public key
+        let Some(sk) = crate::decode64(v.trim())
+            .ok()
+            .and_then(|v| sign::SecretKey::from_slice(&v))
+        else {
+            bail!("invalid key pair in {:?}", file);
+        };
+        return Ok(sk);
+    }
+    let (_, sk) = sign::gen_keypair();
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut f = options.open(&file)?;
+    std::io::Write::write_all(&mut f, crate::encode64(&sk.0).as_bytes())?;
+    log::info!("LAN rendezvous key pair generated");
+    Ok(sk)
+}
+
+/// Run the server while it is enabled.
+pub async fn start() {
+    loop {
+        if is_enabled() {
+            let port = get_port();
+            match (get_secret_key(), bind(port).await) {
+                (Ok(sk), Ok(sockets)) => {
+                    log::info!("LAN rendezvous server started on port {}", port);
+                    select! {
+                        res = serve(sockets, sk, Some(Config::path(PEERS_FILE))) => {
+                            allow_err!(res);
+                        }
+                        _ = wait_changed(port) => {
+                            log::info!("LAN rendezvous server stopped");
+                        }
+                    }
+                }
+                (Err(err), _) => {
+                    log::error!("LAN rendezvous server: {}", err);
+                    wait_changed(port).await;
+                }
+                (_, Err(err)) => {
+                    log::error!("Failed to start LAN rendezvous server on {}: {}", port, err);
+                    wait_changed(port).await;
+                }
+            }
+        }
+        sleep(1.).await;
+    }
+}
+
+async fn wait_changed(port: u16) {
+    while is_enabled() && get_port() == port {
+        sleep(1.).await;
+    }
+}
+
+pub async fn bind(port: u16) -> ResultType<Sockets> {
+    Ok(Sockets {
+        port,
+        udp: FramedSocket::new(SocketAddr::from(([0, 0, 0, 0], port))).await?,
+        tcp: tcp::listen_any(port).await?,
+        nat: tcp::listen_any(port - 1).await?,
+        relay: tcp::listen_any(port + 1).await?,
+    })
+}
+
+pub async fn serve(
+    sockets: Sockets,
+    sk: sign::SecretKey,
+    peers_file: Option<PathBuf>,
+) -> ResultType<()> {
+    let (udp_tx, udp_rx) = unbounded_channel();
+    let mut state = State::default();
+    if let Some(file) = peers_file.as_ref() {
+        state.peers = load_peers(file);
+    }
+    let server = LanServer {
+        port: sockets.port,
+        key: crate::encode64(&sk.0[sign::SECRETKEYBYTES - sign::PUBLICKEYBYTES..]),
+        sk,
+        peers_file,
+        state: Arc::new(Mutex::new(state)),
+        udp_tx,
+    };
+    select! {
+        res = server.clone().serve_udp(sockets.udp, udp_rx) => res,
+        res = server.clone().serve_tcp(sockets.tcp) => res,
+        res = server.clone().serve_tcp(sockets.nat) => res,
+        res = server.serve_relay(sockets.relay) => res,
+    }
+}
+
+fn load_peers(file: &PathBuf) -> HashMap<String, Peer> {
+    let Ok(v) = std::fs::read_to_string(file) else {
+        return Default::default();
+    };
+    let saved: HashMap<String, SavedPeer> = serde_json::from_str(&v).unwrap_or_default();
+    saved
+        .into_iter()
+        .filter_map(|(id, p)| {
+            let peer = Peer {
+                uuid: crate::decode64(p.uuid).ok()?.into(),
+                pk: crate::decode64(p.pk).ok()?.into(),
+                ..Default::default()
+            };
+            Some((id, peer))
+        })
+        .collect()
+}
+
+// the ids of hbbs
+fn is_valid_id(id: &str) -> bool {
+    (6..=16).contains(&id.len())
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// the listeners are dual stack
+fn normalize(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        _ => addr,
+    }
+}
+
+impl LanServer {
+    fn relay_server(&self, ip: IpAddr) -> String {
+        SocketAddr::new(ip, self.port + 1).to_string()
+    }
+
+    fn sign_id_pk(&self, id: &str, pk: Bytes) -> Vec<u8> {
+        let id_pk = IdPk {
+            id: id.to_owned(),
+            pk,
+            ..Default::default()
+        };
+        sign::sign(&id_pk.write_to_bytes().unwrap_or_default(), &self.sk)
+    }
+
+    fn save_peers(&self, peers: &HashMap<String, Peer>) {
+        let Some(file) = self.peers_file.as_ref() else {
+            return;
+        };
+        let saved: HashMap<&String, SavedPeer> = peers
+            .iter()
+            .map(|(id, p)| {
+                let peer = SavedPeer {
+                    uuid: crate::encode64(&p.uuid),
+                    pk: crate::encode64(&p.pk),
+                };
+                (id, peer)
+            })
+            .collect();
+        match serde_json::to_string(&saved) {
+            Ok(v) => allow_err!(std::fs::write(file, v)),
+            Err(err) => log::error!("Failed to save the peers: {}", err),
+        }
+    }
+
+    async fn serve_udp(
+        self,
+        mut socket: FramedSocket,
+        mut rx: UnboundedReceiver<(Message, SocketAddr)>,
+    ) -> ResultType<()> {
+        loop {
+            select! {
+                res = socket.next() => match res {
+                    Some(Ok((bytes, TargetAddr::Ip(addr)))) => {
+                        if let Ok(msg) = Message::parse_from_bytes(&bytes) {
+                            if let Some(msg_out) = self.handle_udp(msg, addr) {
+                                allow_err!(socket.send(&msg_out, addr).await);
+                            }
+                        }
+                    }
+                    Some(Ok(_)) => {}
+                    // e.g. the icmp port unreachable of a peer gone on windows
+                    Some(Err(err)) => log::debug!("LAN rendezvous server udp error: {}", err),
+                    None => bail!("LAN rendezvous server udp socket closed"),
+                },
+                Some((msg, addr)) = rx.recv() => {
+                    allow_err!(socket.send(&msg, addr).await);
+                }
+            }
+        }
+    }
+
+    fn handle_udp(&self, msg: Message, addr: SocketAddr) -> Option<Message> {
+        let mut msg_out = Message::new();
+        match msg.union {
+            Some(rendezvous_message::Union::RegisterPeer(rp)) => {
+                let mut state = self.state.lock().unwrap();
+                // the key is checked again if the ip changes, and the address is only updated then
+                let request_pk = match state.peers.get_mut(&rp.id) {
+                    Some(peer) if peer.addr.map(|a| a.ip()) == Some(addr.ip()) => {
+                        peer.addr = Some(addr);
+                        peer.last_reg = Some(Instant::now());
+                        false
+                    }
+                    _ => true,
+                };
+                msg_out.set_register_peer_response(RegisterPeerResponse {
+                    request_pk,
+                    ..Default::default()
+                });
+            }
+            Some(rendezvous_message::Union::RegisterPk(rk)) => {
+                let result = self.register_pk(rk, addr);
+                msg_out.set_register_pk_response(RegisterPkResponse {
+                    result: result.into(),
+                    ..Default::default()
+                });
+            }
+            _ => return None,
+        }
+        Some(msg_out)
+    }
+
+    fn register_pk(&self, rk: RegisterPk, addr: SocketAddr) -> register_pk_response::Result {
+        if !is_valid_id(&rk.id) || rk.uuid.is_empty() || rk.pk.len() != sign::PUBLICKEYBYTES {
+            return register_pk_response::Result::INVALID_ID_FORMAT;
+        }
+        let mut state = self.state.lock().unwrap();
+        let changed = match state.peers.get(&rk.id) {
+            Some(peer) if peer.uuid != rk.uuid => {
+                log::warn!("UUID mismatch of {} from {}", rk.id, addr);
+                return register_pk_response::Result::UUID_MISMATCH;
+            }
+            Some(peer) => peer.pk != rk.pk,
+            None => true,
+        };
+        let peer = state.peers.entry(rk.id.clone()).or_default();
+        peer.uuid = rk.uuid;
+        peer.pk = rk.pk;
+        peer.addr = Some(addr);
+        peer.last_reg = Some(Instant::now());
+        if changed {
+            log::info!("Peer {} registered from {}", rk.id, addr);
+            self.save_peers(&state.peers);
+        }
+        register_pk_response::Result::OK
+    }
+
+    async fn serve_tcp(self, listener: TcpListener) -> ResultType<()> {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            stream.set_nodelay(true).ok();
+            let local_addr = normalize(stream.local_addr()?);
+            let server = self.clone();
+            tokio::spawn(async move {
+                let stream = FramedStream::from(stream, local_addr);
+                allow_err!(server.handle_tcp(stream, normalize(addr)).await);
+            });
+        }
+    }
+
+    async fn handle_tcp(self, mut stream: FramedStream, addr: SocketAddr) -> ResultType<()> {
+        let (tx, mut rx) = unbounded_channel();
+        let res = self.handle_tcp_(&mut stream, addr, tx, &mut rx).await;
+        let socket_addr = AddrMangle::encode(addr);
+        self.state.lock().unwrap().pending.remove(&socket_addr);
+        res
+    }
+
+    async fn handle_tcp_(
+        &self,
+        stream: &mut FramedStream,
+        addr: SocketAddr,
+        tx: UnboundedSender<Message>,
+        rx: &mut UnboundedReceiver<Message>,
+    ) -> ResultType<()> {
+        // the clients with a token secure the connection, the others skip it
+        let (our_pk_b, our_sk_b) = box_::gen_keypair();
+        let mut msg_out = Message::new();
+        msg_out.set_key_exchange(KeyExchange {
+            keys: vec![sign::sign(&our_pk_b.0, &self.sk).into()],
+            ..Default::default()
+        });
+        stream.send(&msg_out).await?;
+        loop {
+            select! {
+                res = stream.next_timeout(CONN_TIMEOUT) => {
+                    let Some(bytes) = res else {
+                        break;
+                    };
+                    let bytes = bytes?;
+                    if bytes.is_empty() {
+                        // heartbeat
+                        stream.send_bytes(Bytes::new()).await?;
+                        continue;
+                    }
+                    let msg = Message::parse_from_bytes(&bytes)?;
+                    if let Some(rendezvous_message::Union::KeyExchange(ex)) = &msg.union {
+                        if ex.keys.len() != 2 {
+                            bail!("Handshake failed: invalid key exchange message");
+                        }
+                        let key = tcp::Encrypt::decode(&ex.keys[1], &ex.keys[0], &our_sk_b)?;
+                        stream.set_key(key);
+                        continue;
+                    }
+                    let local_addr = stream.local_addr();
+                    if let Some(msg_out) = self.handle_tcp_msg(msg, addr, local_addr, &tx) {
+                        stream.send(&msg_out).await?;
+                    }
+                }
+                Some(msg) = rx.recv() => {
+                    stream.send(&msg).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_tcp_msg(
+        &self,
+        msg: Message,
+        addr: SocketAddr,
+        local_addr: SocketAddr,
+        tx: &UnboundedSender<Message>,
+    ) -> Option<Message> {
+        let mut msg_out = Message::new();
+        match msg.union {
+            Some(rendezvous_message::Union::PunchHoleRequest(ph)) => {
+                let failure = self.handle_punch_hole_request(ph, addr, local_addr.ip(), tx)?;
+                msg_out.set_punch_hole_response(PunchHoleResponse {
+                    failure: failure.into(),
+                    ..Default::default()
+                });
+            }
+            Some(rendezvous_message::Union::PunchHoleSent(phs)) => {
+                self.handle_punch_hole_sent(phs, addr);
+                return None;
+            }
+            Some(rendezvous_message::Union::RequestRelay(rr)) => {
+                let refuse_reason = self.handle_request_relay(rr, addr, local_addr.ip(), tx)?;
+                msg_out.set_relay_response(RelayResponse {
+                    refuse_reason,
+                    ..Default::default()
+                });
+            }
+            Some(rendezvous_message::Union::RelayResponse(rr)) => {
+                self.handle_relay_response(rr);
+                return None;
+            }
+            Some(rendezvous_message::Union::TestNatRequest(_)) => {
+                msg_out.set_test_nat_response(TestNatResponse {
+                    port: addr.port() as _,
+                    ..Default::default()
+                });
+            }
+            Some(rendezvous_message::Union::OnlineRequest(or)) => {
+                let state = self.state.lock().unwrap();
+                let mut states = vec![0u8; (or.peers.len() + 7) / 8];
+                for (i, id) in or.peers.iter().enumerate() {
+                    if state.peers.get(id).and_then(|p| p.online_addr()).is_some() {
+                        // bytes index from left to right
+                        states[i / 8] |= 0x01 << (7 - i % 8);
+                    }
+                }
+                msg_out.set_online_response(OnlineResponse {
+                    states: states.into(),
+                    ..Default::default()
+                });
+            }
+            _ => return None,
+        }
+        Some(msg_out)
+    }
+
+    // Ask the peer to punch hole to the requester, the failure if it can not be asked.
+    fn handle_punch_hole_request(
+        &self,
+        ph: PunchHoleRequest,
+        addr: SocketAddr,
+        local_ip: IpAddr,
+        tx: &UnboundedSender<Message>,
+    ) -> Option<punch_hole_response::Failure> {
+        if ph.licence_key.trim() != self.key {
+            return Some(punch_hole_response::Failure::LICENSE_MISMATCH);
+        }
+        let peer_addr = match self.request_peer(&ph.id, addr, local_ip, tx) {
+            Ok(peer_addr) => peer_addr,
+            Err(failure) => return Some(failure),
+        };
+        log::debug!("Punch hole request from {} to {}", addr, ph.id);
+        let mut msg_out = Message::new();
+        msg_out.set_punch_hole(PunchHole {
+            socket_addr: AddrMangle::encode(addr).into(),
+            relay_server: self.relay_server_for(peer_addr, local_ip),
+            nat_type: ph.nat_type,
+            quic: ph.quic,
+            ..Default::default()
+        });
+        self.udp_tx.send((msg_out, peer_addr)).ok();
+        None
+    }
+
+    // Add the pending request to the peer, its address if it is online.
+    fn request_peer(
+        &self,
+        id: &str,
+        addr: SocketAddr,
+        local_ip: IpAddr,
+        tx: &UnboundedSender<Message>,
+    ) -> Result<SocketAddr, punch_hole_response::Failure> {
+        let mut state = self.state.lock().unwrap();
+        let Some(peer) = state.peers.get(id) else {
+            return Err(punch_hole_response::Failure::ID_NOT_EXIST);
+        };
+        let Some(peer_addr) = peer.online_addr() else {
+            return Err(punch_hole_response::Failure::OFFLINE);
+        };
+        let pending = Pending {
+            id: id.to_owned(),
+            local_ip,
+            tx: tx.clone(),
+        };
+        state.pending.insert(AddrMangle::encode(addr), pending);
+        Ok(peer_addr)
+    }
+
+    // the relay server the peer can reach
+    fn relay_server_for(&self, peer_addr: SocketAddr, local_ip: IpAddr) -> String {
+        self.relay_server(crate::lan::get_ipaddr_by_peer(peer_addr).unwrap_or(local_ip))
+    }
+
+    fn handle_punch_hole_sent(&self, phs: PunchHoleSent, addr: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        let Some(pending) = state.pending.remove(&phs.socket_addr[..]) else {
+            return;
+        };
+        if pending.id != phs.id {
+            log::warn!(
+                "Punch hole of {} sent by {} from {}",
+                pending.id,
+                phs.id,
+                addr
+            );
+            return;
+        }
+        let Some(pk) = state.peers.get(&pending.id).map(|p| p.pk.clone()) else {
+            return;
+        };
+        drop(state);
+        // the peer is on this host
+        let mut peer_addr = addr;
+        if peer_addr.ip().is_loopback() {
+            peer_addr.set_ip(pending.local_ip);
+        }
+        let mut msg_out = Message::new();
+        let mut ph = PunchHoleResponse {
+            socket_addr: AddrMangle::encode(peer_addr).into(),
+            pk: self.sign_id_pk(&pending.id, pk).into(),
+            relay_server: self.relay_server(pending.local_ip),
+            quic: phs.quic,
+            ..Default::default()
+        };
+        ph.set_nat_type(phs.nat_type.enum_value_or_default());
+        msg_out.set_punch_hole_response(ph);
+        pending.tx.send(msg_out).ok();
+    }
+
+    // Ask the peer to connect to the relay, the refuse reason if it can not be asked.
+    fn handle_request_relay(
+        &self,
+        rr: RequestRelay,
+        addr: SocketAddr,
+        local_ip: IpAddr,
+        tx: &UnboundedSender<Message>,
+    ) -> Option<String> {
+        if rr.licence_key.trim() != self.key {
+            return Some("Key mismatch".to_owned());
+        }
+        let peer_addr = match self.request_peer(&rr.id, addr, local_ip, tx) {
+            Ok(peer_addr) => peer_addr,
+            Err(punch_hole_response::Failure::OFFLINE) => {
+                return Some("Remote desktop is offline".to_owned())
+            }
+            Err(_) => return Some("ID does not exist".to_owned()),
+        };
+        log::debug!("Relay request from {} to {}", addr, rr.id);
+        let mut msg_out = Message::new();
+        msg_out.set_request_relay(RequestRelay {
+            socket_addr: AddrMangle::encode(addr).into(),
+            relay_server: self.relay_server_for(peer_addr, local_ip),
+            uuid: rr.uuid,
+            secure: rr.secure,
+            ..Default::default()
+        });
+        self.udp_tx.send((msg_out, peer_addr)).ok();
+        None
+    }
+
+    // The peer connects to the relay, requested by the requester or by itself.
+    fn handle_relay_response(&self, rr: RelayResponse) {
+        let mut state = self.state.lock().unwrap();
+        let Some(pending) = state.pending.remove(&rr.socket_addr[..]) else {
+            return;
+        };
+        if !rr.id().is_empty() && rr.id() != pending.id {
+            log::warn!("Relay of {} sent by {}", pending.id, rr.id());
+            return;
+        }
+        let Some(pk) = state.peers.get(&pending.id).map(|p| p.pk.clone()) else {
+            return;
+        };
+        drop(state);
+        let mut msg_out = Message::new();
+        let mut rr = RelayResponse {
+            socket_addr: rr.socket_addr,
+            uuid: rr.uuid,
+            relay_server: self.relay_server(pending.local_ip),
+            version: rr.version,
+            ..Default::default()
+        };
+        rr.set_pk(self.sign_id_pk(&pending.id, pk).into());
+        msg_out.set_relay_response(rr);
+        pending.tx.send(msg_out).ok();
+    }
+
+    async fn serve_relay(self, listener: TcpListener) -> ResultType<()> {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            stream.set_nodelay(true).ok();
+            let local_addr = stream.local_addr()?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                let stream = FramedStream::from(stream, local_addr);
+                allow_err!(server.handle_relay(stream, normalize(addr)).await);
+            });
+        }
+    }
+
+    async fn handle_relay(self, mut stream: FramedStream, addr: SocketAddr) -> ResultType<()> {
+        let Some(bytes) = stream.next_timeout(CONN_TIMEOUT).await else {
+            return Ok(());
+        };
+        let Some(rendezvous_message::Union::RequestRelay(rr)) =
+            Message::parse_from_bytes(&bytes?)?.union
+        else {
+            bail!("Invalid relay request from {}", addr);
+        };
+        if rr.licence_key.trim() != self.key {
+            bail!("Key mismatch of the relay request from {}", addr);
+        }
+        if rr.uuid.is_empty() {
+            bail!("Empty uuid of the relay request from {}", addr);
+        }
+        let other = {
+            let mut state = self.state.lock().unwrap();
+            let timeout = Duration::from_millis(CONN_TIMEOUT);
+            state.relays.retain(|_, (_, t)| t.elapsed() < timeout);
+            match state.relays.remove(&rr.uuid) {
+                Some((other, _)) => other,
+                None => {
+                    state.relays.insert(rr.uuid, (stream, Instant::now()));
+                    return Ok(());
+                }
+            }
+        };
+        log::info!("Relay {} started by {}", rr.uuid, addr);
+        relay(stream, other).await;
+        log::info!("Relay {} ended", rr.uuid);
+        Ok(())
+    }
+}
+
+async fn relay(mut a: FramedStream, mut b: FramedStream) {
+    loop {
+        select! {
+            res = a.next() => match res {
+                Some(Ok(bytes)) if b.send_bytes(bytes.freeze()).await.is_ok() => {}
+                _ => break,
+            },
+            res = b.next() => match res {
+                Some(Ok(bytes)) if a.send_bytes(bytes.freeze()).await.is_ok() => {}
+                _ => break,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hbb_common::{socket_client::connect_tcp, timeout};
+
+    async fn next_msg(stream: &mut FramedStream) -> Option<rendezvous_message::Union> {
+        loop {
+            let bytes = stream.next_timeout(3_000).await?.ok()?;
+            let msg = Message::parse_from_bytes(&bytes).ok()?;
+            if !matches!(msg.union, Some(rendezvous_message::Union::KeyExchange(_))) {
+                return msg.union;
+            }
+        }
+    }
+
+    async fn register(socket: &mut FramedSocket, server: SocketAddr, id: &str, pk: &[u8]) {
+        let mut msg_out = Message::new();
+        msg_out.set_register_pk(RegisterPk {
+            id: id.to_owned(),
+            uuid: id.as_bytes().to_vec().into(),
+            pk: pk.to_vec().into(),
+            ..Default::default()
+        });
+        socket.send(&msg_out, server).await.unwrap();
+        let (bytes, _) = socket.next_timeout(3_000).await.unwrap().unwrap();
+        let msg = Message::parse_from_bytes(&bytes).unwrap();
+        let Some(rendezvous_message::Union::RegisterPkResponse(rpr)) = msg.union else {
+            panic!("unexpected {:?}", msg);
+        };
+        assert_eq!(
+            rpr.result.enum_value(),
+            Ok(register_pk_response::Result::OK)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_loopback() {
+        let mut sockets = None;
+        for _ in 0..10 {
+            let port = 30_000 + hbb_common::rand::random::<u16>() % 20_000;
+            if let Ok(s) = bind(port).await {
+                sockets = Some(s);
+                break;
+            }
+        }
+        let sockets = sockets.unwrap();
+        let port = sockets.port;
+        let (pk, sk) = sign::gen_keypair();
+        let key = crate::encode64(&pk);
+        tokio::spawn(serve(sockets, sk, None));
+        let server = SocketAddr::from(([127, 0, 0, 1], port));
+
+        // the controlled instance
+        let mut peer = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let (peer_pk, _) = sign::gen_keypair();
+        register(&mut peer, server, "123456789", &peer_pk.0).await;
+        let mut other = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let mut msg_out = Message::new();
+        msg_out.set_register_pk(RegisterPk {
+            id: "123456789".to_owned(),
+            uuid: b"another".to_vec().into(),
+            pk: peer_pk.0.to_vec().into(),
+            ..Default::default()
+        });
+        other.send(&msg_out, server).await.unwrap();
+        let (bytes, _) = other.next_timeout(3_000).await.unwrap().unwrap();
+        let Some(rendezvous_message::Union::RegisterPkResponse(rpr)) =
+            Message::parse_from_bytes(&bytes).unwrap().union
+        else {
+            panic!("no register pk response");
+        };
+        assert_eq!(
+            rpr.result.enum_value(),
+            Ok(register_pk_response::Result::UUID_MISMATCH)
+        );
+
+        // online states
+        let mut conn = connect_tcp(SocketAddr::from(([127, 0, 0, 1], port - 1)), 3_000)
+            .await
+            .unwrap();
+        let mut msg_out = Message::new();
+        msg_out.set_online_request(OnlineRequest {
+            peers: vec!["123456789".to_owned(), "987654321".to_owned()],
+            ..Default::default()
+        });
+        conn.send(&msg_out).await.unwrap();
+        let Some(rendezvous_message::Union::OnlineResponse(or)) = next_msg(&mut conn).await else {
+            panic!("no online response");
+        };
+        assert_eq!(or.states.to_vec(), vec![0x80]);
+
+        // the controlling instance
+        let mut conn = connect_tcp(server, 3_000).await.unwrap();
+        let mut msg_out = Message::new();
+        msg_out.set_punch_hole_request(PunchHoleRequest {
+            id: "123456789".to_owned(),
+            licence_key: "wrong".to_owned(),
+            ..Default::default()
+        });
+        conn.send(&msg_out).await.unwrap();
+        let Some(rendezvous_message::Union::PunchHoleResponse(ph)) = next_msg(&mut conn).await
+        else {
+            panic!("no punch hole response");
+        };
+        assert_eq!(
+            ph.failure.enum_value(),
+            Ok(punch_hole_response::Failure::LICENSE_MISMATCH)
+        );
+        // not forwarded to the peer, whose next message is the punch hole below
+        msg_out.set_request_relay(RequestRelay {
+            id: "123456789".to_owned(),
+            uuid: "uuid".to_owned(),
+            licence_key: "wrong".to_owned(),
+            ..Default::default()
+        });
+        conn.send(&msg_out).await.unwrap();
+        let Some(rendezvous_message::Union::RelayResponse(rr)) = next_msg(&mut conn).await else {
+            panic!("no relay response");
+        };
+        assert_eq!(rr.refuse_reason, "Key mismatch");
+        msg_out.set_punch_hole_request(PunchHoleRequest {
+            id: "123456789".to_owned(),
+            licence_key: key.clone(),
+            quic: true,
+            ..Default::default()
+        });
+        conn.send(&msg_out).await.unwrap();
+
+        let (bytes, _) = peer.next_timeout(3_000).await.unwrap().unwrap();
+        let Some(rendezvous_message::Union::PunchHole(ph)) =
+            Message::parse_from_bytes(&bytes).unwrap().union
+        else {
+            panic!("no punch hole");
+        };
+        assert_eq!(AddrMangle::decode(&ph.socket_addr), conn.local_addr());
+        assert!(ph.quic);
+        let mut sent = connect_tcp(server, 3_000).await.unwrap();
+        let mut msg_out = Message::new();
+        msg_out.set_punch_hole_sent(PunchHoleSent {
+            socket_addr: ph.socket_addr,
+            id: "123456789".to_owned(),
+            quic: true,
+            ..Default::default()
+        });
+        sent.send(&msg_out).await.unwrap();
+        let Some(rendezvous_message::Union::PunchHoleResponse(ph)) = next_msg(&mut conn).await
+        else {
+            panic!("no punch hole response");
+        };
+        assert_eq!(AddrMangle::decode(&ph.socket_addr), sent.local_addr());
+        assert!(ph.quic);
+        assert_eq!(ph.relay_server, format!("127.0.0.1:{}", port + 1));
+        let (id, id_pk) = crate::common::decode_id_pk(&ph.pk, &pk).unwrap();
+        assert_eq!(id, "123456789");
+        assert_eq!(id_pk, peer_pk.0);
+
+        // relay
+        let mut a = connect_tcp(ph.relay_server.as_str(), 3_000).await.unwrap();
+        let mut b = connect_tcp(ph.relay_server.as_str(), 3_000).await.unwrap();
+        for s in [&mut a, &mut b] {
+            let mut msg_out = Message::new();
+            msg_out.set_request_relay(RequestRelay {
+                uuid: "uuid".to_owned(),
+                licence_key: key.clone(),
+                ..Default::default()
+            });
+            s.send(&msg_out).await.unwrap();
+        }
+        a.send_bytes(Bytes::from_static(b"ping")).await.unwrap();
+        let bytes = timeout(3_000, b.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!(&bytes[..], b"ping");
+        b.send_bytes(Bytes::from_static(b"pong")).await.unwrap();
+        let bytes = timeout(3_000, a.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!(&bytes[..], b"pong");
+    }
+}
@@ -609,5 +609,18 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("no_audio_input_device_tip", "Nebylo nalezeno žádné vstupní zvukové zařízení."),
         ("Incoming", ""),
         ("Outgoing", ""),
+        ("reverse_port_forward_tip", ""),
+        ("port_forward_request_tip", ""),
+        ("unattended_window_ending_tip", ""),
+        ("Preserve File Attributes", ""),
+        ("Mirror Folder", ""),
+        ("Files to add", ""),
+        ("Files to update", ""),
+        ("Extraneous files on the target", ""),
+        ("Delete extraneous files on the target", ""),
+        ("Port Forward Profiles", ""),
+        ("Forwards", ""),
+        ("Reverse Forwards", ""),
+        ("port_forward_profile_tip", ""),
     ].iter().cloned().collect();
 }
@@ -225,5 +225,13 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Follow remote window focus", ""),
         ("default_proxy_tip", "Default protocol and port are Socks5 and 1080"),
         ("no_audio_input_device_tip", "No audio input device found."),
+        ("reverse_port_forward_tip", "The peer asks to listen on this port and forward the connections to its side"),
+        ("port_forward_request_tip", "The peer asks to connect to this target through the port forward"),
+        ("unattended_window_ending_tip", "The unattended access window ends soon, the session will be closed."),
+        ("Preserve File Attributes", "Preserve file attributes"),
+        ("Mirror Folder", "Mirror folder"),
+        ("Port Forward Profiles", "Port forward profiles"),
+        ("Reverse Forwards", "Reverse forwards"),
+        ("port_forward_profile_tip", "One per line as local port:remote host:remote port, or remote port:local host:local port for reverse forwards. A remote port of 0 makes the local port a SOCKS5 proxy."),
     ].iter().cloned().collect();
 }
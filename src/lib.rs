@@ -15,6 +15,8 @@ mod client;
 #[cfg(not(any(target_os = "ios")))]
 mod lan;
 #[cfg(not(any(target_os = "ios")))]
+mod lan_rendezvous;
+#[cfg(not(any(target_os = "ios")))]
 mod rendezvous_mediator;
 #[cfg(not(any(target_os = "ios")))]
 pub use self::rendezvous_mediator::*;
@@ -39,10 +41,12 @@ pub mod flutter;
 pub mod flutter_ffi;
 use common::*;
 mod auth_2fa;
+mod auth_pk;
 #[cfg(feature = "cli")]
 pub mod cli;
 #[cfg(not(any(target_os = "android", target_os = "ios", feature = "cli")))]
 pub mod core_main;
+mod crypto;
 mod custom_server;
 mod lang;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
@@ -1,20 +1,41 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
 use crate::client::*;
 use hbb_common::{
     allow_err, bail,
-    config::READ_TIMEOUT,
+    bytes::Bytes,
+    config::{Config, CONNECT_TIMEOUT, READ_TIMEOUT},
     futures::{SinkExt, StreamExt},
     log,
     message_proto::*,
     protobuf::Message as _,
+    proxy::{
+        socks5_accept, socks5_reply, socks5_udp_make, socks5_udp_parse, Socks5Command,
+        SOCKS5_REPLY_GENERAL_FAILURE, SOCKS5_REPLY_HOST_UNREACHABLE, SOCKS5_REPLY_SUCCEEDED,
+    },
     rendezvous_proto::ConnType,
     tcp, timeout,
-    tokio::{self, net::TcpStream, sync::mpsc},
-    tokio_util::codec::{BytesCodec, Framed},
-    ResultType, Stream,
+    tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpStream, UdpSocket},
+        sync::mpsc,
+    },
+    tokio_util::codec::{BytesCodec, Framed, FramedRead},
+    ResultType, Stream, TargetAddr,
 };
 
+// seconds
+const RECONNECT_MAX_INTERVAL: u64 = 30;
+// messages queued per tunnel stream, or from all of them towards the peer
+const TUNNEL_QUEUE_SIZE: usize = 64;
+// milliseconds the connection waits for a full tunnel stream before closing it
+const TUNNEL_SEND_TIMEOUT: u64 = 3_000;
+
 fn run_rdp(port: u16) {
     std::process::Command::new("cmdkey")
         .arg("/delete:localhost")
@@ -66,11 +87,15 @@ pub async fn listen(
         tokio::select! {
             Ok((forward, addr)) = listener.accept() => {
                 log::info!("new connection from {:?}", addr);
-                lc.write().unwrap().port_forward = (remote_host.clone(), remote_port);
+                {
+                    let mut lc = lc.write().unwrap();
+                    lc.port_forward = (remote_host.clone(), remote_port);
+                    lc.port_forward_multiplex = false;
+                }
                 let id = id.clone();
                 let password = password.clone();
                 let mut forward = Framed::new(forward, BytesCodec::new());
-                match connect_and_login(&id, &password, &mut ui_receiver, interface.clone(), &mut forward, key, token, is_rdp).await {
+                match connect_and_login(&id, &password, &mut ui_receiver, interface.clone(), Some(&mut forward), key, token, is_rdp).await {
                     Ok(Some(stream)) => {
                         let interface = interface.clone();
                         tokio::spawn(async move {
@@ -103,12 +128,557 @@ pub async fn listen(
     Ok(())
 }
 
+/// Carries all the forwards over one connection, which is re-established when it drops.
+///
+/// `forwards` are (local port, remote host, remote port), a remote port of 0 makes the local
+/// port a socks5 proxy, whose connections and udp associations are opened on the peer.
+/// The proxy only listens on the loopback address, unless option "allow-socks5-forward-lan"
+/// opens it to the network.
+/// `reverse_forwards` are (remote port, local host, local port), listened on by the peer.
+pub async fn listen_multiplex(
+    id: String,
+    password: String,
+    forwards: Vec<(i32, String, i32)>,
+    reverse_forwards: Vec<(i32, String, i32)>,
+    interface: impl Interface,
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+    lc: Arc<RwLock<LoginConfigHandler>>,
+) -> ResultType<()> {
+    if forwards.is_empty() && reverse_forwards.is_empty() {
+        bail!("No port forward");
+    }
+    let mut listeners = Vec::new();
+    let socks5_lan = Config::get_option("allow-socks5-forward-lan") == "Y";
+    for (port, _, remote_port) in forwards.iter() {
+        let host = if *remote_port == 0 && !socks5_lan {
+            "127.0.0.1"
+        } else {
+            "0.0.0.0"
+        };
+        let listener = tcp::new_listener(format!("{}:{}", host, port), true).await?;
+        log::info!("listening on port {:?}", listener.local_addr()?);
+        listeners.push(listener);
+    }
+    let (tx_accept, mut rx_accept) = mpsc::unbounded_channel::<(usize, TcpStream)>();
+    let accept_tasks: Vec<_> = listeners
+        .into_iter()
+        .enumerate()
+        .map(|(i, listener)| {
+            let tx_accept = tx_accept.clone();
+            tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((forward, addr)) => {
+                            log::info!("new connection from {:?}", addr);
+                            if tx_accept.send((i, forward)).is_err() {
+                                break;
+                            }
+                        }
+                        Err(err) => {
+                            log::error!("Failed to accept: {}", err);
+                            break;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    let mut ui_receiver = ui_receiver;
+    let mut retries = 0;
+    loop {
+        {
+            let mut lc = lc.write().unwrap();
+            lc.port_forward = ("".to_owned(), 0);
+            lc.port_forward_multiplex = true;
+        }
+        match connect_and_login(
+            &id,
+            &password,
+            &mut ui_receiver,
+            interface.clone(),
+            None,
+            key,
+            token,
+            false,
+        )
+        .await
+        {
+            Ok(Some(stream)) => {
+                retries = 0;
+                match run_multiplex(
+                    stream,
+                    &forwards,
+                    &reverse_forwards,
+                    &mut rx_accept,
+                    &mut ui_receiver,
+                    interface.clone(),
+                )
+                .await
+                {
+                    Ok(_) => break,
+                    Err(err) => log::info!("multiplexed port forwarding disconnected: {}", err),
+                }
+            }
+            Ok(None) => break,
+            Err(err) => {
+                log::error!("Failed to connect for multiplexed port forwarding: {}", err);
+            }
+        }
+        retries += 1;
+        let secs = (1u64 << retries.min(5)).min(RECONNECT_MAX_INTERVAL);
+        log::info!("reconnect in {} seconds", secs);
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(secs)) => {}
+            d = ui_receiver.recv() => {
+                if matches!(d, Some(Data::Close) | None) {
+                    break;
+                }
+            }
+        }
+    }
+    for task in accept_tasks {
+        task.abort();
+    }
+    Ok(())
+}
+
+// Returns `Ok` if closed by the ui or the peer, `Err` if the connection drops.
+async fn run_multiplex(
+    mut stream: Stream,
+    forwards: &[(i32, String, i32)],
+    reverse_forwards: &[(i32, String, i32)],
+    rx_accept: &mut mpsc::UnboundedReceiver<(usize, TcpStream)>,
+    ui_receiver: &mut mpsc::UnboundedReceiver<Data>,
+    interface: impl Interface,
+) -> ResultType<()> {
+    log::info!("multiplexed port forwarding started");
+    let (tx_local, mut rx_local) = mpsc::channel::<(i32, Option<Bytes>)>(TUNNEL_QUEUE_SIZE);
+    let (tx_socks5, mut rx_socks5) = mpsc::channel::<Socks5Request>(TUNNEL_QUEUE_SIZE);
+    let (tx_udp, mut rx_udp) = mpsc::channel::<(i32, String, i32, Bytes)>(TUNNEL_QUEUE_SIZE);
+    let mut streams: HashMap<i32, mpsc::Sender<Bytes>> = HashMap::new();
+    // socks5 connections waiting for the peer to connect the target
+    let mut pending: HashMap<i32, TcpStream> = HashMap::new();
+    let mut udp_streams: HashMap<i32, mpsc::Sender<(String, i32, Bytes)>> = HashMap::new();
+    let mut next_id = 0;
+    for (i, (port, _, _)) in reverse_forwards.iter().enumerate() {
+        let mut tunnel = Tunnel::new();
+        tunnel.set_listen(TunnelListen {
+            listen_id: i as _,
+            port: *port,
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_tunnel(tunnel);
+        stream.send(&msg_out).await?;
+    }
+    loop {
+        tokio::select! {
+            Some((i, forward)) = rx_accept.recv() => {
+                if forwards[i].2 == 0 {
+                    tokio::spawn(accept_socks5(forward, tx_socks5.clone()));
+                    continue;
+                }
+                next_id += 1;
+                let mut tunnel = Tunnel::new();
+                tunnel.set_open(TunnelOpen {
+                    stream_id: next_id,
+                    host: forwards[i].1.clone(),
+                    port: forwards[i].2,
+                    ..Default::default()
+                });
+                let mut msg_out = Message::new();
+                msg_out.set_tunnel(tunnel);
+                stream.send(&msg_out).await?;
+                let (tx, rx) = mpsc::channel(TUNNEL_QUEUE_SIZE);
+                streams.insert(next_id, tx);
+                tokio::spawn(run_tunnel_stream(next_id, forward, rx, tx_local.clone()));
+            }
+            Some(req) = rx_socks5.recv() => {
+                next_id += 1;
+                let (host, port) = match &req.addr {
+                    TargetAddr::Ip(addr) => (addr.ip().to_string(), addr.port()),
+                    TargetAddr::Domain(domain, port) => (domain.to_string(), *port),
+                };
+                let mut tunnel = Tunnel::new();
+                match req.cmd {
+                    Socks5Command::Connect => {
+                        log::info!("socks5 stream {} to {}:{}", next_id, host, port);
+                        tunnel.set_open(TunnelOpen {
+                            stream_id: next_id,
+                            host,
+                            port: port as _,
+                            ..Default::default()
+                        });
+                        pending.insert(next_id, req.stream);
+                    }
+                    Socks5Command::UdpAssociate => {
+                        let (tx, rx) = mpsc::channel(TUNNEL_QUEUE_SIZE);
+                        udp_streams.insert(next_id, tx);
+                        tokio::spawn(run_socks5_udp(
+                            next_id,
+                            req.stream,
+                            rx,
+                            tx_udp.clone(),
+                            tx_local.clone(),
+                        ));
+                        continue;
+                    }
+                }
+                let mut msg_out = Message::new();
+                msg_out.set_tunnel(tunnel);
+                stream.send(&msg_out).await?;
+            }
+            Some((stream_id, host, port, data)) = rx_udp.recv() => {
+                let mut tunnel = Tunnel::new();
+                tunnel.set_udp(TunnelUdp {
+                    stream_id,
+                    host,
+                    port,
+                    data,
+                    ..Default::default()
+                });
+                let mut msg_out = Message::new();
+                msg_out.set_tunnel(tunnel);
+                stream.send(&msg_out).await?;
+            }
+            Some((stream_id, data)) = rx_local.recv() => {
+                let mut tunnel = Tunnel::new();
+                if let Some(data) = data {
+                    tunnel.set_data(TunnelData {
+                        stream_id,
+                        data,
+                        ..Default::default()
+                    });
+                } else {
+                    streams.remove(&stream_id);
+                    udp_streams.remove(&stream_id);
+                    tunnel.set_close(TunnelClose {
+                        stream_id,
+                        ..Default::default()
+                    });
+                }
+                let mut msg_out = Message::new();
+                msg_out.set_tunnel(tunnel);
+                stream.send(&msg_out).await?;
+            }
+            res = timeout(READ_TIMEOUT, stream.next()) => match res {
+                Err(_) => {
+                    bail!("Timeout");
+                }
+                Ok(Some(Ok(bytes))) => {
+                    let msg_in = Message::parse_from_bytes(&bytes)?;
+                    match msg_in.union {
+                        Some(message::Union::Tunnel(tunnel)) => match tunnel.union {
+                            Some(tunnel::Union::Open(open)) => {
+                                // opened by the peer for a connection accepted on a reverse forward
+                                let Some((_, host, port)) =
+                                    reverse_forwards.get(open.listen_id as usize)
+                                else {
+                                    stream.send(&new_tunnel_close(open.stream_id, "")).await?;
+                                    continue;
+                                };
+                                let addr = format!("{}:{}", host, port);
+                                log::info!("Open reverse tunnel stream {} to {}", open.stream_id, addr);
+                                let (tx, rx) = mpsc::channel(TUNNEL_QUEUE_SIZE);
+                                streams.insert(open.stream_id, tx);
+                                tokio::spawn(connect_tunnel_stream(
+                                    open.stream_id,
+                                    addr,
+                                    rx,
+                                    tx_local.clone(),
+                                ));
+                            }
+                            Some(tunnel::Union::Data(data)) => {
+                                let Some(tx) = streams.get(&data.stream_id) else {
+                                    continue;
+                                };
+                                // a full stream holds back the connection, but only for a while
+                                let res = match tx.try_send(data.data) {
+                                    Err(mpsc::error::TrySendError::Full(bytes)) => {
+                                        match timeout(TUNNEL_SEND_TIMEOUT, tx.send(bytes)).await {
+                                            Ok(res) => res.map_err(|_| ""),
+                                            Err(_) => Err("the local connection is too slow"),
+                                        }
+                                    }
+                                    res => res.map_err(|_| ""),
+                                };
+                                if let Err(error) = res {
+                                    // a closed stream reports its close itself
+                                    streams.remove(&data.stream_id);
+                                    if !error.is_empty() {
+                                        log::error!("tunnel stream {}: {}", data.stream_id, error);
+                                        stream.send(&new_tunnel_close(data.stream_id, error)).await?;
+                                    }
+                                }
+                            }
+                            Some(tunnel::Union::Opened(opened)) => {
+                                if let Some(forward) = pending.remove(&opened.stream_id) {
+                                    let (tx, rx) = mpsc::channel(TUNNEL_QUEUE_SIZE);
+                                    streams.insert(opened.stream_id, tx);
+                                    tokio::spawn(run_socks5_stream(
+                                        opened.stream_id,
+                                        forward,
+                                        rx,
+                                        tx_local.clone(),
+                                    ));
+                                }
+                            }
+                            Some(tunnel::Union::Udp(udp)) => {
+                                if let Some(tx) = udp_streams.get(&udp.stream_id) {
+                                    // datagrams may be dropped, so a full association drops them
+                                    if let Err(mpsc::error::TrySendError::Closed(_)) =
+                                        tx.try_send((udp.host, udp.port, udp.data))
+                                    {
+                                        udp_streams.remove(&udp.stream_id);
+                                    }
+                                }
+                            }
+                            Some(tunnel::Union::Close(close)) => {
+                                streams.remove(&close.stream_id);
+                                udp_streams.remove(&close.stream_id);
+                                if let Some(mut forward) = pending.remove(&close.stream_id) {
+                                    tokio::spawn(async move {
+                                        let reply = SOCKS5_REPLY_HOST_UNREACHABLE;
+                                        socks5_reply(&mut forward, reply, None).await.ok();
+                                    });
+                                }
+                                if !close.error.is_empty() {
+                                    log::error!("tunnel stream {}: {}", close.stream_id, close.error);
+                                }
+                            }
+                            Some(tunnel::Union::ListenResponse(res)) => {
+                                if res.error.is_empty() {
+                                    log::info!("peer is listening on port {}", res.port);
+                                } else {
+                                    log::error!("reverse port forward on {}: {}", res.port, res.error);
+                                    interface.msgbox("error", "Error", &res.error, "");
+                                }
+                            }
+                            _ => {}
+                        },
+                        Some(message::Union::TestDelay(t)) => {
+                            interface.handle_test_delay(t, &mut stream).await;
+                        }
+                        Some(message::Union::Misc(misc)) => {
+                            if let Some(misc::Union::CloseReason(reason)) = misc.union {
+                                interface.msgbox("error", "Connection Error", &reason, "");
+                                return Ok(());
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(Some(Err(err))) => {
+                    bail!("Connection closed: {}", err);
+                }
+                _ => {
+                    bail!("Reset by the peer");
+                }
+            },
+            d = ui_receiver.recv() => {
+                match d {
+                    Some(Data::Close) | None => {
+                        return Ok(());
+                    }
+                    Some(Data::Message(msg)) => {
+                        allow_err!(stream.send(&msg).await);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+// Closed when its sender is dropped, or reports `None` when the local connection closes.
+// The writing half drains `rx` on its own, so a full `tx` never holds back the data from the peer.
+async fn run_tunnel_stream(
+    stream_id: i32,
+    forward: TcpStream,
+    mut rx: mpsc::Receiver<Bytes>,
+    tx: mpsc::Sender<(i32, Option<Bytes>)>,
+) {
+    let (reader, mut writer) = forward.into_split();
+    let mut reader = FramedRead::new(reader, BytesCodec::new());
+    // both return whether the stream was closed by `run_multiplex`
+    let writing = async {
+        while let Some(data) = rx.recv().await {
+            if writer.write_all(&data).await.is_err() {
+                return false;
+            }
+        }
+        true
+    };
+    let reading = async {
+        while let Some(Ok(bytes)) = reader.next().await {
+            if tx.send((stream_id, Some(bytes.freeze()))).await.is_err() {
+                return true;
+            }
+        }
+        false
+    };
+    let closed = tokio::select! {
+        closed = writing => closed,
+        closed = reading => closed,
+    };
+    if !closed {
+        tx.send((stream_id, None)).await.ok();
+    }
+}
+
+fn new_tunnel_close(stream_id: i32, error: &str) -> Message {
+    let mut tunnel = Tunnel::new();
+    tunnel.set_close(TunnelClose {
+        stream_id,
+        error: error.to_owned(),
+        ..Default::default()
+    });
+    let mut msg_out = Message::new();
+    msg_out.set_tunnel(tunnel);
+    msg_out
+}
+
+struct Socks5Request {
+    stream: TcpStream,
+    cmd: Socks5Command,
+    addr: TargetAddr<'static>,
+}
+
+async fn accept_socks5(mut stream: TcpStream, tx: mpsc::Sender<Socks5Request>) {
+    match timeout(CONNECT_TIMEOUT, socks5_accept(&mut stream)).await {
+        Ok(Ok((cmd, addr))) => {
+            tx.send(Socks5Request { stream, cmd, addr }).await.ok();
+        }
+        Ok(Err(err)) => log::error!("socks5 handshake failed: {}", err),
+        Err(_) => log::error!("socks5 handshake timeout"),
+    }
+}
+
+async fn run_socks5_stream(
+    stream_id: i32,
+    mut forward: TcpStream,
+    rx: mpsc::Receiver<Bytes>,
+    tx: mpsc::Sender<(i32, Option<Bytes>)>,
+) {
+    let bind = forward.local_addr().ok();
+    if let Err(err) = socks5_reply(&mut forward, SOCKS5_REPLY_SUCCEEDED, bind).await {
+        log::error!("socks5 stream {} failed to reply: {}", stream_id, err);
+        tx.send((stream_id, None)).await.ok();
+        return;
+    }
+    run_tunnel_stream(stream_id, forward, rx, tx).await;
+}
+
+// A socks5 udp association, which lasts as long as its tcp connection.
+async fn run_socks5_udp(
+    stream_id: i32,
+    mut forward: TcpStream,
+    mut rx: mpsc::Receiver<(String, i32, Bytes)>,
+    tx_udp: mpsc::Sender<(i32, String, i32, Bytes)>,
+    tx: mpsc::Sender<(i32, Option<Bytes>)>,
+) {
+    let socket = match forward.local_addr() {
+        Ok(addr) => UdpSocket::bind((addr.ip(), 0)).await,
+        Err(err) => Err(err),
+    };
+    let (socket, bind) = match socket.and_then(|s| s.local_addr().map(|addr| (s, addr))) {
+        Ok(res) => res,
+        Err(err) => {
+            log::error!("socks5 udp {} failed to bind: {}", stream_id, err);
+            socks5_reply(&mut forward, SOCKS5_REPLY_GENERAL_FAILURE, None)
+                .await
+                .ok();
+            tx.send((stream_id, None)).await.ok();
+            return;
+        }
+    };
+    if socks5_reply(&mut forward, SOCKS5_REPLY_SUCCEEDED, Some(bind))
+        .await
+        .is_err()
+    {
+        tx.send((stream_id, None)).await.ok();
+        return;
+    }
+    log::info!("socks5 udp {} relays on {}", stream_id, bind);
+    let client_ip = forward.peer_addr().map(|addr| addr.ip()).ok();
+    let mut client = None;
+    let mut buf = vec![0u8; 65536];
+    let mut ctrl = [0u8; 64];
+    loop {
+        tokio::select! {
+            res = socket.recv_from(&mut buf) => {
+                let Ok((n, addr)) = res else {
+                    break;
+                };
+                // datagrams from others than the socks5 client are dropped
+                if client_ip.is_some() && client_ip != Some(addr.ip()) {
+                    continue;
+                }
+                client = Some(addr);
+                match socks5_udp_parse(&buf[..n]) {
+                    Ok((target, data)) => {
+                        let (host, port) = match target {
+                            TargetAddr::Ip(addr) => (addr.ip().to_string(), addr.port()),
+                            TargetAddr::Domain(domain, port) => (domain.to_string(), port),
+                        };
+                        let data = Bytes::copy_from_slice(data);
+                        if tx_udp.send((stream_id, host, port as _, data)).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) => log::debug!("socks5 udp {}: {}", stream_id, err),
+                }
+            }
+            res = rx.recv() => {
+                let Some((host, port, data)) = res else {
+                    return;
+                };
+                let Some(client) = client else {
+                    continue;
+                };
+                let from = match host.parse() {
+                    Ok(ip) => TargetAddr::Ip(std::net::SocketAddr::new(ip, port as _)),
+                    Err(_) => TargetAddr::Domain(host.into(), port as _),
+                };
+                socket.send_to(&socks5_udp_make(&from, &data), client).await.ok();
+            }
+            res = forward.read(&mut ctrl) => {
+                if !matches!(res, Ok(n) if n > 0) {
+                    break;
+                }
+            }
+        }
+    }
+    tx.send((stream_id, None)).await.ok();
+}
+
+async fn connect_tunnel_stream(
+    stream_id: i32,
+    addr: String,
+    rx: mpsc::Receiver<Bytes>,
+    tx: mpsc::Sender<(i32, Option<Bytes>)>,
+) {
+    match timeout(CONNECT_TIMEOUT, TcpStream::connect(&addr)).await {
+        Ok(Ok(forward)) => run_tunnel_stream(stream_id, forward, rx, tx).await,
+        Ok(Err(err)) => {
+            log::error!("Failed to connect to {}: {}", addr, err);
+            tx.send((stream_id, None)).await.ok();
+        }
+        Err(_) => {
+            log::error!("Timeout connecting to {}", addr);
+            tx.send((stream_id, None)).await.ok();
+        }
+    }
+}
+
 async fn connect_and_login(
     id: &str,
     password: &str,
     ui_receiver: &mut mpsc::UnboundedReceiver<Data>,
     interface: impl Interface,
-    forward: &mut Framed<TcpStream, BytesCodec>,
+    mut forward: Option<&mut Framed<TcpStream, BytesCodec>>,
     key: &str,
     token: &str,
     is_rdp: bool,
@@ -175,7 +745,12 @@ async fn connect_and_login(
                     _ => {}
                 }
             },
-            res = forward.next() => {
+            res = async {
+                match forward.as_deref_mut() {
+                    Some(forward) => forward.next().await,
+                    None => std::future::pending().await,
+                }
+            } => {
                 if let Some(Ok(bytes)) = res {
                     buffer.extend(bytes);
                 } else {
@@ -184,6 +759,9 @@ async fn connect_and_login(
             },
         }
     }
+    if forward.is_none() {
+        return Ok(Some(stream));
+    }
     stream.set_raw();
     if !buffer.is_empty() {
         allow_err!(stream.send_bytes(buffer.into()).await);
@@ -12,7 +12,9 @@ use uuid::Uuid;
 use hbb_common::{
     allow_err,
     anyhow::{self, bail},
-    config::{self, Config, CONNECT_TIMEOUT, READ_TIMEOUT, REG_INTERVAL, RENDEZVOUS_PORT},
+    config::{
+        self, Config, CONNECT_TIMEOUT, READ_TIMEOUT, REG_INTERVAL, RELAY_PORT, RENDEZVOUS_PORT,
+    },
     futures::future::join_all,
     log,
     protobuf::Message as _,
@@ -39,6 +41,7 @@ type Message = RendezvousMessage;
 
 const TIMER_OUT: Duration = Duration::from_secs(1);
 const DEFAULT_KEEP_ALIVE: i32 = 60_000;
+const HEALTH_CHECK_INTERVAL: f32 = 30.;
 
 lazy_static::lazy_static! {
     static ref SOLVING_PK_MISMATCH: Arc<Mutex<String>> = Default::default();
@@ -82,6 +85,8 @@ impl RendezvousMediator {
         tokio::spawn(async move {
             direct_server(server_cloned).await;
         });
+        tokio::spawn(crate::lan_rendezvous::start());
+        tokio::spawn(check_servers_health());
         #[cfg(not(any(target_os = "android", target_os = "ios")))]
         if crate::platform::is_installed() {
             std::thread::spawn(move || {
@@ -105,13 +110,29 @@ impl RendezvousMediator {
                 }
                 let mut futs = Vec::new();
                 let servers = Config::get_rendezvous_servers();
+                // with several servers, we stay registered on the others while one is down
+                let failover = servers.len() > 1;
                 SHOULD_EXIT.store(false, Ordering::SeqCst);
                 MANUAL_RESTARTED.store(false, Ordering::SeqCst);
                 for host in servers.clone() {
                     let server = server.clone();
                     futs.push(tokio::spawn(async move {
-                        if let Err(err) = Self::start(server, host).await {
-                            log::error!("rendezvous mediator error: {err}");
+                        loop {
+                            let start_time = Instant::now();
+                            if let Err(err) = Self::start(server.clone(), host.clone()).await {
+                                log::error!("rendezvous mediator error of {host}: {err}");
+                                Config::update_server_health(&host, RENDEZVOUS_PORT, None);
+                            }
+                            if !failover || SHOULD_EXIT.load(Ordering::SeqCst) {
+                                break;
+                            }
+                            let elapsed = start_time.elapsed().as_millis() as u64;
+                            if elapsed < CONNECT_TIMEOUT {
+                                sleep(((CONNECT_TIMEOUT - elapsed) / 1000) as _).await;
+                            }
+                            if SHOULD_EXIT.load(Ordering::SeqCst) {
+                                break;
+                            }
                         }
                         // SHOULD_EXIT here is to ensure once one exits, the others also exit.
                         SHOULD_EXIT.store(true, Ordering::SeqCst);
@@ -132,6 +153,7 @@ impl RendezvousMediator {
     }
 
     fn get_host_prefix(host: &str) -> String {
+        let host = host.rsplit("://").next().unwrap_or(host);
         host.split(".")
             .next()
             .map(|x| {
@@ -392,7 +414,12 @@ impl RendezvousMediator {
         } else {
             false
         };
-        if (cfg!(debug_assertions) && option_env!("TEST_TCP").is_some()) || is_http_proxy {
+        // udp can not go through a websocket
+        let is_websocket = hbb_common::websocket::is_ws_url(&host);
+        if (cfg!(debug_assertions) && option_env!("TEST_TCP").is_some())
+            || is_http_proxy
+            || is_websocket
+        {
             Self::start_tcp(server, host).await
         } else {
             Self::start_udp(server, host).await
@@ -400,9 +427,15 @@ impl RendezvousMediator {
     }
 
     async fn handle_request_relay(&self, rr: RequestRelay, server: ServerPtr) -> ResultType<()> {
+        // the relay server handed out behind a websocket bridge is usually the tcp one
+        let relay_server = if hbb_common::websocket::is_ws_url(&self.host) {
+            self.get_relay_server(rr.relay_server)
+        } else {
+            rr.relay_server
+        };
         self.create_relay(
             rr.socket_addr.into(),
-            rr.relay_server,
+            relay_server,
             rr.uuid,
             server,
             rr.secure,
@@ -505,7 +538,7 @@ impl RendezvousMediator {
         });
         let bytes = msg_out.write_to_bytes()?;
         socket.send_raw(bytes).await?;
-        crate::accept_connection(server.clone(), socket, peer_addr, true).await;
+        crate::accept_connection(server.clone(), socket, peer_addr, true, None).await;
         Ok(())
     }
 
@@ -537,6 +570,25 @@ impl RendezvousMediator {
             allow_err!(socket_client::connect_tcp_local(peer_addr, Some(local_addr), 30).await);
             socket
         };
+        // the peer connects to the same port with QUIC if it can, or else with tcp
+        #[cfg(feature = "quic")]
+        let quic_socket = if ph.quic && hbb_common::quic::is_enabled() {
+            use hbb_common::quic;
+            match quic::new_socket(socket.local_addr()) {
+                Ok(quic_socket) => {
+                    quic::punch(&quic_socket, peer_addr);
+                    Some(quic_socket)
+                }
+                Err(err) => {
+                    log::warn!("Failed to bind the QUIC socket: {}", err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        #[cfg(not(feature = "quic"))]
+        let quic_socket = None;
         let mut msg_out = Message::new();
         use hbb_common::protobuf::Enum;
         let nat_type = NatType::from_i32(Config::get_nat_type()).unwrap_or(NatType::UNKNOWN_NAT);
@@ -546,11 +598,12 @@ impl RendezvousMediator {
             relay_server,
             nat_type: nat_type.into(),
             version: crate::VERSION.to_owned(),
+            quic: quic_socket.is_some(),
             ..Default::default()
         });
         let bytes = msg_out.write_to_bytes()?;
         socket.send_raw(bytes).await?;
-        crate::accept_connection(server.clone(), socket, peer_addr, true).await;
+        crate::accept_connection(server.clone(), socket, peer_addr, true, quic_socket).await;
         Ok(())
     }
 
@@ -615,7 +668,7 @@ impl RendezvousMediator {
     }
 
     fn get_relay_server(&self, provided_by_rendezvous_server: String) -> String {
-        let mut relay_server = Config::get_option("relay-server");
+        let mut relay_server = Config::get_relay_server();
         if relay_server.is_empty() {
             relay_server = provided_by_rendezvous_server;
         }
@@ -626,6 +679,35 @@ impl RendezvousMediator {
     }
 }
 
+// Probe the custom servers regularly, so that clients and relays pick a live one
+// before they run into a dead one.
+async fn check_servers_health() {
+    loop {
+        let rendezvous_servers = Config::get_server_list("custom-rendezvous-server");
+        let relay_servers = Config::get_server_list("relay-server");
+        if rendezvous_servers.len() > 1 || relay_servers.len() > 1 {
+            let mut futs = Vec::new();
+            let servers = rendezvous_servers
+                .into_iter()
+                .map(|host| (host, RENDEZVOUS_PORT))
+                .chain(relay_servers.into_iter().map(|host| (host, RELAY_PORT)));
+            for (host, port) in servers {
+                futs.push(tokio::spawn(async move {
+                    let tm = Instant::now();
+                    let res = connect_tcp(check_port(&host, port), CONNECT_TIMEOUT).await;
+                    if let Err(err) = &res {
+                        log::debug!("health check of {host} failed: {err}");
+                    }
+                    let latency = res.ok().map(|_| tm.elapsed().as_micros() as i64);
+                    Config::update_server_health(&host, port, latency);
+                }));
+            }
+            join_all(futs).await;
+        }
+        sleep(HEALTH_CHECK_INTERVAL).await;
+    }
+}
+
 fn get_direct_port() -> i32 {
     let mut port = Config::get_option("direct-access-port")
         .parse::<i32>()
@@ -55,7 +55,13 @@ pub const NAME_WINDOW_FOCUS: &'static str = "";
 }
 }
 
+pub mod access_rules;
+pub mod access_schedule;
+pub mod audit_log;
 mod connection;
+pub mod login_lockout;
+pub mod permission_profiles;
+pub mod port_forward_policy;
 pub mod display_service;
 #[cfg(windows)]
 pub mod portable_service;
@@ -112,7 +118,12 @@ pub fn new() -> ServerPtr {
     Arc::new(RwLock::new(server))
 }
 
-async fn accept_connection_(server: ServerPtr, socket: Stream, secure: bool) -> ResultType<()> {
+async fn accept_connection_(
+    server: ServerPtr,
+    socket: Stream,
+    secure: bool,
+    quic_socket: Option<std::net::UdpSocket>,
+) -> ResultType<()> {
     let local_addr = socket.local_addr();
     drop(socket);
     // even we drop socket, below still may fail if not use reuse_addr,
@@ -120,11 +131,41 @@ async fn accept_connection_(server: ServerPtr, socket: Stream, secure: bool) ->
     // see “Only one usage of each socket address is normally permitted” on windows sometimes,
     let listener = new_listener(local_addr, true).await?;
     log::info!("Server listening on: {}", &listener.local_addr()?);
-    if let Ok((stream, addr)) = timeout(CONNECT_TIMEOUT, listener.accept()).await? {
-        stream.set_nodelay(true).ok();
-        let stream_addr = stream.local_addr()?;
-        create_tcp_connection(server, Stream::from(stream, stream_addr), addr, secure).await?;
-    }
+    let accept_tcp = timeout(CONNECT_TIMEOUT, async {
+        // a failed accept, eg. of a connection reset before it, must not end the wait for the
+        // peer, nor the one of QUIC
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    stream.set_nodelay(true).ok();
+                    let stream_addr = stream.local_addr()?;
+                    return ResultType::Ok((Stream::from(stream, stream_addr), addr));
+                }
+                Err(err) => {
+                    log::info!("Failed to accept tcp connection: {}", err);
+                    hbb_common::sleep(0.1).await;
+                }
+            }
+        }
+    });
+    #[cfg(not(feature = "quic"))]
+    drop(quic_socket);
+    // the peer falls back to tcp if QUIC fails
+    let accept_quic = async {
+        #[cfg(feature = "quic")]
+        if let Some(quic_socket) = quic_socket {
+            match hbb_common::quic::accept(quic_socket, CONNECT_TIMEOUT).await {
+                Ok(res) => return res,
+                Err(err) => log::info!("Failed to accept QUIC connection: {}", err),
+            }
+        }
+        std::future::pending().await
+    };
+    let (stream, addr) = tokio::select! {
+        res = accept_tcp => res??,
+        res = accept_quic => res,
+    };
+    create_tcp_connection(server, stream, addr, secure).await?;
     Ok(())
 }
 
@@ -148,6 +189,7 @@ pub async fn create_tcp_connection(
                 &IdPk {
                     id: Config::get_id(),
                     pk: Bytes::from(our_pk_b.0.to_vec()),
+                    key_version: tcp::KEY_VERSION,
                     ..Default::default()
                 }
                 .write_to_bytes()
@@ -164,11 +206,21 @@ pub async fn create_tcp_connection(
                 if let Ok(msg_in) = Message::parse_from_bytes(&bytes) {
                     if let Some(message::Union::PublicKey(pk)) = msg_in.union {
                         if pk.asymmetric_value.len() == box_::PUBLICKEYBYTES {
-                            stream.set_key(tcp::Encrypt::decode(
+                            let key = tcp::Encrypt::decode(
                                 &pk.symmetric_value,
                                 &pk.asymmetric_value,
                                 &our_sk_b,
-                            )?);
+                            )?;
+                            // the old peers do not send the version
+                            if pk.key_version >= 2 {
+                                let their_pk_b = box_::PublicKey::from_slice(&pk.asymmetric_value)
+                                    .context("Handshake failed: invalid public key")?;
+                                let key =
+                                    tcp::Encrypt::session_key(&key, &our_pk_b, &their_pk_b, false);
+                                stream.set_session_key(key, false);
+                            } else {
+                                stream.set_key(key);
+                            }
                         } else if pk.asymmetric_value.is_empty() {
                             Config::set_key_confirmed(false);
                             log::info!("Force to update pk");
@@ -207,8 +259,9 @@ pub async fn accept_connection(
     socket: Stream,
     peer_addr: SocketAddr,
     secure: bool,
+    quic_socket: Option<std::net::UdpSocket>,
 ) {
-    if let Err(err) = accept_connection_(server, socket, secure).await {
+    if let Err(err) = accept_connection_(server, socket, secure, quic_socket).await {
         log::error!("Failed to accept connection from {}: {}", peer_addr, err);
     }
 }
@@ -474,6 +527,8 @@ pub async fn start_server(is_server: bool) {
             }
         });
         input_service::fix_key_down_timeout_loop();
+        video_service::start_record_retention();
+        crate::hbbs_http::record_upload::resume_pending();
         #[cfg(target_os = "linux")]
         if crate::platform::current_is_wayland() {
             allow_err!(input_service::setup_uinput(0, 1920, 0, 1080).await);
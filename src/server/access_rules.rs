@@ -0,0 +1,390 @@
+use std::net::IpAddr;
+
+use cidr_utils::cidr::IpCidr;
+use hbb_common::{
+    bail,
+    chrono::{Local, NaiveTime},
+    config::Config,
+    log, ResultType,
+};
+use serde_derive::{Deserialize, Serialize};
+
+// json of `AccessRules`, the legacy `whitelist` is used if it is empty
+pub const OPTION_ACCESS_RULES: &str = "access-rules";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessAction {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConnKind {
+    Remote,
+    FileTransfer,
+    PortForward,
+}
+
+/// A rule matches if all its conditions match, an empty condition matches anything.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessRule {
+    #[serde(default)]
+    pub name: String,
+    pub action: AccessAction,
+    // CIDRs or ips
+    #[serde(default)]
+    pub ip: Vec<String>,
+    // peer id globs, with `*` and `?`. The id is what the client claims in its login request
+    // and is not verified, so these rules are advisory: an allow rule by peer id does not keep
+    // anyone out who knows an allowed id, use the ip or the password for that.
+    #[serde(default)]
+    pub peer_id: Vec<String>,
+    // local time, `HH:MM-HH:MM`, which may cross midnight
+    #[serde(default)]
+    pub time: String,
+    #[serde(default)]
+    pub conn_type: Vec<ConnKind>,
+}
+
+/// Ordered rules, the first matched rule decides.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessRules {
+    #[serde(default = "default_action")]
+    pub default: AccessAction,
+    #[serde(default)]
+    pub rules: Vec<AccessRule>,
+    // converted from the `whitelist` option
+    #[serde(skip)]
+    pub legacy: bool,
+}
+
+fn default_action() -> AccessAction {
+    AccessAction::Allow
+}
+
+/// What is known about the incoming connection. The peer id and the connection type
+/// are unknown until the login request is received.
+pub struct AccessRequest<'a> {
+    pub ip: IpAddr,
+    pub peer_id: Option<&'a str>,
+    pub conn_type: Option<ConnKind>,
+    pub time: NaiveTime,
+}
+
+impl<'a> AccessRequest<'a> {
+    pub fn new(ip: IpAddr, peer_id: Option<&'a str>, conn_type: Option<ConnKind>) -> Self {
+        Self {
+            ip,
+            peer_id,
+            conn_type,
+            time: Local::now().time(),
+        }
+    }
+}
+
+impl AccessRules {
+    pub fn load() -> Self {
+        let rules = Config::get_option(OPTION_ACCESS_RULES);
+        if rules.is_empty() {
+            return Self::from_whitelist(&Config::get_option("whitelist"));
+        }
+        Self::from_option(&rules)
+    }
+
+    fn from_option(rules: &str) -> Self {
+        match Self::parse(rules) {
+            Ok(rules) => rules,
+            Err(err) => {
+                // fail closed, a broken rule set must not open the door
+                log::error!(
+                    "Invalid {}, all connections are denied: {}",
+                    OPTION_ACCESS_RULES,
+                    err
+                );
+                Self {
+                    default: AccessAction::Deny,
+                    rules: Vec::new(),
+                    legacy: false,
+                }
+            }
+        }
+    }
+
+    // An ip or time window which can't be parsed fails the whole rule set, since the rule would
+    // never match and a deny rule would be skipped.
+    fn parse(rules: &str) -> ResultType<Self> {
+        let rules: Self = serde_json::from_str(rules)?;
+        for (i, rule) in rules.rules.iter().enumerate() {
+            if let Some(ip) = rule.ip.iter().find(|x| IpCidr::from_str(x).is_err()) {
+                bail!("invalid ip {} of rule {}", ip, rules.rule_name(Some(i)));
+            }
+            if !rule.time.is_empty() && parse_time_window(&rule.time).is_none() {
+                bail!(
+                    "invalid time window {} of rule {}",
+                    rule.time,
+                    rules.rule_name(Some(i))
+                );
+            }
+        }
+        Ok(rules)
+    }
+
+    fn from_whitelist(whitelist: &str) -> Self {
+        let ips: Vec<String> = whitelist
+            .split(",")
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_owned())
+            .collect();
+        if ips.is_empty() || ips.iter().any(|x| x == "0.0.0.0") {
+            return Self {
+                default: AccessAction::Allow,
+                rules: Vec::new(),
+                legacy: true,
+            };
+        }
+        Self {
+            default: AccessAction::Deny,
+            rules: vec![AccessRule {
+                name: "whitelist".to_owned(),
+                action: AccessAction::Allow,
+                ip: ips,
+                peer_id: Vec::new(),
+                time: String::new(),
+                conn_type: Vec::new(),
+            }],
+            legacy: true,
+        }
+    }
+
+    /// Returns the action and the index of the matched rule, `None` for the default action.
+    /// Returns `None` if a rule may match but depends on what is not known yet.
+    pub fn evaluate(&self, req: &AccessRequest) -> Option<(AccessAction, Option<usize>)> {
+        for (i, rule) in self.rules.iter().enumerate() {
+            match rule.matches(req) {
+                Some(true) => return Some((rule.action, Some(i))),
+                Some(false) => continue,
+                None => return None,
+            }
+        }
+        Some((self.default, None))
+    }
+
+    pub fn rule_name(&self, index: Option<usize>) -> String {
+        match index.and_then(|i| self.rules.get(i).map(|r| (i, r))) {
+            Some((_, rule)) if !rule.name.is_empty() => rule.name.clone(),
+            Some((i, _)) => format!("#{}", i),
+            None => "default".to_owned(),
+        }
+    }
+}
+
+impl AccessRule {
+    // `None` if it depends on the unknown peer id or connection type
+    fn matches(&self, req: &AccessRequest) -> Option<bool> {
+        if !self.ip.is_empty()
+            && !self
+                .ip
+                .iter()
+                .any(|x| IpCidr::from_str(x).map_or(false, |y| y.contains(req.ip)))
+        {
+            return Some(false);
+        }
+        if !self.time.is_empty() && !in_time_window(&self.time, req.time) {
+            return Some(false);
+        }
+        let mut known = true;
+        if !self.peer_id.is_empty() {
+            match req.peer_id {
+                Some(id) => {
+                    if !self.peer_id.iter().any(|p| glob_match(p, id)) {
+                        return Some(false);
+                    }
+                }
+                None => known = false,
+            }
+        }
+        if !self.conn_type.is_empty() {
+            match req.conn_type {
+                Some(t) => {
+                    if !self.conn_type.contains(&t) {
+                        return Some(false);
+                    }
+                }
+                None => known = false,
+            }
+        }
+        if known {
+            Some(true)
+        } else {
+            None
+        }
+    }
+}
+
+fn parse_time_window(window: &str) -> Option<(NaiveTime, NaiveTime)> {
+    let parse = |x: &str| NaiveTime::parse_from_str(x.trim(), "%H:%M").ok();
+    let (start, end) = window.split_once('-')?;
+    Some((parse(start)?, parse(end)?))
+}
+
+// the windows of the loaded rules are checked in `AccessRules::parse`
+fn in_time_window(window: &str, time: NaiveTime) -> bool {
+    let Some((start, end)) = parse_time_window(window) else {
+        return false;
+    };
+    if start <= end {
+        start <= time && time < end
+    } else {
+        time >= start || time < end
+    }
+}
+
+pub(super) fn glob_match(pattern: &str, s: &str) -> bool {
+    let p: Vec<char> = pattern.to_lowercase().chars().collect();
+    let s: Vec<char> = s.to_lowercase().chars().collect();
+    let (mut pi, mut si) = (0, 0);
+    // the position of the last `*` and the position in `s` it is matched to
+    let mut star: Option<(usize, usize)> = None;
+    while si < s.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == s[si]) {
+            pi += 1;
+            si += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, si));
+            pi += 1;
+        } else if let Some((star_pi, star_si)) = star {
+            pi = star_pi + 1;
+            si = star_si + 1;
+            star = Some((star_pi, star_si + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(s, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("123*", "123456"));
+        assert!(glob_match("1?3*6", "123456"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*-Office", "hq-office"));
+        assert!(!glob_match("123", "1234"));
+        assert!(!glob_match("1*5", "123456"));
+    }
+
+    #[test]
+    fn test_time_window() {
+        assert!(in_time_window("09:00-17:00", time("09:00")));
+        assert!(!in_time_window("09:00-17:00", time("17:00")));
+        assert!(in_time_window("22:00-06:00", time("23:30")));
+        assert!(in_time_window("22:00-06:00", time("05:59")));
+        assert!(!in_time_window("22:00-06:00", time("12:00")));
+        assert!(!in_time_window("9-17", time("12:00")));
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let rules: AccessRules = serde_json::from_str(
+            r#"{
+                "default": "deny",
+                "rules": [
+                    {"name": "block", "action": "deny", "ip": ["10.0.0.5"]},
+                    {"name": "ops", "action": "allow", "ip": ["10.0.0.0/8"], "peer_id": ["9*"],
+                     "conn_type": ["file-transfer"]},
+                    {"action": "allow", "ip": ["192.168.1.0/24"], "time": "09:00-17:00"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let noon = time("12:00");
+        let req = |ip: &str, peer_id, conn_type| AccessRequest {
+            ip: ip.parse().unwrap(),
+            peer_id,
+            conn_type,
+            time: noon,
+        };
+        assert_eq!(
+            rules.evaluate(&req("10.0.0.5", None, None)),
+            Some((AccessAction::Deny, Some(0)))
+        );
+        // the peer id is not known yet
+        assert_eq!(rules.evaluate(&req("10.1.1.1", None, None)), None);
+        assert_eq!(
+            rules.evaluate(&req("10.1.1.1", Some("912"), Some(ConnKind::FileTransfer))),
+            Some((AccessAction::Allow, Some(1)))
+        );
+        assert_eq!(
+            rules.evaluate(&req("10.1.1.1", Some("912"), Some(ConnKind::Remote))),
+            Some((AccessAction::Deny, None))
+        );
+        assert_eq!(
+            rules.evaluate(&req("192.168.1.7", None, None)),
+            Some((AccessAction::Allow, Some(2)))
+        );
+        let mut night = req("192.168.1.7", None, None);
+        night.time = time("23:00");
+        assert_eq!(rules.evaluate(&night), Some((AccessAction::Deny, None)));
+        assert_eq!(rules.rule_name(Some(2)), "#2");
+        assert_eq!(rules.rule_name(None), "default");
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let req = |ip: &str| AccessRequest::new(ip.parse().unwrap(), None, None);
+        // the deny rule would never match with its invalid ip, so nothing is allowed
+        let rules = AccessRules::from_option(
+            r#"{"rules": [{"name": "block", "action": "deny", "ip": ["10.0.0.300"]}]}"#,
+        );
+        assert_eq!(rules.default, AccessAction::Deny);
+        assert!(rules.rules.is_empty());
+        assert_eq!(
+            rules.evaluate(&req("10.0.0.3")),
+            Some((AccessAction::Deny, None))
+        );
+        let rules = AccessRules::from_option(
+            r#"{"rules": [{"action": "deny", "ip": ["10.0.0.0/8"], "time": "9-17"}]}"#,
+        );
+        assert_eq!(
+            rules.evaluate(&req("172.16.0.1")),
+            Some((AccessAction::Deny, None))
+        );
+        let rules = AccessRules::from_option(
+            r#"{"rules": [{"action": "deny", "ip": ["10.0.0.0/8"], "time": "09:00-17:00"}]}"#,
+        );
+        assert_eq!(rules.rules.len(), 1);
+        assert_eq!(
+            rules.evaluate(&req("172.16.0.1")),
+            Some((AccessAction::Allow, None))
+        );
+    }
+
+    #[test]
+    fn test_whitelist() {
+        let req = |ip: &str| AccessRequest::new(ip.parse().unwrap(), None, None);
+        let rules = AccessRules::from_whitelist("10.0.0.0/8,192.168.1.1");
+        assert_eq!(
+            rules.evaluate(&req("10.2.3.4")),
+            Some((AccessAction::Allow, Some(0)))
+        );
+        assert_eq!(
+            rules.evaluate(&req("172.16.0.1")),
+            Some((AccessAction::Deny, None))
+        );
+        let rules = AccessRules::from_whitelist("10.0.0.0/8,0.0.0.0");
+        assert_eq!(
+            rules.evaluate(&req("172.16.0.1")),
+            Some((AccessAction::Allow, None))
+        );
+    }
+}
@@ -0,0 +1,343 @@
+use std::{str::FromStr, time::Duration};
+
+use chrono::{
+    DateTime, Datelike, Local, LocalResult, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday,
+};
+use chrono_tz::Tz;
+use hbb_common::{bail, config::Config, log, ResultType};
+use serde_derive::{Deserialize, Serialize};
+
+// json of `ScheduleConfig`, the unattended access is not limited if it is empty
+pub const OPTION_UNATTENDED_SCHEDULE: &str = "unattended-schedule";
+
+const DEFAULT_WARN_MINUTES: u64 = 5;
+// the windows are looked up to a week ahead, a window open longer than that does not end
+const HORIZON_DAYS: i64 = 8;
+// the longest clock change, a wall-clock time skipped by it ends at the first one after
+const MAX_CLOCK_CHANGE_MINUTES: i64 = 180;
+
+/// What happens to the password and public key logins outside the windows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outside {
+    // they are accepted in the connection manager
+    #[default]
+    Click,
+    Deny,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WeeklyWindow {
+    // `mon`, `tue`, ..., empty for every day
+    #[serde(default)]
+    pub days: Vec<String>,
+    // `HH:MM`, the window goes on to the next day if `end` is not after `start`
+    pub start: String,
+    pub end: String,
+}
+
+/// A one-off change, it takes precedence over the weekly windows.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Exception {
+    // `YYYY-MM-DD HH:MM`
+    pub start: String,
+    pub end: String,
+    // open, or closed if false
+    #[serde(default)]
+    pub open: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScheduleConfig {
+    // iana zone like `Europe/Berlin`, empty for the local time of the host
+    #[serde(default)]
+    pub timezone: String,
+    #[serde(default)]
+    pub weekly: Vec<WeeklyWindow>,
+    #[serde(default)]
+    pub exceptions: Vec<Exception>,
+    #[serde(default)]
+    pub outside: Outside,
+    // the sessions are warned this long before their window ends
+    pub warn_minutes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Schedule {
+    // the windows are in the wall-clock time of the zone, they follow its clock changes
+    zone: Option<Tz>,
+    weekly: Vec<(Vec<Weekday>, NaiveTime, NaiveTime)>,
+    exceptions: Vec<(NaiveDateTime, NaiveDateTime, bool)>,
+    pub outside: Outside,
+    pub warn: Duration,
+    // the option is invalid, it is never open
+    broken: bool,
+}
+
+impl Schedule {
+    /// `None` if there is no schedule.
+    pub fn load() -> Option<Self> {
+        let v = Config::get_option(OPTION_UNATTENDED_SCHEDULE);
+        if v.is_empty() {
+            return None;
+        }
+        match serde_json::from_str::<ScheduleConfig>(&v)
+            .map_err(|e| e.into())
+            .and_then(Self::parse)
+        {
+            Ok(schedule) => Some(schedule),
+            Err(err) => {
+                // fail closed like the access rules, but the connection manager still works
+                log::error!(
+                    "Invalid {}, the unattended access is closed: {}",
+                    OPTION_UNATTENDED_SCHEDULE,
+                    err
+                );
+                Some(Self {
+                    warn: Duration::from_secs(DEFAULT_WARN_MINUTES * 60),
+                    broken: true,
+                    ..Default::default()
+                })
+            }
+        }
+    }
+
+    pub fn parse(config: ScheduleConfig) -> ResultType<Self> {
+        let zone = match config.timezone.trim() {
+            "" => None,
+            tz => match Tz::from_str(tz) {
+                Ok(tz) => Some(tz),
+                Err(_) => bail!("Invalid timezone {}", tz),
+            },
+        };
+        let mut weekly = Vec::new();
+        for w in config.weekly.iter() {
+            let mut days = Vec::new();
+            for d in w.days.iter() {
+                match Weekday::from_str(d.trim()) {
+                    Ok(d) => days.push(d),
+                    Err(_) => bail!("Invalid day {}", d),
+                }
+            }
+            weekly.push((days, parse_time(&w.start)?, parse_time(&w.end)?));
+        }
+        let mut exceptions = Vec::new();
+        for e in config.exceptions.iter() {
+            exceptions.push((parse_date_time(&e.start)?, parse_date_time(&e.end)?, e.open));
+        }
+        Ok(Self {
+            zone,
+            weekly,
+            exceptions,
+            outside: config.outside,
+            warn: Duration::from_secs(config.warn_minutes.unwrap_or(DEFAULT_WARN_MINUTES) * 60),
+            broken: false,
+        })
+    }
+
+    fn wall_clock(&self, t: DateTime<Utc>) -> NaiveDateTime {
+        match self.zone {
+            Some(tz) => t.with_timezone(&tz).naive_local(),
+            None => t.with_timezone(&Local).naive_local(),
+        }
+    }
+
+    // The instant of a wall-clock time, the earlier one if the clock is turned back.
+    fn instant(&self, t: NaiveDateTime) -> Option<DateTime<Utc>> {
+        for i in 0..=MAX_CLOCK_CHANGE_MINUTES {
+            let t = t + chrono::Duration::minutes(i);
+            let res = match self.zone {
+                Some(tz) => earliest(tz.from_local_datetime(&t)),
+                None => earliest(Local.from_local_datetime(&t)),
+            };
+            if res.is_some() {
+                return res;
+            }
+        }
+        None
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.is_open_at(self.wall_clock(Utc::now()))
+    }
+
+    /// How long the current window lasts, zero if it is closed, `None` if it does not end.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.remaining_at(Utc::now())
+    }
+
+    fn remaining_at(&self, now: DateTime<Utc>) -> Option<Duration> {
+        let t = self.wall_clock(now);
+        if !self.is_open_at(t) {
+            return Some(Duration::ZERO);
+        }
+        self.open_until(t).map(|end| match self.instant(end) {
+            Some(end) => (end - now).to_std().unwrap_or_default(),
+            None => Duration::ZERO,
+        })
+    }
+
+    // the weekly windows starting from the day before `t` to the horizon
+    fn weekly_windows(&self, t: NaiveDateTime) -> Vec<(NaiveDateTime, NaiveDateTime)> {
+        let mut windows = Vec::new();
+        for i in -1..HORIZON_DAYS {
+            let Some(date) = t.date().checked_add_signed(chrono::Duration::days(i)) else {
+                continue;
+            };
+            for (days, start, end) in self.weekly.iter() {
+                if !days.is_empty() && !days.contains(&date.weekday()) {
+                    continue;
+                }
+                let start = date.and_time(*start);
+                let mut end = date.and_time(*end);
+                if end <= start {
+                    end += chrono::Duration::days(1);
+                }
+                windows.push((start, end));
+            }
+        }
+        windows
+    }
+
+    fn is_open_at(&self, t: NaiveDateTime) -> bool {
+        if self.broken {
+            return false;
+        }
+        if let Some((_, _, open)) = self.exceptions.iter().find(|(s, e, _)| *s <= t && t < *e) {
+            return *open;
+        }
+        self.weekly_windows(t)
+            .iter()
+            .any(|(s, e)| *s <= t && t < *e)
+    }
+
+    // The first time after `t` it is closed, it only changes at the bounds of the windows.
+    fn open_until(&self, t: NaiveDateTime) -> Option<NaiveDateTime> {
+        let horizon = t + chrono::Duration::days(HORIZON_DAYS);
+        let mut bounds: Vec<NaiveDateTime> = self
+            .weekly_windows(t)
+            .into_iter()
+            .chain(self.exceptions.iter().map(|(s, e, _)| (*s, *e)))
+            .flat_map(|(s, e)| [s, e])
+            .filter(|b| *b > t && *b <= horizon)
+            .collect();
+        bounds.sort();
+        bounds.dedup();
+        bounds.into_iter().find(|b| !self.is_open_at(*b))
+    }
+}
+
+fn earliest<Z: TimeZone>(t: LocalResult<DateTime<Z>>) -> Option<DateTime<Utc>> {
+    match t {
+        LocalResult::Single(t) | LocalResult::Ambiguous(t, _) => Some(t.with_timezone(&Utc)),
+        LocalResult::None => None,
+    }
+}
+
+fn parse_time(v: &str) -> ResultType<NaiveTime> {
+    match NaiveTime::parse_from_str(v.trim(), "%H:%M") {
+        Ok(t) => Ok(t),
+        Err(_) => bail!("Invalid time {}", v),
+    }
+}
+
+fn parse_date_time(v: &str) -> ResultType<NaiveDateTime> {
+    match NaiveDateTime::parse_from_str(v.trim(), "%Y-%m-%d %H:%M") {
+        Ok(t) => Ok(t),
+        Err(_) => bail!("Invalid date time {}", v),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_windows() {
+        let config: ScheduleConfig = serde_json::from_str(
+            r#"{
+                "timezone": "Europe/Berlin",
+                "weekly": [
+                    {"days": ["mon", "wed"], "start": "22:00", "end": "02:00"},
+                    {"days": ["sat"], "start": "08:00", "end": "12:00"}
+                ],
+                "exceptions": [
+                    {"start": "2026-10-14 00:00", "end": "2026-10-15 00:00"},
+                    {"start": "2026-10-17 11:00", "end": "2026-10-17 18:00", "open": true}
+                ],
+                "outside": "deny"
+            }"#,
+        )
+        .unwrap();
+        let schedule = Schedule::parse(config).unwrap();
+        assert_eq!(schedule.outside, Outside::Deny);
+        assert_eq!(schedule.warn, Duration::from_secs(300));
+        let t = |v: &str| parse_date_time(v).unwrap();
+        // monday night to tuesday
+        assert!(schedule.is_open_at(t("2026-10-12 23:00")));
+        assert!(schedule.is_open_at(t("2026-10-13 01:59")));
+        assert!(!schedule.is_open_at(t("2026-10-13 02:00")));
+        assert_eq!(
+            schedule.open_until(t("2026-10-12 23:00")),
+            Some(t("2026-10-13 02:00"))
+        );
+        // the wednesday window is closed by the exception, until midnight
+        assert!(!schedule.is_open_at(t("2026-10-14 23:00")));
+        assert!(schedule.is_open_at(t("2026-10-15 00:30")));
+        // the saturday window is extended by the exception
+        assert!(schedule.is_open_at(t("2026-10-17 09:00")));
+        assert_eq!(
+            schedule.open_until(t("2026-10-17 09:00")),
+            Some(t("2026-10-17 18:00"))
+        );
+        assert!(!schedule.is_open_at(t("2026-10-18 09:00")));
+
+        let always = Schedule::parse(
+            serde_json::from_str(r#"{"weekly": [{"start": "00:00", "end": "00:00"}]}"#).unwrap(),
+        )
+        .unwrap();
+        assert!(always.is_open_at(t("2026-10-12 23:00")));
+        assert_eq!(always.open_until(t("2026-10-12 23:00")), None);
+
+        assert!(Schedule::parse(
+            serde_json::from_str(r#"{"weekly": [{"start": "8:00", "end": "25:00"}]}"#).unwrap()
+        )
+        .is_err());
+        assert!(
+            Schedule::parse(serde_json::from_str(r#"{"timezone": "+02:00"}"#).unwrap()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_clock_change() {
+        let schedule = Schedule::parse(
+            serde_json::from_str(
+                r#"{
+                    "timezone": "Europe/Berlin",
+                    "weekly": [{"start": "01:00", "end": "02:30"}, {"start": "08:00", "end": "09:00"}]
+                }"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let utc = |v: &str| Utc.from_utc_datetime(&parse_date_time(v).unwrap());
+        // 08:00 is 06:00 utc in summer and 07:00 utc in winter
+        assert!(schedule.is_open_at(schedule.wall_clock(utc("2026-07-01 06:30"))));
+        assert!(!schedule.is_open_at(schedule.wall_clock(utc("2026-12-01 06:30"))));
+        assert!(schedule.is_open_at(schedule.wall_clock(utc("2026-12-01 07:30"))));
+        assert_eq!(
+            schedule.remaining_at(utc("2026-12-01 07:30")),
+            Some(Duration::from_secs(30 * 60))
+        );
+        // 02:30 is skipped on 2026-03-29, the window ends when the clock jumps at 01:00 utc
+        assert_eq!(
+            schedule.remaining_at(utc("2026-03-29 00:30")),
+            Some(Duration::from_secs(30 * 60))
+        );
+        // 02:30 happens twice on 2026-10-25, the window ends at the first one
+        assert_eq!(
+            schedule.remaining_at(utc("2026-10-24 23:30")),
+            Some(Duration::from_secs(60 * 60))
+        );
+    }
+}
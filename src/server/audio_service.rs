@@ -66,6 +66,7 @@ mod pa_impl {
                 )))
                 .await
         );
+        super::video_service::record_audio(&create_format_msg(crate::platform::PA_SAMPLE_RATE, 2));
         #[cfg(target_os = "linux")]
         let zero_audio_frame: Vec<f32> = vec![0.; AUDIO_DATA_SIZE_U8 / 4];
         while sp.ok() && !RESTARTING.load(Ordering::SeqCst) {
@@ -276,10 +277,9 @@ mod cpal_impl {
             f => bail!("unsupported audio format: {:?}", f),
         };
         stream.play()?;
-        Ok((
-            Box::new(stream),
-            Arc::new(create_format_msg(sample_rate, ch as _)),
-        ))
+        let format = create_format_msg(sample_rate, ch as _);
+        super::video_service::record_audio(&format);
+        Ok((Box::new(stream), Arc::new(format)))
     }
 
     fn build_input_stream<T>(
@@ -396,6 +396,7 @@ fn send_f32(data: &[f32], encoder: &mut Encoder, sp: &GenericService) {
                             data: data.into(),
                             ..Default::default()
                         });
+                        super::video_service::record_audio(&msg_out);
                         sp.send(msg_out);
                     }
                     Err(_) => {}
@@ -415,6 +416,7 @@ fn send_f32(data: &[f32], encoder: &mut Encoder, sp: &GenericService) {
                 data: data.into(),
                 ..Default::default()
             });
+            super::video_service::record_audio(&msg_out);
             sp.send(msg_out);
         }
         Err(_) => {}
@@ -0,0 +1,504 @@
+// Local audit log, which does not depend on the api server.
+//
+// The events are appended as json lines to `audit.jsonl` in the `audit` directory of the config,
+// and each line carries the hash of the previous one:
+//
+//     {"seq":1,"time":1700000000000,"event":"login","data":{..},"prev":"..","hash":".."}
+//
+// `hash` is the hex HMAC-SHA256 of the line without `hash`, keyed with a random secret of the
+// host in `audit_key` of the config directory, so a changed, inserted or removed line breaks the
+// chain, and it can't be rebuilt without the key, which only the user of the service can read.
+// The chain goes on across the rotated files, `audit.1.jsonl` is the newest of them, and the
+// removal of the oldest ones is only seen as the chain starting later. An unreadable last line, e.g.
+// of a write cut off by a crash, is kept and followed by a `chain_break` entry linked to the last
+// readable one.
+//
+// Options:
+// - `enable-audit-log`: "N" to disable it
+// - `audit-log-max-size-mb`: the size to rotate at, 10 by default
+// - `audit-log-max-files`: the rotated files to keep, 5 by default
+// - `audit-log-syslog`: "Y" to forward the events to syslog, which is read by journald too
+
+use crate::crypto::{hmac_sha256, to_hex};
+use hbb_common::{bail, config::Config, get_time, log, rand, ResultType};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+pub const OPTION_ENABLE_AUDIT_LOG: &str = "enable-audit-log";
+pub const OPTION_AUDIT_LOG_MAX_SIZE_MB: &str = "audit-log-max-size-mb";
+pub const OPTION_AUDIT_LOG_MAX_FILES: &str = "audit-log-max-files";
+pub const OPTION_AUDIT_LOG_SYSLOG: &str = "audit-log-syslog";
+
+// appended after an unreadable entry at the end of the log
+pub const EVENT_CHAIN_BREAK: &str = "chain_break";
+
+const FILE_NAME: &str = "audit";
+const KEY_FILE: &str = "audit_key";
+const EXTENSION: &str = "jsonl";
+const DEFAULT_MAX_SIZE_MB: u64 = 10;
+const DEFAULT_MAX_FILES: usize = 5;
+
+lazy_static::lazy_static! {
+    // `None` until the tail of the chain is read from the files
+    static ref WRITER: Mutex<Option<Chain>> = Mutex::new(None);
+    // empty until read from `KEY_FILE`
+    static ref KEY: Mutex<Vec<u8>> = Default::default();
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub seq: u64,
+    // ms
+    pub time: i64,
+    pub event: String,
+    pub data: Value,
+    pub prev: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub hash: String,
+}
+
+impl AuditEntry {
+    fn compute_hash(&self, key: &[u8]) -> String {
+        let mut entry = self.clone();
+        entry.hash.clear();
+        let line = serde_json::to_string(&entry).unwrap_or_default();
+        to_hex(&hmac_sha256(key, line.as_bytes()))
+    }
+}
+
+// the tail of the chain
+#[derive(Debug, Default)]
+struct Chain {
+    seq: u64,
+    hash: String,
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    Config::get_option(OPTION_ENABLE_AUDIT_LOG) != "N"
+}
+
+pub fn dir() -> PathBuf {
+    Config::path(FILE_NAME)
+}
+
+// 0 for the current file
+fn file_path(dir: &Path, index: usize) -> PathBuf {
+    if index == 0 {
+        dir.join(format!("{}.{}", FILE_NAME, EXTENSION))
+    } else {
+        dir.join(format!("{}.{}.{}", FILE_NAME, index, EXTENSION))
+    }
+}
+
+/// The key of the chain, generated on the first use and never replaced.
+fn key() -> ResultType<Vec<u8>> {
+    let mut key = KEY.lock().unwrap();
+    if !key.is_empty() {
+        return Ok(key.clone());
+    }
+    let file = Config::path(KEY_FILE);
+    if file.exists() {
+        *key = read_key(&file)?;
+        return Ok(key.clone());
+    }
+    let v = rand::random::<[u8; 32]>().to_vec();
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(&file)?
+        .write_all(crate::encode64(&v).as_bytes())?;
+    log::info!("audit log key generated");
+    *key = v;
+    Ok(key.clone())
+}
+
+/// The key of the chain to check it with, which is never generated here.
+pub fn load_key() -> ResultType<Vec<u8>> {
+    let file = Config::path(KEY_FILE);
+    if !file.exists() {
+        bail!("No audit log key in {:?}", file);
+    }
+    read_key(&file)
+}
+
+fn read_key(file: &Path) -> ResultType<Vec<u8>> {
+    match crate::decode64(fs::read_to_string(file)?.trim()) {
+        Ok(v) if !v.is_empty() => Ok(v),
+        _ => bail!("invalid audit log key in {:?}", file),
+    }
+}
+
+fn option_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    Config::get_option(name).parse().unwrap_or(default)
+}
+
+/// Append an event, errors are only logged so that they never break a connection.
+pub fn record(event: &str, data: Value) {
+    if !is_enabled() {
+        return;
+    }
+    let max_size = option_or(OPTION_AUDIT_LOG_MAX_SIZE_MB, DEFAULT_MAX_SIZE_MB) * 1024 * 1024;
+    let max_files = option_or(OPTION_AUDIT_LOG_MAX_FILES, DEFAULT_MAX_FILES);
+    let mut writer = WRITER.lock().unwrap();
+    let res =
+        key().and_then(|key| append(&dir(), &key, &mut writer, event, data, max_size, max_files));
+    match res {
+        Ok(line) => {
+            if Config::get_option(OPTION_AUDIT_LOG_SYSLOG) == "Y" {
+                syslog::send(event, &line);
+            }
+        }
+        Err(err) => log::error!("Failed to write the audit log: {}", err),
+    }
+}
+
+// Returns the appended line.
+fn append(
+    dir: &Path,
+    key: &[u8],
+    chain: &mut Option<Chain>,
+    event: &str,
+    data: Value,
+    max_size: u64,
+    max_files: usize,
+) -> ResultType<String> {
+    let mut broken = false;
+    if chain.is_none() {
+        fs::create_dir_all(dir)?;
+        let tail = read_tail(dir)?;
+        *chain = Some(tail.0);
+        broken = tail.1;
+    }
+    let Some(chain) = chain.as_mut() else {
+        bail!("No audit chain");
+    };
+    let path = file_path(dir, 0);
+    if max_size > 0 && fs::metadata(&path).map_or(false, |m| m.len() >= max_size) {
+        rotate(dir, max_files)?;
+    }
+    if broken {
+        // the unreadable line is kept for the export to show it, and the chain goes on from the
+        // last readable entry with the break recorded
+        log::warn!("The audit log ends with an unreadable entry");
+        end_line(&path)?;
+        write_entry(
+            &path,
+            key,
+            chain,
+            EVENT_CHAIN_BREAK,
+            json!({"reason": "unreadable entry"}),
+        )?;
+    }
+    write_entry(&path, key, chain, event, data)
+}
+
+fn write_entry(
+    path: &Path,
+    key: &[u8],
+    chain: &mut Chain,
+    event: &str,
+    data: Value,
+) -> ResultType<String> {
+    let mut entry = AuditEntry {
+        seq: chain.seq + 1,
+        time: get_time(),
+        event: event.to_owned(),
+        data,
+        prev: chain.hash.clone(),
+        hash: "".to_owned(),
+    };
+    entry.hash = entry.compute_hash(key);
+    let line = serde_json::to_string(&entry)?;
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(format!("{}\n", line).as_bytes())?;
+    chain.seq = entry.seq;
+    chain.hash = entry.hash;
+    Ok(line)
+}
+
+// Terminate a line cut off by a crash, so that the next entry is not appended to it.
+fn end_line(path: &Path) -> ResultType<()> {
+    let mut file = match OpenOptions::new().read(true).append(true).open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let len = file.metadata()?.len();
+    if len > 0 {
+        let mut last = [0u8];
+        file.seek(SeekFrom::Start(len - 1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            file.write_all(b"\n")?;
+        }
+    }
+    Ok(())
+}
+
+fn is_blank(line: &[u8]) -> bool {
+    line.iter().all(u8::is_ascii_whitespace)
+}
+
+// The last entry of the newest file which has one, and whether unreadable lines follow it, e.g. of
+// a write cut off by a crash.
+fn read_tail(dir: &Path) -> ResultType<(Chain, bool)> {
+    let mut broken = false;
+    for path in files(dir).iter().rev() {
+        let mut last = None;
+        let mut unreadable = false;
+        for line in BufReader::new(File::open(path)?).split(b'\n') {
+            let line = line?;
+            if is_blank(&line) {
+                continue;
+            }
+            match serde_json::from_slice::<AuditEntry>(&line) {
+                Ok(entry) => {
+                    last = Some(entry);
+                    unreadable = false;
+                }
+                Err(_) => unreadable = true,
+            }
+        }
+        broken |= unreadable;
+        if let Some(entry) = last {
+            let chain = Chain {
+                seq: entry.seq,
+                hash: entry.hash,
+            };
+            return Ok((chain, broken));
+        }
+    }
+    Ok((Chain::default(), broken))
+}
+
+fn rotate(dir: &Path, max_files: usize) -> ResultType<()> {
+    let oldest = file_path(dir, max_files.max(1));
+    if oldest.exists() {
+        fs::remove_file(&oldest)?;
+    }
+    for i in (0..max_files.max(1)).rev() {
+        let from = file_path(dir, i);
+        if from.exists() {
+            fs::rename(&from, file_path(dir, i + 1))?;
+        }
+    }
+    // the files beyond the limit after it is lowered
+    let mut i = max_files.max(1) + 1;
+    while file_path(dir, i).exists() {
+        fs::remove_file(file_path(dir, i))?;
+        i += 1;
+    }
+    Ok(())
+}
+
+/// The existing files, from the oldest to the current one.
+pub fn files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut i = 1;
+    while file_path(dir, i).exists() {
+        files.push(file_path(dir, i));
+        i += 1;
+    }
+    files.reverse();
+    let current = file_path(dir, 0);
+    if current.exists() {
+        files.push(current);
+    }
+    files
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Verification {
+    pub entries: u64,
+    // the seq of the first entry, > 1 if the oldest files are rotated out
+    pub first_seq: u64,
+    // the line numbers in the exported order and the reasons of the broken links
+    pub errors: Vec<(u64, String)>,
+}
+
+/// Write all the entries to `out` from the oldest, and check the chain with `key`.
+pub fn export(dir: &Path, key: &[u8], out: &mut impl Write) -> ResultType<Verification> {
+    let mut res = Verification::default();
+    let mut prev: Option<AuditEntry> = None;
+    let mut n = 0;
+    for path in files(dir) {
+        for line in BufReader::new(File::open(&path)?).split(b'\n') {
+            let line = line?;
+            if is_blank(&line) {
+                continue;
+            }
+            n += 1;
+            out.write_all(&line)?;
+            out.write_all(b"\n")?;
+            let entry: AuditEntry = match serde_json::from_slice(&line) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    res.errors.push((n, format!("invalid entry: {}", err)));
+                    continue;
+                }
+            };
+            res.entries += 1;
+            if entry.compute_hash(key) != entry.hash {
+                res.errors.push((n, "hash mismatch".to_owned()));
+            }
+            match prev.as_ref() {
+                Some(prev) => {
+                    if entry.prev != prev.hash {
+                        res.errors
+                            .push((n, "not linked to the previous entry".to_owned()));
+                    }
+                    if entry.seq != prev.seq + 1 {
+                        res.errors
+                            .push((n, format!("seq {} after {}", entry.seq, prev.seq)));
+                    }
+                }
+                None => {
+                    res.first_seq = entry.seq;
+                    if entry.seq == 1 && !entry.prev.is_empty() {
+                        res.errors
+                            .push((n, "the first entry has a previous one".to_owned()));
+                    }
+                }
+            }
+            prev = Some(entry);
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(unix)]
+mod syslog {
+    use std::os::unix::net::UnixDatagram;
+
+    // authpriv
+    const FACILITY: u8 = 10;
+    const SEVERITY_WARNING: u8 = 4;
+    const SEVERITY_INFO: u8 = 6;
+
+    lazy_static::lazy_static! {
+        static ref SOCKET: Option<UnixDatagram> = connect();
+    }
+
+    fn connect() -> Option<UnixDatagram> {
+        let socket = UnixDatagram::unbound().ok()?;
+        for path in ["/dev/log", "/var/run/syslog", "/var/run/log"] {
+            if socket.connect(path).is_ok() {
+                return Some(socket);
+            }
+        }
+        hbb_common::log::warn!("No syslog socket for the audit log");
+        None
+    }
+
+    pub fn send(event: &str, line: &str) {
+        let severity = if event == "login_failure" || event == "alarm" {
+            SEVERITY_WARNING
+        } else {
+            SEVERITY_INFO
+        };
+        if let Some(socket) = SOCKET.as_ref() {
+            let msg = format!(
+                "<{}>{}[{}]: {}",
+                FACILITY * 8 + severity,
+                crate::get_app_name().to_lowercase(),
+                std::process::id(),
+                line
+            );
+            socket.send(msg.as_bytes()).ok();
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod syslog {
+    pub fn send(_event: &str, _line: &str) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"audit log test key";
+
+    #[test]
+    fn test_chain() {
+        let dir = std::env::temp_dir().join(format!("audit_log_test_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let mut chain = None;
+        for i in 0..10 {
+            append(&dir, KEY, &mut chain, "login", json!({ "i": i }), 300, 2).unwrap();
+        }
+        // the chain goes on after a restart
+        let mut chain = None;
+        append(&dir, KEY, &mut chain, "close", json!({}), 300, 2).unwrap();
+        assert_eq!(chain.as_ref().unwrap().seq, 11);
+        assert!(file_path(&dir, 2).exists());
+        assert!(!file_path(&dir, 3).exists());
+
+        let mut out = Vec::new();
+        let res = export(&dir, KEY, &mut out).unwrap();
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert!(res.first_seq > 1);
+        assert_eq!(res.first_seq + res.entries - 1, 11);
+
+        // tamper with an entry of the current file
+        let path = file_path(&dir, 0);
+        let content = fs::read_to_string(&path).unwrap();
+        fs::write(&path, content.replace("\"close\"", "\"login\"")).unwrap();
+        let res = export(&dir, KEY, &mut Vec::new()).unwrap();
+        assert_eq!(res.errors.len(), 1);
+        assert_eq!(res.errors[0].1, "hash mismatch");
+
+        // checked with another key
+        let res = export(&dir, b"another key", &mut Vec::new()).unwrap();
+        assert_eq!(res.errors.len() as u64, res.entries);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_truncated_tail() {
+        let dir = std::env::temp_dir().join(format!("audit_log_test_tail_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let mut chain = None;
+        for i in 0..3 {
+            append(&dir, KEY, &mut chain, "login", json!({ "i": i }), 0, 2).unwrap();
+        }
+        // a write cut off in the middle of a character
+        let path = file_path(&dir, 0);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"seq\":4,\"time\":1,\"event\":\"\xc3")
+            .unwrap();
+        drop(file);
+
+        let mut chain = None;
+        append(&dir, KEY, &mut chain, "close", json!({}), 0, 2).unwrap();
+        assert_eq!(chain.as_ref().unwrap().seq, 5);
+        let mut out = Vec::new();
+        let res = export(&dir, KEY, &mut out).unwrap();
+        assert_eq!(res.entries, 5);
+        assert_eq!(res.errors.len(), 1);
+        assert_eq!(res.errors[0].0, 4);
+        assert!(res.errors[0].1.starts_with("invalid entry"));
+        let lines: Vec<_> = out.split(|c| *c == b'\n').collect();
+        let entry: AuditEntry = serde_json::from_slice(lines[4]).unwrap();
+        assert_eq!(entry.event, EVENT_CHAIN_BREAK);
+        assert_eq!(entry.seq, 4);
+        let entry: AuditEntry = serde_json::from_slice(lines[5]).unwrap();
+        assert_eq!(entry.event, "close");
+
+        // the chain goes on without another break
+        let mut chain = None;
+        append(&dir, KEY, &mut chain, "close", json!({}), 0, 2).unwrap();
+        assert_eq!(chain.as_ref().unwrap().seq, 6);
+        fs::remove_dir_all(&dir).ok();
+    }
+}
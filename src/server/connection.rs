@@ -1,4 +1,13 @@
-use super::{input_service::*, *};
+use super::{
+    access_rules::{AccessAction, AccessRequest, AccessRules, ConnKind},
+    access_schedule::{self, Outside},
+    audit_log,
+    input_service::*,
+    login_lockout::{self, LoginKind},
+    permission_profiles::{self, AuthMethod, PermissionProfile},
+    port_forward_policy::PortForwardPolicy,
+    *,
+};
 #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
 use crate::clipboard_file::*;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
@@ -22,7 +31,6 @@ use crate::{
 };
 #[cfg(any(target_os = "android", target_os = "ios"))]
 use crate::{common::DEVICE_NAME, flutter::connection_manager::start_channel};
-use cidr_utils::cidr::IpCidr;
 #[cfg(target_os = "linux")]
 use hbb_common::platform::linux::run_cmds;
 #[cfg(target_os = "android")]
@@ -36,11 +44,12 @@ use hbb_common::{
     password_security::{self as password, ApproveMode},
     sleep, timeout,
     tokio::{
+        io::AsyncWriteExt,
         net::TcpStream,
-        sync::mpsc,
+        sync::{mpsc, oneshot, Semaphore},
         time::{self, Duration, Instant},
     },
-    tokio_util::codec::{BytesCodec, Framed},
+    tokio_util::codec::{BytesCodec, Framed, FramedRead},
 };
 #[cfg(any(target_os = "android", target_os = "ios"))]
 use scrap::android::{call_main_service_key_event, call_main_service_pointer_input};
@@ -50,6 +59,7 @@ use sha2::{Digest, Sha256};
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 use std::sync::atomic::Ordering;
 use std::{
+    net::IpAddr,
     num::NonZeroI64,
     path::PathBuf,
     sync::{atomic::AtomicI64, mpsc as std_mpsc},
@@ -64,7 +74,6 @@ use std::collections::HashSet;
 pub type Sender = mpsc::UnboundedSender<(Instant, Arc<Message>)>;
 
 lazy_static::lazy_static! {
-    static ref LOGIN_FAILURES: [Arc::<Mutex<HashMap<String, (i32, i32, i32)>>>; 2] = Default::default();
     static ref SESSIONS: Arc::<Mutex<HashMap<String, Session>>> = Default::default();
     static ref ALIVE_CONNS: Arc::<Mutex<Vec<i32>>> = Default::default();
     pub static ref AUTHED_CONNS: Arc::<Mutex<Vec<(i32, AuthConnType)>>> = Default::default();
@@ -177,9 +186,26 @@ pub struct Connection {
     file_transfer: Option<(String, bool)>,
     port_forward_socket: Option<Framed<TcpStream, BytesCodec>>,
     port_forward_address: String,
+    // streams of a multiplexed port forward connection
+    port_forward_tunnels: Option<Tunnels>,
+    // loaded at login
+    port_forward_policy: Arc<PortForwardPolicy>,
     tx_to_cm: mpsc::UnboundedSender<ipc::Data>,
     authorized: bool,
-    require_2fa: Option<totp_rs::TOTP>,
+    require_2fa: Option<crate::auth_2fa::TwoFactor>,
+    // the factor of 2fa, for the audit
+    tfa_factor: Option<String>,
+    // the name of the authorized key logged in with, for the audit
+    auth_key: Option<String>,
+    // `None` for the password
+    auth_method: Option<AuthMethod>,
+    // applied at login
+    permission_profile: Option<PermissionProfile>,
+    // the end of the max session duration of the permission profile
+    session_deadline: Option<Instant>,
+    // the end of the window of the unattended access schedule, and when to warn about it
+    schedule_deadline: Option<Instant>,
+    schedule_warning: Option<Instant>,
     keyboard: bool,
     clipboard: bool,
     audio: bool,
@@ -240,6 +266,8 @@ pub struct Connection {
     follow_remote_cursor: bool,
     follow_remote_window: bool,
     multi_ui_session: bool,
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    session_recorder: Option<scrap::session_record::SessionRecorder>,
 }
 
 impl ConnInner {
@@ -283,6 +311,10 @@ const MILLI1: Duration = Duration::from_millis(1);
 const SEND_TIMEOUT_VIDEO: u64 = 12_000;
 const SEND_TIMEOUT_OTHER: u64 = SEND_TIMEOUT_VIDEO * 10;
 const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+// messages queued per tunnel stream, or from all of them towards the peer
+const TUNNEL_QUEUE_SIZE: usize = 64;
+// milliseconds the connection waits for a full tunnel stream before closing it
+const TUNNEL_SEND_TIMEOUT: u64 = 3_000;
 
 impl Connection {
     pub async fn start(
@@ -295,6 +327,7 @@ impl Connection {
         let hash = Hash {
             salt: Config::get_salt(),
             challenge: Config::get_auto_password(6),
+            public_key_auth: !crate::auth_pk::load_authorized_keys().is_empty(),
             ..Default::default()
         };
         let (tx_from_cm_holder, mut rx_from_cm) = mpsc::unbounded_channel::<ipc::Data>();
@@ -322,6 +355,13 @@ impl Connection {
                 tx_video: Some(tx_video),
             },
             require_2fa: crate::auth_2fa::get_2fa(None),
+            tfa_factor: None,
+            auth_key: None,
+            auth_method: None,
+            permission_profile: None,
+            session_deadline: None,
+            schedule_deadline: None,
+            schedule_warning: None,
             display_idx: *display_service::PRIMARY_DISPLAY_IDX,
             stream,
             server,
@@ -332,6 +372,8 @@ impl Connection {
             file_transfer: None,
             port_forward_socket: None,
             port_forward_address: "".to_owned(),
+            port_forward_tunnels: None,
+            port_forward_policy: Default::default(),
             tx_to_cm,
             authorized: false,
             keyboard: Connection::permission("enable-keyboard"),
@@ -349,6 +391,8 @@ impl Connection {
             follow_remote_cursor: false,
             follow_remote_window: false,
             multi_ui_session: false,
+            #[cfg(not(any(target_os = "android", target_os = "ios")))]
+            session_recorder: None,
             ip: "".to_owned(),
             disable_audio: false,
             #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
@@ -447,6 +491,7 @@ impl Connection {
                     match data {
                         ipc::Data::Authorize => {
                             conn.require_2fa.take();
+                            conn.auth_method = Some(AuthMethod::Click);
                             conn.send_logon_response().await;
                             if conn.port_forward_socket.is_some() {
                                 break;
@@ -479,6 +524,7 @@ impl Connection {
                         }
                         ipc::Data::SwitchPermission{name, enabled} => {
                             log::info!("Change permission {} -> {}", name, enabled);
+                            conn.audit("permission", json!({"permission": name, "enabled": enabled}));
                             if &name == "keyboard" {
                                 conn.keyboard = enabled;
                                 conn.send_permission(Permission::Keyboard, enabled).await;
@@ -563,6 +609,12 @@ impl Connection {
                         ipc::Data::VoiceCallResponse(accepted) => {
                             conn.handle_voice_call(accepted).await;
                         }
+                        ipc::Data::ReverseForwardResponse((listen_id, accepted)) => {
+                            conn.handle_reverse_forward_response(listen_id, accepted).await;
+                        }
+                        ipc::Data::PortForwardResponse((stream_id, accepted)) => {
+                            conn.handle_port_forward_response(stream_id, accepted);
+                        }
                         ipc::Data::CloseVoiceCall(_reason) => {
                             log::debug!("Close the voice call from the ipc.");
                             conn.close_voice_call().await;
@@ -631,6 +683,8 @@ impl Connection {
                     if !conn.video_ack_required {
                         video_service::notify_video_frame_fetched(id, Some(instant.into()));
                     }
+                    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+                    conn.record_session_message(&value);
                     if let Err(err) = conn.stream.send(&value as &Message).await {
                         conn.on_close(&err.to_string(), false).await;
                         break;
@@ -651,6 +705,9 @@ impl Connection {
                         }
                     }
                     match &msg.union {
+                        Some(message::Union::Clipboard(cb)) => {
+                            conn.audit("clipboard", json!({"direction": "out", "size": cb.content.len()}));
+                        }
                         Some(message::Union::Misc(m)) => {
                             match &m.union {
                                 Some(misc::Union::StopService(_)) => {
@@ -681,10 +738,27 @@ impl Connection {
                         _ => {}
                     }
                     let msg: &Message = &msg;
-                    if let Err(err) = conn.stream.send(msg).await {
+                    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+                    conn.record_session_message(msg);
+                    let res = if let Some(message::Union::VideoFrame(_)) = &msg.union {
+                        conn.stream.send_video(msg).await
+                    } else {
+                        conn.stream.send(msg).await
+                    };
+                    if let Err(err) = res {
                         conn.on_close(&err.to_string(), false).await;
                         break;
                     }
+                    if let (Some(tunnels), Some(message::Union::Tunnel(tunnel))) =
+                        (conn.port_forward_tunnels.as_ref(), &msg.union)
+                    {
+                        if matches!(
+                            tunnel.union,
+                            Some(tunnel::Union::Data(_)) | Some(tunnel::Union::Udp(_))
+                        ) {
+                            tunnels.credits.add_permits(1);
+                        }
+                    }
                 },
                 _ = second_timer.tick() => {
                     #[cfg(windows)]
@@ -696,6 +770,20 @@ impl Connection {
                             break;
                         }
                     }
+                    if conn.session_deadline.map_or(false, |t| Instant::now() >= t) {
+                        conn.send_close_reason_no_retry("The session duration limit is reached").await;
+                        conn.on_close("session duration limit", true).await;
+                        break;
+                    }
+                    if conn.schedule_warning.map_or(false, |t| Instant::now() >= t) {
+                        conn.schedule_warning = None;
+                        conn.send_schedule_warning().await;
+                    }
+                    if conn.schedule_deadline.map_or(false, |t| Instant::now() >= t) {
+                        conn.send_close_reason_no_retry("The unattended access window has ended").await;
+                        conn.on_close("unattended access window end", true).await;
+                        break;
+                    }
                     conn.file_remove_log_control.on_timer().drain(..).map(|x| conn.send_to_cm(x)).count();
                     #[cfg(feature = "vram")]
                     conn.update_supported_encoding();
@@ -733,7 +821,7 @@ impl Connection {
             conn.lr.my_id.clone(),
         );
         video_service::notify_video_frame_fetched(id, None);
-        if conn.authorized {
+        if conn.authorized && password::rotate_temporary_password_after_session() {
             password::update_temporary_password();
         }
         if let Err(err) = conn.try_port_forward_loop(&mut rx_from_cm).await {
@@ -859,6 +947,10 @@ impl Connection {
             log::info!("Running port forwarding loop");
             self.stream.set_raw();
             let mut hbbs_rx = crate::hbbs_http::sync::signal_receiver();
+            let session_deadline = self.session_deadline;
+            let schedule_deadline = self.schedule_deadline;
+            // the stream is raw, the peer can not be shown a message box
+            let mut schedule_warning = self.schedule_warning.take();
             loop {
                 tokio::select! {
                     Some(data) = rx_from_cm.recv() => {
@@ -900,6 +992,16 @@ impl Connection {
                             bail!("Closed manually by the web console");
                         }
                     }
+                    _ = wait_until(session_deadline) => {
+                        bail!("The session duration limit is reached");
+                    }
+                    _ = wait_until(schedule_warning) => {
+                        schedule_warning = None;
+                        log::warn!("The unattended access window of the port forward is ending");
+                    }
+                    _ = wait_until(schedule_deadline) => {
+                        bail!("The unattended access window has ended");
+                    }
                 }
             }
         }
@@ -928,38 +1030,60 @@ impl Connection {
         }
     }
 
-    async fn check_whitelist(&mut self, addr: &SocketAddr) -> bool {
-        let whitelist: Vec<String> = Config::get_option("whitelist")
-            .split(",")
-            .filter(|x| !x.is_empty())
-            .map(|x| x.to_owned())
-            .collect();
-        if !whitelist.is_empty()
-            && whitelist
-                .iter()
-                .filter(|x| x == &"0.0.0.0")
-                .next()
-                .is_none()
-            && whitelist
-                .iter()
-                .filter(|x| IpCidr::from_str(x).map_or(false, |y| y.contains(addr.ip())))
-                .next()
-                .is_none()
-        {
+    // Checked on open with the ip only, and again after the login request with the peer id
+    // and the connection type, rules depending on them are skipped on open.
+    async fn check_access_rules(
+        &mut self,
+        ip: IpAddr,
+        peer_id: Option<&str>,
+        conn_type: Option<ConnKind>,
+    ) -> bool {
+        let rules = AccessRules::load();
+        let (action, index) = match rules.evaluate(&AccessRequest::new(ip, peer_id, conn_type)) {
+            Some(res) => res,
+            None => return true,
+        };
+        let rule = rules.rule_name(index);
+        log::info!(
+            "#{} Access rule {} matched: {:?}, ip: {}, peer: {:?}, type: {:?}",
+            self.inner.id,
+            rule,
+            action,
+            ip,
+            peer_id,
+            conn_type
+        );
+        if action == AccessAction::Allow {
+            return true;
+        }
+        if peer_id.is_none() {
             self.send_login_error("Your ip is blocked by the peer")
                 .await;
+        } else {
+            self.send_login_error("Connection not allowed").await;
+        }
+        if rules.legacy {
             Self::post_alarm_audit(
                 AlarmAuditType::IpWhitelist, //"ip whitelist",
-                json!({ "ip":addr.ip() }),
+                json!({ "ip": ip }),
+            );
+        } else {
+            Self::post_alarm_audit(
+                AlarmAuditType::AccessRule,
+                json!({
+                    "ip": ip,
+                    "peer_id": peer_id,
+                    "conn_type": conn_type,
+                    "rule": rule,
+                }),
             );
-            return false;
         }
-        true
+        false
     }
 
     async fn on_open(&mut self, addr: SocketAddr) -> bool {
         log::debug!("#{} Connection opened from {}.", self.inner.id, addr);
-        if !self.check_whitelist(&addr).await {
+        if !self.check_access_rules(addr.ip(), None, None).await {
             return false;
         }
         #[cfg(not(any(target_os = "android", target_os = "ios")))]
@@ -994,7 +1118,22 @@ impl Connection {
         );
     }
 
+    // Record an event of this connection in the local audit log.
+    fn audit(&self, event: &str, mut data: Value) {
+        data["conn_id"] = json!(self.inner.id);
+        data["peer_id"] = json!(self.lr.my_id);
+        data["name"] = json!(self.lr.my_name);
+        data["ip"] = json!(self.ip);
+        audit_log::record(event, data);
+    }
+
     fn post_conn_audit(&self, v: Value) {
+        match v["action"].as_str() {
+            Some("new") => self.audit("connect", json!({})),
+            // recorded with the reason in `on_close`
+            Some("close") => {}
+            _ => self.audit("login", v.clone()),
+        }
         if self.server_audit_conn.is_empty() {
             return;
         }
@@ -1016,6 +1155,15 @@ impl Connection {
         files: Vec<(String, i64)>,
         info: Value,
     ) {
+        self.audit(
+            "file",
+            json!({
+                "type": r#type as i8,
+                "path": path,
+                "num": files.len(),
+                "files": files.iter().take(10).collect::<Vec<_>>(),
+            }),
+        );
         if self.server_audit_file.is_empty() {
             return;
         }
@@ -1045,6 +1193,7 @@ impl Connection {
     }
 
     pub fn post_alarm_audit(typ: AlarmAuditType, info: Value) {
+        audit_log::record("alarm", json!({"typ": typ as i8, "info": info}));
         let url = crate::get_audit_server(
             Config::get_option("api-server"),
             Config::get_option("custom-rendezvous-server"),
@@ -1063,6 +1212,21 @@ impl Connection {
         });
     }
 
+    // the peer of the alarm audit of a denied port forward target
+    fn port_forward_alarm_peer(ip: &str, lr: &LoginRequest) -> Value {
+        json!({
+            "ip": ip,
+            "id": lr.my_id.clone(),
+            "name": lr.my_name.clone(),
+        })
+    }
+
+    fn post_port_forward_alarm(mut info: Value, target: &str) {
+        log::warn!("Port forward target {} is denied", target);
+        info["target"] = json!(target);
+        Self::post_alarm_audit(AlarmAuditType::PortForwardTarget, info);
+    }
+
     #[inline]
     async fn post_audit_async(url: String, v: Value) -> ResultType<String> {
         crate::post_request(url, v.to_string(), "").await
@@ -1072,14 +1236,31 @@ impl Connection {
         if self.authorized {
             return;
         }
-        if self.require_2fa.is_some() && !self.is_recent_session(true) && !self.from_switch {
-            self.send_login_error(crate::client::REQUIRE_2FA).await;
+        if self.require_2fa.is_some() {
+            if self.is_recent_session(true) {
+                self.tfa_factor = Some("recent-session".to_owned());
+            } else if !self.from_switch {
+                self.send_login_error(crate::client::REQUIRE_2FA).await;
+                return;
+            }
+        }
+        if !self.apply_permission_profile().await {
+            return;
+        }
+        if self.port_forward_socket.is_some()
+            && self.port_forward_policy.approve
+            && self.auth_method != Some(AuthMethod::Click)
+        {
+            // the target is shown in cm, accepting it authorizes the connection
+            self.send_login_error(crate::client::LOGIN_MSG_NO_PASSWORD_ACCESS)
+                .await;
             return;
         }
         self.authorized = true;
+        self.set_schedule_deadline();
         let (conn_type, auth_conn_type) = if self.file_transfer.is_some() {
             (1, AuthConnType::FileTransfer)
-        } else if self.port_forward_socket.is_some() {
+        } else if self.is_port_forward() {
             (2, AuthConnType::PortForward)
         } else {
             (0, AuthConnType::Remote)
@@ -1088,9 +1269,18 @@ impl Connection {
             self.inner.id(),
             auth_conn_type,
         ));
-        self.post_conn_audit(
-            json!({"peer": ((&self.lr.my_id, &self.lr.my_name)), "type": conn_type}),
-        );
+        if conn_type == 0 && self.stream.is_quic() {
+            // the video frames go through their own stream
+            allow_err!(self.stream.open_video_stream().await);
+        }
+        let mut audit = json!({"peer": ((&self.lr.my_id, &self.lr.my_name)), "type": conn_type});
+        if let Some(factor) = self.tfa_factor.as_ref() {
+            audit["tfa"] = json!(factor);
+        }
+        if let Some(key) = self.auth_key.as_ref() {
+            audit["key"] = json!(key);
+        }
+        self.post_conn_audit(audit);
         #[allow(unused_mut)]
         let mut username = crate::platform::get_active_username();
         let mut res = LoginResponse::new();
@@ -1165,15 +1355,20 @@ impl Connection {
         log::info!("peer info supported_encoding: {:?}", supported_encoding);
         pi.encoding = Some(supported_encoding).into();
 
-        if self.port_forward_socket.is_some() {
+        if self.is_port_forward() {
             let mut msg_out = Message::new();
             res.set_peer_info(pi);
             msg_out.set_login_response(res);
             self.send(msg_out).await;
             return;
         }
+        if self.file_transfer.is_none() {
+            scrap::record_store::peer_connected(self.inner.id(), &self.lr.my_id);
+            #[cfg(not(any(target_os = "android", target_os = "ios")))]
+            self.start_session_recorder();
+        }
         #[cfg(target_os = "linux")]
-        if !self.file_transfer.is_some() && !self.port_forward_socket.is_some() {
+        if !self.file_transfer.is_some() && !self.is_port_forward() {
             let dtype = crate::platform::linux::get_display_server();
             if dtype != crate::platform::linux::DISPLAY_SERVER_X11
                 && dtype != crate::platform::linux::DISPLAY_SERVER_WAYLAND
@@ -1306,7 +1501,7 @@ impl Connection {
     }
 
     fn try_sub_services(&mut self) {
-        let is_remote = self.file_transfer.is_none() && self.port_forward_socket.is_none();
+        let is_remote = self.file_transfer.is_none() && !self.is_port_forward();
         if is_remote && !self.services_subed {
             self.services_subed = true;
             if let Some(s) = self.server.upgrade() {
@@ -1493,6 +1688,10 @@ impl Connection {
         if password::temporary_enabled() {
             let password = password::temporary_password();
             if self.validate_one_password(password.clone()) {
+                if password::temporary_password_one_time() {
+                    // the reconnection of this session is still validated with it
+                    password::update_temporary_password();
+                }
                 SESSIONS.lock().unwrap().insert(
                     self.lr.my_id.clone(),
                     Session {
@@ -1514,6 +1713,144 @@ impl Connection {
         false
     }
 
+    fn validate_public_key(&mut self, conn_type: ConnKind) -> bool {
+        let Some(auth) = self.lr.public_key_auth.as_ref() else {
+            return false;
+        };
+        let keys = crate::auth_pk::load_authorized_keys();
+        if keys.is_empty()
+            || login_lockout::check(LoginKind::Password, &self.login_failure_keys()).is_some()
+        {
+            return false;
+        }
+        let binding = self.stream.session_binding();
+        match crate::auth_pk::verify_login(
+            &keys,
+            &Config::get_id(),
+            &self.hash,
+            &binding,
+            auth,
+            conn_type,
+        ) {
+            crate::auth_pk::Verified::Unknown => false,
+            crate::auth_pk::Verified::BadSignature => {
+                self.update_failure(LoginKind::Password, false);
+                false
+            }
+            crate::auth_pk::Verified::Ok(name) => {
+                self.update_failure(LoginKind::Password, true);
+                self.auth_key = Some(name);
+                self.auth_method = Some(AuthMethod::PublicKey);
+                true
+            }
+        }
+    }
+
+    // Returns false if the port forward target is not allowed by the profile.
+    async fn apply_permission_profile(&mut self) -> bool {
+        let profiles = permission_profiles::load();
+        if profiles.is_empty() {
+            return true;
+        }
+        let auth = if self.from_switch {
+            AuthMethod::Switch
+        } else {
+            self.auth_method.unwrap_or(AuthMethod::Password)
+        };
+        let Some((index, profile)) = permission_profiles::find(&profiles, &self.lr.my_id, auth)
+        else {
+            return true;
+        };
+        log::info!(
+            "Apply permission profile {} to {}",
+            profile.name(index),
+            self.lr.my_id
+        );
+        if self.port_forward_socket.is_some()
+            && !profile.allows_port_forward(&self.port_forward_address)
+        {
+            self.send_login_error("No permission of the port forward target")
+                .await;
+            return false;
+        }
+        let grants = profile.grants(auth);
+        for (permission, enabled) in [
+            (Permission::Keyboard, profile.keyboard),
+            (Permission::Clipboard, profile.clipboard),
+            (Permission::Audio, profile.audio),
+            (Permission::File, profile.file),
+            (Permission::Restart, profile.restart),
+            (Permission::Recording, profile.recording),
+            (Permission::BlockInput, profile.block_input),
+        ] {
+            let Some(enabled) = enabled else {
+                continue;
+            };
+            if enabled && !grants {
+                log::warn!(
+                    "Permission profile {} can not enable {:?} without public key authentication",
+                    profile.name(index),
+                    permission
+                );
+                continue;
+            }
+            let value = match permission {
+                Permission::Keyboard => &mut self.keyboard,
+                Permission::Clipboard => &mut self.clipboard,
+                Permission::Audio => &mut self.audio,
+                Permission::File => &mut self.file,
+                Permission::Restart => &mut self.restart,
+                Permission::Recording => &mut self.recording,
+                Permission::BlockInput => &mut self.block_input,
+            };
+            if *value != enabled {
+                *value = enabled;
+                self.send_permission(permission, enabled).await;
+                self.audit(
+                    "permission",
+                    json!({
+                        "permission": format!("{:?}", permission),
+                        "enabled": enabled,
+                        "profile": profile.name(index),
+                    }),
+                );
+            }
+        }
+        if profile.max_duration > 0 {
+            self.session_deadline =
+                Some(Instant::now() + Duration::from_secs(profile.max_duration * 60));
+        }
+        self.permission_profile = Some(profile);
+        true
+    }
+
+    // The sessions not accepted in cm are closed at the end of their window of the schedule.
+    fn set_schedule_deadline(&mut self) {
+        if self.from_switch || self.auth_method == Some(AuthMethod::Click) {
+            return;
+        }
+        let Some(schedule) = access_schedule::Schedule::load() else {
+            return;
+        };
+        if let Some(remaining) = schedule.remaining() {
+            let now = Instant::now();
+            self.schedule_deadline = Some(now + remaining);
+            self.schedule_warning = Some(now + remaining.saturating_sub(schedule.warn));
+        }
+    }
+
+    async fn send_schedule_warning(&mut self) {
+        let mut msg_out = Message::new();
+        msg_out.set_message_box(MessageBox {
+            msgtype: "nook-nocancel-hasclose".to_owned(),
+            title: "Prompt".to_owned(),
+            text: "unattended_window_ending_tip".to_owned(),
+            link: "".to_owned(),
+            ..Default::default()
+        });
+        self.send(msg_out).await;
+    }
+
     fn is_recent_session(&mut self, tfa: bool) -> bool {
         SESSIONS
             .lock()
@@ -1609,11 +1946,36 @@ impl Connection {
     }
 
     async fn on_message(&mut self, msg: Message) -> bool {
+        #[cfg(not(any(target_os = "android", target_os = "ios")))]
+        if self.authorized && scrap::session_record::is_input_or_chat(&msg) {
+            self.record_session_message(&msg);
+        }
         if let Some(message::Union::LoginRequest(lr)) = msg.union {
             self.handle_login_request_without_validation(&lr).await;
             if self.authorized {
                 return true;
             }
+            let conn_type = match lr.union {
+                Some(login_request::Union::FileTransfer(_)) => ConnKind::FileTransfer,
+                Some(login_request::Union::PortForward(_)) => ConnKind::PortForward,
+                _ => ConnKind::Remote,
+            };
+            // fail closed, the rules can not be checked without the ip
+            let allowed = match self.ip.parse::<IpAddr>() {
+                Ok(ip) => {
+                    self.check_access_rules(ip, Some(&lr.my_id), Some(conn_type))
+                        .await
+                }
+                Err(_) => {
+                    log::error!("#{} Invalid ip: {:?}", self.inner.id, self.ip);
+                    self.send_login_error("Connection not allowed").await;
+                    false
+                }
+            };
+            if !allowed {
+                sleep(1.).await;
+                return false;
+            }
             match lr.union {
                 Some(login_request::Union::FileTransfer(ft)) => {
                     if !Connection::permission("enable-file-transfer") {
@@ -1624,6 +1986,17 @@ impl Connection {
                     }
                     self.file_transfer = Some((ft.dir, ft.show_hidden));
                 }
+                Some(login_request::Union::PortForward(pf)) if pf.multiplex => {
+                    if !Connection::permission("enable-tunnel") {
+                        self.send_login_error("No permission of IP tunneling").await;
+                        sleep(1.).await;
+                        return false;
+                    }
+                    // the targets are opened on demand by `TunnelOpen`
+                    self.port_forward_address = "*".to_owned();
+                    self.port_forward_tunnels = Some(Tunnels::default());
+                    self.port_forward_policy = Arc::new(PortForwardPolicy::load());
+                }
                 Some(login_request::Union::PortForward(mut pf)) => {
                     if !Connection::permission("enable-tunnel") {
                         self.send_login_error("No permission of IP tunneling").await;
@@ -1641,7 +2014,28 @@ impl Connection {
                     }
                     let mut addr = format!("{}:{}", pf.host, pf.port);
                     self.port_forward_address = addr.clone();
-                    match timeout(3000, TcpStream::connect(&addr)).await {
+                    self.port_forward_policy = Arc::new(PortForwardPolicy::load());
+                    let addrs = match timeout(
+                        3000,
+                        self.port_forward_policy.resolve_allowed(&pf.host, pf.port),
+                    )
+                    .await
+                    {
+                        Ok(Ok(Some(addrs))) => addrs,
+                        Ok(Ok(None)) => {
+                            Self::post_port_forward_alarm(
+                                Self::port_forward_alarm_peer(&self.ip, &self.lr),
+                                &addr,
+                            );
+                            self.send_login_error("No permission of the port forward target")
+                                .await;
+                            sleep(1.).await;
+                            return false;
+                        }
+                        // failed to resolve, so is the connecting
+                        _ => vec![],
+                    };
+                    match timeout(3000, TcpStream::connect(&addrs[..])).await {
                         Ok(Ok(sock)) => {
                             self.port_forward_socket = Some(Framed::new(sock, BytesCodec::new()));
                         }
@@ -1689,8 +2083,31 @@ impl Connection {
                 self.send_login_error(crate::client::LOGIN_MSG_OFFLINE)
                     .await;
                 return false;
-            } else if password::approve_mode() == ApproveMode::Click
-                || password::approve_mode() == ApproveMode::Both && !password::has_valid_password()
+            }
+            let mut approve_mode = password::approve_mode();
+            if approve_mode != ApproveMode::Click {
+                if let Some(schedule) = access_schedule::Schedule::load() {
+                    if !schedule.is_open() {
+                        if schedule.outside == Outside::Deny {
+                            self.send_login_error("Connection not allowed").await;
+                            return false;
+                        }
+                        // outside the window, only the connection manager accepts
+                        approve_mode = ApproveMode::Click;
+                    }
+                }
+            }
+            if approve_mode != ApproveMode::Click && self.validate_public_key(conn_type) {
+                if err_msg.is_empty() {
+                    #[cfg(target_os = "linux")]
+                    self.linux_headless_handle.wait_desktop_cm_ready().await;
+                    self.send_logon_response().await;
+                    self.try_start_cm(lr.my_id, lr.my_name, self.authorized);
+                } else {
+                    self.send_login_error(err_msg).await;
+                }
+            } else if approve_mode == ApproveMode::Click
+                || approve_mode == ApproveMode::Both && !password::has_valid_password()
             {
                 self.try_start_cm(lr.my_id, lr.my_name, false);
                 if hbb_common::get_version_number(&lr.version)
@@ -1700,9 +2117,7 @@ impl Connection {
                         .await;
                 }
                 return true;
-            } else if password::approve_mode() == ApproveMode::Password
-                && !password::has_valid_password()
-            {
+            } else if approve_mode == ApproveMode::Password && !password::has_valid_password() {
                 self.send_login_error("Connection not allowed").await;
                 return false;
             } else if self.is_recent_session(false) {
@@ -1716,6 +2131,11 @@ impl Connection {
                 }
             } else if lr.password.is_empty() {
                 if err_msg.is_empty() {
+                    if lr.public_key_auth.is_some() {
+                        // the key is not authorized, the client did not ask for the password
+                        self.send_login_error(crate::client::LOGIN_MSG_PASSWORD_EMPTY)
+                            .await;
+                    }
                     self.try_start_cm(lr.my_id, lr.my_name, false);
                 } else {
                     self.send_login_error(
@@ -1724,12 +2144,11 @@ impl Connection {
                     .await;
                 }
             } else {
-                let (failure, res) = self.check_failure(0).await;
-                if !res {
+                if !self.check_failure(LoginKind::Password).await {
                     return true;
                 }
                 if !self.validate_password() {
-                    self.update_failure(failure, false, 0);
+                    self.update_failure(LoginKind::Password, false);
                     if err_msg.is_empty() {
                         self.send_login_error(crate::client::LOGIN_MSG_PASSWORD_WRONG)
                             .await;
@@ -1741,7 +2160,7 @@ impl Connection {
                         .await;
                     }
                 } else {
-                    self.update_failure(failure, true, 0);
+                    self.update_failure(LoginKind::Password, true);
                     if err_msg.is_empty() {
                         #[cfg(target_os = "linux")]
                         self.linux_headless_handle.wait_desktop_cm_ready().await;
@@ -1753,15 +2172,15 @@ impl Connection {
                 }
             }
         } else if let Some(message::Union::Auth2fa(tfa)) = msg.union {
-            let (failure, res) = self.check_failure(1).await;
-            if !res {
+            if !self.check_failure(LoginKind::Tfa).await {
                 return true;
             }
-            if let Some(totp) = self.require_2fa.as_ref() {
-                if let Ok(res) = totp.check_current(&tfa.code) {
-                    if res {
-                        self.update_failure(failure, true, 1);
+            if self.require_2fa.is_some() {
+                match crate::auth_2fa::verify_login(&tfa.code) {
+                    Some(factor) => {
+                        self.update_failure(LoginKind::Tfa, true);
                         self.require_2fa.take();
+                        self.tfa_factor = Some(factor);
                         self.send_logon_response().await;
                         self.try_start_cm(
                             self.lr.my_id.to_owned(),
@@ -1791,8 +2210,9 @@ impl Connection {
                                 },
                             );
                         }
-                    } else {
-                        self.update_failure(failure, false, 1);
+                    }
+                    None => {
+                        self.update_failure(LoginKind::Tfa, false);
                         self.send_login_error(crate::client::LOGIN_MSG_2FA_WRONG)
                             .await;
                     }
@@ -1844,6 +2264,12 @@ impl Connection {
             if self.port_forward_socket.is_some() {
                 return true;
             }
+            if self.port_forward_tunnels.is_some() {
+                if let Some(message::Union::Tunnel(tunnel)) = msg.union {
+                    self.handle_tunnel(tunnel).await;
+                }
+                return true;
+            }
             match msg.union {
                 #[allow(unused_mut)]
                 Some(message::Union::MouseEvent(mut me)) => {
@@ -2007,8 +2433,11 @@ impl Connection {
                     }
                     self.update_auto_disconnect_timer();
                 }
-                Some(message::Union::Clipboard(_cb)) =>
-                {
+                Some(message::Union::Clipboard(_cb)) => {
+                    self.audit(
+                        "clipboard",
+                        json!({"direction": "in", "size": _cb.content.len(), "allowed": self.clipboard}),
+                    );
                     #[cfg(not(any(target_os = "android", target_os = "ios")))]
                     if self.clipboard {
                         update_clipboard(_cb, None);
@@ -2059,7 +2488,13 @@ impl Connection {
                                     s.include_hidden,
                                     false,
                                     od,
-                                ) {
+                                )
+                                .and_then(|mut job| {
+                                    if s.preserve_attributes {
+                                        job.load_attributes()?;
+                                    }
+                                    Ok(job)
+                                }) {
                                     Err(err) => {
                                         self.send(fs::new_error(id, err, 0)).await;
                                     }
@@ -2105,6 +2540,21 @@ impl Connection {
                                     overwrite_detection: od,
                                     total_size: r.total_size,
                                     conn_id: self.inner.id(),
+                                    preserve_attributes: r.preserve_attributes,
+                                    attributes: if r.preserve_attributes {
+                                        r.files
+                                            .iter()
+                                            .map(|f| {
+                                                f.attributes
+                                                    .as_ref()
+                                                    .and_then(|a| a.write_to_bytes().ok())
+                                                    .unwrap_or_default()
+                                                    .into()
+                                            })
+                                            .collect()
+                                    } else {
+                                        Vec::new()
+                                    },
                                 });
                                 self.post_file_audit(
                                     FileAuditType::RemoteReceive,
@@ -2119,6 +2569,10 @@ impl Connection {
                                 self.file_transferred = true;
                             }
                             Some(file_action::Union::RemoveDir(d)) => {
+                                self.audit(
+                                    "file",
+                                    json!({"action": "remove_dir", "path": d.path, "recursive": d.recursive}),
+                                );
                                 self.send_fs(ipc::FS::RemoveDir {
                                     path: d.path.clone(),
                                     id: d.id,
@@ -2127,6 +2581,10 @@ impl Connection {
                                 self.file_remove_log_control.on_remove_dir(d);
                             }
                             Some(file_action::Union::RemoveFile(f)) => {
+                                self.audit(
+                                    "file",
+                                    json!({"action": "remove_file", "path": f.path}),
+                                );
                                 self.send_fs(ipc::FS::RemoveFile {
                                     path: f.path.clone(),
                                     id: f.id,
@@ -2135,6 +2593,7 @@ impl Connection {
                                 self.file_remove_log_control.on_remove_file(f);
                             }
                             Some(file_action::Union::Create(c)) => {
+                                self.audit("file", json!({"action": "create_dir", "path": c.path}));
                                 self.send_fs(ipc::FS::CreateDir {
                                     path: c.path.clone(),
                                     id: c.id,
@@ -2171,17 +2630,27 @@ impl Connection {
                 }
                 Some(message::Union::FileResponse(fr)) => match fr.union {
                     Some(file_response::Union::Block(block)) => {
-                        self.send_fs(ipc::FS::WriteBlock {
-                            id: block.id,
-                            file_num: block.file_num,
-                            data: block.data,
-                            compressed: block.compressed,
-                        });
+                        if let Some(range) = block.copy_from.as_ref() {
+                            self.send_fs(ipc::FS::WriteCopy {
+                                id: block.id,
+                                file_num: block.file_num,
+                                offset: range.offset,
+                                length: range.length,
+                            });
+                        } else {
+                            self.send_fs(ipc::FS::WriteBlock {
+                                id: block.id,
+                                file_num: block.file_num,
+                                data: block.data,
+                                compressed: block.compressed,
+                            });
+                        }
                     }
                     Some(file_response::Union::Done(d)) => {
                         self.send_fs(ipc::FS::WriteDone {
                             id: d.id,
                             file_num: d.file_num,
+                            hashes: d.hashes.into_iter().map(|h| (h.file_num, h.hash)).collect(),
                         });
                     }
                     Some(file_response::Union::Digest(d)) => self.send_fs(ipc::FS::CheckDigest {
@@ -2392,61 +2861,258 @@ impl Connection {
         true
     }
 
-    fn update_failure(&self, (mut failure, time): ((i32, i32, i32), i32), remove: bool, i: usize) {
+    fn login_failure_keys(&self) -> Vec<String> {
+        login_lockout::keys(&self.ip, &self.lr.my_id)
+    }
+
+    fn update_failure(&self, kind: LoginKind, remove: bool) {
+        let keys = self.login_failure_keys();
         if remove {
-            if failure.0 != 0 {
-                LOGIN_FAILURES[i].lock().unwrap().remove(&self.ip);
-            }
-            return;
-        }
-        if failure.0 == time {
-            failure.1 += 1;
-            failure.2 += 1;
+            login_lockout::on_success(kind, &keys);
         } else {
-            failure.0 = time;
-            failure.1 = 1;
-            failure.2 += 1;
+            self.audit("login_failure", json!({"kind": format!("{:?}", kind)}));
+            login_lockout::on_failure(kind, &keys);
         }
-        LOGIN_FAILURES[i]
-            .lock()
-            .unwrap()
-            .insert(self.ip.clone(), failure);
     }
 
-    async fn check_failure(&mut self, i: usize) -> (((i32, i32, i32), i32), bool) {
-        let failure = LOGIN_FAILURES[i]
-            .lock()
-            .unwrap()
-            .get(&self.ip)
-            .map(|x| x.clone())
-            .unwrap_or((0, 0, 0));
-        let time = (get_time() / 60_000) as i32;
-        let res = if failure.2 > 30 {
+    async fn check_failure(&mut self, kind: LoginKind) -> bool {
+        let Some(lockout) = login_lockout::check(kind, &self.login_failure_keys()) else {
+            return true;
+        };
+        let remaining = lockout.locked_until - get_time();
+        if lockout.exceeded || remaining > 60_000 {
             self.send_login_error("Too many wrong attempts").await;
-            Self::post_alarm_audit(
-                AlarmAuditType::ExceedThirtyAttempts,
-                json!({
-                            "ip": self.ip,
-                            "id": self.lr.my_id.clone(),
-                            "name": self.lr.my_name.clone(),
-                }),
-            );
-            false
-        } else if time == failure.0 && failure.1 > 6 {
+        } else {
             self.send_login_error("Please try 1 minute later").await;
-            Self::post_alarm_audit(
-                AlarmAuditType::SixAttemptsWithinOneMinute,
-                json!({
-                            "ip": self.ip,
-                            "id": self.lr.my_id.clone(),
-                            "name": self.lr.my_name.clone(),
-                }),
-            );
-            false
+        }
+        Self::post_alarm_audit(
+            if lockout.exceeded {
+                AlarmAuditType::ExceedThirtyAttempts
+            } else {
+                AlarmAuditType::SixAttemptsWithinOneMinute
+            },
+            json!({
+                        "ip": self.ip,
+                        "id": self.lr.my_id.clone(),
+                        "name": self.lr.my_name.clone(),
+                        "key": lockout.key,
+                        "locked_until": lockout.locked_until,
+            }),
+        );
+        false
+    }
+
+    #[inline]
+    fn is_port_forward(&self) -> bool {
+        self.port_forward_socket.is_some() || self.port_forward_tunnels.is_some()
+    }
+
+    async fn handle_tunnel(&mut self, tunnel: Tunnel) {
+        let Some(tunnels) = self.port_forward_tunnels.as_mut() else {
+            return;
+        };
+        let profile = self.permission_profile.as_ref();
+        match tunnel.union {
+            Some(tunnel::Union::Open(open)) => {
+                let host = if open.host.is_empty() {
+                    "localhost"
+                } else {
+                    &open.host
+                };
+                let addr = if host.contains(':') {
+                    format!("[{}]:{}", host, open.port)
+                } else {
+                    format!("{}:{}", host, open.port)
+                };
+                if !profile.map_or(true, |p| p.allows_port_forward(&addr)) {
+                    self.inner.send(Arc::new(new_tunnel_close(
+                        open.stream_id,
+                        "No permission of the port forward target".to_owned(),
+                    )));
+                    return;
+                }
+                log::info!("Open tunnel stream {} to {}", open.stream_id, addr);
+                let (tx, rx) = mpsc::channel(TUNNEL_QUEUE_SIZE);
+                add_tunnel_stream(&tunnels.streams, open.stream_id, tx);
+                let approval = if self.port_forward_policy.approve {
+                    let (tx, rx) = oneshot::channel();
+                    tunnels.approvals.insert(open.stream_id, tx);
+                    Some((rx, self.tx_to_cm.clone()))
+                } else {
+                    None
+                };
+                let check = TunnelCheck {
+                    policy: self.port_forward_policy.clone(),
+                    approval,
+                    peer: Self::port_forward_alarm_peer(&self.ip, &self.lr),
+                };
+                tokio::spawn(run_tunnel_stream(
+                    open.stream_id,
+                    host.to_owned(),
+                    open.port,
+                    check,
+                    rx,
+                    self.inner.clone(),
+                    tunnels.credits.clone(),
+                ));
+            }
+            Some(tunnel::Union::Data(data)) => {
+                let Some(tx) = tunnels
+                    .streams
+                    .lock()
+                    .unwrap()
+                    .get(&data.stream_id)
+                    .cloned()
+                else {
+                    return;
+                };
+                // a full stream holds back the connection, but only for a while
+                let res = match tx.try_send(data.data) {
+                    Err(mpsc::error::TrySendError::Full(bytes)) => {
+                        match timeout(TUNNEL_SEND_TIMEOUT, tx.send(bytes)).await {
+                            Ok(res) => res.map_err(|_| ""),
+                            Err(_) => Err("The port forward target is too slow"),
+                        }
+                    }
+                    res => res.map_err(|_| ""),
+                };
+                if let Err(error) = res {
+                    // a closed stream reports its close itself
+                    tunnels.streams.lock().unwrap().remove(&data.stream_id);
+                    if !error.is_empty() {
+                        log::error!("Tunnel stream {}: {}", data.stream_id, error);
+                        self.inner
+                            .send(Arc::new(new_tunnel_close(data.stream_id, error.to_owned())));
+                    }
+                }
+            }
+            Some(tunnel::Union::Close(close)) => {
+                tunnels.streams.lock().unwrap().remove(&close.stream_id);
+                tunnels.udp.remove(&close.stream_id);
+                tunnels.approvals.remove(&close.stream_id);
+            }
+            Some(tunnel::Union::Udp(udp)) => {
+                if udp.port <= 0 || udp.port > u16::MAX as i32 {
+                    return;
+                }
+                tunnels.udp.retain(|_, tx| !tx.is_closed());
+                if !tunnels.udp.contains_key(&udp.stream_id) {
+                    if self.port_forward_policy.approve {
+                        self.inner.send(Arc::new(new_tunnel_close(
+                            udp.stream_id,
+                            "Udp port forward is not allowed with the approval".to_owned(),
+                        )));
+                        return;
+                    }
+                    let socket = match std::net::UdpSocket::bind("0.0.0.0:0").and_then(|s| {
+                        s.set_nonblocking(true)?;
+                        tokio::net::UdpSocket::from_std(s)
+                    }) {
+                        Ok(socket) => socket,
+                        Err(err) => {
+                            self.inner.send(Arc::new(new_tunnel_close(
+                                udp.stream_id,
+                                format!("Failed to bind udp socket: {}", err),
+                            )));
+                            return;
+                        }
+                    };
+                    log::info!("Open tunnel udp {}", udp.stream_id);
+                    let (tx, rx) = mpsc::channel(TUNNEL_QUEUE_SIZE);
+                    tunnels.udp.insert(udp.stream_id, tx);
+                    let check = TunnelCheck {
+                        policy: self.port_forward_policy.clone(),
+                        approval: None,
+                        peer: Self::port_forward_alarm_peer(&self.ip, &self.lr),
+                    };
+                    tokio::spawn(run_tunnel_udp(
+                        udp.stream_id,
+                        socket,
+                        check,
+                        rx,
+                        self.inner.clone(),
+                        tunnels.credits.clone(),
+                    ));
+                }
+                let addr = if udp.host.contains(':') {
+                    format!("[{}]:{}", udp.host, udp.port)
+                } else {
+                    format!("{}:{}", udp.host, udp.port)
+                };
+                if !profile.map_or(true, |p| p.allows_port_forward(&addr)) {
+                    return;
+                }
+                // datagrams may be dropped, so a full association drops them
+                if let Some(tx) = tunnels.udp.get(&udp.stream_id) {
+                    tx.try_send((udp.host, udp.port, udp.data)).ok();
+                }
+            }
+            Some(tunnel::Union::Listen(listen)) => {
+                if listen.port <= 0 || listen.port > u16::MAX as i32 {
+                    self.inner.send(Arc::new(new_tunnel_listen_response(
+                        listen.listen_id,
+                        listen.port,
+                        "Invalid port".to_owned(),
+                    )));
+                    return;
+                }
+                log::info!(
+                    "Reverse port forward {} requested on port {}",
+                    listen.listen_id,
+                    listen.port
+                );
+                tunnels.requests.insert(listen.listen_id, listen.port);
+                self.send_to_cm(ipc::Data::ReverseForwardRequest((
+                    listen.listen_id,
+                    listen.port,
+                )));
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_port_forward_response(&mut self, stream_id: i32, accepted: bool) {
+        if let Some(tunnels) = self.port_forward_tunnels.as_mut() {
+            if let Some(tx) = tunnels.approvals.remove(&stream_id) {
+                tx.send(accepted).ok();
+            }
+        }
+    }
+
+    async fn handle_reverse_forward_response(&mut self, listen_id: i32, accepted: bool) {
+        let Some(tunnels) = self.port_forward_tunnels.as_mut() else {
+            return;
+        };
+        let Some(mut port) = tunnels.requests.remove(&listen_id) else {
+            return;
+        };
+        let error = if !accepted {
+            "Reverse port forward is rejected by the peer".to_owned()
         } else {
-            true
+            // only reachable on this machine, the peer must not open a port to the network
+            match tcp::new_listener(format!("127.0.0.1:{}", port), true).await {
+                Ok(listener) => {
+                    if let Ok(addr) = listener.local_addr() {
+                        port = addr.port() as _;
+                    }
+                    log::info!("Reverse port forward {} listening on {}", listen_id, port);
+                    tunnels.listeners.push(tokio::spawn(run_tunnel_listener(
+                        listen_id,
+                        listener,
+                        tunnels.streams.clone(),
+                        self.inner.clone(),
+                        tunnels.credits.clone(),
+                    )));
+                    "".to_owned()
+                }
+                Err(err) => format!("Failed to listen on {}: {}", port, err),
+            }
         };
-        ((failure, time), res)
+        if !error.is_empty() {
+            log::info!("Reverse port forward {}: {}", listen_id, error);
+        }
+        self.send(new_tunnel_listen_response(listen_id, port, error))
+            .await;
     }
 
     fn refresh_video_display(&self, display: Option<usize>) {
@@ -3013,6 +3679,7 @@ impl Connection {
         }
         self.closed = true;
         log::info!("#{} Connection closed: {}", self.inner.id(), reason);
+        self.audit("disconnect", json!({"reason": reason}));
         if lock && self.lock_after_session_end && self.keyboard {
             #[cfg(not(any(target_os = "android", target_os = "ios")))]
             lock_screen().await;
@@ -3053,9 +3720,42 @@ impl Connection {
 
     #[inline]
     async fn send(&mut self, msg: Message) {
+        #[cfg(not(any(target_os = "android", target_os = "ios")))]
+        self.record_session_message(&msg);
         allow_err!(self.stream.send(&msg).await);
     }
 
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    fn start_session_recorder(&mut self) {
+        if Config::get_option("allow-auto-record-incoming").is_empty()
+            || Config::get_option("record-session-stream").is_empty()
+        {
+            return;
+        }
+        #[cfg(windows)]
+        let root = crate::platform::is_root();
+        #[cfg(not(windows))]
+        let root = false;
+        match scrap::session_record::SessionRecorder::new(
+            true,
+            &Config::get_id(),
+            &crate::ui_interface::video_save_directory(root),
+        ) {
+            Ok(recorder) => self.session_recorder = Some(recorder),
+            Err(e) => log::error!("Failed to create session recorder: {}", e),
+        }
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    fn record_session_message(&mut self, msg: &Message) {
+        if let Some(recorder) = self.session_recorder.as_mut() {
+            if let Err(e) = recorder.write_message(msg) {
+                log::error!("Failed to record session message: {}", e);
+                self.session_recorder = None;
+            }
+        }
+    }
+
     pub fn alive_conns() -> Vec<i32> {
         ALIVE_CONNS.lock().unwrap().clone()
     }
@@ -3064,7 +3764,7 @@ impl Connection {
     fn portable_check(&mut self) {
         if self.portable.is_installed
             || self.file_transfer.is_some()
-            || self.port_forward_socket.is_some()
+            || self.is_port_forward()
             || !self.keyboard
         {
             return;
@@ -3190,6 +3890,374 @@ pub fn insert_switch_sides_uuid(id: String, uuid: uuid::Uuid) {
         .insert(id, (tokio::time::Instant::now(), uuid));
 }
 
+// Completes at `deadline`, never if there is none.
+async fn wait_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+static REVERSE_TUNNEL_STREAM_ID: std::sync::atomic::AtomicI32 =
+    std::sync::atomic::AtomicI32::new(-1);
+// the destinations checked for a udp association are cached
+const MAX_TUNNEL_UDP_TARGETS: usize = 1024;
+
+// State of a multiplexed port forward connection
+struct Tunnels {
+    streams: Arc<Mutex<HashMap<i32, mpsc::Sender<Bytes>>>>,
+    // udp associations, stream id -> sender of (host, port, datagram)
+    udp: HashMap<i32, mpsc::Sender<(String, i32, Bytes)>>,
+    // reverse forwards waiting for the approval of cm, listen id -> port
+    requests: HashMap<i32, i32>,
+    listeners: Vec<tokio::task::JoinHandle<()>>,
+    // streams waiting for the approval of cm
+    approvals: HashMap<i32, oneshot::Sender<bool>>,
+    // the data of all the streams queued to the peer, a permit is added back once one is sent
+    credits: Arc<Semaphore>,
+}
+
+impl Default for Tunnels {
+    fn default() -> Self {
+        Self {
+            streams: Default::default(),
+            udp: Default::default(),
+            requests: Default::default(),
+            listeners: Default::default(),
+            approvals: Default::default(),
+            credits: Arc::new(Semaphore::new(TUNNEL_QUEUE_SIZE)),
+        }
+    }
+}
+
+// What is checked before the target of a tunnel is connected
+struct TunnelCheck {
+    policy: Arc<PortForwardPolicy>,
+    // the approval of cm, and the sender to ask cm for it
+    approval: Option<(oneshot::Receiver<bool>, mpsc::UnboundedSender<ipc::Data>)>,
+    // for the alarm audit
+    peer: Value,
+}
+
+impl Drop for Tunnels {
+    fn drop(&mut self) {
+        for listener in self.listeners.iter() {
+            listener.abort();
+        }
+    }
+}
+
+fn add_tunnel_stream(
+    streams: &Mutex<HashMap<i32, mpsc::Sender<Bytes>>>,
+    stream_id: i32,
+    tx: mpsc::Sender<Bytes>,
+) {
+    let mut streams = streams.lock().unwrap();
+    // the streams closed by this side are not removed by the peer
+    streams.retain(|_, tx| !tx.is_closed());
+    streams.insert(stream_id, tx);
+}
+
+fn new_tunnel_listen_response(listen_id: i32, port: i32, error: String) -> Message {
+    let mut tunnel = Tunnel::new();
+    tunnel.set_listen_response(TunnelListenResponse {
+        listen_id,
+        port,
+        error,
+        ..Default::default()
+    });
+    let mut msg_out = Message::new();
+    msg_out.set_tunnel(tunnel);
+    msg_out
+}
+
+fn new_tunnel_close(stream_id: i32, error: String) -> Message {
+    let mut tunnel = Tunnel::new();
+    tunnel.set_close(TunnelClose {
+        stream_id,
+        error,
+        ..Default::default()
+    });
+    let mut msg_out = Message::new();
+    msg_out.set_tunnel(tunnel);
+    msg_out
+}
+
+// Accepted connections of a reverse forward are opened to the peer, with negative stream ids.
+async fn run_tunnel_listener(
+    listen_id: i32,
+    listener: tokio::net::TcpListener,
+    streams: Arc<Mutex<HashMap<i32, mpsc::Sender<Bytes>>>>,
+    mut inner: ConnInner,
+    credits: Arc<Semaphore>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => {
+                let stream_id = REVERSE_TUNNEL_STREAM_ID.fetch_sub(1, Ordering::SeqCst);
+                log::info!(
+                    "Reverse port forward {}: new connection from {:?}, stream {}",
+                    listen_id,
+                    addr,
+                    stream_id
+                );
+                let (tx, rx) = mpsc::channel(TUNNEL_QUEUE_SIZE);
+                add_tunnel_stream(&streams, stream_id, tx);
+                let mut tunnel = Tunnel::new();
+                tunnel.set_open(TunnelOpen {
+                    stream_id,
+                    listen_id,
+                    ..Default::default()
+                });
+                let mut msg_out = Message::new();
+                msg_out.set_tunnel(tunnel);
+                inner.send(Arc::new(msg_out));
+                tokio::spawn(run_tunnel_socket(
+                    stream_id,
+                    socket,
+                    rx,
+                    inner.clone(),
+                    credits.clone(),
+                ));
+            }
+            Err(err) => {
+                log::error!(
+                    "Reverse port forward {} failed to accept: {}",
+                    listen_id,
+                    err
+                );
+                break;
+            }
+        }
+    }
+}
+
+async fn run_tunnel_stream(
+    stream_id: i32,
+    host: String,
+    port: i32,
+    check: TunnelCheck,
+    rx: mpsc::Receiver<Bytes>,
+    mut inner: ConnInner,
+    credits: Arc<Semaphore>,
+) {
+    let addr = if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+    let addrs = match timeout(3000, check.policy.resolve_allowed(&host, port)).await {
+        Ok(Ok(Some(addrs))) => addrs,
+        Ok(Ok(None)) => {
+            Connection::post_port_forward_alarm(check.peer, &addr);
+            inner.send(Arc::new(new_tunnel_close(
+                stream_id,
+                "No permission of the port forward target".to_owned(),
+            )));
+            return;
+        }
+        // failed to resolve, so is the connecting
+        _ => vec![],
+    };
+    if let Some((rx_approval, tx_to_cm)) = check.approval {
+        if !addrs.is_empty() {
+            tx_to_cm
+                .send(ipc::Data::PortForwardRequest((stream_id, addr.clone())))
+                .ok();
+            // the sender is dropped if the stream is closed
+            if !matches!(rx_approval.await, Ok(true)) {
+                log::info!("Tunnel target {} is not approved", addr);
+                inner.send(Arc::new(new_tunnel_close(
+                    stream_id,
+                    "Port forward is rejected by the peer".to_owned(),
+                )));
+                return;
+            }
+        }
+    }
+    match timeout(3000, TcpStream::connect(&addrs[..])).await {
+        Ok(Ok(sock)) => {
+            let mut tunnel = Tunnel::new();
+            tunnel.set_opened(TunnelOpened {
+                stream_id,
+                ..Default::default()
+            });
+            let mut msg_out = Message::new();
+            msg_out.set_tunnel(tunnel);
+            inner.send(Arc::new(msg_out));
+            run_tunnel_socket(stream_id, sock, rx, inner, credits).await;
+        }
+        _ => {
+            log::error!("Failed to access tunnel target {}", addr);
+            inner.send(Arc::new(new_tunnel_close(
+                stream_id,
+                format!(
+                    "Failed to access remote {}, please make sure if it is open",
+                    addr
+                ),
+            )));
+        }
+    }
+}
+
+// Waits while `TUNNEL_QUEUE_SIZE` messages of the tunnels are queued to the peer.
+async fn send_tunnel_data(inner: &mut ConnInner, credits: &Semaphore, msg: Message) -> bool {
+    match credits.acquire().await {
+        Ok(permit) => permit.forget(),
+        Err(_) => return false,
+    }
+    inner.send(Arc::new(msg));
+    true
+}
+
+// One stream of a multiplexed port forward connection, closed when its sender is dropped.
+// The target is not read while the queue to the peer is full, and the writing half drains `rx`
+// on its own meanwhile.
+async fn run_tunnel_socket(
+    stream_id: i32,
+    forward: TcpStream,
+    mut rx: mpsc::Receiver<Bytes>,
+    mut inner: ConnInner,
+    credits: Arc<Semaphore>,
+) {
+    let (reader, mut writer) = forward.into_split();
+    let mut reader = FramedRead::new(reader, BytesCodec::new());
+    let mut inner_reading = inner.clone();
+    // both return whether the stream was closed by the peer
+    let writing = async {
+        while let Some(data) = rx.recv().await {
+            let res = timeout(SEND_TIMEOUT_OTHER, writer.write_all(&data)).await;
+            if !matches!(res, Ok(Ok(_))) {
+                return false;
+            }
+        }
+        true
+    };
+    let reading = async {
+        while let Some(Ok(bytes)) = reader.next().await {
+            let mut tunnel = Tunnel::new();
+            tunnel.set_data(TunnelData {
+                stream_id,
+                data: bytes.freeze(),
+                ..Default::default()
+            });
+            let mut msg_out = Message::new();
+            msg_out.set_tunnel(tunnel);
+            if !send_tunnel_data(&mut inner_reading, &credits, msg_out).await {
+                return true;
+            }
+        }
+        false
+    };
+    let closed = tokio::select! {
+        closed = writing => closed,
+        closed = reading => closed,
+    };
+    if closed {
+        return;
+    }
+    log::info!("Tunnel stream {} closed", stream_id);
+    inner.send(Arc::new(new_tunnel_close(stream_id, "".to_owned())));
+}
+
+// A udp association of a multiplexed port forward connection, closed when its sender is dropped.
+async fn run_tunnel_udp(
+    stream_id: i32,
+    socket: tokio::net::UdpSocket,
+    check: TunnelCheck,
+    mut rx: mpsc::Receiver<(String, i32, Bytes)>,
+    mut inner: ConnInner,
+    credits: Arc<Semaphore>,
+) {
+    // the checked destinations, `None` for the denied ones
+    let mut targets: HashMap<(String, i32), Option<SocketAddr>> = HashMap::new();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        tokio::select! {
+            res = socket.recv_from(&mut buf) => {
+                match res {
+                    Ok((n, addr)) => {
+                        let mut tunnel = Tunnel::new();
+                        tunnel.set_udp(TunnelUdp {
+                            stream_id,
+                            host: addr.ip().to_string(),
+                            port: addr.port() as _,
+                            data: Bytes::copy_from_slice(&buf[..n]),
+                            ..Default::default()
+                        });
+                        let mut msg_out = Message::new();
+                        msg_out.set_tunnel(tunnel);
+                        if !send_tunnel_data(&mut inner, &credits, msg_out).await {
+                            return;
+                        }
+                    }
+                    Err(err) => {
+                        log::error!("Tunnel udp {} failed to receive: {}", stream_id, err);
+                        break;
+                    }
+                }
+            }
+            res = rx.recv() => {
+                match res {
+                    Some((host, port, data)) => {
+                        let key = (host, port);
+                        let target = match targets.get(&key) {
+                            Some(target) => *target,
+                            None => {
+                                let Some(target) = resolve_tunnel_udp_target(&check, &key.0, port).await else {
+                                    log::debug!("Tunnel udp {} failed to resolve {}", stream_id, key.0);
+                                    continue;
+                                };
+                                if targets.len() >= MAX_TUNNEL_UDP_TARGETS {
+                                    targets.clear();
+                                }
+                                targets.insert(key.clone(), target);
+                                target
+                            }
+                        };
+                        let Some(target) = target else {
+                            continue;
+                        };
+                        let (host, port) = key;
+                        let res = socket.send_to(&data, target).await;
+                        if let Err(err) = res {
+                            log::debug!("Tunnel udp {} failed to send to {}:{}: {}", stream_id, host, port, err);
+                        }
+                    }
+                    None => return,
+                }
+            }
+        }
+    }
+    inner.send(Arc::new(new_tunnel_close(stream_id, "".to_owned())));
+}
+
+// `None` if it failed to resolve, `Some(None)` if it is denied.
+async fn resolve_tunnel_udp_target(
+    check: &TunnelCheck,
+    host: &str,
+    port: i32,
+) -> Option<Option<SocketAddr>> {
+    match timeout(3000, check.policy.resolve_allowed(host, port)).await {
+        // the socket is bound to ipv4
+        Ok(Ok(Some(addrs))) => Some(
+            addrs
+                .iter()
+                .find(|a| a.is_ipv4())
+                .or(addrs.first())
+                .copied(),
+        ),
+        Ok(Ok(None)) => {
+            Connection::post_port_forward_alarm(
+                check.peer.clone(),
+                &format!("udp {}:{}", host, port),
+            );
+            Some(None)
+        }
+        _ => None,
+    }
+}
+
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 async fn start_ipc(
     mut rx_to_cm: mpsc::UnboundedReceiver<ipc::Data>,
@@ -3348,6 +4416,8 @@ pub enum AlarmAuditType {
     IpWhitelist = 0,
     ExceedThirtyAttempts = 1,
     SixAttemptsWithinOneMinute = 2,
+    AccessRule = 3,
+    PortForwardTarget = 4,
 }
 
 pub enum FileAuditType {
@@ -3530,6 +4600,7 @@ impl Default for PortableState {
 
 impl Drop for Connection {
     fn drop(&mut self) {
+        scrap::record_store::peer_disconnected(self.inner.id());
         #[cfg(not(any(target_os = "android", target_os = "ios")))]
         self.release_pressed_modifiers();
     }
@@ -3768,3 +4839,49 @@ mod test {
         assert_eq!(pos.y, 510);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tunnel_queue_bounded() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // the target keeps sending
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let data = vec![0u8; 1024];
+            while socket.write_all(&data).await.is_ok() {}
+        });
+        let forward = TcpStream::connect(addr).await.unwrap();
+        // the peer is stalled, nothing queued to it is sent
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let inner = ConnInner::new(0, Some(tx), None);
+        let credits = Arc::new(Semaphore::new(TUNNEL_QUEUE_SIZE));
+        let (_tx_stream, rx_stream) = mpsc::channel(TUNNEL_QUEUE_SIZE);
+        let task = tokio::spawn(run_tunnel_socket(
+            1,
+            forward,
+            rx_stream,
+            inner,
+            credits.clone(),
+        ));
+        let queued = |rx: &mut mpsc::UnboundedReceiver<(Instant, Arc<Message>)>| {
+            let mut n = 0;
+            while rx.try_recv().is_ok() {
+                n += 1;
+            }
+            n
+        };
+        sleep(1.).await;
+        assert_eq!(queued(&mut rx), TUNNEL_QUEUE_SIZE);
+        sleep(0.5).await;
+        assert_eq!(queued(&mut rx), 0);
+        // one more once one is sent
+        credits.add_permits(1);
+        sleep(0.5).await;
+        assert_eq!(queued(&mut rx), 1);
+        task.abort();
+    }
+}
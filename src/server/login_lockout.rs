@@ -0,0 +1,393 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use hbb_common::{
+    config::{Config, LoginFailure, LoginFailures},
+    get_time, log,
+};
+use serde_derive::{Deserialize, Serialize};
+
+// seconds of the window in which `login-failure-window-attempts` failures are allowed
+pub const OPTION_FAILURE_WINDOW: &str = "login-failure-window";
+pub const OPTION_FAILURE_WINDOW_ATTEMPTS: &str = "login-failure-window-attempts";
+// failures allowed in total before the login is blocked until the lockouts are cleared, as it
+// was before the thresholds were configurable
+pub const OPTION_FAILURE_MAX_ATTEMPTS: &str = "login-failure-max-attempts";
+// seconds of the first lockout, doubled on each following lockout
+pub const OPTION_LOCKOUT_DURATION: &str = "login-lockout-duration";
+pub const OPTION_LOCKOUT_MAX_DURATION: &str = "login-lockout-max-duration";
+// the ip allows this many times the failures of one peer id from it, as it may try many ids
+const IP_ATTEMPTS_FACTOR: i32 = 5;
+
+lazy_static::lazy_static! {
+    static ref FAILURES: Mutex<LoginFailures> = Mutex::new(LoginFailures::load());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginKind {
+    Password,
+    Tfa,
+}
+
+impl LoginKind {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Password => "password",
+            Self::Tfa => "2fa",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockoutPolicy {
+    // ms
+    pub window: i64,
+    pub window_attempts: i32,
+    pub max_attempts: i32,
+    // ms
+    pub duration: i64,
+    pub max_duration: i64,
+}
+
+impl Default for LockoutPolicy {
+    // the thresholds used before they were configurable
+    fn default() -> Self {
+        Self {
+            window: 60_000,
+            window_attempts: 6,
+            max_attempts: 30,
+            duration: 60_000,
+            max_duration: 86_400_000,
+        }
+    }
+}
+
+impl LockoutPolicy {
+    pub fn load() -> Self {
+        let d = Self::default();
+        let get = |name: &str, default: i64| {
+            Config::get_option(name)
+                .parse::<i64>()
+                .ok()
+                .filter(|x| *x > 0)
+                .unwrap_or(default)
+        };
+        let max_duration = get(OPTION_LOCKOUT_MAX_DURATION, d.max_duration / 1000) * 1000;
+        Self {
+            window: get(OPTION_FAILURE_WINDOW, d.window / 1000) * 1000,
+            window_attempts: get(OPTION_FAILURE_WINDOW_ATTEMPTS, d.window_attempts as _) as _,
+            max_attempts: get(OPTION_FAILURE_MAX_ATTEMPTS, d.max_attempts as _) as _,
+            duration: (get(OPTION_LOCKOUT_DURATION, d.duration / 1000) * 1000).min(max_duration),
+            max_duration,
+        }
+    }
+
+    fn for_ip(&self) -> Self {
+        Self {
+            window_attempts: self.window_attempts.saturating_mul(IP_ATTEMPTS_FACTOR),
+            max_attempts: self.max_attempts.saturating_mul(IP_ATTEMPTS_FACTOR),
+            ..self.clone()
+        }
+    }
+
+    fn lockout_duration(&self, lockouts: i32) -> i64 {
+        let shift = (lockouts.max(1) - 1).min(32) as u32;
+        self.duration
+            .saturating_mul(1i64 << shift)
+            .min(self.max_duration)
+    }
+
+    // Returns true if it is locked by this failure.
+    fn on_failure(&self, failure: &mut LoginFailure, now: i64) -> bool {
+        if now - failure.window_start >= self.window {
+            failure.window_start = now;
+            failure.window_count = 0;
+        }
+        failure.window_count += 1;
+        failure.total += 1;
+        if failure.total > self.max_attempts {
+            failure.lockouts += 1;
+            failure.locked_until = i64::MAX;
+        } else if failure.window_count > self.window_attempts {
+            failure.lockouts += 1;
+            failure.locked_until = now + self.lockout_duration(failure.lockouts);
+            failure.window_start = now;
+            failure.window_count = 0;
+        } else {
+            return false;
+        }
+        true
+    }
+
+    // Entries idle for the longest lockout are forgotten, including their backoff, except the
+    // blocked ones.
+    fn is_expired(&self, failure: &LoginFailure, now: i64) -> bool {
+        failure.locked_until <= now
+            && now - failure.window_start.max(failure.locked_until) > self.max_duration
+    }
+}
+
+/// An active lockout
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoginLockout {
+    pub kind: String,
+    pub key: String,
+    pub attempts: i32,
+    // ms
+    pub locked_until: i64,
+    // the failures exceeded `login-failure-max-attempts`, blocked until cleared
+    pub exceeded: bool,
+}
+
+pub fn ip_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+// The peer id is claimed by the client, so it is only counted along with the ip, or anyone could
+// lock out a peer with its id.
+pub fn ip_id_key(ip: &str, id: &str) -> String {
+    format!("ip:{}|id:{}", ip, id)
+}
+
+/// The ip, and the peer id from it.
+pub fn keys(ip: &str, id: &str) -> Vec<String> {
+    vec![ip_key(ip), ip_id_key(ip, id)]
+}
+
+fn failures_of(
+    failures: &mut LoginFailures,
+    kind: LoginKind,
+) -> &mut HashMap<String, LoginFailure> {
+    match kind {
+        LoginKind::Password => &mut failures.password,
+        LoginKind::Tfa => &mut failures.tfa,
+    }
+}
+
+/// Returns the longest active lockout of the keys.
+pub fn check(kind: LoginKind, keys: &[String]) -> Option<LoginLockout> {
+    let now = get_time();
+    let mut lock = FAILURES.lock().unwrap();
+    find_lockout(failures_of(&mut lock, kind), kind, keys, now)
+}
+
+fn find_lockout(
+    failures: &HashMap<String, LoginFailure>,
+    kind: LoginKind,
+    keys: &[String],
+    now: i64,
+) -> Option<LoginLockout> {
+    keys.iter()
+        .filter_map(|key| failures.get(key).map(|f| (key, f)))
+        .filter(|(_, f)| f.locked_until > now)
+        .max_by_key(|(_, f)| f.locked_until)
+        .map(|(key, f)| LoginLockout {
+            kind: kind.name().to_owned(),
+            key: key.clone(),
+            attempts: f.total,
+            locked_until: f.locked_until,
+            exceeded: f.locked_until == i64::MAX,
+        })
+}
+
+pub fn on_failure(kind: LoginKind, keys: &[String]) {
+    let policy = LockoutPolicy::load();
+    let now = get_time();
+    let mut lock = FAILURES.lock().unwrap();
+    prune(&mut lock, &policy, now);
+    add_failure(failures_of(&mut lock, kind), &policy, kind, keys, now);
+    lock.store();
+}
+
+fn add_failure(
+    failures: &mut HashMap<String, LoginFailure>,
+    policy: &LockoutPolicy,
+    kind: LoginKind,
+    keys: &[String],
+    now: i64,
+) {
+    let ip_policy = policy.for_ip();
+    for key in keys {
+        let policy = if key.contains("|id:") {
+            policy
+        } else {
+            &ip_policy
+        };
+        let failure = failures.entry(key.clone()).or_default();
+        if policy.on_failure(failure, now) {
+            log::warn!(
+                "Login of {} locked until {} after {} {} failures",
+                key,
+                failure.locked_until,
+                failure.total,
+                kind.name()
+            );
+        }
+    }
+}
+
+pub fn on_success(kind: LoginKind, keys: &[String]) {
+    let mut lock = FAILURES.lock().unwrap();
+    let failures = failures_of(&mut lock, kind);
+    let mut changed = false;
+    for key in keys {
+        changed |= failures.remove(key).is_some();
+    }
+    if changed {
+        lock.store();
+    }
+}
+
+fn prune(failures: &mut LoginFailures, policy: &LockoutPolicy, now: i64) {
+    failures.password.retain(|_, f| !policy.is_expired(f, now));
+    failures.tfa.retain(|_, f| !policy.is_expired(f, now));
+}
+
+pub fn list() -> Vec<LoginLockout> {
+    let now = get_time();
+    let mut lock = FAILURES.lock().unwrap();
+    let mut v = Vec::new();
+    for kind in [LoginKind::Password, LoginKind::Tfa] {
+        for (key, f) in failures_of(&mut lock, kind).iter() {
+            if f.locked_until > now {
+                v.push(LoginLockout {
+                    kind: kind.name().to_owned(),
+                    key: key.clone(),
+                    attempts: f.total,
+                    locked_until: f.locked_until,
+                    exceeded: f.locked_until == i64::MAX,
+                });
+            }
+        }
+    }
+    v.sort_by(|a, b| a.key.cmp(&b.key).then(a.kind.cmp(&b.kind)));
+    v
+}
+
+/// Clears the failures of the key, or all if `None`, returns how many entries are removed.
+pub fn clear(key: Option<&str>) -> usize {
+    let mut lock = FAILURES.lock().unwrap();
+    let n = lock.password.len() + lock.tfa.len();
+    match key {
+        Some(key) => {
+            lock.password.remove(key);
+            lock.tfa.remove(key);
+        }
+        None => {
+            lock.password.clear();
+            lock.tfa.clear();
+        }
+    }
+    let removed = n - lock.password.len() - lock.tfa.len();
+    if removed > 0 {
+        lock.store();
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> LockoutPolicy {
+        LockoutPolicy {
+            window: 60_000,
+            window_attempts: 2,
+            max_attempts: 8,
+            duration: 10_000,
+            max_duration: 100_000,
+        }
+    }
+
+    #[test]
+    fn test_window_lockout_backoff() {
+        let p = policy();
+        let mut f = LoginFailure::default();
+        let mut now = 1_000_000;
+        assert!(!p.on_failure(&mut f, now));
+        assert!(!p.on_failure(&mut f, now));
+        assert!(p.on_failure(&mut f, now));
+        assert_eq!(f.locked_until, now + 10_000);
+        now = f.locked_until;
+        assert!(!p.on_failure(&mut f, now));
+        assert!(!p.on_failure(&mut f, now));
+        assert!(p.on_failure(&mut f, now));
+        assert_eq!(f.locked_until, now + 20_000);
+        assert_eq!(p.lockout_duration(3), 40_000);
+        assert_eq!(p.lockout_duration(5), 100_000);
+        assert_eq!(p.lockout_duration(100), 100_000);
+    }
+
+    #[test]
+    fn test_window_reset() {
+        let p = policy();
+        let mut f = LoginFailure::default();
+        let now = 1_000_000;
+        assert!(!p.on_failure(&mut f, now));
+        assert!(!p.on_failure(&mut f, now));
+        assert!(!p.on_failure(&mut f, now + 60_000));
+        assert_eq!(f.window_count, 1);
+        assert_eq!(f.total, 3);
+    }
+
+    #[test]
+    fn test_max_attempts() {
+        let p = policy();
+        let mut f = LoginFailure::default();
+        let mut now = 1_000_000;
+        for _ in 0..8 {
+            assert!(!p.on_failure(&mut f, now));
+            now += 60_000;
+        }
+        assert!(p.on_failure(&mut f, now));
+        assert_eq!(f.locked_until, i64::MAX);
+        assert!(!p.is_expired(&f, now + 100_000));
+        assert!(!p.is_expired(&f, now + 200_001));
+    }
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(
+            keys("1.2.3.4", "123456789"),
+            vec!["ip:1.2.3.4", "ip:1.2.3.4|id:123456789"]
+        );
+        assert_eq!(keys("1.2.3.4", ""), vec!["ip:1.2.3.4", "ip:1.2.3.4|id:"]);
+    }
+
+    #[test]
+    fn test_per_id() {
+        let p = policy();
+        let mut failures = HashMap::new();
+        let now = 1_000_000;
+        let (ip_a, ip_b) = ("1.2.3.4", "5.6.7.8");
+        let check = |failures: &HashMap<String, LoginFailure>, ip, id| {
+            find_lockout(failures, LoginKind::Password, &keys(ip, id), now).map(|l| l.key)
+        };
+        for _ in 0..3 {
+            add_failure(
+                &mut failures,
+                &p,
+                LoginKind::Password,
+                &keys(ip_a, "111"),
+                now,
+            );
+        }
+        assert_eq!(check(&failures, ip_a, "111"), Some(ip_id_key(ip_a, "111")));
+        // the other ids from the same ip are counted apart
+        assert_eq!(check(&failures, ip_a, "222"), None);
+        // the failures from one ip do not lock the peer id out from the others
+        assert_eq!(check(&failures, ip_b, "111"), None);
+        // many ids from one ip still lock the ip
+        for i in 0..8 {
+            let id = format!("3{}", i);
+            add_failure(
+                &mut failures,
+                &p,
+                LoginKind::Password,
+                &keys(ip_a, &id),
+                now,
+            );
+        }
+        assert_eq!(check(&failures, ip_a, "222"), Some(ip_key(ip_a)));
+        assert_eq!(check(&failures, ip_b, "222"), None);
+    }
+}
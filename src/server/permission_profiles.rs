@@ -0,0 +1,179 @@
+use super::access_rules::glob_match;
+use hbb_common::{config::Config, log};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// json of `Vec<PermissionProfile>`, the first matched profile is applied at login
+pub const OPTION_PERMISSION_PROFILES: &str = "permission-profiles";
+// json of the peer id globs of each tag, `{"support": ["123*"]}`. The address book is not used,
+// it is the data of the user logged in, not the policy of the host.
+pub const OPTION_PERMISSION_PROFILE_TAGS: &str = "permission-profile-tags";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthMethod {
+    // the permanent or temporary password, including the reconnection of a recent session
+    Password,
+    PublicKey,
+    // accepted in the connection manager
+    Click,
+    // switched sides
+    Switch,
+}
+
+/// A profile matches if all its conditions match, an empty condition matches anything.
+/// The permissions not set keep the values of the global options.
+/// The peer id is claimed by the peer, so a profile matched by the peer id or the tags only
+/// takes permissions away, unless the peer is authenticated by its public key.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PermissionProfile {
+    #[serde(default)]
+    pub name: String,
+    // peer id globs, with `*` and `?`
+    #[serde(default)]
+    pub peer_id: Vec<String>,
+    // tags of the peer in `OPTION_PERMISSION_PROFILE_TAGS`
+    #[serde(default)]
+    pub tag: Vec<String>,
+    #[serde(default)]
+    pub auth: Vec<AuthMethod>,
+    pub keyboard: Option<bool>,
+    pub clipboard: Option<bool>,
+    pub audio: Option<bool>,
+    pub file: Option<bool>,
+    pub restart: Option<bool>,
+    pub recording: Option<bool>,
+    pub block_input: Option<bool>,
+    // `host:port` globs of the port forward targets, empty for any
+    #[serde(default)]
+    pub port_forward: Vec<String>,
+    // minutes, 0 for no limit
+    #[serde(default)]
+    pub max_duration: u64,
+}
+
+impl PermissionProfile {
+    fn matches(&self, peer_id: &str, tags: &[String], auth: AuthMethod) -> bool {
+        (self.peer_id.is_empty() || self.peer_id.iter().any(|p| glob_match(p, peer_id)))
+            && (self.tag.is_empty() || self.tag.iter().any(|t| tags.contains(t)))
+            && (self.auth.is_empty() || self.auth.contains(&auth))
+    }
+
+    /// Whether the permissions enabled by the profile are granted to a peer authenticated by
+    /// `auth`.
+    pub fn grants(&self, auth: AuthMethod) -> bool {
+        (self.peer_id.is_empty() && self.tag.is_empty()) || auth == AuthMethod::PublicKey
+    }
+
+    /// `addr` is `host:port`, or `[host]:port` for ipv6.
+    pub fn allows_port_forward(&self, addr: &str) -> bool {
+        self.port_forward.is_empty() || self.port_forward.iter().any(|p| glob_match(p, addr))
+    }
+
+    pub fn name(&self, index: usize) -> String {
+        if self.name.is_empty() {
+            format!("#{}", index)
+        } else {
+            self.name.clone()
+        }
+    }
+}
+
+pub fn load() -> Vec<PermissionProfile> {
+    let v = Config::get_option(OPTION_PERMISSION_PROFILES);
+    if v.is_empty() {
+        return vec![];
+    }
+    match serde_json::from_str(&v) {
+        Ok(profiles) => profiles,
+        Err(err) => {
+            log::error!("Invalid {}: {}", OPTION_PERMISSION_PROFILES, err);
+            vec![]
+        }
+    }
+}
+
+/// Returns the first matched profile and its index.
+pub fn find(
+    profiles: &[PermissionProfile],
+    peer_id: &str,
+    auth: AuthMethod,
+) -> Option<(usize, PermissionProfile)> {
+    let tags = if profiles.iter().any(|p| !p.tag.is_empty()) {
+        peer_tags(&load_tags(), peer_id)
+    } else {
+        vec![]
+    };
+    profiles
+        .iter()
+        .enumerate()
+        .find(|(_, p)| p.matches(peer_id, &tags, auth))
+        .map(|(i, p)| (i, p.clone()))
+}
+
+fn load_tags() -> HashMap<String, Vec<String>> {
+    let v = Config::get_option(OPTION_PERMISSION_PROFILE_TAGS);
+    if v.is_empty() {
+        return Default::default();
+    }
+    match serde_json::from_str(&v) {
+        Ok(tags) => tags,
+        Err(err) => {
+            log::error!("Invalid {}: {}", OPTION_PERMISSION_PROFILE_TAGS, err);
+            Default::default()
+        }
+    }
+}
+
+fn peer_tags(tags: &HashMap<String, Vec<String>>, peer_id: &str) -> Vec<String> {
+    tags.iter()
+        .filter(|(_, ids)| ids.iter().any(|p| glob_match(p, peer_id)))
+        .map(|(tag, _)| tag.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let profiles: Vec<PermissionProfile> = serde_json::from_str(
+            r#"[
+                {"name": "support", "tag": ["support"], "auth": ["public-key"],
+                 "keyboard": true, "file": true, "max_duration": 60},
+                {"name": "vendor", "peer_id": ["9*"], "keyboard": false, "clipboard": false,
+                 "port_forward": ["localhost:80", "10.0.0.*:22"]},
+                {"name": "view", "keyboard": false}
+            ]"#,
+        )
+        .unwrap();
+        let support = vec!["support".to_owned()];
+        let find = |peer_id, tags: &[String], auth| {
+            profiles
+                .iter()
+                .find(|p| p.matches(peer_id, tags, auth))
+                .map(|p| p.name.as_str())
+        };
+        assert_eq!(
+            find("123", &support, AuthMethod::PublicKey),
+            Some("support")
+        );
+        assert_eq!(find("123", &support, AuthMethod::Password), Some("view"));
+        assert_eq!(find("987", &support, AuthMethod::Click), Some("vendor"));
+        assert_eq!(find("123", &[], AuthMethod::Switch), Some("view"));
+        assert_eq!(profiles[0].keyboard, Some(true));
+        assert_eq!(profiles[0].clipboard, None);
+        assert!(profiles[0].allows_port_forward("localhost:3389"));
+        assert!(profiles[1].allows_port_forward("10.0.0.8:22"));
+        assert!(!profiles[1].allows_port_forward("10.0.0.8:23"));
+        assert!(!profiles[1].allows_port_forward("localhost:3389"));
+        assert!(profiles[0].grants(AuthMethod::PublicKey));
+        assert!(!profiles[1].grants(AuthMethod::Password));
+        assert!(profiles[2].grants(AuthMethod::Password));
+        let tags: HashMap<String, Vec<String>> =
+            serde_json::from_str(r#"{"support": ["12*"], "vendor": ["9*"]}"#).unwrap();
+        assert_eq!(peer_tags(&tags, "123"), support);
+        assert!(peer_tags(&tags, "456").is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+use std::net::{IpAddr, SocketAddr};
+
+use super::access_rules::glob_match;
+use cidr_utils::cidr::IpCidr;
+use hbb_common::{bail, config::Config, log, tokio::net::lookup_host, ResultType};
+use serde_derive::{Deserialize, Serialize};
+
+// json of `PortForwardPolicy`, any target is allowed without approval if it is empty
+pub const OPTION_PORT_FORWARD_POLICY: &str = "port-forward-policy";
+
+/// A target matches if both its hosts and its ports match, an empty list matches anything.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PortForwardTarget {
+    // CIDRs, ips or host name globs with `*` and `?`. A CIDR matches a host name if all the
+    // addresses it resolves to are in the CIDR.
+    #[serde(default)]
+    pub host: Vec<String>,
+    // ports or ranges, like `8000-8100`
+    #[serde(default)]
+    pub port: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PortForwardPolicy {
+    // the allowed targets, empty for any
+    #[serde(default)]
+    pub allow: Vec<PortForwardTarget>,
+    // every target is approved in the connection manager too, udp associations are refused
+    // because they are not opened per target
+    #[serde(default)]
+    pub approve: bool,
+    // the option is invalid, nothing is allowed
+    #[serde(skip)]
+    pub broken: bool,
+}
+
+impl PortForwardTarget {
+    fn matches(&self, host: &str, port: u16, ips: &[IpAddr]) -> bool {
+        (self.port.is_empty() || self.port.iter().any(|p| port_in_range(p, port)))
+            && (self.host.is_empty()
+                || self.host.iter().any(|h| match IpCidr::from_str(h) {
+                    Ok(cidr) => !ips.is_empty() && ips.iter().all(|ip| cidr.contains(*ip)),
+                    Err(_) => glob_match(h, host),
+                }))
+    }
+}
+
+impl PortForwardPolicy {
+    pub fn load() -> Self {
+        let policy = Config::get_option(OPTION_PORT_FORWARD_POLICY);
+        if policy.is_empty() {
+            return Self::default();
+        }
+        match serde_json::from_str(&policy) {
+            Ok(policy) => policy,
+            Err(err) => {
+                // fail closed like the access rules
+                log::error!(
+                    "Invalid {}, all port forward targets are denied: {}",
+                    OPTION_PORT_FORWARD_POLICY,
+                    err
+                );
+                Self {
+                    broken: true,
+                    ..Default::default()
+                }
+            }
+        }
+    }
+
+    pub fn allows(&self, host: &str, port: u16, ips: &[IpAddr]) -> bool {
+        !self.broken
+            && (self.allow.is_empty() || self.allow.iter().any(|t| t.matches(host, port, ips)))
+    }
+
+    /// Resolves the target and checks it with the addresses, which are returned to connect to,
+    /// so that a host name can not resolve to another address after it is checked.
+    /// `None` if the target is not allowed.
+    pub async fn resolve_allowed(
+        &self,
+        host: &str,
+        port: i32,
+    ) -> ResultType<Option<Vec<SocketAddr>>> {
+        if port <= 0 || port > u16::MAX as i32 {
+            bail!("Invalid port {}", port);
+        }
+        if self.broken {
+            return Ok(None);
+        }
+        let addrs: Vec<SocketAddr> = lookup_host((host, port as u16)).await?.collect();
+        let ips: Vec<IpAddr> = addrs.iter().map(|a| a.ip()).collect();
+        if self.allows(host, port as u16, &ips) {
+            Ok(Some(addrs))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+fn port_in_range(range: &str, port: u16) -> bool {
+    let (start, end) = range.split_once('-').unwrap_or((range, range));
+    match (start.trim().parse::<u16>(), end.trim().parse::<u16>()) {
+        (Ok(start), Ok(end)) => start <= port && port <= end,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allows() {
+        let policy: PortForwardPolicy = serde_json::from_str(
+            r#"{
+                "allow": [
+                    {"host": ["10.0.0.0/8"], "port": ["22", "8000-8100"]},
+                    {"host": ["*.corp.example"]},
+                    {"host": ["localhost"], "port": ["3389"]}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let ip = |s: &str| vec![s.parse::<IpAddr>().unwrap()];
+        assert!(policy.allows("10.1.2.3", 22, &ip("10.1.2.3")));
+        assert!(policy.allows("10.1.2.3", 8080, &ip("10.1.2.3")));
+        assert!(!policy.allows("10.1.2.3", 80, &ip("10.1.2.3")));
+        // resolved to the allowed network
+        assert!(policy.allows("build", 22, &ip("10.0.0.7")));
+        // resolved out of it
+        assert!(!policy.allows("build", 22, &ip("192.168.1.7")));
+        assert!(!policy.allows("build", 22, &[]));
+        assert!(policy.allows("git.corp.example", 443, &ip("192.168.1.7")));
+        assert!(policy.allows("LOCALHOST", 3389, &ip("127.0.0.1")));
+        assert!(!policy.allows("localhost", 3390, &ip("127.0.0.1")));
+
+        assert!(PortForwardPolicy::default().allows("any", 1, &[]));
+        let broken = PortForwardPolicy {
+            broken: true,
+            ..Default::default()
+        };
+        assert!(!broken.allows("any", 1, &[]));
+    }
+
+    #[test]
+    fn test_port_in_range() {
+        assert!(port_in_range("22", 22));
+        assert!(port_in_range("8000-8100", 8000));
+        assert!(port_in_range("8000-8100", 8100));
+        assert!(!port_in_range("8000-8100", 8101));
+        assert!(!port_in_range("ssh", 22));
+    }
+}
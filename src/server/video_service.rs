@@ -75,6 +75,25 @@ lazy_static::lazy_static! {
     pub static ref VIDEO_QOS: Arc<Mutex<VideoQoS>> = Default::default();
     pub static ref IS_UAC_RUNNING: Arc<Mutex<bool>> = Default::default();
     pub static ref IS_FOREGROUND_WINDOW_ELEVATED: Arc<Mutex<bool>> = Default::default();
+    // recorders of incoming sessions, and the audio format they record with
+    static ref RECORDERS: Mutex<Vec<std::sync::Weak<Mutex<Option<Recorder>>>>> = Default::default();
+    static ref RECORD_AUDIO_FORMAT: Mutex<Option<AudioFormat>> = Default::default();
+}
+
+/// Write the audio message of `audio_service` to the records of incoming sessions.
+pub fn record_audio(msg: &Message) {
+    if let Some(message::Union::Misc(misc)) = &msg.union {
+        if let Some(misc::Union::AudioFormat(f)) = &misc.union {
+            *RECORD_AUDIO_FORMAT.lock().unwrap() = Some(f.clone());
+        }
+    }
+    let mut recorders = RECORDERS.lock().unwrap();
+    recorders.retain(|r| r.strong_count() > 0);
+    for r in recorders.iter().filter_map(|r| r.upgrade()) {
+        if let Some(r) = r.lock().unwrap().as_mut() {
+            r.write_message(msg);
+        }
+    }
 }
 
 #[inline]
@@ -766,7 +785,7 @@ fn get_recorder(
         } else {
             None
         };
-        Recorder::new(RecorderContext {
+        match Recorder::new(RecorderContext {
             server: true,
             id: Config::get_id(),
             dir: crate::ui_interface::video_save_directory(root),
@@ -774,16 +793,39 @@ fn get_recorder(
             width,
             height,
             format: codec_format.clone(),
+            audio: RECORD_AUDIO_FORMAT.lock().unwrap().clone(),
             tx,
-        })
-        .map_or(Default::default(), |r| Arc::new(Mutex::new(Some(r))))
+        }) {
+            Ok(r) => Arc::new(Mutex::new(Some(r))),
+            Err(e) => {
+                log::error!("Failed to record: {}", e);
+                Default::default()
+            }
+        }
     } else {
         Default::default()
     };
+    if recorder.lock().unwrap().is_some() {
+        RECORDERS.lock().unwrap().push(Arc::downgrade(&recorder));
+    }
 
     recorder
 }
 
+/// The retention policy of recordings is applied when a recording finishes, and periodically
+/// here for the ones which expire while nothing is recorded.
+pub fn start_record_retention() {
+    #[cfg(windows)]
+    let root = crate::platform::is_root();
+    #[cfg(not(windows))]
+    let root = false;
+    std::thread::spawn(move || loop {
+        let dir = crate::ui_interface::video_save_directory(root);
+        allow_err!(scrap::record_store::enforce_retention(&dir));
+        std::thread::sleep(Duration::from_secs(3600));
+    });
+}
+
 fn check_privacy_mode_changed(sp: &GenericService, privacy_mode_id: i32) -> ResultType<()> {
     let privacy_mode_id_2 = get_privacy_mode_conn_id().unwrap_or(INVALID_PRIVACY_MODE_CONN_ID);
     if privacy_mode_id != privacy_mode_id_2 {
@@ -1,9 +1,6 @@
 use crate::client::translate;
-#[cfg(windows)]
 use crate::ipc::Data;
-#[cfg(windows)]
-use hbb_common::tokio;
-use hbb_common::{allow_err, log};
+use hbb_common::{allow_err, log, tokio};
 use std::sync::{Arc, Mutex};
 #[cfg(windows)]
 use std::time::Duration;
@@ -46,7 +43,17 @@ pub fn make_tray() -> hbb_common::ResultType<()> {
     let tray_menu = Menu::new();
     let quit_i = MenuItem::new(translate("Exit".to_owned()), true, None);
     let open_i = MenuItem::new(translate("Open".to_owned()), true, None);
-    tray_menu.append_items(&[&open_i, &quit_i]).ok();
+    let password_text = |password: &str| {
+        format!(
+            "{}: {}",
+            translate("One-time Password".to_owned()),
+            if password.is_empty() { "-" } else { password }
+        )
+    };
+    let password_i = MenuItem::new(password_text(""), false, None);
+    tray_menu
+        .append_items(&[&password_i, &open_i, &quit_i])
+        .ok();
     let tooltip = |count: usize| {
         if count == 0 {
             format!(
@@ -108,6 +115,10 @@ pub fn make_tray() -> hbb_common::ResultType<()> {
     std::thread::spawn(move || {
         start_query_session_count(ipc_sender.clone());
     });
+    let (password_sender, password_receiver) = std::sync::mpsc::channel::<String>();
+    std::thread::spawn(move || {
+        start_query_temporary_password(password_sender);
+    });
     #[cfg(windows)]
     let mut last_click = std::time::Instant::now();
     event_loop.run(move |_event, _, control_flow| {
@@ -149,6 +160,10 @@ pub fn make_tray() -> hbb_common::ResultType<()> {
             }
         }
 
+        if let Ok(password) = password_receiver.try_recv() {
+            password_i.set_text(password_text(&password));
+        }
+
         #[cfg(windows)]
         if let Ok(data) = ipc_receiver.try_recv() {
             match data {
@@ -201,6 +216,36 @@ async fn start_query_session_count(sender: std::sync::mpsc::Sender<Data>) {
     }
 }
 
+// The temporary password of the service, which sends it again when it changes.
+#[tokio::main(flavor = "current_thread")]
+async fn start_query_temporary_password(sender: std::sync::mpsc::Sender<String>) {
+    loop {
+        if let Ok(mut c) = crate::ipc::connect(1000, "").await {
+            if c.send(&Data::WatchTemporaryPassword(None)).await.is_ok() {
+                loop {
+                    match c.next().await {
+                        Err(err) => {
+                            log::error!("ipc connection closed: {}", err);
+                            break;
+                        }
+                        Ok(Some(Data::WatchTemporaryPassword(Some(info)))) => {
+                            if sender.send(info.password).is_err() {
+                                return;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        // unknown until the service is reached again
+        if sender.send("".to_owned()).is_err() {
+            return;
+        }
+        hbb_common::sleep(5.).await;
+    }
+}
+
 fn load_icon_from_asset() -> Option<image::DynamicImage> {
     let Some(path) = std::env::current_exe().map_or(None, |x| x.parent().map(|x| x.to_path_buf()))
     else {
@@ -65,6 +65,14 @@ impl InvokeUiCM for SciterHandler {
     }
 
     fn file_transfer_log(&self, _action: &str, _log: &str) {}
+
+    fn reverse_forward_request(&self, id: i32, listen_id: i32, port: i32) {
+        self.call("reverseForwardRequest", &make_args!(id, listen_id, port));
+    }
+
+    fn port_forward_request(&self, id: i32, stream_id: i32, target: &str) {
+        self.call("portForwardRequest", &make_args!(id, stream_id, target));
+    }
 }
 
 impl SciterHandler {
@@ -134,6 +142,14 @@ impl SciterConnectionManager {
         crate::ui_cm_interface::send_chat(id, text);
     }
 
+    fn reverse_forward_response(&self, id: i32, listen_id: i32, accepted: bool) {
+        crate::ui_cm_interface::reverse_forward_response(id, listen_id, accepted);
+    }
+
+    fn port_forward_response(&self, id: i32, stream_id: i32, accepted: bool) {
+        crate::ui_cm_interface::port_forward_response(id, stream_id, accepted);
+    }
+
     fn t(&self, name: String) -> String {
         crate::client::translate(name)
     }
@@ -167,6 +183,8 @@ impl sciter::EventHandler for SciterConnectionManager {
         fn authorize(i32);
         fn switch_permission(i32, String, bool);
         fn send_msg(i32, String);
+        fn reverse_forward_response(i32, i32, bool);
+        fn port_forward_response(i32, i32, bool);
         fn can_elevate();
         fn elevate_portable(i32);
         fn get_option(String);
@@ -177,7 +177,15 @@ impl InvokeUiSession for SciterHandler {
             }
             self.call(
                 "addJob",
-                &make_args!(cnt, path, to, job.file_num, job.show_hidden, job.is_remote),
+                &make_args!(
+                    cnt,
+                    path,
+                    to,
+                    job.file_num,
+                    job.show_hidden,
+                    job.is_remote,
+                    job.preserve_attributes
+                ),
             );
         }
     }
@@ -217,6 +225,10 @@ impl InvokeUiSession for SciterHandler {
         );
     }
 
+    fn update_mirror_plan(&self, id: i32, plan: &str) {
+        self.call("updateMirrorPlan", &make_args!(id, plan));
+    }
+
     fn job_progress(&self, id: i32, file_num: i32, speed: f64, finished_size: f64) {
         self.call(
             "jobProgress",
@@ -457,9 +469,11 @@ impl sciter::EventHandler for SciterSession {
         fn confirm_delete_files(i32, i32);
         fn set_no_confirm(i32);
         fn cancel_job(i32);
-        fn send_files(i32, String, String, i32, bool, bool);
-        fn add_job(i32, String, String, i32, bool, bool);
+        fn send_files(i32, String, String, i32, bool, bool, bool);
+        fn add_job(i32, String, String, i32, bool, bool, bool);
         fn resume_job(i32, bool);
+        fn mirror_dir(i32, String, String, bool, bool);
+        fn start_mirror(i32, bool);
         fn get_platform(bool);
         fn get_path_sep(bool);
         fn get_icon_path(i32, String);
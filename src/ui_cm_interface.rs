@@ -103,6 +103,10 @@ pub trait InvokeUiCM: Send + Clone + 'static + Sized {
     fn update_voice_call_state(&self, client: &Client);
 
     fn file_transfer_log(&self, action: &str, log: &str);
+
+    fn reverse_forward_request(&self, id: i32, listen_id: i32, port: i32);
+
+    fn port_forward_request(&self, id: i32, stream_id: i32, target: &str);
 }
 
 impl<T: InvokeUiCM> Deref for ConnectionManager<T> {
@@ -302,6 +306,26 @@ pub fn switch_permission(id: i32, name: String, enabled: bool) {
     };
 }
 
+#[inline]
+#[cfg(not(any(target_os = "ios")))]
+pub fn reverse_forward_response(id: i32, listen_id: i32, accepted: bool) {
+    if let Some(client) = CLIENTS.read().unwrap().get(&id) {
+        allow_err!(client
+            .tx
+            .send(Data::ReverseForwardResponse((listen_id, accepted))));
+    };
+}
+
+#[inline]
+#[cfg(not(any(target_os = "ios")))]
+pub fn port_forward_response(id: i32, stream_id: i32, accepted: bool) {
+    if let Some(client) = CLIENTS.read().unwrap().get(&id) {
+        allow_err!(client
+            .tx
+            .send(Data::PortForwardResponse((stream_id, accepted))));
+    };
+}
+
 #[cfg(any(target_os = "android", target_os = "ios", feature = "flutter"))]
 #[inline]
 pub fn get_clients_state() -> String {
@@ -477,6 +501,12 @@ impl<T: InvokeUiCM> IpcTaskRunner<T> {
                                 Data::CloseVoiceCall(reason) => {
                                     self.cm.voice_call_closed(self.conn_id, reason.as_str());
                                 }
+                                Data::ReverseForwardRequest((listen_id, port)) => {
+                                    self.cm.reverse_forward_request(self.conn_id, listen_id, port);
+                                }
+                                Data::PortForwardRequest((stream_id, target)) => {
+                                    self.cm.port_forward_request(self.conn_id, stream_id, &target);
+                                }
                                 _ => {
 
                                 }
@@ -533,6 +563,10 @@ impl<T: InvokeUiCM> IpcTaskRunner<T> {
                 }
             }
         }
+        // the done messages of the unfinished jobs never come
+        for job in write_jobs.iter_mut() {
+            job.rename_written_files();
+        }
     }
 
     async fn ipc_task(stream: Connection, cm: ConnectionManager<T>) {
@@ -662,6 +696,10 @@ pub async fn start_listen<T: InvokeUiCM>(
             _ => {}
         }
     }
+    // the done messages of the unfinished jobs never come
+    for job in write_jobs.iter_mut() {
+        job.rename_written_files();
+    }
     cm.remove_connection(current_id, true);
 }
 
@@ -702,6 +740,8 @@ async fn handle_fs(
             overwrite_detection,
             total_size,
             conn_id,
+            preserve_attributes,
+            attributes,
         } => {
             // cm has no show_hidden context
             // dummy remote, show_hidden, is_remote
@@ -714,14 +754,20 @@ async fn handle_fs(
                 false,
                 files
                     .drain(..)
-                    .map(|f| FileEntry {
+                    .enumerate()
+                    .map(|(i, f)| FileEntry {
                         name: f.0,
                         modified_time: f.1,
+                        attributes: attributes
+                            .get(i)
+                            .and_then(|a| FileAttributes::parse_from_bytes(a).ok())
+                            .into(),
                         ..Default::default()
                     })
                     .collect(),
                 overwrite_detection,
             );
+            job.set_preserve_attributes(preserve_attributes);
             job.total_size = total_size;
             job.conn_id = conn_id;
             write_jobs.push(job);
@@ -729,22 +775,52 @@ async fn handle_fs(
         ipc::FS::CancelWrite { id } => {
             if let Some(job) = fs::get_job(id, write_jobs) {
                 job.remove_download_file();
+                job.rename_written_files();
                 tx_log.map(|tx: &UnboundedSender<String>| {
                     tx.send(serialize_transfer_job(job, false, true, ""))
                 });
                 fs::remove_job(id, write_jobs);
             }
         }
-        ipc::FS::WriteDone { id, file_num } => {
+        ipc::FS::WriteDone {
+            id,
+            file_num,
+            hashes,
+        } => {
             if let Some(job) = fs::get_job(id, write_jobs) {
-                job.modify_time();
-                send_raw(fs::new_done(id, file_num), tx);
-                tx_log.map(|tx| tx.send(serialize_transfer_job(job, true, false, "")));
+                let hashes: Vec<_> = hashes
+                    .into_iter()
+                    .map(|(file_num, hash)| FileTransferHash {
+                        file_num,
+                        hash,
+                        ..Default::default()
+                    })
+                    .collect();
+                job.finish(&hashes).await;
+                if job.failed_files().is_empty() {
+                    job.create_links();
+                    send_raw(fs::new_done(id, file_num), tx);
+                    tx_log.map(|tx| tx.send(serialize_transfer_job(job, true, false, "")));
+                } else {
+                    // the client sends these files again
+                    for file_num in job.failed_files() {
+                        send_raw(fs::new_error(id, fs::ERR_HASH_MISMATCH, *file_num), tx);
+                    }
+                    tx_log.map(|tx| {
+                        tx.send(serialize_transfer_job(
+                            job,
+                            false,
+                            false,
+                            fs::ERR_HASH_MISMATCH,
+                        ))
+                    });
+                }
                 fs::remove_job(id, write_jobs);
             }
         }
         ipc::FS::WriteError { id, file_num, err } => {
             if let Some(job) = fs::get_job(id, write_jobs) {
+                job.rename_written_files();
                 tx_log.map(|tx| tx.send(serialize_transfer_job(job, false, false, &err)));
                 send_raw(fs::new_error(job.id(), err, file_num), tx);
                 fs::remove_job(job.id(), write_jobs);
@@ -771,6 +847,32 @@ async fn handle_fs(
                 }
             }
         }
+        ipc::FS::WriteCopy {
+            id,
+            file_num,
+            offset,
+            length,
+        } => {
+            if let Some(job) = fs::get_job(id, write_jobs) {
+                if let Err(err) = job
+                    .write(FileTransferBlock {
+                        id,
+                        file_num,
+                        copy_from: hbb_common::protobuf::MessageField::some(
+                            FileTransferBlockCopy {
+                                offset,
+                                length,
+                                ..Default::default()
+                            },
+                        ),
+                        ..Default::default()
+                    })
+                    .await
+                {
+                    send_raw(fs::new_error(id, err, file_num), &tx);
+                }
+            }
+        }
         ipc::FS::CheckDigest {
             id,
             file_num,
@@ -805,6 +907,8 @@ async fn handle_fs(
                                 DigestCheckResult::NeedConfirm(mut digest) => {
                                     // upload to server, but server has the same file, request
                                     digest.is_upload = is_upload;
+                                    digest.signatures =
+                                        job.local_block_signatures(file_num).await.into();
                                     let mut msg_out = Message::new();
                                     let mut fr = FileResponse::new();
                                     fr.set_digest(digest);
@@ -969,3 +1073,70 @@ pub fn close_voice_call(id: i32) {
         allow_err!(client.tx.send(Data::CloseVoiceCall("".to_owned())));
     };
 }
+
+#[cfg(test)]
+#[cfg(not(any(target_os = "ios")))]
+mod tests {
+    use super::*;
+    use hbb_common::bytes::Bytes;
+    use sha2::{Digest, Sha256};
+
+    // the blocks go over ipc as json, like from the connection to the cm
+    fn over_ipc(fs: ipc::FS) -> ipc::FS {
+        let data = serde_json::to_string(&Data::FS(fs)).unwrap();
+        match serde_json::from_str::<Data>(&data).unwrap() {
+            Data::FS(fs) => fs,
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_block_over_ipc() {
+        let dir = std::env::temp_dir().join(format!("cm_hash_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut write_jobs = Vec::new();
+        let id = 1;
+        for fs in [
+            ipc::FS::NewWrite {
+                path: dir.to_string_lossy().to_string(),
+                id,
+                file_num: 0,
+                files: vec![("a.txt".to_owned(), 0)],
+                overwrite_detection: false,
+                total_size: 5,
+                conn_id: 0,
+                preserve_attributes: false,
+                attributes: Vec::new(),
+            },
+            ipc::FS::WriteBlock {
+                id,
+                file_num: 0,
+                data: Bytes::from_static(b"hellp"),
+                compressed: false,
+            },
+            ipc::FS::WriteDone {
+                id,
+                file_num: 0,
+                hashes: vec![(0, Sha256::digest(b"hello").to_vec().into())],
+            },
+        ] {
+            handle_fs(over_ipc(fs), &mut write_jobs, &tx, None).await;
+        }
+        let mut mismatched = false;
+        while let Ok(Data::RawMessage(bytes)) = rx.try_recv() {
+            let msg = Message::parse_from_bytes(&bytes).unwrap();
+            if let Some(message::Union::FileResponse(FileResponse {
+                union: Some(file_response::Union::Error(err)),
+                ..
+            })) = msg.union
+            {
+                mismatched |= err.error == fs::ERR_HASH_MISMATCH && err.file_num == 0;
+            }
+        }
+        assert!(mismatched);
+        assert!(!dir.join("a.txt").exists());
+        assert!(!dir.join("a.txt.download").exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
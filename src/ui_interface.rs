@@ -1373,6 +1373,58 @@ pub fn verify2fa(code: String) -> bool {
     res
 }
 
+pub fn generate2fa_device(
+    name: String,
+    algorithm: String,
+    digits: usize,
+    period: u64,
+    hotp: bool,
+) -> String {
+    crate::auth_2fa::generate2fa_device(name, algorithm, digits, period, hotp)
+}
+
+pub fn list_2fa_devices() -> String {
+    serde_json::to_string(&crate::auth_2fa::list_2fa_devices()).unwrap_or_default()
+}
+
+pub fn revoke_2fa_device(name: String) -> bool {
+    let res = crate::auth_2fa::revoke_2fa_device(&name);
+    if res {
+        refresh_options();
+    }
+    res
+}
+
+pub fn generate_2fa_recovery_codes() -> Vec<String> {
+    let res = crate::auth_2fa::generate_2fa_recovery_codes();
+    if !res.is_empty() {
+        refresh_options();
+    }
+    res
+}
+
+pub fn count_2fa_recovery_codes() -> usize {
+    crate::auth_2fa::count_2fa_recovery_codes()
+}
+
+pub fn gen_auth_key() -> String {
+    match crate::auth_pk::gen_auth_key() {
+        Ok(pk) => pk,
+        Err(e) => {
+            log::error!("Failed to generate the auth key: {}", e);
+            "".to_owned()
+        }
+    }
+}
+
+pub fn get_auth_public_key() -> String {
+    crate::auth_pk::get_auth_public_key()
+}
+
+pub fn remove_auth_key() {
+    allow_err!(crate::auth_pk::set_auth_key(""));
+}
+
 pub fn check_hwcodec() {
     #[cfg(feature = "hwcodec")]
     #[cfg(any(target_os = "windows", target_os = "linux"))]
@@ -543,6 +543,44 @@ impl<T: InvokeUiSession> Session<T> {
         self.send(Data::AddPortForward(pf));
     }
 
+    pub fn get_port_forward_profiles(&self) -> HashMap<String, Vec<(i32, String, i32)>> {
+        self.lc.read().unwrap().port_forward_profiles.clone()
+    }
+
+    // An empty `forwards` removes the profile.
+    pub fn set_port_forward_profile(&self, name: String, forwards: Vec<(i32, String, i32)>) {
+        let mut config = self.load_config();
+        if forwards.is_empty() {
+            config.port_forward_profiles.remove(&name);
+        } else {
+            config.port_forward_profiles.insert(name, forwards);
+        }
+        self.save_config(config);
+    }
+
+    pub fn get_reverse_port_forwards(&self) -> HashMap<String, Vec<(i32, String, i32)>> {
+        self.lc.read().unwrap().reverse_port_forwards.clone()
+    }
+
+    // Reverse forwards of a profile, an empty `forwards` removes them.
+    pub fn set_reverse_port_forwards(&self, name: String, forwards: Vec<(i32, String, i32)>) {
+        let mut config = self.load_config();
+        if forwards.is_empty() {
+            config.reverse_port_forwards.remove(&name);
+        } else {
+            config.reverse_port_forwards.insert(name, forwards);
+        }
+        self.save_config(config);
+    }
+
+    pub fn start_port_forward_profile(&self, name: String) {
+        self.send(Data::StartPortForwardProfile(name));
+    }
+
+    pub fn stop_port_forward_profile(&self, name: String) {
+        self.send(Data::StopPortForwardProfile(name));
+    }
+
     pub fn get_option(&self, k: String) -> String {
         if k.eq("remote_dir") {
             return self.lc.read().unwrap().get_remote_dir();
@@ -1366,6 +1404,7 @@ pub trait InvokeUiSession: Send + Sync + Clone + 'static + Sized + Default {
         is_upload: bool,
         is_identical: bool,
     );
+    fn update_mirror_plan(&self, id: i32, plan: &str);
     fn update_block_input_state(&self, on: bool);
     fn job_progress(&self, id: i32, file_num: i32, speed: f64, finished_size: f64);
     fn adapt_size(&self);
@@ -1612,9 +1651,13 @@ pub async fn io_loop<T: InvokeUiSession>(handler: Session<T>, round: u32) {
             );
             log::info!("Remote rdp port: {}", port);
             start_one_port_forward(handler, 0, "".to_owned(), port, receiver, &key, &token).await;
+        } else if handler.args.len() == 2 && handler.args[0] == "--profile" {
+            let name = handler.args[1].clone();
+            start_port_forward_profile(handler, name, receiver, &key, &token).await;
         } else if handler.args.len() == 0 {
             let pfs = handler.lc.read().unwrap().port_forwards.clone();
             let mut queues = HashMap::<i32, mpsc::UnboundedSender<Data>>::new();
+            let mut profiles = HashMap::<String, mpsc::UnboundedSender<Data>>::new();
             for d in pfs {
                 sender.send(Data::AddPortForward(d)).ok();
             }
@@ -1647,11 +1690,32 @@ pub async fn io_loop<T: InvokeUiSession>(handler: Session<T>, round: u32) {
                             s.send(Data::Close).ok();
                         }
                     }
+                    Some(Data::StartPortForwardProfile(name)) => {
+                        if profiles.contains_key(&name) {
+                            continue;
+                        }
+                        let (sender, receiver) = mpsc::unbounded_channel::<Data>();
+                        profiles.insert(name.clone(), sender);
+                        let handler = handler.clone();
+                        let key = key.clone();
+                        let token = token.clone();
+                        tokio::spawn(async move {
+                            start_port_forward_profile(handler, name, receiver, &key, &token).await;
+                        });
+                    }
+                    Some(Data::StopPortForwardProfile(name)) => {
+                        if let Some(s) = profiles.remove(&name) {
+                            s.send(Data::Close).ok();
+                        }
+                    }
                     Some(Data::Close) => {
+                        for s in profiles.values() {
+                            s.send(Data::Close).ok();
+                        }
                         break;
                     }
                     Some(d) => {
-                        for (_, s) in queues.iter() {
+                        for s in queues.values().chain(profiles.values()) {
                             s.send(d.clone()).ok();
                         }
                     }
@@ -1748,6 +1812,48 @@ async fn start_one_port_forward<T: InvokeUiSession>(
     log::info!("port forward (:{}) exit", port);
 }
 
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+async fn start_port_forward_profile<T: InvokeUiSession>(
+    handler: Session<T>,
+    name: String,
+    receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+) {
+    let (forwards, reverse_forwards) = {
+        let lc = handler.lc.read().unwrap();
+        (
+            lc.port_forward_profiles
+                .get(&name)
+                .cloned()
+                .unwrap_or_default(),
+            lc.reverse_port_forwards
+                .get(&name)
+                .cloned()
+                .unwrap_or_default(),
+        )
+    };
+    if let Err(err) = crate::port_forward::listen_multiplex(
+        handler.get_id(),
+        handler.password.clone(),
+        forwards,
+        reverse_forwards,
+        handler.clone(),
+        receiver,
+        key,
+        token,
+        handler.lc.clone(),
+    )
+    .await
+    {
+        handler.on_error(&format!(
+            "Failed to start port forward profile {}: {}",
+            name, err
+        ));
+    }
+    log::info!("port forward profile ({}) exit", name);
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn send_note(url: String, id: String, sid: u64, note: String) {
     let body = serde_json::json!({ "id": id, "session_id": sid, "note": note });